@@ -0,0 +1,33 @@
+CREATE TABLE users (
+    name TEXT PRIMARY KEY NOT NULL,
+    email TEXT,
+    password TEXT NOT NULL,
+    role_id INTEGER NOT NULL,
+    -- serialized UserSource
+    source TEXT NOT NULL
+);
+
+CREATE TABLE mqtt_clients (
+    name TEXT PRIMARY KEY NOT NULL,
+    url TEXT NOT NULL
+);
+
+CREATE TABLE topics (
+    client TEXT NOT NULL,
+    topic TEXT NOT NULL,
+    PRIMARY KEY (client, topic)
+);
+
+CREATE TABLE history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    client TEXT NOT NULL,
+    -- unix timestamp in milliseconds
+    timestamp INTEGER NOT NULL,
+    topic TEXT NOT NULL,
+    payload BLOB NOT NULL,
+    qos INTEGER NOT NULL,
+    retain INTEGER NOT NULL,
+    tag TEXT
+);
+
+CREATE INDEX history_client ON history (client, id);
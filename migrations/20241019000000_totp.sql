@@ -0,0 +1,7 @@
+-- serialized TotpConfig of users with a second factor
+ALTER TABLE users ADD COLUMN totp TEXT;
+
+CREATE TABLE settings (
+    key TEXT PRIMARY KEY NOT NULL,
+    value TEXT NOT NULL
+);
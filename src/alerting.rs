@@ -0,0 +1,353 @@
+use std::time::Duration;
+
+use actix_web::http::header::CONTENT_TYPE;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use serde::Serialize;
+
+use crate::{
+    models::{
+        alert_definition::{AlertCondition, AlertDefinition, Notification, NotificationKind},
+        user::User,
+    },
+    mqtt::{MqttClientManager, MqttError},
+    settings::{SmtpSecurity, SmtpSettings},
+    storage::StorageError,
+    webhook_delivery,
+};
+
+const TICK: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sends the notifications of the alerts over their channels.
+#[derive(Clone)]
+pub struct Notifier {
+    pool: crate::DbPool,
+    repo: crate::Repo,
+    http: reqwest::Client,
+    mailer: Option<(AsyncSmtpTransport<Tokio1Executor>, Mailbox)>,
+    /// url of the alerts page linked in emails
+    alerts_url: String,
+    max_len: usize,
+}
+
+/// JSON body sent to the webhook of an alert.
+#[derive(Serialize, Debug)]
+struct AlertEnvelope<'a> {
+    alert: &'a str,
+    client: &'a str,
+    kind: NotificationKind,
+    message: &'a str,
+    /// RFC 3339 time of the notification
+    timestamp: String,
+}
+
+fn mailer(
+    settings: &SmtpSettings,
+) -> anyhow::Result<Option<(AsyncSmtpTransport<Tokio1Executor>, Mailbox)>> {
+    let Some(host) = &settings.host else {
+        return Ok(None);
+    };
+    let mut transport = match settings.security {
+        SmtpSecurity::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?,
+        SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host)?,
+        SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+    }
+    .port(settings.port)
+    .timeout(Some(REQUEST_TIMEOUT));
+    if let Some((username, password)) = settings.username.clone().zip(settings.password.clone()) {
+        transport = transport.credentials(Credentials::new(username, password));
+    }
+    Ok(Some((transport.build(), settings.from.parse()?)))
+}
+
+impl Notifier {
+    pub fn new(
+        pool: crate::DbPool,
+        repo: crate::Repo,
+        smtp: &SmtpSettings,
+        base_url: &str,
+        max_len: usize,
+    ) -> anyhow::Result<Self> {
+        Ok(Notifier {
+            pool,
+            repo,
+            http: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()?,
+            mailer: mailer(smtp)?,
+            alerts_url: format!("{base_url}/alerts/"),
+            max_len,
+        })
+    }
+
+    /// Deliver `notification` of `alert`, failing channels only get logged.
+    pub async fn send(&self, alert: &AlertDefinition, notification: &Notification) {
+        if alert.channels.in_app {
+            if let Err(e) = notification
+                .insert(&self.pool, &alert.owner, self.max_len)
+                .await
+            {
+                log::error!("Alert {}: cannot store notification: {}", alert.name, e);
+            }
+        }
+        if alert.channels.email {
+            if let Err(e) = self.send_email(alert, notification).await {
+                log::error!("Alert {}: cannot send email: {:#}", alert.name, e);
+            }
+        }
+        if let Some(url) = &alert.channels.webhook {
+            if let Err(e) = self.send_webhook(alert, url, notification).await {
+                log::error!("Alert {}: webhook {} failed: {:#}", alert.name, url, e);
+            }
+        }
+    }
+
+    async fn send_email(
+        &self,
+        alert: &AlertDefinition,
+        notification: &Notification,
+    ) -> anyhow::Result<()> {
+        let Some((mailer, from)) = &self.mailer else {
+            anyhow::bail!("no smtp host is configured");
+        };
+        let Some(email) = User::get_by_name(&self.repo, &alert.owner)
+            .await?
+            .and_then(|user| user.email)
+        else {
+            anyhow::bail!("user {} has no email address", alert.owner);
+        };
+        let message = Message::builder()
+            .from(from.clone())
+            .to(email.parse()?)
+            .subject(format!("[MQTTPal] {}", notification.message))
+            .body(format!(
+                "{}\n\nAlert: {}\nClient: {}\nCondition: {}\nTime: {} UTC\n\n{}\n",
+                notification.message,
+                alert.name,
+                alert.client,
+                alert.condition,
+                notification.time(),
+                self.alerts_url
+            ))?;
+        mailer.send(message).await?;
+        Ok(())
+    }
+
+    async fn send_webhook(
+        &self,
+        alert: &AlertDefinition,
+        url: &str,
+        notification: &Notification,
+    ) -> anyhow::Result<()> {
+        let envelope = AlertEnvelope {
+            alert: &alert.name,
+            client: &alert.client,
+            kind: notification.kind,
+            message: &notification.message,
+            timestamp: chrono::DateTime::from_timestamp_millis(notification.timestamp)
+                .unwrap_or_default()
+                .to_rfc3339(),
+        };
+        let body = serde_json::to_vec(&envelope)?;
+        let mut request = self
+            .http
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Mqttpal-Alert", &alert.name);
+        if let Some(secret) = &alert.channels.webhook_secret {
+            request = request.header(
+                "X-Mqttpal-Signature",
+                format!("sha256={}", webhook_delivery::sign(secret, &body)),
+            );
+        }
+        request.body(body).send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Background task evaluating the alerts and sending their notifications.
+pub async fn run(mqtt: MqttClientManager, notifier: Notifier) {
+    loop {
+        // run each tick on its own task so a failing redis does not end the evaluation
+        let tick = tokio::spawn(evaluate(mqtt.clone(), notifier.clone()));
+        if let Err(e) = tick.await {
+            log::error!("Alert evaluation failed: {e}");
+        }
+        tokio::time::sleep(TICK).await;
+    }
+}
+
+async fn evaluate(mqtt: MqttClientManager, notifier: Notifier) {
+    if let Err(e) = evaluate_alerts(&mqtt, &notifier).await {
+        log::error!("Cannot evaluate alerts: {e}");
+    }
+}
+
+/// Whether the condition of `alert` holds, `None` if that is not known yet.
+async fn active(mqtt: &MqttClientManager, alert: &AlertDefinition, now: i64) -> Option<bool> {
+    let observation = mqtt.alert_observation(&alert.name)?;
+    match &alert.condition {
+        AlertCondition::Silent { minutes, .. } => {
+            let last = observation.last_message?;
+            Some(now - last >= *minutes as i64 * 60 * 1000)
+        }
+        AlertCondition::Threshold { .. } => observation.breached,
+        AlertCondition::Disconnected => match mqtt.connected(&alert.client).await {
+            Ok(connected) => Some(!connected),
+            Err(MqttError::UnknownClient(_)) => Some(true),
+            Err(_) => None,
+        },
+    }
+}
+
+async fn evaluate_alerts(
+    mqtt: &MqttClientManager,
+    notifier: &Notifier,
+) -> Result<(), StorageError> {
+    let now = chrono::Utc::now().timestamp_millis();
+    let alerts = AlertDefinition::list(&notifier.pool).await?;
+    let mut states = AlertDefinition::states(&notifier.pool).await?;
+    for alert in alerts {
+        let Some(active) = active(mqtt, &alert, now).await else {
+            continue;
+        };
+        let mut state = states.remove(&alert.name).unwrap_or_default();
+        let before = state.clone();
+        let kind = state.update(active, now, alert.hold as i64 * 1000);
+        if state != before {
+            AlertDefinition::save_state(&notifier.pool, &alert.name, &state).await?;
+        }
+        let Some(kind) = kind else {
+            continue;
+        };
+        let message = match kind {
+            NotificationKind::Firing => format!("{} is firing: {}", alert.name, alert.condition),
+            NotificationKind::Resolved => format!("{} is resolved", alert.name),
+            NotificationKind::Flapping => format!(
+                "{} is flapping, further changes are not notified until it settles",
+                alert.name
+            ),
+        };
+        log::warn!("Alert {} of {}: {}", alert.name, alert.owner, message);
+        if alert.silenced(now) {
+            log::info!("Alert {} is silenced, not notifying", alert.name);
+            continue;
+        }
+        let notification = Notification {
+            timestamp: now,
+            alert: alert.name.clone(),
+            client: alert.client.clone(),
+            kind,
+            message,
+        };
+        // a slow mail server must not hold up the other alerts
+        let notifier = notifier.clone();
+        tokio::spawn(async move { notifier.send(&alert, &notification).await });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        models::alert_definition::AlertChannels,
+        settings::{MqttSettings, RetentionSettings},
+        storage::MemoryRepository,
+        test_support::{redis_pool, wait_for, HttpServer, SmtpServer},
+    };
+
+    #[tokio::test]
+    async fn fires_into_the_bell_the_mail_and_the_webhook() {
+        let pool = redis_pool().await;
+        let repo: crate::Repo = Arc::new(MemoryRepository::new(10));
+        User {
+            name: "kim".into(),
+            email: Some("kim@example.com".into()),
+            password: "secret".into(),
+            role_id: 1,
+            source: Default::default(),
+            totp: None,
+        }
+        .insert(&repo)
+        .await
+        .unwrap();
+        let smtp = SmtpServer::start().await;
+        let hook = HttpServer::start(|_| (200, serde_json::json!({}))).await;
+        let settings = SmtpSettings {
+            host: Some("127.0.0.1".into()),
+            port: smtp.port,
+            security: SmtpSecurity::None,
+            from: "MQTTPal <mqttpal@example.com>".into(),
+            ..Default::default()
+        };
+        let notifier = Notifier::new(
+            pool.clone(),
+            repo.clone(),
+            &settings,
+            "http://mqttpal.test",
+            10,
+        )
+        .unwrap();
+        // the client is unknown, so it counts as disconnected right away
+        AlertDefinition {
+            name: "offline".into(),
+            owner: "kim".into(),
+            client: "c1".into(),
+            condition: AlertCondition::Disconnected,
+            channels: AlertChannels {
+                in_app: true,
+                email: true,
+                webhook: Some(format!("{}/alert", hook.url)),
+                webhook_secret: Some("s3cret".into()),
+            },
+            hold: 0,
+            silences: Vec::new(),
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+        let mqtt = MqttClientManager::new(
+            repo,
+            Some(pool.clone()),
+            MqttSettings::default(),
+            RetentionSettings::default(),
+        );
+        mqtt.reload_alerts().await;
+
+        evaluate_alerts(&mqtt, &notifier).await.unwrap();
+        // the bell entry is stored before the mail and the webhook are sent
+        let mail = wait_for(|| smtp.mails().pop()).await;
+        let request = wait_for(|| hook.requests().pop()).await;
+
+        let notifications = Notification::list(&pool, "kim").await.unwrap();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].alert, "offline");
+        assert_eq!(notifications[0].kind, NotificationKind::Firing);
+        assert_eq!(Notification::unread(&pool, "kim").await.unwrap(), 1);
+
+        assert!(mail.contains("To: kim@example.com"), "{mail}");
+        assert!(
+            mail.contains("Subject: [MQTTPal] offline is firing"),
+            "{mail}"
+        );
+        assert!(mail.contains("http://mqttpal.test/alerts/"), "{mail}");
+
+        assert_eq!(request.path, "/alert");
+        assert_eq!(request.header("x-mqttpal-alert"), Some("offline"));
+        let signature = format!("sha256={}", webhook_delivery::sign("s3cret", &request.body));
+        assert_eq!(
+            request.header("x-mqttpal-signature"),
+            Some(signature.as_str())
+        );
+        let body = request.json();
+        assert_eq!(body["alert"], "offline");
+        assert_eq!(body["client"], "c1");
+        assert_eq!(body["kind"], "firing");
+    }
+}
@@ -0,0 +1,381 @@
+use crate::{
+    error::AppError,
+    message_export,
+    middleware::{
+        fullpage_render::FullPageRender, htmx::form_error, login_guard::LoginGuard,
+        user_session::UserSession,
+    },
+    models::{
+        alert_definition::{
+            AlertChannels, AlertCondition, AlertDefinition, AlertState, Notification, SilenceWindow,
+        },
+        mqtt_client::MqttClient,
+        rule::Comparison,
+    },
+    mqtt::MqttClientManager,
+    rules::{non_empty, parse_condition},
+    storage::StorageError,
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use askama::Template;
+use serde::{Deserialize, Serialize};
+
+pub fn alerts_scoped(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/alerts")
+            .service(
+                web::resource("/")
+                    .route(web::get().to(get).wrap(FullPageRender))
+                    .route(web::post().to(post)),
+            )
+            .service(web::resource("/{name}").route(web::delete().to(delete)))
+            .service(
+                web::resource("/{name}/silences")
+                    .route(web::post().to(post_silence))
+                    .route(web::delete().to(delete_silences)),
+            ),
+    )
+    .service(
+        web::scope("/notifications")
+            .service(
+                web::resource("/").route(web::get().to(get_notifications).wrap(FullPageRender)),
+            )
+            .service(web::resource("/bell").route(web::get().to(get_bell))),
+    );
+}
+
+struct AlertRow {
+    alert: AlertDefinition,
+    state: AlertState,
+    silenced: bool,
+}
+
+#[derive(Template)]
+#[template(path = "alerts.html")]
+struct AlertListTemplate {
+    rows: Vec<AlertRow>,
+    clients: Vec<String>,
+}
+
+impl AlertListTemplate {
+    async fn load(
+        db: &crate::DbPool,
+        repo: &crate::Repo,
+        user: &str,
+    ) -> Result<Self, StorageError> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut states = AlertDefinition::states(db).await?;
+        let rows = AlertDefinition::list_by_owner(db, user)
+            .await?
+            .into_iter()
+            .map(|alert| AlertRow {
+                state: states.remove(&alert.name).unwrap_or_default(),
+                silenced: alert.silenced(now),
+                alert,
+            })
+            .collect();
+        let mut clients: Vec<String> = MqttClient::list(repo)
+            .await?
+            .into_iter()
+            .map(|c| c.name)
+            .collect();
+        clients.sort();
+        Ok(AlertListTemplate { rows, clients })
+    }
+}
+
+async fn get(
+    _: LoginGuard,
+    usession: UserSession,
+    db: web::Data<crate::DbPool>,
+    repo: web::Data<crate::Repo>,
+) -> Result<HttpResponse, AppError> {
+    let user = usession.username.unwrap_or_default();
+    let template = AlertListTemplate::load(&db, &repo, &user).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct AlertForm {
+    name: String,
+    client: String,
+    /// `silent`, `threshold` or `disconnected`
+    kind: String,
+    topic: Option<String>,
+    minutes: Option<String>,
+    condition_path: Option<String>,
+    condition_op: Option<Comparison>,
+    condition_value: Option<String>,
+    hold: Option<String>,
+    in_app: Option<String>,
+    email: Option<String>,
+    webhook: Option<String>,
+    webhook_secret: Option<String>,
+}
+
+impl AlertForm {
+    fn into_alert(self, owner: String) -> Result<AlertDefinition, String> {
+        if self.name.trim().is_empty() {
+            return Err("Alert name is required.".into());
+        }
+        let number = |value: Option<String>, field: &str| match non_empty(value) {
+            Some(value) => value
+                .trim()
+                .parse::<u64>()
+                .map(Some)
+                .map_err(|_| format!("{field} has to be a whole number.")),
+            None => Ok(None),
+        };
+        let topic = || match non_empty(self.topic.clone()) {
+            Some(topic) if rumqttc::valid_filter(&topic) => Ok(topic),
+            Some(topic) => Err(format!("'{topic}' is not a valid topic filter.")),
+            None => Err("This alert needs a topic filter.".to_string()),
+        };
+        let condition = match self.kind.as_str() {
+            "silent" => {
+                let minutes = number(self.minutes.clone(), "Minutes")?.unwrap_or(0);
+                if minutes == 0 {
+                    return Err("Silence has to last at least 1 minute.".into());
+                }
+                AlertCondition::Silent {
+                    topic: topic()?,
+                    minutes,
+                }
+            }
+            "threshold" => {
+                let Some(condition) = parse_condition(
+                    self.condition_path.clone(),
+                    self.condition_op,
+                    self.condition_value.clone(),
+                )?
+                else {
+                    return Err("A threshold needs a JSONPath.".into());
+                };
+                AlertCondition::Threshold {
+                    topic: topic()?,
+                    condition,
+                }
+            }
+            "disconnected" => AlertCondition::Disconnected,
+            kind => return Err(format!("Unknown alert kind '{kind}'.")),
+        };
+        let webhook = non_empty(self.webhook);
+        if let Some(url) = &webhook {
+            match reqwest::Url::parse(url) {
+                Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+                _ => return Err(format!("'{url}' is not a valid http(s) url.")),
+            }
+        }
+        let channels = AlertChannels {
+            in_app: self.in_app.is_some(),
+            email: self.email.is_some(),
+            webhook,
+            webhook_secret: non_empty(self.webhook_secret),
+        };
+        if !channels.in_app && !channels.email && channels.webhook.is_none() {
+            return Err("An alert needs at least one channel.".into());
+        }
+        Ok(AlertDefinition {
+            name: self.name,
+            owner,
+            client: self.client,
+            condition,
+            channels,
+            hold: number(self.hold, "Hold")?.unwrap_or(0),
+            silences: Vec::new(),
+        })
+    }
+}
+
+async fn post(
+    _: LoginGuard,
+    usession: UserSession,
+    req: HttpRequest,
+    db: web::Data<crate::DbPool>,
+    repo: web::Data<crate::Repo>,
+    mqtt: web::Data<MqttClientManager>,
+    form: web::Form<AlertForm>,
+) -> Result<HttpResponse, AppError> {
+    let user = usession.username.unwrap_or_default();
+    let alert = match form.into_inner().into_alert(user.clone()) {
+        Ok(alert) => alert,
+        Err(e) => return Ok(form_error(&req, "#alert-errors", &e)),
+    };
+    if MqttClient::get_by_name(&repo, &alert.client)
+        .await?
+        .is_none()
+    {
+        let message = format!("Client '{}' does not exist.", alert.client);
+        return Ok(form_error(&req, "#alert-errors", &message));
+    }
+    if AlertDefinition::get_by_name(&db, &alert.name)
+        .await?
+        .is_some()
+    {
+        let message = format!("Alert '{}' already exists.", alert.name);
+        return Ok(form_error(&req, "#alert-errors", &message));
+    }
+    alert.insert(&db).await?;
+    mqtt.reload_alerts().await;
+    if let Some(topic) = alert.condition.topic() {
+        if mqtt.get_client_actor_addr(&alert.client).await.is_some() {
+            let _ = mqtt.subscribe(&alert.client, &topic.to_string()).await;
+        }
+    }
+    let template = AlertListTemplate::load(&db, &repo, &user).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+/// The alert `name` if `user` owns it.
+async fn owned(db: &crate::DbPool, name: &str, user: &str) -> Result<AlertDefinition, AppError> {
+    match AlertDefinition::get_by_name(db, name).await? {
+        Some(alert) if alert.owner == user => Ok(alert),
+        Some(_) => Err(AppError::Forbidden(
+            "Only the owner can change an alert".into(),
+        )),
+        None => Err(AppError::NotFound("Alert".into())),
+    }
+}
+
+async fn delete(
+    _: LoginGuard,
+    usession: UserSession,
+    db: web::Data<crate::DbPool>,
+    mqtt: web::Data<MqttClientManager>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let user = usession.username.unwrap_or_default();
+    let alert = owned(&db, &name, &user).await?;
+    AlertDefinition::delete(&db, &name).await?;
+    mqtt.reload_alerts().await;
+    let Some(topic) = alert.condition.topic().map(String::from) else {
+        return Ok(HttpResponse::Ok().body(""));
+    };
+    mqtt.release_filter(&alert.client, &topic).await?;
+    Ok(HttpResponse::Ok().body(""))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SilenceForm {
+    /// silence from now on, takes precedence over `from` and `until`
+    minutes: Option<String>,
+    from: Option<String>,
+    until: Option<String>,
+    comment: Option<String>,
+}
+
+impl SilenceForm {
+    fn window(self, now: i64) -> Result<SilenceWindow, String> {
+        let comment = non_empty(self.comment).unwrap_or_default();
+        if let Some(minutes) = non_empty(self.minutes) {
+            let minutes: i64 = minutes
+                .trim()
+                .parse()
+                .ok()
+                .filter(|minutes| *minutes > 0)
+                .ok_or("Minutes have to be a positive whole number.")?;
+            return Ok(SilenceWindow {
+                from: now,
+                until: now + minutes * 60 * 1000,
+                comment,
+            });
+        }
+        let (Some(from), Some(until)) = (non_empty(self.from), non_empty(self.until)) else {
+            return Err("Give the minutes or the start and end of the silence.".into());
+        };
+        let (from, until) = (
+            message_export::parse_time(&from)?,
+            message_export::parse_time(&until)?,
+        );
+        if until <= from {
+            return Err("The silence has to end after it starts.".into());
+        }
+        Ok(SilenceWindow {
+            from,
+            until,
+            comment,
+        })
+    }
+}
+
+async fn post_silence(
+    _: LoginGuard,
+    usession: UserSession,
+    req: HttpRequest,
+    db: web::Data<crate::DbPool>,
+    repo: web::Data<crate::Repo>,
+    form: web::Form<SilenceForm>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let user = usession.username.unwrap_or_default();
+    let mut alert = owned(&db, &name, &user).await?;
+    let now = chrono::Utc::now().timestamp_millis();
+    let window = match form.into_inner().window(now) {
+        Ok(window) => window,
+        Err(e) => return Ok(form_error(&req, "#silence-errors", &e)),
+    };
+    alert.silences.retain(|silence| silence.until > now);
+    alert.silences.push(window);
+    alert.insert(&db).await?;
+    let template = AlertListTemplate::load(&db, &repo, &user).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+async fn delete_silences(
+    _: LoginGuard,
+    usession: UserSession,
+    db: web::Data<crate::DbPool>,
+    repo: web::Data<crate::Repo>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let user = usession.username.unwrap_or_default();
+    let mut alert = owned(&db, &name, &user).await?;
+    alert.silences.clear();
+    alert.insert(&db).await?;
+    let template = AlertListTemplate::load(&db, &repo, &user).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+#[derive(Template)]
+#[template(path = "notifications.html")]
+struct NotificationListTemplate {
+    notifications: Vec<Notification>,
+    /// notifications after this time were not seen before
+    read_until: i64,
+}
+
+async fn get_notifications(
+    _: LoginGuard,
+    usession: UserSession,
+    db: web::Data<crate::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    let user = usession.username.unwrap_or_default();
+    let notifications = Notification::list(&db, &user).await?;
+    let read_until = Notification::read_until(&db, &user).await?;
+    if let Some(latest) = notifications.first() {
+        Notification::mark_read(&db, &user, latest.timestamp).await?;
+    }
+    let template = NotificationListTemplate {
+        notifications,
+        read_until,
+    };
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+#[derive(Template)]
+#[template(path = "notification_bell.html")]
+struct NotificationBellTemplate {
+    unread: usize,
+}
+
+async fn get_bell(
+    _: LoginGuard,
+    usession: UserSession,
+    db: web::Data<crate::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    let user = usession.username.unwrap_or_default();
+    let template = NotificationBellTemplate {
+        unread: Notification::unread(&db, &user).await?,
+    };
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
@@ -0,0 +1,200 @@
+use crate::{
+    config_sync,
+    error::AppError,
+    message_export,
+    middleware::{admin_guard::AdminGuard, fullpage_render::FullPageRender},
+    models::{
+        audit::{AuditAction, AuditEntry, AuditQuery},
+        user::UserSource,
+    },
+    storage::StorageError,
+};
+use actix_session::Session;
+use actix_web::{
+    dev::Payload, error::ErrorInternalServerError, web, Error, FromRequest, HttpRequest,
+    HttpResponse,
+};
+use askama::Template;
+use futures_util::future::Ready;
+use serde::{Deserialize, Serialize};
+
+/// Entries shown on the audit page, the export has all of them.
+const PAGE_SIZE: usize = 200;
+/// Characters of a published payload kept in the audit log.
+const MAX_PAYLOAD: usize = 1024;
+
+pub fn audit_scoped(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/audit")
+            .service(web::resource("/").route(web::get().to(get).wrap(FullPageRender)))
+            .service(web::resource("/export").route(web::get().to(get_export))),
+    );
+}
+
+/// Records the audit trail, into the capped `audit` stream if redis is used.
+///
+/// Every entry is logged with the target `audit` as well, which is the only
+/// trail with the other databases.
+#[derive(Clone)]
+pub struct AuditLog {
+    db: Option<crate::DbPool>,
+    max_len: usize,
+}
+
+impl AuditLog {
+    pub fn new(db: Option<crate::DbPool>, max_len: usize) -> Self {
+        AuditLog { db, max_len }
+    }
+
+    /// The action was done already, failing to record it only gets logged.
+    pub async fn record(
+        &self,
+        user: &str,
+        action: AuditAction,
+        target: &str,
+        diff: Option<serde_json::Value>,
+    ) {
+        let entry = AuditEntry {
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            user: user.to_string(),
+            action,
+            target: target.to_string(),
+            diff,
+        };
+        log::info!(
+            target: "audit",
+            "{} {} {} {}",
+            entry.user,
+            entry.action,
+            entry.target,
+            entry.diff_string()
+        );
+        if let Some(db) = &self.db {
+            if let Err(e) = entry.insert(db, self.max_len).await {
+                log::error!("Cannot store audit entry {entry:?}: {e}");
+            }
+        }
+    }
+
+    /// Topic and payload of a publish, long payloads are cut off.
+    pub fn publish_details(topic: &str, payload: &str, rpc: bool) -> serde_json::Value {
+        let mut kept: String = payload.chars().take(MAX_PAYLOAD).collect();
+        if kept.len() < payload.len() {
+            kept.push('…');
+        }
+        serde_json::json!({"topic": topic, "payload": kept, "rpc": rpc})
+    }
+
+    /// Record a completed login of `user` from `ip`.
+    pub async fn login_succeeded(&self, user: &str, source: &UserSource, ip: &str) {
+        let details = serde_json::json!({"source": config_sync::source_name(source), "ip": ip});
+        self.record(user, AuditAction::LoginSuccess, user, Some(details))
+            .await;
+    }
+
+    /// Record a refused login of the name `user` from `ip`.
+    pub async fn login_failed(&self, user: &str, reason: &str, ip: &str) {
+        let details = serde_json::json!({"reason": reason, "ip": ip});
+        self.record(user, AuditAction::LoginFailure, user, Some(details))
+            .await;
+    }
+}
+
+/// The audit log of a handler, recording the logged in user as the actor.
+#[derive(Clone)]
+pub struct Auditor {
+    log: web::Data<AuditLog>,
+    /// name of the logged in user
+    pub user: String,
+}
+
+impl FromRequest for Auditor {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let user = Session::extract(req)
+            .into_inner()
+            .ok()
+            .and_then(|session| session.get::<String>("username").unwrap_or(None))
+            .unwrap_or_default();
+        let auditor = req
+            .app_data::<web::Data<AuditLog>>()
+            .cloned()
+            .map(|log| Auditor { log, user })
+            .ok_or_else(|| ErrorInternalServerError("no audit log"));
+        futures_util::future::ready(auditor)
+    }
+}
+
+impl Auditor {
+    pub async fn record(&self, action: AuditAction, target: &str, diff: Option<serde_json::Value>) {
+        self.log.record(&self.user, action, target, diff).await;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct AuditFilterForm {
+    user: Option<String>,
+    action: Option<String>,
+    target: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+}
+
+impl TryFrom<&AuditFilterForm> for AuditQuery {
+    type Error = String;
+    fn try_from(form: &AuditFilterForm) -> Result<Self, Self::Error> {
+        let text = |text: &Option<String>| text.clone().filter(|text| !text.trim().is_empty());
+        let time = |time: &Option<String>| {
+            text(time)
+                .map(|time| message_export::parse_time(&time))
+                .transpose()
+        };
+        Ok(AuditQuery {
+            user: text(&form.user),
+            action: text(&form.action).map(|a| a.parse()).transpose()?,
+            target: text(&form.target),
+            from: time(&form.from)?,
+            to: time(&form.to)?,
+        })
+    }
+}
+
+#[derive(Template)]
+#[template(path = "audit.html")]
+struct AuditTemplate {
+    entries: Vec<AuditEntry>,
+    filter: AuditFilterForm,
+    actions: [AuditAction; 10],
+}
+
+async fn get(
+    _: AdminGuard,
+    db: web::Data<crate::DbPool>,
+    form: web::Query<AuditFilterForm>,
+) -> Result<HttpResponse, AppError> {
+    let filter = form.into_inner();
+    let query = AuditQuery::try_from(&filter).map_err(AppError::BadRequest)?;
+    let template = AuditTemplate {
+        entries: AuditEntry::query(&db, &query, PAGE_SIZE).await?,
+        filter,
+        actions: AuditAction::ALL,
+    };
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+async fn get_export(
+    _: AdminGuard,
+    db: web::Data<crate::DbPool>,
+    audit: web::Data<AuditLog>,
+    form: web::Query<AuditFilterForm>,
+) -> Result<HttpResponse, AppError> {
+    let query = AuditQuery::try_from(&form.into_inner()).map_err(AppError::BadRequest)?;
+    // the stream is trimmed approximately, it may hold a few more entries
+    let entries = AuditEntry::query(&db, &query, audit.max_len * 2).await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .append_header(("Content-Disposition", "attachment; filename=\"audit.json\""))
+        .body(serde_json::to_string_pretty(&entries).map_err(StorageError::from)?))
+}
@@ -0,0 +1,140 @@
+use crate::{
+    error::AppError,
+    middleware::{fullpage_render::FullPageRender, htmx::form_error, login_guard::LoginGuard},
+    models::{bridge::Bridge, mqtt_client::MqttClient},
+    mqtt::MqttClientManager,
+    storage::StorageError,
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use askama::Template;
+use serde::{Deserialize, Serialize};
+
+pub fn bridges_scoped(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/bridges")
+            .service(
+                web::resource("/")
+                    .route(web::get().to(get).wrap(FullPageRender))
+                    .route(web::post().to(post)),
+            )
+            .service(web::resource("/{name}").route(web::delete().to(delete))),
+    );
+}
+
+#[derive(Template)]
+#[template(path = "bridges.html")]
+struct BridgeListTemplate {
+    bridges: Vec<Bridge>,
+    clients: Vec<String>,
+}
+
+impl BridgeListTemplate {
+    async fn load(db: &crate::DbPool, repo: &crate::Repo) -> Result<Self, StorageError> {
+        let mut clients: Vec<String> = MqttClient::list(repo)
+            .await?
+            .into_iter()
+            .map(|c| c.name)
+            .collect();
+        clients.sort();
+        Ok(BridgeListTemplate {
+            bridges: Bridge::list(db).await?,
+            clients,
+        })
+    }
+}
+
+async fn get(
+    _: LoginGuard,
+    db: web::Data<crate::DbPool>,
+    repo: web::Data<crate::Repo>,
+) -> Result<HttpResponse, AppError> {
+    let template = BridgeListTemplate::load(&db, &repo).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BridgeForm {
+    name: String,
+    source: String,
+    target: String,
+    topic: String,
+    strip_prefix: Option<String>,
+    add_prefix: Option<String>,
+    qos: Option<String>,
+}
+
+impl TryFrom<BridgeForm> for Bridge {
+    type Error = String;
+    fn try_from(form: BridgeForm) -> Result<Self, Self::Error> {
+        if form.name.trim().is_empty() {
+            return Err("Bridge name is required.".into());
+        }
+        if !rumqttc::valid_filter(&form.topic) {
+            return Err(format!("'{}' is not a valid topic filter.", form.topic));
+        }
+        let strip_prefix = form.strip_prefix.filter(|p| !p.is_empty());
+        let add_prefix = form.add_prefix.filter(|p| !p.is_empty());
+        if form.source == form.target && add_prefix.is_none() && strip_prefix.is_none() {
+            return Err("Bridging a client onto itself needs a topic rewrite.".into());
+        }
+        let qos = match form.qos.as_deref() {
+            None | Some("") => None,
+            Some(qos) => match qos.parse::<u8>() {
+                Ok(qos) if qos <= 2 => Some(qos),
+                _ => return Err(format!("'{qos}' is not a valid QoS.")),
+            },
+        };
+        Ok(Bridge {
+            name: form.name,
+            source: form.source,
+            target: form.target,
+            topic: form.topic,
+            strip_prefix,
+            add_prefix,
+            qos,
+        })
+    }
+}
+
+async fn post(
+    _: LoginGuard,
+    req: HttpRequest,
+    db: web::Data<crate::DbPool>,
+    repo: web::Data<crate::Repo>,
+    mqtt: web::Data<MqttClientManager>,
+    form: web::Form<BridgeForm>,
+) -> Result<HttpResponse, AppError> {
+    let bridge: Result<Bridge, String> = form.into_inner().try_into();
+    let bridge = match bridge {
+        Ok(bridge) if Bridge::get_by_name(&db, &bridge.name).await?.is_some() => {
+            Err(format!("Bridge '{}' already exists.", bridge.name))
+        }
+        other => other,
+    };
+    let bridge = match bridge {
+        Ok(bridge) => bridge,
+        Err(e) => return Ok(form_error(&req, "#bridge-errors", &e)),
+    };
+    bridge.insert(&db).await?;
+    mqtt.reload_bridges().await;
+    if mqtt.get_client_actor_addr(&bridge.source).await.is_some() {
+        let _ = mqtt.subscribe(&bridge.source, &bridge.topic).await;
+    }
+    let template = BridgeListTemplate::load(&db, &repo).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+async fn delete(
+    _: LoginGuard,
+    db: web::Data<crate::DbPool>,
+    mqtt: web::Data<MqttClientManager>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let Some(bridge) = Bridge::get_by_name(&db, &name).await? else {
+        return Err(AppError::NotFound("Bridge".into()));
+    };
+    Bridge::delete(&db, &name).await?;
+    mqtt.reload_bridges().await;
+    mqtt.release_filter(&bridge.source, &bridge.topic).await?;
+    Ok(HttpResponse::Ok().body(""))
+}
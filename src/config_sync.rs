@@ -0,0 +1,590 @@
+use std::{collections::HashSet, fmt::Display};
+
+use crate::{
+    audit::AuditLog,
+    error::AppError,
+    middleware::{admin_guard::AdminGuard, fullpage_render::FullPageRender, htmx::form_error},
+    models::{
+        audit::{self, AuditAction},
+        mqtt_client::MqttClient,
+        user::{Role, User, UserSource},
+    },
+    mqtt::MqttClientManager,
+    mqtt_client,
+    session_store::ServerSessionStore,
+    storage::StorageError,
+};
+use actix_multipart::Multipart;
+use actix_web::{web, HttpRequest, HttpResponse};
+use askama::Template;
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+
+/// Largest config file accepted through the web interface.
+const MAX_CONFIG_SIZE: usize = 1024 * 1024;
+
+/// Declarative description of the clients and users of an instance.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub clients: Vec<ClientConfig>,
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientConfig {
+    pub name: String,
+    pub url: String,
+    /// topic filters the client subscribes to
+    #[serde(default)]
+    pub topics: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RoleConfig {
+    Admin,
+    User,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserConfig {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    pub role: RoleConfig,
+    /// `local`, `oauth:<provider>` or `ldap:<provider>`
+    #[serde(default = "default_source")]
+    pub source: String,
+    /// only needed for new local users, existing users keep their password if omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+fn default_source() -> String {
+    "local".into()
+}
+
+/// Serialization formats of the config file.
+#[derive(Debug, Clone, Copy)]
+pub enum ConfigFormat {
+    Yaml,
+    Json,
+}
+
+impl std::str::FromStr for ConfigFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            "json" => Ok(ConfigFormat::Json),
+            other => Err(format!("unknown config format '{other}'")),
+        }
+    }
+}
+
+impl RoleConfig {
+    pub fn role_id(&self) -> i32 {
+        match self {
+            RoleConfig::Admin => Role::Admin as i32,
+            RoleConfig::User => Role::User as i32,
+        }
+    }
+}
+
+pub fn source_name(source: &UserSource) -> String {
+    match source {
+        UserSource::Local => "local".into(),
+        UserSource::OAuth(provider) => format!("oauth:{provider}"),
+        UserSource::Ldap(provider) => format!("ldap:{provider}"),
+    }
+}
+
+fn parse_source(source: &str) -> Result<UserSource, String> {
+    match source.split_once(':') {
+        None if source == "local" => Ok(UserSource::Local),
+        Some(("oauth", provider)) if !provider.is_empty() => {
+            Ok(UserSource::OAuth(provider.to_string()))
+        }
+        Some(("ldap", provider)) if !provider.is_empty() => {
+            Ok(UserSource::Ldap(provider.to_string()))
+        }
+        _ => Err(format!("unknown user source '{source}'")),
+    }
+}
+
+impl Config {
+    /// Read the current config from storage, passwords are left out.
+    pub async fn export(repo: &crate::Repo) -> Result<Config, StorageError> {
+        let mut clients = Vec::new();
+        for client in MqttClient::list(repo).await? {
+            let mut topics = MqttClient::topics(repo, &client.name).await?;
+            topics.sort();
+            clients.push(ClientConfig {
+                name: client.name,
+                url: client.url,
+                topics,
+            });
+        }
+        clients.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut users: Vec<UserConfig> = User::list(repo)
+            .await?
+            .into_iter()
+            .map(|user| UserConfig {
+                role: match Role::from(user.role_id) {
+                    Role::Admin => RoleConfig::Admin,
+                    Role::User => RoleConfig::User,
+                },
+                source: source_name(&user.source),
+                name: user.name,
+                email: user.email,
+                password: None,
+            })
+            .collect();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Config { clients, users })
+    }
+
+    /// Parse a config file, JSON is read as YAML.
+    pub fn parse(content: &str) -> Result<Config, String> {
+        let config: Config = serde_yaml::from_str(content).map_err(|e| e.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn render(&self, format: ConfigFormat) -> String {
+        match format {
+            ConfigFormat::Yaml => serde_yaml::to_string(self).expect("Cannot serialize config"),
+            ConfigFormat::Json => {
+                serde_json::to_string_pretty(self).expect("Cannot serialize config")
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let mut names = HashSet::new();
+        for client in &self.clients {
+            if client.name.trim().is_empty() {
+                return Err("client without name".into());
+            }
+            if !names.insert(&client.name) {
+                return Err(format!("client '{}' is listed twice", client.name));
+            }
+            if let Some(topic) = client.topics.iter().find(|t| !rumqttc::valid_filter(t)) {
+                return Err(format!(
+                    "'{}' of client '{}' is not a valid topic filter",
+                    topic, client.name
+                ));
+            }
+        }
+        let mut names = HashSet::new();
+        for user in &self.users {
+            if user.name.trim().is_empty() {
+                return Err("user without name".into());
+            }
+            if !names.insert(&user.name) {
+                return Err(format!("user '{}' is listed twice", user.name));
+            }
+            parse_source(&user.source)?;
+        }
+        Ok(())
+    }
+}
+
+/// Single difference between a config file and the stored config.
+#[derive(Debug, Clone)]
+pub enum Change {
+    AddClient(ClientConfig),
+    UpdateClient { name: String, url: String },
+    RemoveClient(String),
+    Subscribe { client: String, topic: String },
+    Unsubscribe { client: String, topic: String },
+    AddUser(UserConfig),
+    UpdateUser(UserConfig),
+    RemoveUser(String),
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Change::AddClient(client) => write!(f, "+ client {} ({})", client.name, client.url),
+            Change::UpdateClient { name, url } => write!(f, "~ client {name}: url {url}"),
+            Change::RemoveClient(name) => write!(f, "- client {name}"),
+            Change::Subscribe { client, topic } => write!(f, "+ topic {topic} on {client}"),
+            Change::Unsubscribe { client, topic } => write!(f, "- topic {topic} on {client}"),
+            Change::AddUser(user) => write!(f, "+ user {} ({:?})", user.name, user.role),
+            Change::UpdateUser(user) => write!(f, "~ user {} ({:?})", user.name, user.role),
+            Change::RemoveUser(name) => write!(f, "- user {name}"),
+        }
+    }
+}
+
+impl Change {
+    pub fn is_removal(&self) -> bool {
+        matches!(
+            self,
+            Change::RemoveClient(_) | Change::Unsubscribe { .. } | Change::RemoveUser(_)
+        )
+    }
+}
+
+/// Changes needed to bring the stored config in line with `config`.
+///
+/// Without `sync` nothing is removed, with it clients, topics and users not
+/// listed in `config` are removed.
+pub async fn diff(repo: &crate::Repo, config: &Config, sync: bool) -> Result<Vec<Change>, String> {
+    let mut changes = Vec::new();
+    let existing_clients = MqttClient::list(repo).await.map_err(|e| e.to_string())?;
+    for client in &config.clients {
+        let Some(existing) = existing_clients.iter().find(|c| c.name == client.name) else {
+            changes.push(Change::AddClient(client.clone()));
+            continue;
+        };
+        if existing.url != client.url {
+            changes.push(Change::UpdateClient {
+                name: client.name.clone(),
+                url: client.url.clone(),
+            });
+        }
+        let topics = MqttClient::topics(repo, &client.name)
+            .await
+            .map_err(|e| e.to_string())?;
+        for topic in client.topics.iter().filter(|t| !topics.contains(t)) {
+            changes.push(Change::Subscribe {
+                client: client.name.clone(),
+                topic: topic.clone(),
+            });
+        }
+        if sync {
+            for topic in topics.iter().filter(|t| !client.topics.contains(t)) {
+                changes.push(Change::Unsubscribe {
+                    client: client.name.clone(),
+                    topic: topic.clone(),
+                });
+            }
+        }
+    }
+    if sync {
+        for client in &existing_clients {
+            if !config.clients.iter().any(|c| c.name == client.name) {
+                changes.push(Change::RemoveClient(client.name.clone()));
+            }
+        }
+    }
+    let existing_users = User::list(repo).await.map_err(|e| e.to_string())?;
+    for user in &config.users {
+        let source = parse_source(&user.source)?;
+        let Some(existing) = existing_users.iter().find(|u| u.name == user.name) else {
+            if source == UserSource::Local && user.password.as_deref().unwrap_or("").is_empty() {
+                return Err(format!("new local user '{}' needs a password", user.name));
+            }
+            changes.push(Change::AddUser(user.clone()));
+            continue;
+        };
+        let changed = existing.email != user.email
+            || existing.role_id != user.role.role_id()
+            || existing.source != source
+            || user
+                .password
+                .as_ref()
+                .is_some_and(|p| *p != existing.password);
+        if changed {
+            changes.push(Change::UpdateUser(user.clone()));
+        }
+    }
+    if sync {
+        for user in &existing_users {
+            if !config.users.iter().any(|u| u.name == user.name) {
+                changes.push(Change::RemoveUser(user.name.clone()));
+            }
+        }
+    }
+    Ok(changes)
+}
+
+/// Write the changes to storage, they are recorded in the audit log as done by `actor`.
+///
+/// With `connect` added or changed clients are (re)connected and
+/// subscriptions are changed on the running clients. Sessions of removed
+/// users and of users with a new role are revoked from `sessions`.
+#[allow(clippy::too_many_arguments)]
+pub async fn apply(
+    repo: &crate::Repo,
+    pool: Option<&crate::DbPool>,
+    mqtt: &MqttClientManager,
+    sessions: Option<&ServerSessionStore>,
+    audit_log: &AuditLog,
+    actor: &str,
+    changes: &[Change],
+    connect: bool,
+) -> Result<(), StorageError> {
+    let mut reconnect: Vec<String> = Vec::new();
+    for change in changes {
+        log::info!("Applying config change: {}", change);
+        match change {
+            Change::AddClient(client) => {
+                let stored = MqttClient {
+                    name: client.name.clone(),
+                    url: client.url.clone(),
+                };
+                stored.insert(repo).await?;
+                let diff = audit::diff(None, Some(&stored));
+                audit_log
+                    .record(actor, AuditAction::ClientCreate, &client.name, diff)
+                    .await;
+                for topic in &client.topics {
+                    MqttClient::subscribe(repo, &client.name, topic).await?;
+                    let details = serde_json::json!({ "topic": topic });
+                    audit_log
+                        .record(actor, AuditAction::Subscribe, &client.name, Some(details))
+                        .await;
+                }
+                reconnect.push(client.name.clone());
+            }
+            Change::UpdateClient { name, url } => {
+                let previous = MqttClient::get_by_name(repo, name).await?;
+                let stored = MqttClient {
+                    name: name.clone(),
+                    url: url.clone(),
+                };
+                stored.insert(repo).await?;
+                let diff = audit::diff(previous.as_ref(), Some(&stored));
+                audit_log
+                    .record(actor, AuditAction::ClientCreate, name, diff)
+                    .await;
+                reconnect.push(name.clone());
+            }
+            Change::RemoveClient(name) => {
+                let previous = MqttClient::get_by_name(repo, name).await?;
+                if mqtt_client::remove_client(repo, pool, mqtt, name).await? {
+                    let diff = audit::diff(previous.as_ref(), None);
+                    audit_log
+                        .record(actor, AuditAction::ClientDelete, name, diff)
+                        .await;
+                }
+            }
+            Change::Subscribe { client, topic } => {
+                MqttClient::subscribe(repo, client, topic).await?;
+                if connect && mqtt.get_client_actor_addr(client).await.is_some() {
+                    let _ = mqtt.subscribe(client, topic).await;
+                }
+                let details = serde_json::json!({ "topic": topic });
+                audit_log
+                    .record(actor, AuditAction::Subscribe, client, Some(details))
+                    .await;
+            }
+            Change::Unsubscribe { client, topic } => {
+                MqttClient::unsubscribe(repo, client, topic).await?;
+                if connect {
+                    mqtt.release_filter(client, topic).await?;
+                }
+                let details = serde_json::json!({ "topic": topic });
+                audit_log
+                    .record(actor, AuditAction::Unsubscribe, client, Some(details))
+                    .await;
+            }
+            Change::AddUser(user) | Change::UpdateUser(user) => {
+                let existing = User::get_by_name(repo, &user.name).await?;
+                let totp = existing.as_ref().and_then(|existing| existing.totp.clone());
+                let password = user
+                    .password
+                    .clone()
+                    .or(existing.as_ref().map(|existing| existing.password.clone()))
+                    .unwrap_or_default();
+                let stored = User {
+                    name: user.name.clone(),
+                    email: user.email.clone(),
+                    password,
+                    role_id: user.role.role_id(),
+                    source: parse_source(&user.source).unwrap_or_default(),
+                    totp,
+                };
+                stored.insert(repo).await?;
+                let action = match existing {
+                    Some(_) => AuditAction::UserUpdate,
+                    None => AuditAction::UserCreate,
+                };
+                let diff = audit::diff(existing.as_ref(), Some(&stored));
+                audit_log.record(actor, action, &user.name, diff).await;
+                // sessions keep the rights of the old role otherwise
+                let role_changed =
+                    existing.is_some_and(|existing| existing.role_id != stored.role_id);
+                if let Some(sessions) = sessions.filter(|_| role_changed) {
+                    sessions.revoke_user(&user.name).await?;
+                }
+            }
+            Change::RemoveUser(name) => {
+                let previous = User::get_by_name(repo, name).await?;
+                if User::delete(repo, name).await? {
+                    if let Some(sessions) = sessions {
+                        sessions.revoke_user(name).await?;
+                    }
+                    let diff = audit::diff(previous.as_ref(), None);
+                    audit_log
+                        .record(actor, AuditAction::UserDelete, name, diff)
+                        .await;
+                }
+            }
+        }
+    }
+    if !connect {
+        return Ok(());
+    }
+    for name in reconnect {
+        let Some(client) = MqttClient::get_by_name(repo, &name).await? else {
+            continue;
+        };
+        if mqtt.get_client_actor_addr(&name).await.is_some() {
+            mqtt.unregister_client(&name).await;
+        }
+        let topics = MqttClient::topics(repo, &name).await?;
+        if let Err(e) = mqtt.register_client(client.name, client.url, topics).await {
+            log::error!("Cannot connect client {}: {}", name, e);
+        }
+    }
+    Ok(())
+}
+
+pub fn config_scoped(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/config")
+            .service(web::resource("/").route(web::get().to(get).wrap(FullPageRender)))
+            .service(web::resource("/export").route(web::get().to(get_export)))
+            .service(web::resource("/import").route(web::post().to(post_import))),
+    );
+}
+
+#[derive(Template)]
+#[template(path = "config.html")]
+struct ConfigTemplate {
+    config: String,
+}
+
+async fn get(_: AdminGuard, repo: web::Data<crate::Repo>) -> Result<HttpResponse, AppError> {
+    let template = ConfigTemplate {
+        config: Config::export(&repo).await?.render(ConfigFormat::Yaml),
+    };
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ExportQuery {
+    format: Option<String>,
+}
+
+async fn get_export(
+    _: AdminGuard,
+    repo: web::Data<crate::Repo>,
+    query: web::Query<ExportQuery>,
+) -> Result<HttpResponse, AppError> {
+    let format: ConfigFormat = query
+        .format
+        .as_deref()
+        .unwrap_or("yaml")
+        .parse()
+        .map_err(AppError::BadRequest)?;
+    let (content_type, extension) = match format {
+        ConfigFormat::Yaml => ("application/yaml", "yaml"),
+        ConfigFormat::Json => ("application/json", "json"),
+    };
+    let config = Config::export(&repo).await?;
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .append_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"mqttpal.{extension}\""),
+        ))
+        .body(config.render(format)))
+}
+
+#[derive(Template)]
+#[template(path = "config_diff.html")]
+struct ConfigDiffTemplate {
+    applied: bool,
+    changes: Vec<Change>,
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn post_import(
+    admin: AdminGuard,
+    req: HttpRequest,
+    repo: web::Data<crate::Repo>,
+    db: Option<web::Data<crate::DbPool>>,
+    mqtt: web::Data<MqttClientManager>,
+    sessions: web::Data<ServerSessionStore>,
+    audit: web::Data<AuditLog>,
+    mut multipart: Multipart,
+) -> Result<HttpResponse, AppError> {
+    let mut sync = false;
+    let mut dry_run = false;
+    let mut content: Vec<u8> = Vec::new();
+    while let Some(mut field) = multipart.try_next().await? {
+        let field_name = field.name().to_string();
+        let mut value: Vec<u8> = Vec::new();
+        while let Some(chunk) = field.try_next().await? {
+            if value.len() + chunk.len() > MAX_CONFIG_SIZE {
+                return Ok(form_error(
+                    &req,
+                    "#config-import",
+                    "The config file is too large.",
+                ));
+            }
+            value.extend_from_slice(&chunk);
+        }
+        match field_name.as_str() {
+            "mode" => sync = value == b"sync",
+            "dry_run" => dry_run = true,
+            "file" => content = value,
+            _ => {}
+        }
+    }
+    let config = match Config::parse(&String::from_utf8_lossy(&content)) {
+        Ok(config) => config,
+        Err(e) => {
+            return Ok(form_error(
+                &req,
+                "#config-import",
+                &format!("Invalid config: {e}"),
+            ))
+        }
+    };
+    let changes = match diff(&repo, &config, sync).await {
+        Ok(changes) => changes,
+        Err(e) => {
+            return Ok(form_error(
+                &req,
+                "#config-import",
+                &format!("Invalid config: {e}"),
+            ))
+        }
+    };
+    let removes_self = changes
+        .iter()
+        .any(|change| matches!(change, Change::RemoveUser(name) if *name == admin.username));
+    if removes_self {
+        return Ok(form_error(
+            &req,
+            "#config-import",
+            "The config would remove your own user, add it to the config first.",
+        ));
+    }
+    if !dry_run {
+        apply(
+            &repo,
+            db.as_ref().map(|db| db.get_ref()),
+            &mqtt,
+            Some(&sessions),
+            &audit,
+            &admin.username,
+            &changes,
+            true,
+        )
+        .await?;
+    }
+    let template = ConfigDiffTemplate {
+        applied: !dry_run,
+        changes,
+    };
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
@@ -0,0 +1,102 @@
+use std::fmt::Display;
+
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use askama::Template;
+
+use crate::{mqtt::MqttError, storage::StorageError};
+
+/// Errors surfacing from request handlers.
+///
+/// Every variant renders as a small html fragment, the [`Htmx`](crate::middleware::htmx::Htmx)
+/// middleware swaps it into the error box of the page for htmx requests.
+#[derive(Debug)]
+pub enum AppError {
+    Storage(StorageError),
+    Template(askama::Error),
+    Mqtt(MqttError),
+    NotFound(String),
+    Forbidden(String),
+    BadRequest(String),
+    Internal(String),
+}
+
+impl Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::Storage(e) => write!(f, "{e}"),
+            AppError::Template(e) => write!(f, "cannot render template: {e}"),
+            AppError::Mqtt(e) => write!(f, "{e}"),
+            AppError::NotFound(what) => write!(f, "{what} not found"),
+            AppError::Forbidden(e) => write!(f, "{e}"),
+            AppError::BadRequest(e) => write!(f, "{e}"),
+            AppError::Internal(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl From<StorageError> for AppError {
+    fn from(e: StorageError) -> Self {
+        AppError::Storage(e)
+    }
+}
+
+impl From<askama::Error> for AppError {
+    fn from(e: askama::Error) -> Self {
+        AppError::Template(e)
+    }
+}
+
+impl From<actix_multipart::MultipartError> for AppError {
+    fn from(e: actix_multipart::MultipartError) -> Self {
+        AppError::BadRequest(format!("The upload failed: {e}"))
+    }
+}
+
+impl From<MqttError> for AppError {
+    fn from(e: MqttError) -> Self {
+        AppError::Mqtt(e)
+    }
+}
+
+/// Error message shown in place of a form or page.
+#[derive(Template)]
+#[template(path = "form_error.html")]
+pub(crate) struct ErrorTemplate<'a> {
+    pub message: &'a str,
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::Storage(_) | AppError::Template(_) | AppError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            AppError::Mqtt(MqttError::UnknownClient(_)) | AppError::NotFound(_) => {
+                StatusCode::NOT_FOUND
+            }
+            AppError::Mqtt(_) => StatusCode::BAD_GATEWAY,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        // internals stay in the log, users only get a hint to retry
+        let message = if status == StatusCode::INTERNAL_SERVER_ERROR {
+            log::error!("{}", self);
+            "Internal error, please try again later.".to_string()
+        } else {
+            log::warn!("{}", self);
+            self.to_string()
+        };
+        let body = ErrorTemplate { message: &message }
+            .render()
+            .unwrap_or_else(|_| message.clone());
+        HttpResponse::build(status)
+            .content_type("text/html; charset=utf-8")
+            .body(body)
+    }
+}
@@ -0,0 +1,91 @@
+use std::time::Duration;
+
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+
+use crate::{mqtt::MqttClientManager, settings::HealthSettings};
+
+/// Upper bound for a single readiness check, probes must answer quickly.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What `/readyz` needs to know beyond the app data shared with the handlers.
+pub struct Readiness {
+    /// oauth providers whose metadata could not be loaded at startup
+    pub oauth_failed: Vec<String>,
+    pub settings: HealthSettings,
+}
+
+/// Probes for the container orchestrator, both work without a session.
+pub fn health_scoped(cfg: &mut web::ServiceConfig) {
+    cfg.service(healthz).service(readyz);
+}
+
+#[derive(Serialize)]
+struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failed: Vec<String>,
+}
+
+impl Check {
+    fn from_failed(failed: Vec<String>) -> Self {
+        Check {
+            ok: failed.is_empty(),
+            error: None,
+            failed,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ReadyReport {
+    status: &'static str,
+    storage: Check,
+    oauth: Check,
+    mqtt: Check,
+}
+
+#[get("/healthz")]
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+#[get("/readyz")]
+async fn readyz(
+    repo: web::Data<crate::Repo>,
+    mqtt: web::Data<MqttClientManager>,
+    readiness: web::Data<Readiness>,
+) -> HttpResponse {
+    let storage = match tokio::time::timeout(CHECK_TIMEOUT, repo.ping()).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some("timed out".to_string()),
+    };
+    let storage = Check {
+        ok: storage.is_none(),
+        error: storage,
+        failed: Vec::new(),
+    };
+    let oauth = Check::from_failed(readiness.oauth_failed.clone());
+    let mut disconnected = Vec::new();
+    for client in &readiness.settings.required_clients {
+        if !mqtt.connected(client).await.unwrap_or(false) {
+            disconnected.push(client.clone());
+        }
+    }
+    let mqtt = Check::from_failed(disconnected);
+    let ready = storage.ok && oauth.ok && mqtt.ok;
+    let report = ReadyReport {
+        status: if ready { "ok" } else { "unavailable" },
+        storage,
+        oauth,
+        mqtt,
+    };
+    if ready {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
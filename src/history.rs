@@ -0,0 +1,173 @@
+use crate::{
+    audit::Auditor,
+    error::AppError,
+    message_export::{self, ExportQuery, ImportMode, MessageFormat},
+    middleware::{htmx::form_error, login_guard::LoginGuard},
+    models::{audit::AuditAction, history::HistoryEntry},
+    mqtt::MqttClientManager,
+};
+use actix_multipart::Multipart;
+use actix_web::{web, HttpRequest, HttpResponse};
+use askama::Template;
+use futures_util::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+
+/// Largest file accepted for an import through the web interface.
+const MAX_IMPORT_SIZE: usize = 64 * 1024 * 1024;
+
+pub fn history_scoped(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("{name}/history")
+            .service(web::resource("").route(web::get().to(get)))
+            .service(web::resource("/export").route(web::get().to(get_export)))
+            .service(web::resource("/import").route(web::post().to(post_import))),
+    );
+}
+
+#[derive(Template)]
+#[template(path = "mqtt_client_history.html")]
+pub struct MqttClientHistoryTemplate {
+    pub name: String,
+    pub history: Vec<HistoryEntry>,
+}
+
+async fn get(
+    _: LoginGuard,
+    repo: web::Data<crate::Repo>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let history = HistoryEntry::latest(&repo, &name, 50).await?;
+    let template = MqttClientHistoryTemplate {
+        name: name.into_inner(),
+        history,
+    };
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ExportForm {
+    filter: Option<String>,
+    format: String,
+    from: Option<String>,
+    to: Option<String>,
+}
+
+impl TryFrom<ExportForm> for (ExportQuery, MessageFormat) {
+    type Error = String;
+    fn try_from(form: ExportForm) -> Result<Self, Self::Error> {
+        let filter = form
+            .filter
+            .filter(|filter| !filter.is_empty())
+            .unwrap_or("#".into());
+        if !rumqttc::valid_filter(&filter) {
+            return Err(format!("'{filter}' is not a valid topic filter."));
+        }
+        let time = |time: Option<String>| {
+            time.filter(|time| !time.is_empty())
+                .map(|time| message_export::parse_time(&time))
+                .transpose()
+        };
+        let query = ExportQuery {
+            filter,
+            from: time(form.from)?,
+            to: time(form.to)?,
+        };
+        Ok((query, form.format.parse()?))
+    }
+}
+
+async fn get_export(
+    _: LoginGuard,
+    repo: web::Data<crate::Repo>,
+    form: web::Query<ExportForm>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let (query, format) = form.into_inner().try_into().map_err(AppError::BadRequest)?;
+    let file_name = format!("{}-history.{}", name, format.extension());
+    let chunks = message_export::export(repo.get_ref().clone(), name.into_inner(), query, format)
+        .map(|chunk| chunk.map(web::Bytes::from));
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .append_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"{file_name}\""),
+        ))
+        .streaming(chunks))
+}
+
+async fn post_import(
+    _: LoginGuard,
+    req: HttpRequest,
+    repo: web::Data<crate::Repo>,
+    mqtt: web::Data<MqttClientManager>,
+    auditor: Auditor,
+    mut multipart: Multipart,
+    name: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let mut mode = ImportMode::History;
+    let mut format: Option<MessageFormat> = None;
+    let mut file: Vec<u8> = Vec::new();
+    while let Some(mut field) = multipart.try_next().await? {
+        let field_name = field.name().to_string();
+        let file_name = field.content_disposition().get_filename().map(String::from);
+        let mut value: Vec<u8> = Vec::new();
+        while let Some(chunk) = field.try_next().await? {
+            if value.len() + chunk.len() > MAX_IMPORT_SIZE {
+                return Ok(form_error(
+                    &req,
+                    "#history-import",
+                    "The file is too large to import.",
+                ));
+            }
+            value.extend_from_slice(&chunk);
+        }
+        let text = String::from_utf8_lossy(&value);
+        match field_name.as_str() {
+            "mode" if text == "publish" => mode = ImportMode::Publish,
+            "format" if text != "auto" => match text.parse() {
+                Ok(parsed) => format = Some(parsed),
+                Err(e) => return Ok(form_error(&req, "#history-import", &e)),
+            },
+            "file" => {
+                format = format.or(file_name.as_deref().and_then(MessageFormat::from_file_name));
+                file = value;
+            }
+            _ => {}
+        }
+    }
+    let Some(format) = format else {
+        return Ok(form_error(
+            &req,
+            "#history-import",
+            "Cannot tell the format from the file name, please select one.",
+        ));
+    };
+    if mode == ImportMode::Publish && mqtt.get_client_actor_addr(&name).await.is_none() {
+        return Ok(form_error(
+            &req,
+            "#history-import",
+            "The client is not connected.",
+        ));
+    }
+    let entries = message_export::decode(format, std::io::Cursor::new(file));
+    Ok(
+        match message_export::import(&repo, &mqtt, &name, entries, mode).await {
+            Ok(imported) => {
+                if mode == ImportMode::Publish {
+                    let details = serde_json::json!({ "imported": imported });
+                    auditor
+                        .record(AuditAction::Publish, &name, Some(details))
+                        .await;
+                }
+                let message = match mode {
+                    ImportMode::History => {
+                        format!("Imported {imported} messages into the history.")
+                    }
+                    ImportMode::Publish => format!("Published {imported} messages."),
+                };
+                HttpResponse::Ok().body(format!("<p>{message}</p>"))
+            }
+            Err(e) => form_error(&req, "#history-import", &format!("Import failed: {e}")),
+        },
+    )
+}
@@ -0,0 +1,428 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use actix_web::web;
+use ldap3::{ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use serde::Deserialize;
+
+use crate::{
+    config_sync::RoleConfig,
+    error::AppError,
+    models::user::{User, UserSource},
+    oauth::{self, Provisioning},
+    settings::Settings,
+};
+
+/// Seconds to wait for the directory before the login counts as failed.
+const CONNECT_TIMEOUT: u64 = 5;
+
+/// Providers by name, tried in this order for users logging in the first time.
+pub type LdapConfigs = web::Data<BTreeMap<String, LdapConfig>>;
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LdapConfig {
+    pub ui_name: String,
+    /// `ldap://` or `ldaps://`
+    pub url: String,
+    /// upgrade an `ldap://` connection with StartTLS
+    #[serde(default)]
+    pub starttls: bool,
+    /// account searching the users, the search is anonymous without it
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    pub search_base: String,
+    /// `{username}` is replaced with the escaped login name
+    #[serde(default = "default_user_filter")]
+    pub user_filter: String,
+    /// attribute of the user entry with the DNs of its groups
+    #[serde(default = "default_group_attribute")]
+    pub group_attribute: String,
+    /// search the groups instead of reading `group_attribute`, for directories
+    /// without `memberOf`
+    pub group_search_base: Option<String>,
+    /// `{dn}` is replaced with the escaped DN of the user
+    #[serde(default = "default_group_filter")]
+    pub group_filter: String,
+    pub email_attribute: Option<String>,
+    /// groups of which the user needs at least one, any user if empty
+    #[serde(default)]
+    pub required_groups: Vec<String>,
+    /// the first matching mapping decides the role, it is synced on every login
+    #[serde(default)]
+    pub group_roles: Vec<GroupRole>,
+    /// role of users no mapping matches
+    #[serde(default = "default_role")]
+    pub default_role: RoleConfig,
+    #[serde(default)]
+    pub provisioning: Provisioning,
+}
+
+/// Membership in a group granting a role, the group is given by its full DN
+/// or by the value of its first RDN, e.g. `mqtt-admins` for
+/// `cn=mqtt-admins,ou=groups,dc=example,dc=com`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GroupRole {
+    pub group: String,
+    pub role: RoleConfig,
+}
+
+fn default_user_filter() -> String {
+    "(uid={username})".into()
+}
+
+fn default_group_attribute() -> String {
+    "memberOf".into()
+}
+
+fn default_group_filter() -> String {
+    "(member={dn})".into()
+}
+
+fn default_role() -> RoleConfig {
+    RoleConfig::User
+}
+
+/// The user entry found in the directory.
+struct DirectoryUser {
+    dn: String,
+    email: Option<String>,
+    groups: Vec<String>,
+}
+
+/// Whether the group DN `dn` is the configured `group`.
+fn is_group(dn: &str, group: &str) -> bool {
+    if dn.eq_ignore_ascii_case(group) {
+        return true;
+    }
+    dn.split(',')
+        .next()
+        .and_then(|rdn| rdn.split_once('='))
+        .is_some_and(|(_, name)| name.trim().eq_ignore_ascii_case(group))
+}
+
+impl LdapConfig {
+    /// Check the group rules, returns the role of the user or why the login
+    /// is refused.
+    fn admit(&self, groups: &[String]) -> Result<i32, String> {
+        let member = |group: &str| groups.iter().any(|dn| is_group(dn, group));
+        if !self.required_groups.is_empty()
+            && !self.required_groups.iter().any(|group| member(group))
+        {
+            return Err("not a member of a required group".into());
+        }
+        let role = self
+            .group_roles
+            .iter()
+            .find(|mapping| member(&mapping.group))
+            .map_or(&self.default_role, |mapping| &mapping.role);
+        Ok(role.role_id())
+    }
+
+    async fn connect(&self) -> anyhow::Result<Ldap> {
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(Duration::from_secs(CONNECT_TIMEOUT))
+            .set_starttls(self.starttls);
+        let (conn, ldap) = LdapConnAsync::with_settings(settings, &self.url).await?;
+        ldap3::drive!(conn);
+        Ok(ldap)
+    }
+
+    /// Look up `username` with the service account.
+    async fn find_user(
+        &self,
+        ldap: &mut Ldap,
+        username: &str,
+    ) -> anyhow::Result<Option<DirectoryUser>> {
+        if let Some(bind_dn) = &self.bind_dn {
+            ldap.simple_bind(bind_dn, self.bind_password.as_deref().unwrap_or(""))
+                .await?
+                .success()?;
+        }
+        let filter = self
+            .user_filter
+            .replace("{username}", &ldap_escape(username));
+        let mut attributes = vec![self.group_attribute.as_str()];
+        if let Some(email) = &self.email_attribute {
+            attributes.push(email);
+        }
+        let (entries, _) = ldap
+            .search(&self.search_base, Scope::Subtree, &filter, attributes)
+            .await?
+            .success()?;
+        let mut entries = entries.into_iter().map(SearchEntry::construct);
+        let (Some(entry), None) = (entries.next(), entries.next()) else {
+            log::info!("No unique entry for {username} in {}", self.search_base);
+            return Ok(None);
+        };
+        let mut groups = entry
+            .attrs
+            .get(&self.group_attribute)
+            .cloned()
+            .unwrap_or_default();
+        if let Some(base) = &self.group_search_base {
+            let filter = self.group_filter.replace("{dn}", &ldap_escape(&entry.dn));
+            let (entries, _) = ldap
+                .search(base, Scope::Subtree, &filter, vec!["1.1"])
+                .await?
+                .success()?;
+            groups.extend(
+                entries
+                    .into_iter()
+                    .map(|entry| SearchEntry::construct(entry).dn),
+            );
+        }
+        let email = self
+            .email_attribute
+            .as_ref()
+            .and_then(|attribute| entry.attrs.get(attribute))
+            .and_then(|values| values.first().cloned());
+        Ok(Some(DirectoryUser {
+            dn: entry.dn,
+            email,
+            groups,
+        }))
+    }
+
+    /// Verify the password by binding as the user, returns the user entry.
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> anyhow::Result<Option<DirectoryUser>> {
+        // an empty password is an anonymous bind, which most servers accept
+        if password.is_empty() {
+            return Ok(None);
+        }
+        let mut ldap = self.connect().await?;
+        let result = async {
+            let Some(user) = self.find_user(&mut ldap, username).await? else {
+                return Ok(None);
+            };
+            let bind = ldap.simple_bind(&user.dn, password).await?;
+            if bind.rc != 0 {
+                log::info!("Bind as {} failed: {}", user.dn, bind);
+                return Ok(None);
+            }
+            Ok(Some(user))
+        }
+        .await;
+        let _ = ldap.unbind().await;
+        result
+    }
+}
+
+/// Read the LDAP providers.
+pub fn get_ldap_configs(settings: &Settings) -> anyhow::Result<LdapConfigs> {
+    let Some(file) = &settings.ldap_file else {
+        return Ok(web::Data::new(BTreeMap::new()));
+    };
+    let file = std::fs::File::open(file)?;
+    let configs: BTreeMap<String, LdapConfig> = serde_yaml::from_reader(file)?;
+    Ok(web::Data::new(configs))
+}
+
+/// Log in `username` against the directory, returns the user and the name of
+/// the provider.
+///
+/// A user who logged in before only uses their provider, users of other
+/// sources are never looked up. Unknown users try the providers in order.
+/// Directories which cannot be reached count as a wrong password.
+pub async fn login(
+    repo: &crate::Repo,
+    configs: &LdapConfigs,
+    username: &str,
+    password: &str,
+) -> Result<Option<(User, String)>, AppError> {
+    let providers: Vec<&String> = match User::get_by_name(repo, username).await? {
+        Some(User {
+            source: UserSource::Ldap(provider),
+            ..
+        }) => configs.keys().filter(|name| **name == provider).collect(),
+        Some(_) => return Ok(None),
+        None => configs.keys().collect(),
+    };
+    for name in providers {
+        let config = &configs[name];
+        let directory_user = match config.authenticate(username, password).await {
+            Ok(Some(user)) => user,
+            Ok(None) => continue,
+            Err(e) => {
+                log::error!("LDAP login of {username} at {name} failed: {e}");
+                continue;
+            }
+        };
+        let role_id = match config.admit(&directory_user.groups) {
+            Ok(role_id) => role_id,
+            Err(reason) => {
+                log::warn!("Refusing LDAP login of {username} at {name}: {reason}");
+                return Err(AppError::Forbidden(format!("Login refused: {reason}.")));
+            }
+        };
+        let user = oauth::provision_user(
+            repo,
+            UserSource::Ldap(name.clone()),
+            &config.provisioning,
+            !config.group_roles.is_empty(),
+            username,
+            directory_user.email,
+            role_id,
+        )
+        .await?;
+        return Ok(Some((user, name.clone())));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        storage::MemoryRepository,
+        test_support::{LdapEntry, LdapServer},
+    };
+
+    fn person(uid: &str, groups: &[&str]) -> LdapEntry {
+        LdapEntry {
+            dn: format!("uid={uid},ou=people,dc=example"),
+            password: Some(format!("{uid}-password")),
+            attributes: vec![
+                ("uid".into(), vec![uid.into()]),
+                ("mail".into(), vec![format!("{uid}@example.com")]),
+                (
+                    "memberOf".into(),
+                    groups.iter().map(|g| g.to_string()).collect(),
+                ),
+            ],
+        }
+    }
+
+    async fn directory() -> LdapServer {
+        LdapServer::start(vec![
+            LdapEntry {
+                dn: "cn=search,dc=example".into(),
+                password: Some("search-password".into()),
+                attributes: vec![],
+            },
+            person(
+                "alice",
+                &[
+                    "cn=mqtt-admins,ou=groups,dc=example",
+                    "cn=mqtt-users,ou=groups,dc=example",
+                ],
+            ),
+            person("bob", &["cn=mqtt-users,ou=groups,dc=example"]),
+            person("eve", &[]),
+            // only listed as member of the group, like in directories without memberOf
+            person("carl", &[]),
+            LdapEntry {
+                dn: "cn=mqtt-admins,ou=groups,dc=example".into(),
+                password: None,
+                attributes: vec![(
+                    "member".into(),
+                    vec!["uid=carl,ou=people,dc=example".into()],
+                )],
+            },
+        ])
+        .await
+    }
+
+    fn config(directory: &LdapServer, extra: &str) -> LdapConfig {
+        serde_yaml::from_str(&format!(
+            "
+ui_name: Directory
+url: {}
+bind_dn: cn=search,dc=example
+bind_password: search-password
+search_base: ou=people,dc=example
+email_attribute: mail
+required_groups: [mqtt-users, mqtt-admins]
+group_roles:
+  - group: mqtt-admins
+    role: admin
+{extra}",
+            directory.url
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn verifies_the_password_by_binding_as_the_user() {
+        let directory = directory().await;
+        let config = config(&directory, "");
+        let alice = config
+            .authenticate("alice", "alice-password")
+            .await
+            .unwrap()
+            .expect("the password is right");
+        assert_eq!(alice.dn, "uid=alice,ou=people,dc=example");
+        assert_eq!(alice.email.as_deref(), Some("alice@example.com"));
+        assert_eq!(alice.groups.len(), 2);
+        assert_eq!(
+            directory.binds(),
+            [
+                ("cn=search,dc=example".to_string(), true),
+                ("uid=alice,ou=people,dc=example".to_string(), true)
+            ]
+        );
+
+        assert!(config
+            .authenticate("alice", "bob-password")
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            directory.binds().last(),
+            Some(&("uid=alice,ou=people,dc=example".to_string(), false))
+        );
+        assert!(config
+            .authenticate("nobody", "alice-password")
+            .await
+            .unwrap()
+            .is_none());
+        // an empty password would be an anonymous bind, the directory is not asked
+        let binds = directory.binds().len();
+        assert!(config.authenticate("alice", "").await.unwrap().is_none());
+        assert_eq!(directory.binds().len(), binds);
+    }
+
+    #[tokio::test]
+    async fn maps_groups_to_roles() {
+        let directory = directory().await;
+        let repo: crate::Repo = Arc::new(MemoryRepository::new(10));
+        let configs = web::Data::new(BTreeMap::from([(
+            "corp".to_string(),
+            config(&directory, ""),
+        )]));
+        let login = |name: &'static str| {
+            let (repo, configs) = (repo.clone(), configs.clone());
+            async move { login(&repo, &configs, name, &format!("{name}-password")).await }
+        };
+
+        let (alice, provider) = login("alice").await.unwrap().expect("alice logs in");
+        assert_eq!(provider, "corp");
+        assert_eq!(alice.role_id, RoleConfig::Admin.role_id());
+        assert_eq!(alice.source, UserSource::Ldap("corp".into()));
+        assert_eq!(alice.email.as_deref(), Some("alice@example.com"));
+        let (bob, _) = login("bob").await.unwrap().expect("bob logs in");
+        assert_eq!(bob.role_id, RoleConfig::User.role_id());
+        assert!(matches!(login("eve").await, Err(AppError::Forbidden(_))));
+        assert!(matches!(login("carl").await, Err(AppError::Forbidden(_))));
+        assert!(User::get_by_name(&repo, "eve").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn searches_the_groups_of_directories_without_member_of() {
+        let directory = directory().await;
+        let repo: crate::Repo = Arc::new(MemoryRepository::new(10));
+        let config = config(&directory, "group_search_base: ou=groups,dc=example");
+        let configs = web::Data::new(BTreeMap::from([("corp".to_string(), config)]));
+        let (carl, _) = login(&repo, &configs, "carl", "carl-password")
+            .await
+            .unwrap()
+            .expect("carl logs in");
+        assert_eq!(carl.role_id, RoleConfig::Admin.role_id());
+    }
+}
@@ -1,10 +1,17 @@
 use crate::{
+    audit::AuditLog,
+    error::AppError,
+    ldap::{self, LdapConfigs},
+    login_throttle::LoginThrottle,
     middleware::{
         fullpage_render::FullPageRender, htmx::HtmxHeaders, login_guard::LoginGuard,
         user_session::UserSession,
     },
-    models::user::User,
-    oauth::OauthConfigs,
+    models::user::{User, UserSource},
+    oauth::{self, OauthConfigs},
+    session_store::ServerSessionStore,
+    totp::TotpCipher,
+    two_factor::{self, SecondStep},
 };
 use actix_session::Session;
 use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
@@ -19,7 +26,11 @@ pub fn login_scoped(cfg: &mut web::ServiceConfig) {
                 .route(web::post().to(post)),
         ),
     );
-    cfg.service(web::scope("/logout").service(web::resource("/").route(web::post().to(logout))));
+    cfg.service(
+        web::scope("/logout")
+            .service(web::resource("/").route(web::post().to(logout)))
+            .service(web::resource("/everywhere").route(web::post().to(logout_everywhere))),
+    );
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -28,19 +39,28 @@ struct LoginForm {
     password: String,
 }
 
+#[allow(dead_code)]
 #[derive(Template)]
 #[template(path = "login.html")]
 pub struct LoginTemplate {
     pub hx: bool,
     pub user: Option<String>,
     pub configs: Vec<(String, String)>,
+    /// LDAP directories whose accounts log in with the form
+    pub directories: Vec<String>,
 }
 
-async fn get(req: HttpRequest, usersession: UserSession, configs: OauthConfigs) -> impl Responder {
+async fn get(
+    req: HttpRequest,
+    usersession: UserSession,
+    configs: OauthConfigs,
+    ldap_configs: LdapConfigs,
+) -> Result<HttpResponse, AppError> {
     let configs: Vec<(String, String)> = configs
         .iter()
         .map(|(k, v)| (k.clone(), v.ui_name.clone()))
         .collect();
+    let directories: Vec<String> = ldap_configs.values().map(|v| v.ui_name.clone()).collect();
     let template = if let Some(htmx) = req.extensions_mut().get_mut::<HtmxHeaders>() {
         log::debug!("Is htmx req? {}", htmx.request());
         if htmx.request() {
@@ -51,64 +71,188 @@ async fn get(req: HttpRequest, usersession: UserSession, configs: OauthConfigs)
             hx: htmx.request(),
             user: usersession.username,
             configs,
+            directories,
         }
     } else {
         LoginTemplate {
             hx: false,
             user: usersession.username,
             configs,
+            directories,
         }
     };
-    HttpResponse::Ok().body(template.render().unwrap())
+    Ok(HttpResponse::Ok().body(template.render()?))
 }
 
-async fn logout(_: LoginGuard, req: HttpRequest, session: Session) -> impl Responder {
-    session.purge();
+/// Send the browser to the login page, or to the provider to log out there as well.
+fn logged_out(req: &HttpRequest, end_session_url: Option<String>) -> HttpResponse {
+    let target = end_session_url.as_deref().unwrap_or("/login/");
     if let Some(htmx) = req.extensions_mut().get_mut::<HtmxHeaders>() {
         if htmx.request() {
-            htmx.set_redirect("/login/");
+            htmx.set_redirect(target);
             return HttpResponse::Ok().finish();
         }
     }
-    HttpResponse::Ok().finish()
+    match end_session_url {
+        Some(url) => HttpResponse::SeeOther()
+            .append_header(("Location", url))
+            .finish(),
+        None => HttpResponse::Ok().finish(),
+    }
+}
+
+async fn logout(
+    _: LoginGuard,
+    req: HttpRequest,
+    configs: OauthConfigs,
+    session: Session,
+) -> impl Responder {
+    let end_session_url = oauth::end_session_url(&session, &configs);
+    session.purge();
+    logged_out(&req, end_session_url)
+}
+
+/// End every session of the current user, including this one.
+async fn logout_everywhere(
+    _: LoginGuard,
+    req: HttpRequest,
+    usersession: UserSession,
+    store: web::Data<ServerSessionStore>,
+    configs: OauthConfigs,
+    session: Session,
+) -> Result<HttpResponse, AppError> {
+    let end_session_url = oauth::end_session_url(&session, &configs);
+    if let Some(username) = &usersession.username {
+        store.revoke_user(username).await?;
+    }
+    session.purge();
+    Ok(logged_out(&req, end_session_url))
+}
+
+/// Answer a refused login with a notice in the form errors.
+fn login_refused(req: &HttpRequest, message: &str) -> HttpResponse {
+    if let Some(htmx) = req.extensions_mut().get_mut::<HtmxHeaders>() {
+        if htmx.request() {
+            htmx.set_retarget("#form-errors");
+            htmx.set_reswap("innerHtml");
+            return HttpResponse::Ok().body(format!("<mark>{message}</mark>"));
+        }
+    }
+    HttpResponse::TooManyRequests().body(message.to_string())
+}
+
+/// Mark the session as logged in as `name`.
+pub fn complete_login(session: &Session, name: &str) {
+    // a fresh key on login, a key known before the login must not be taken over
+    session.renew();
+    let _ = session.insert("loggedin", "true");
+    let _ = session.insert("username", name);
+}
+
+/// The local user with the step left to log in, `None` if the password is
+/// wrong or the user unknown.
+async fn local_login(
+    repo: &crate::Repo,
+    cipher: &TotpCipher,
+    form: &LoginForm,
+) -> Result<Option<(User, SecondStep)>, AppError> {
+    if !User::check(repo, &form.name, &form.password, UserSource::Local).await? {
+        return Ok(None);
+    }
+    let user = User::get_by_name(repo, &form.name)
+        .await?
+        .ok_or_else(|| AppError::NotFound("User".into()))?;
+    let step = SecondStep::of(repo, cipher, &user).await?;
+    Ok(Some((user, step)))
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn post(
     req: HttpRequest,
-    db: web::Data<crate::DbPool>,
+    repo: web::Data<crate::Repo>,
+    throttle: web::Data<LoginThrottle>,
+    cipher: web::Data<TotpCipher>,
+    ldap_configs: LdapConfigs,
+    audit: web::Data<AuditLog>,
     form: web::Form<LoginForm>,
     session: Session,
-) -> impl Responder {
-    log::debug!("User: {form:?}");
-    let is_user = User::check(
-        &db,
-        &form.name,
-        &form.password,
-        crate::models::user::UserSource::Local,
-    )
-    .await;
-    if is_user {
-        let _ = session.insert("loggedin", "true");
-        let _ = session.insert("username", &form.name);
+) -> Result<HttpResponse, AppError> {
+    log::debug!("Login attempt of {}", form.name);
+    let ip = throttle.client_ip(&req);
+    let attempt = match throttle.attempt(&form.name, &ip).await? {
+        Ok(attempt) => attempt,
+        Err(blocked) => {
+            log::info!("Refusing login of {} from {}", form.name, ip);
+            audit.login_failed(&form.name, "locked", &ip).await;
+            return Ok(login_refused(&req, &blocked.message()));
+        }
+    };
+    // nobody is to blame if the password cannot be checked at all
+    let local = match local_login(&repo, &cipher, &form).await {
+        Ok(local) => local,
+        Err(e) => {
+            throttle.release(&attempt).await?;
+            return Err(e);
+        }
+    };
+    let is_user = local.is_some();
+    if let Some((user, step)) = local {
+        match step {
+            SecondStep::None => {
+                throttle.succeeded(&attempt).await?;
+                complete_login(&session, &form.name);
+                audit.login_succeeded(&user.name, &user.source, &ip).await;
+            }
+            step => {
+                // the failures are only forgotten once the second step is done
+                throttle.passed(&attempt).await?;
+                session.renew();
+                return two_factor::begin(&req, &session, &cipher, &user, step).await;
+            }
+        }
+    }
+    // the directory verifies the password, the second factor is left to it
+    let ldap_user = if is_user {
+        None
     } else {
+        match ldap::login(&repo, &ldap_configs, &form.name, &form.password).await {
+            Ok(ldap_user) => ldap_user,
+            Err(e) => {
+                throttle.failed(&attempt);
+                audit.login_failed(&form.name, &e.to_string(), &ip).await;
+                return Err(e);
+            }
+        }
+    };
+    if let Some((user, provider)) = &ldap_user {
+        throttle.succeeded(&attempt).await?;
+        complete_login(&session, &user.name);
+        let _ = session.insert("ldap_config", provider);
+        audit.login_succeeded(&user.name, &user.source, &ip).await;
+    } else if !is_user {
+        throttle.failed(&attempt);
         session.purge();
+        audit
+            .login_failed(&form.name, "wrong password or unknown user", &ip)
+            .await;
     }
+    let is_user = is_user || ldap_user.is_some();
     log::debug!("Session status: {:?}", session.status());
     // handle differently based on htmx or not
     if let Some(htmx) = req.extensions_mut().get_mut::<HtmxHeaders>() {
         if htmx.request() {
             if is_user {
                 htmx.set_redirect("/");
-                HttpResponse::Ok().finish()
+                Ok(HttpResponse::Ok().finish())
             } else {
                 htmx.set_retarget("#form-errors");
                 htmx.set_reswap("innerHtml");
-                HttpResponse::Ok().body("<mark>Password wrong or user unknown.</mark>")
+                Ok(HttpResponse::Ok().body("<mark>Password wrong or user unknown.</mark>"))
             }
         } else {
-            HttpResponse::Unauthorized().finish()
+            Ok(HttpResponse::Unauthorized().finish())
         }
     } else {
-        HttpResponse::Unauthorized().finish()
+        Ok(HttpResponse::Unauthorized().finish())
     }
 }
@@ -0,0 +1,501 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use actix_web::HttpRequest;
+use bb8_redis::redis::cmd;
+use serde::{Deserialize, Serialize};
+
+use crate::{settings::LoginSettings, storage::StorageError};
+
+/// Failed logins of one username or IP address.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Attempts {
+    failures: u32,
+    /// unix timestamp in milliseconds until which logins are refused
+    blocked_until: i64,
+    /// whether the block is a lockout rather than a delay
+    locked: bool,
+}
+
+/// Why a login attempt is refused before the password is checked.
+#[derive(Debug, Clone)]
+pub struct Blocked {
+    /// unix timestamp in milliseconds
+    pub until: i64,
+    pub locked: bool,
+}
+
+impl Blocked {
+    pub fn message(&self) -> String {
+        let seconds = ((self.until - now()) as f64 / 1000.0).ceil().max(1.0) as i64;
+        if self.locked {
+            format!(
+                "Too many failed logins, locked for {} min.",
+                (seconds + 59) / 60
+            )
+        } else {
+            format!("Too many failed logins, try again in {seconds}s.")
+        }
+    }
+}
+
+/// A login attempt admitted by [`LoginThrottle::attempt`].
+#[derive(Debug, Clone)]
+pub struct Attempt {
+    name: String,
+    ip: String,
+    /// failures of the username before and including this attempt
+    user_before: Attempts,
+    user: Attempts,
+    /// failures of the address before and including this attempt
+    address_before: Attempts,
+    address: Attempts,
+}
+
+/// Lock state of a user shown on the edit page.
+pub struct LockStatus {
+    pub failures: u32,
+    pub locked_until: Option<String>,
+}
+
+#[derive(Clone)]
+enum Backend {
+    Redis(crate::DbPool),
+    /// attempts by key with their expiry in unix milliseconds
+    Memory(Arc<Mutex<HashMap<String, (Attempts, i64)>>>),
+}
+
+/// Slows down password guessing per username and per IP address.
+///
+/// Every failure doubles the delay until the next attempt is accepted, after
+/// too many failures the username or address is locked out for a while.
+/// Failures are forgotten after a quiet window.
+#[derive(Clone)]
+pub struct LoginThrottle {
+    backend: Backend,
+    settings: LoginSettings,
+}
+
+/// Set `KEYS[1]` to `ARGV[2]` with a ttl of `ARGV[3]` milliseconds if it
+/// still holds `ARGV[1]`, an empty string standing for a missing key.
+const COMPARE_AND_SET: &str = r#"
+if (redis.call('GET', KEYS[1]) or '') ~= ARGV[1] then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[2], 'PX', ARGV[3])
+return 1
+"#;
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+fn user_key(name: &str) -> String {
+    format!("login_attempts:user:{}", name)
+}
+
+fn ip_key(ip: &str) -> String {
+    format!("login_attempts:ip:{}", ip)
+}
+
+impl LoginThrottle {
+    pub fn new(pool: Option<crate::DbPool>, settings: &LoginSettings) -> Self {
+        let backend = match pool {
+            Some(pool) => Backend::Redis(pool),
+            None => Backend::Memory(Arc::new(Mutex::new(HashMap::new()))),
+        };
+        LoginThrottle {
+            backend,
+            settings: settings.clone(),
+        }
+    }
+
+    /// Address the attempt comes from, proxy headers are only used if trusted.
+    pub fn client_ip(&self, req: &HttpRequest) -> String {
+        if self.settings.trust_proxy_headers {
+            if let Some(ip) = req.connection_info().realip_remote_addr() {
+                return ip.to_string();
+            }
+        }
+        req.peer_addr()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_default()
+    }
+
+    async fn get(&self, key: &str) -> Result<Attempts, StorageError> {
+        match &self.backend {
+            Backend::Redis(pool) => {
+                let mut conn = pool.get().await?;
+                let attempts: Option<String> = cmd("GET").arg(key).query_async(&mut *conn).await?;
+                Ok(attempts
+                    .map(|attempts| serde_json::from_str(&attempts))
+                    .transpose()?
+                    .unwrap_or_default())
+            }
+            Backend::Memory(attempts) => Ok(attempts
+                .lock()
+                .map_err(|_| StorageError::Backend("login throttle is poisoned".into()))?
+                .get(key)
+                .filter(|(_, expires)| *expires > now())
+                .map(|(attempts, _)| attempts.clone())
+                .unwrap_or_default()),
+        }
+    }
+
+    /// Milliseconds the attempts are kept, at least as long as their block.
+    fn ttl(&self, attempts: &Attempts, now: i64) -> i64 {
+        (self.settings.window as i64 * 1000)
+            .max(attempts.blocked_until - now)
+            .max(1)
+    }
+
+    /// Atomically replace the attempts at `key` by the result of `change`,
+    /// `None` leaves them untouched. Returns the attempts before and after.
+    ///
+    /// Concurrent logins must not read the same count, on redis the new
+    /// attempts are only written if the key did not change in between.
+    async fn update<F>(
+        &self,
+        key: &str,
+        change: F,
+    ) -> Result<(Attempts, Option<Attempts>), StorageError>
+    where
+        F: Fn(&Attempts) -> Option<Attempts>,
+    {
+        let now = now();
+        match &self.backend {
+            Backend::Redis(pool) => {
+                let mut conn = pool.get().await?;
+                loop {
+                    let current: Option<String> =
+                        cmd("GET").arg(key).query_async(&mut *conn).await?;
+                    let before: Attempts = current
+                        .as_deref()
+                        .map(serde_json::from_str)
+                        .transpose()?
+                        .unwrap_or_default();
+                    let Some(after) = change(&before) else {
+                        return Ok((before, None));
+                    };
+                    let swapped: i32 = cmd("EVAL")
+                        .arg(COMPARE_AND_SET)
+                        .arg(1)
+                        .arg(key)
+                        .arg(current.unwrap_or_default())
+                        .arg(serde_json::to_string(&after)?)
+                        .arg(self.ttl(&after, now))
+                        .query_async(&mut *conn)
+                        .await?;
+                    if swapped == 1 {
+                        return Ok((before, Some(after)));
+                    }
+                }
+            }
+            Backend::Memory(map) => {
+                let mut map = map
+                    .lock()
+                    .map_err(|_| StorageError::Backend("login throttle is poisoned".into()))?;
+                map.retain(|_, (_, expires)| *expires > now);
+                let before = map
+                    .get(key)
+                    .map(|(attempts, _)| attempts.clone())
+                    .unwrap_or_default();
+                let after = change(&before);
+                if let Some(after) = &after {
+                    map.insert(key.to_string(), (after.clone(), now + self.ttl(after, now)));
+                }
+                Ok((before, after))
+            }
+        }
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), StorageError> {
+        match &self.backend {
+            Backend::Redis(pool) => {
+                let mut conn = pool.get().await?;
+                let _: () = cmd("DEL").arg(key).query_async(&mut *conn).await?;
+            }
+            Backend::Memory(map) => {
+                map.lock()
+                    .map_err(|_| StorageError::Backend("login throttle is poisoned".into()))?
+                    .remove(key);
+            }
+        }
+        Ok(())
+    }
+
+    /// Count one more failure, locking after `lockout_after` failures.
+    fn counted(&self, attempts: &Attempts, lockout_after: u32, now: i64) -> Attempts {
+        let settings = &self.settings;
+        let failures = attempts.failures + 1;
+        if failures >= lockout_after {
+            Attempts {
+                failures,
+                blocked_until: now + settings.lockout_duration as i64 * 1000,
+                locked: true,
+            }
+        } else {
+            let delay = settings
+                .base_delay
+                .saturating_mul(1 << (failures - 1).min(31))
+                .min(settings.max_delay);
+            Attempts {
+                failures,
+                blocked_until: now + delay as i64 * 1000,
+                locked: false,
+            }
+        }
+    }
+
+    /// Count an attempt against `key` unless it is blocked, returns the attempts before and after.
+    async fn count(
+        &self,
+        key: &str,
+        lockout_after: u32,
+    ) -> Result<Result<(Attempts, Attempts), Blocked>, StorageError> {
+        let now = now();
+        let (before, after) = self
+            .update(key, |attempts| {
+                (attempts.blocked_until <= now).then(|| self.counted(attempts, lockout_after, now))
+            })
+            .await?;
+        Ok(match after {
+            Some(after) => Ok((before, after)),
+            None => Err(Blocked {
+                until: before.blocked_until,
+                locked: before.locked,
+            }),
+        })
+    }
+
+    /// Take back an attempt counted against `key`, which changed it from
+    /// `before` to `after`.
+    async fn uncount(
+        &self,
+        key: &str,
+        before: &Attempts,
+        after: &Attempts,
+    ) -> Result<(), StorageError> {
+        self.update(key, |current| {
+            Some(if current.blocked_until == after.blocked_until {
+                // nothing was counted since
+                before.clone()
+            } else {
+                Attempts {
+                    failures: current.failures.saturating_sub(1),
+                    ..current.clone()
+                }
+            })
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Admit a login of `name` from `ip` unless the username or the address
+    /// is blocked.
+    ///
+    /// The attempt is counted as a failure before the password is checked, so
+    /// parallel guesses cannot pass before the first failure is recorded. It
+    /// is taken back by [`LoginThrottle::passed`], [`LoginThrottle::succeeded`]
+    /// and [`LoginThrottle::release`].
+    pub async fn attempt(
+        &self,
+        name: &str,
+        ip: &str,
+    ) -> Result<Result<Attempt, Blocked>, StorageError> {
+        let settings = &self.settings;
+        let user_key = user_key(name);
+        let (user_before, user) = match self.count(&user_key, settings.user_lockout_after).await? {
+            Ok(counted) => counted,
+            Err(blocked) => return Ok(Err(blocked)),
+        };
+        let (address_before, address) =
+            match self.count(&ip_key(ip), settings.ip_lockout_after).await? {
+                Ok(counted) => counted,
+                Err(blocked) => {
+                    // the username is not to blame for a blocked address
+                    self.uncount(&user_key, &user_before, &user).await?;
+                    return Ok(Err(blocked));
+                }
+            };
+        Ok(Ok(Attempt {
+            name: name.to_string(),
+            ip: ip.to_string(),
+            user_before,
+            user,
+            address_before,
+            address,
+        }))
+    }
+
+    /// Note that the admitted `attempt` failed, it is already counted.
+    pub fn failed(&self, attempt: &Attempt) {
+        let settings = &self.settings;
+        if attempt.user.failures == settings.user_lockout_after {
+            log::warn!(
+                "Login of {} locked after too many failures, last from {}",
+                attempt.name,
+                attempt.ip
+            );
+        }
+        if attempt.address.failures == settings.ip_lockout_after {
+            log::warn!("Logins from {} locked after too many failures", attempt.ip);
+        }
+    }
+
+    /// The password of `attempt` was right, take it back from the address.
+    ///
+    /// The username keeps the failure until the login is complete, so a
+    /// second factor cannot be guessed by logging in again and again, but
+    /// not its delay, the second step follows right away.
+    pub async fn passed(&self, attempt: &Attempt) -> Result<(), StorageError> {
+        self.uncount(
+            &ip_key(&attempt.ip),
+            &attempt.address_before,
+            &attempt.address,
+        )
+        .await?;
+        let blocked_until = attempt.user.blocked_until;
+        self.update(&user_key(&attempt.name), |current| {
+            (!current.locked && current.blocked_until == blocked_until).then(|| Attempts {
+                blocked_until: 0,
+                ..current.clone()
+            })
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Forget the failures of the username after a complete login.
+    pub async fn succeeded(&self, attempt: &Attempt) -> Result<(), StorageError> {
+        self.uncount(
+            &ip_key(&attempt.ip),
+            &attempt.address_before,
+            &attempt.address,
+        )
+        .await?;
+        self.remove(&user_key(&attempt.name)).await
+    }
+
+    /// Take back an `attempt` which could not be checked, e.g. because the
+    /// storage failed, neither the username nor the address are to blame.
+    pub async fn release(&self, attempt: &Attempt) -> Result<(), StorageError> {
+        self.uncount(
+            &ip_key(&attempt.ip),
+            &attempt.address_before,
+            &attempt.address,
+        )
+        .await?;
+        self.uncount(
+            &user_key(&attempt.name),
+            &attempt.user_before,
+            &attempt.user,
+        )
+        .await
+    }
+
+    /// Lift a lockout of `name`, the failures are forgotten as well.
+    ///
+    /// Blocks of the addresses the failures came from stay until they end.
+    pub async fn unlock(&self, name: &str) -> Result<(), StorageError> {
+        log::info!("Unlocking login of {name}");
+        self.remove(&user_key(name)).await
+    }
+
+    pub async fn status(&self, name: &str) -> Result<LockStatus, StorageError> {
+        let attempts = self.get(&user_key(name)).await?;
+        let locked_until = (attempts.locked && attempts.blocked_until > now())
+            .then(|| chrono::DateTime::from_timestamp_millis(attempts.blocked_until))
+            .flatten()
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string());
+        Ok(LockStatus {
+            failures: attempts.failures,
+            locked_until,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle() -> LoginThrottle {
+        LoginThrottle::new(None, &LoginSettings::default())
+    }
+
+    #[test]
+    fn doubles_the_delay_up_to_the_maximum() {
+        let throttle = LoginThrottle::new(
+            None,
+            &LoginSettings {
+                user_lockout_after: 100,
+                ..LoginSettings::default()
+            },
+        );
+        let mut attempts = Attempts::default();
+        let mut delays = Vec::new();
+        for _ in 0..8 {
+            attempts = throttle.counted(&attempts, 100, 0);
+            assert!(!attempts.locked);
+            delays.push(attempts.blocked_until / 1000);
+        }
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60]);
+        // far beyond the shift width the delay stays at the maximum
+        attempts.failures = 70;
+        assert_eq!(throttle.counted(&attempts, 100, 0).blocked_until, 60_000);
+    }
+
+    #[test]
+    fn locks_out_after_too_many_failures() {
+        let throttle = throttle();
+        let attempts = Attempts {
+            failures: 4,
+            ..Attempts::default()
+        };
+        let locked = throttle.counted(&attempts, 5, 1000);
+        assert!(locked.locked);
+        assert_eq!(locked.failures, 5);
+        assert_eq!(locked.blocked_until, 1000 + 15 * 60 * 1000);
+    }
+
+    #[tokio::test]
+    async fn refuses_attempts_during_the_delay() {
+        let throttle = throttle();
+        let attempt = throttle.attempt("kim", "10.0.0.1").await.unwrap().unwrap();
+        throttle.failed(&attempt);
+        let blocked = throttle
+            .attempt("kim", "10.0.0.2")
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert!(!blocked.locked);
+        // the address of the refused attempt is not counted
+        let other = throttle.attempt("ann", "10.0.0.2").await.unwrap().unwrap();
+        assert_eq!(other.address.failures, 1);
+    }
+
+    #[tokio::test]
+    async fn admits_the_second_step_right_after_the_password() {
+        let throttle = throttle();
+        let password = throttle.attempt("kim", "10.0.0.1").await.unwrap().unwrap();
+        throttle.passed(&password).await.unwrap();
+        let second = throttle.attempt("kim", "10.0.0.1").await.unwrap().unwrap();
+        // the username keeps counting until the login is complete
+        assert_eq!(second.user.failures, 2);
+        assert_eq!(second.address.failures, 1);
+        throttle.succeeded(&second).await.unwrap();
+        assert_eq!(throttle.status("kim").await.unwrap().failures, 0);
+    }
+
+    #[tokio::test]
+    async fn releases_attempts_which_could_not_be_checked() {
+        let throttle = throttle();
+        let attempt = throttle.attempt("kim", "10.0.0.1").await.unwrap().unwrap();
+        throttle.release(&attempt).await.unwrap();
+        let again = throttle.attempt("kim", "10.0.0.1").await.unwrap().unwrap();
+        assert_eq!(again.user.failures, 1);
+        assert_eq!(again.address.failures, 1);
+    }
+}
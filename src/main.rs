@@ -4,7 +4,11 @@ use crate::models::mqtt_client::MqttClient;
 use crate::models::user::Role;
 use actix::System;
 use actix_files::NamedFile;
-use actix_session::{storage::CookieSessionStore, Session, SessionMiddleware};
+use actix_session::{
+    config::{BrowserSession, TtlExtensionPolicy},
+    Session, SessionMiddleware,
+};
+use actix_web::dev::ServerHandle;
 use actix_web::{cookie::Key, get, web, App, HttpResponse, HttpServer, Responder};
 use base64::Engine;
 use bb8::Pool;
@@ -13,26 +17,64 @@ use clap::Args;
 use clap::Parser;
 use clap::Subcommand;
 use middleware::htmx::Htmx;
+use session_store::ServerSessionStore;
+use settings::{PoolSettings, ServeArgs, Settings, ShutdownSettings};
 
+mod alerting;
+mod alerts;
+mod audit;
+mod bridges;
+mod config_sync;
+mod error;
+mod health;
+mod history;
+mod ldap;
 mod login;
+mod login_throttle;
+mod message_export;
 mod middleware;
 mod models;
 mod mqtt;
 mod mqtt_client;
 mod mqtt_clients;
 mod oauth;
+mod payload;
+mod publish_templates;
+mod recordings;
+mod rules;
+mod scheduler;
+mod schedules;
+mod session_store;
+mod sessions;
+mod settings;
+mod storage;
 mod subscribe;
+#[cfg(test)]
+mod test_support;
+mod tls;
+mod totp;
+mod two_factor;
 mod user;
 mod users;
+mod webhook_delivery;
+mod webhooks;
 
 type DbPool = Pool<RedisMultiplexedConnectionManager>;
+type Repo = std::sync::Arc<dyn storage::Repository>;
 
 #[derive(Subcommand, Debug)]
 enum CliCommands {
-    Serve,
+    /// run the web server, the default command
+    Serve(ServeArgs),
+    /// print the effective settings and check them for problems
+    CheckConfig(ServeArgs),
     CreateSessionKey,
     CreateInitUser(CreateInitUserArgs),
     CreateClient(CreateClientArgs),
+    ExportMessages(ExportMessagesArgs),
+    ImportMessages(ImportMessagesArgs),
+    ExportConfig(ExportConfigArgs),
+    ImportConfig(ImportConfigArgs),
 }
 
 #[derive(Args, Debug)]
@@ -49,10 +91,67 @@ struct CreateClientArgs {
     url: String,
 }
 
+#[derive(Args, Debug)]
+struct ExportMessagesArgs {
+    client: String,
+    /// topic filter of the exported messages
+    #[arg(long, default_value = "#")]
+    filter: String,
+    /// jsonl, csv or binary
+    #[arg(long, default_value = "jsonl")]
+    format: String,
+    /// start of the exported range, RFC 3339 or YYYY-MM-DDTHH:MM in UTC
+    #[arg(long)]
+    from: Option<String>,
+    /// end of the exported range, RFC 3339 or YYYY-MM-DDTHH:MM in UTC
+    #[arg(long)]
+    to: Option<String>,
+    /// file to write to instead of stdout
+    #[arg(long)]
+    output: Option<std::path::PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct ImportMessagesArgs {
+    client: String,
+    file: std::path::PathBuf,
+    /// jsonl, csv or binary, guessed from the file extension if not given
+    #[arg(long)]
+    format: Option<String>,
+    /// publish the messages on the client instead of storing them in its history
+    #[arg(long)]
+    publish: bool,
+}
+
+#[derive(Args, Debug)]
+struct ExportConfigArgs {
+    /// yaml or json
+    #[arg(long, default_value = "yaml")]
+    format: String,
+    /// file to write to instead of stdout
+    #[arg(long)]
+    output: Option<std::path::PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct ImportConfigArgs {
+    /// YAML or JSON config file
+    file: std::path::PathBuf,
+    /// remove clients, topics and users not listed in the file
+    #[arg(long)]
+    sync: bool,
+    /// only print the changes
+    #[arg(long)]
+    dry_run: bool,
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
 struct CliArgs {
+    /// TOML or YAML config file, defaults to MQTTPAL_CONFIG
+    #[arg(long, global = true)]
+    config: Option<std::path::PathBuf>,
     // command used (default "server")
     #[command(subcommand)]
     command: Option<CliCommands>,
@@ -78,8 +177,8 @@ fn create_session_key() -> Key {
     Key::generate()
 }
 
-fn get_session_key() -> Key {
-    let key = std::env::var("SESSION_KEY").unwrap_or_else(|_| {
+fn get_session_key(settings: &Settings) -> Key {
+    let key = settings.session_key.clone().unwrap_or_else(|| {
         let key = create_session_key();
         let key_master = base64::engine::general_purpose::STANDARD.encode(key.master());
         let key_sign = base64::engine::general_purpose::STANDARD.encode(key.signing());
@@ -94,23 +193,132 @@ fn get_session_key() -> Key {
     )
 }
 
+async fn redis_pool(url: &str, settings: &PoolSettings) -> DbPool {
+    let manager =
+        bb8_redis::RedisMultiplexedConnectionManager::new(url).expect("Cannot connect to redis");
+    bb8::Pool::builder()
+        .min_idle(Some(settings.min_idle))
+        .max_size(settings.max_size)
+        .connection_timeout(settings.connection_timeout())
+        .build(manager)
+        .await
+        .expect("Cannot create redis pool")
+}
+
+/// Pick the storage backend from the scheme of the database url.
+///
+/// Rules, bridges, webhooks, recordings, schedules and templates are only
+/// available with a redis connection.
+async fn connect_storage(settings: &Settings) -> (Repo, Option<DbPool>) {
+    let database_url = settings.database_url.as_deref().unwrap_or_default();
+    let history_max_len = settings.retention.history;
+    match database_url.split_once(':').map(|(scheme, _)| scheme) {
+        Some("memory") => {
+            log::warn!("Using in-memory storage, nothing is persisted across restarts");
+            let repo = storage::MemoryRepository::new(history_max_len);
+            (std::sync::Arc::new(repo), None)
+        }
+        Some("sqlite") => {
+            let repo =
+                storage::SqliteRepository::connect(database_url, &settings.pool, history_max_len)
+                    .await
+                    .expect("Cannot open sqlite database");
+            (std::sync::Arc::new(repo), None)
+        }
+        Some("redis" | "rediss" | "redis+unix" | "unix") => {
+            let pool = redis_pool(database_url, &settings.pool).await;
+            let repo = storage::RedisRepository::new(pool.clone(), history_max_len);
+            (std::sync::Arc::new(repo), Some(pool))
+        }
+        _ => panic!(
+            "Unsupported DATABASE_URL '{database_url}', use redis://, sqlite:// or memory://"
+        ),
+    }
+}
+
+/// Wait for SIGTERM or Ctrl-C, then disconnect the MQTT clients and stop the
+/// http servers, all within the shutdown timeout.
+async fn shutdown_on_signal(
+    mqtt: mqtt::MqttClientManager,
+    settings: ShutdownSettings,
+    servers: Vec<ServerHandle>,
+) {
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                log::error!("Cannot listen for SIGTERM: {e}");
+                std::future::pending::<()>().await
+            }
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => log::info!("Received Ctrl-C"),
+        _ = terminate => log::info!("Received SIGTERM"),
+    }
+    log::info!("Shutting down within {}s", settings.timeout);
+    // one deadline for all steps, the last quarter is left to the http servers
+    let start = tokio::time::Instant::now();
+    let deadline = start + settings.timeout();
+    let graceful = async {
+        mqtt.shutdown(&settings, start + settings.timeout() * 3 / 4)
+            .await;
+        futures_util::future::join_all(servers.iter().map(|server| server.stop(true))).await;
+    };
+    if tokio::time::timeout_at(deadline, graceful).await.is_err() {
+        log::warn!("Shutdown timed out, closing the remaining connections");
+        futures_util::future::join_all(servers.iter().map(|server| server.stop(false))).await;
+    }
+}
+
+/// Print the effective settings and all problems found in them.
+fn check_config(settings: &Settings) -> std::io::Result<()> {
+    println!("{}", settings.to_redacted_toml());
+    match settings.validate() {
+        Ok(()) => {
+            eprintln!("Configuration is valid");
+            Ok(())
+        }
+        Err(problems) => {
+            for problem in &problems {
+                eprintln!("error: {problem}");
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
-    pretty_env_logger::init_timed();
     let cli = CliArgs::parse();
-    log::debug!("Command Line Args: {:?}", cli);
-    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let manager = bb8_redis::RedisMultiplexedConnectionManager::new(database_url)
-        .expect("Cannot connect to redis");
-    let pool = bb8::Pool::builder()
-        .min_idle(Some(2))
-        .max_size(5)
-        .connection_timeout(std::time::Duration::from_secs(5))
-        .build(manager)
-        .await
-        .expect("Cannot create redis pool");
-    match cli.command.unwrap_or(CliCommands::Serve) {
+    let command = cli
+        .command
+        .unwrap_or_else(|| CliCommands::Serve(ServeArgs::default()));
+    let mut settings = Settings::load(cli.config.as_deref()).unwrap_or_else(|e| {
+        eprintln!("error: {e:#}");
+        std::process::exit(1);
+    });
+    if let CliCommands::Serve(args) | CliCommands::CheckConfig(args) = &command {
+        args.apply(&mut settings);
+    }
+    if let CliCommands::CheckConfig(_) = command {
+        return check_config(&settings);
+    }
+    pretty_env_logger::formatted_timed_builder()
+        .parse_filters(&settings.log.filter)
+        .init();
+    log::debug!("Command: {:?}", command);
+    if let Err(problems) = settings.validate() {
+        for problem in problems {
+            log::error!("Invalid configuration: {problem}");
+        }
+        std::process::exit(1);
+    }
+    let (repo, pool) = connect_storage(&settings).await;
+    match command {
         CliCommands::CreateSessionKey => {
             log::info!("Generating session key");
             let key = create_session_key();
@@ -121,7 +329,10 @@ async fn main() -> std::io::Result<()> {
             Ok(())
         }
         CliCommands::CreateInitUser(user) => {
-            let existing_users = models::user::User::list(&pool).await.len();
+            let existing_users = models::user::User::list(&repo)
+                .await
+                .expect("Cannot list users")
+                .len();
             if existing_users > 0 {
                 log::info!("Users already exist, skipping init user creation");
                 return Ok(());
@@ -132,8 +343,9 @@ async fn main() -> std::io::Result<()> {
                 email: user.email,
                 role_id: user.role_id.unwrap_or(Role::Admin as i32),
                 source: models::user::UserSource::Local,
+                totp: None,
             };
-            user.insert(&pool).await;
+            user.insert(&repo).await.expect("Cannot insert user");
             log::info!("Inserted User: {}", user.name);
             // do some serve
             Ok(())
@@ -143,54 +355,309 @@ async fn main() -> std::io::Result<()> {
                 name: client.name,
                 url: client.url,
             };
-            let result = new_client.insert(&pool).await;
+            let result = new_client.insert(&repo).await;
             log::info!("Inserted Client: {result:?}");
             // do some serve
             Ok(())
         }
-        CliCommands::Serve => {
-            let mqtt_manager = mqtt::MqttClientManager::new();
-            let session_key = get_session_key();
-            let clients = MqttClient::list(&pool).await;
-            let oauth_cfg = oauth::get_oauth_configs().await.unwrap();
-            for client in clients {
-                let topics = MqttClient::topics(&pool, &client.name).await;
+        CliCommands::ExportMessages(args) => {
+            let format: message_export::MessageFormat =
+                args.format.parse().expect("invalid format");
+            let time = |time: Option<String>| {
+                time.map(|time| message_export::parse_time(&time).expect("invalid time"))
+            };
+            let query = message_export::ExportQuery {
+                filter: args.filter,
+                from: time(args.from),
+                to: time(args.to),
+            };
+            let mut output: Box<dyn std::io::Write> = match args.output {
+                Some(path) => Box::new(std::fs::File::create(path)?),
+                None => Box::new(std::io::stdout().lock()),
+            };
+            let chunks = message_export::export(repo.clone(), args.client, query, format);
+            futures_util::pin_mut!(chunks);
+            while let Some(chunk) = futures_util::StreamExt::next(&mut chunks).await {
+                output.write_all(&chunk?)?;
+            }
+            output.flush()
+        }
+        CliCommands::ImportMessages(args) => {
+            let format = match args.format {
+                Some(format) => format.parse().expect("invalid format"),
+                None => message_export::MessageFormat::from_file_name(&args.file.to_string_lossy())
+                    .expect("cannot tell the format from the file name, use --format"),
+            };
+            let entries = message_export::decode(format, std::fs::File::open(&args.file)?);
+            let mqtt_manager = mqtt::MqttClientManager::new(
+                repo.clone(),
+                pool.clone(),
+                settings.mqtt.clone(),
+                settings.retention.clone(),
+            );
+            let mode = if args.publish {
+                let client = MqttClient::get_by_name(&repo, &args.client)
+                    .await
+                    .expect("Cannot query client")
+                    .expect("client not found");
                 mqtt_manager
-                    .register_client(client.name, client.url, topics)
+                    .register_client(client.name, client.url, vec![])
+                    .await
+                    .expect("cannot connect client");
+                message_export::ImportMode::Publish
+            } else {
+                message_export::ImportMode::History
+            };
+            let result =
+                message_export::import(&repo, &mqtt_manager, &args.client, entries, mode).await;
+            if args.publish {
+                mqtt_manager
+                    .unregister_client_and_wait(&args.client, std::time::Duration::from_secs(10))
+                    .await;
+            }
+            match result {
+                Ok(imported) => log::info!("Imported {} messages", imported),
+                Err(e) => log::error!("Import failed: {}", e),
+            }
+            Ok(())
+        }
+        CliCommands::ExportConfig(args) => {
+            let format: config_sync::ConfigFormat = args.format.parse().expect("invalid format");
+            let config = config_sync::Config::export(&repo)
+                .await
+                .expect("Cannot read config")
+                .render(format);
+            match args.output {
+                Some(path) => std::fs::write(path, config),
+                None => {
+                    print!("{config}");
+                    Ok(())
+                }
+            }
+        }
+        CliCommands::ImportConfig(args) => {
+            let content = std::fs::read_to_string(&args.file)?;
+            let config = config_sync::Config::parse(&content).expect("invalid config");
+            let changes = config_sync::diff(&repo, &config, args.sync)
+                .await
+                .expect("invalid config");
+            for change in &changes {
+                println!("{change}");
+            }
+            if !args.dry_run {
+                // clients connect on the next start of the server
+                let mqtt_manager = mqtt::MqttClientManager::new(
+                    repo.clone(),
+                    pool.clone(),
+                    settings.mqtt.clone(),
+                    settings.retention.clone(),
+                );
+                let audit_log = audit::AuditLog::new(pool.clone(), settings.retention.audit);
+                // sessions only outlive the server in redis
+                let sessions = pool
+                    .clone()
+                    .map(|pool| ServerSessionStore::new(Some(pool), &settings.session));
+                config_sync::apply(
+                    &repo,
+                    pool.as_ref(),
+                    &mqtt_manager,
+                    sessions.as_ref(),
+                    &audit_log,
+                    "cli",
+                    &changes,
+                    false,
+                )
+                .await
+                .expect("Cannot apply config");
+                log::info!("Applied {} changes", changes.len());
+            }
+            Ok(())
+        }
+        CliCommands::CheckConfig(_) => unreachable!("handled before connecting the storage"),
+        CliCommands::Serve(_) => {
+            let mqtt_manager = mqtt::MqttClientManager::new(
+                repo.clone(),
+                pool.clone(),
+                settings.mqtt.clone(),
+                settings.retention.clone(),
+            );
+            let session_key = get_session_key(&settings);
+            let session_store = ServerSessionStore::new(pool.clone(), &settings.session);
+            let login_throttle = web::Data::new(login_throttle::LoginThrottle::new(
+                pool.clone(),
+                &settings.login,
+            ));
+            let totp_cipher = web::Data::new(
+                totp::TotpCipher::new(settings.totp_key.as_deref()).expect("Invalid totp_key"),
+            );
+            let idle_timeout =
+                actix_web::cookie::time::Duration::seconds(settings.session.idle_timeout as i64);
+            let clients = MqttClient::list(&repo).await.expect("Cannot list clients");
+            let (oauth_cfg, oauth_failed) = oauth::get_oauth_configs(&settings)
+                .await
+                .expect("Cannot read oauth configs");
+            let ldap_cfg = ldap::get_ldap_configs(&settings).expect("Cannot read ldap configs");
+            let audit_log =
+                web::Data::new(audit::AuditLog::new(pool.clone(), settings.retention.audit));
+            let readiness = web::Data::new(health::Readiness {
+                oauth_failed,
+                settings: settings.health.clone(),
+            });
+            mqtt_manager.reload_bridges().await;
+            mqtt_manager.reload_webhooks().await;
+            mqtt_manager.reload_recordings().await;
+            mqtt_manager.reload_alerts().await;
+            if let Some(pool) = &pool {
+                let recordings = models::recording::Recording::list(pool)
+                    .await
+                    .expect("Cannot list recordings");
+                for recording in recordings {
+                    if recording.active() {
+                        recordings::stop_after_window(
+                            pool.clone(),
+                            mqtt_manager.clone(),
+                            &recording,
+                        );
+                    }
+                }
+                tokio::spawn(webhook_delivery::run(
+                    pool.clone(),
+                    settings.retention.webhook_log,
+                ));
+                tokio::spawn(scheduler::run(
+                    pool.clone(),
+                    repo.clone(),
+                    mqtt_manager.clone(),
+                ));
+                let notifier = alerting::Notifier::new(
+                    pool.clone(),
+                    repo.clone(),
+                    &settings.smtp,
+                    settings.server.base_url(),
+                    settings.retention.notifications,
+                )
+                .expect("Cannot set up alert notifications");
+                tokio::spawn(alerting::run(mqtt_manager.clone(), notifier));
+            }
+            for client in clients {
+                let topics = MqttClient::topics(&repo, &client.name)
+                    .await
+                    .expect("Cannot query topics");
+                if let Err(e) = mqtt_manager
+                    .register_client(client.name.clone(), client.url, topics)
                     .await
-                    .unwrap();
+                {
+                    log::error!("Cannot connect client {}: {}", client.name, e);
+                }
             }
             log::info!(
                 "Current Actix System: {}",
                 System::id(&System::try_current().unwrap())
             );
-            HttpServer::new(move || {
-                App::new()
-                    .app_data(web::Data::new(pool.clone()))
+            let extras = pool.is_some();
+            let shutdown_manager = mqtt_manager.clone();
+            let secure = settings.server.secure();
+            let oauth_revalidate = middleware::oauth_revalidate::OauthRevalidate::default();
+            let server = HttpServer::new(move || {
+                let app = App::new();
+                // handlers of the redis only features take the pool as app data
+                let app = match &pool {
+                    Some(pool) => app.app_data(web::Data::new(pool.clone())),
+                    None => app,
+                };
+                app.app_data(web::Data::new(repo.clone()))
                     .app_data(web::Data::new(mqtt_manager.clone()))
                     .app_data(web::Data::clone(&oauth_cfg))
-                    .wrap(actix_web::middleware::Logger::default())
-                    .wrap(SessionMiddleware::new(
-                        CookieSessionStore::default(),
-                        session_key.clone(),
-                    ))
+                    .app_data(web::Data::clone(&ldap_cfg))
+                    .app_data(web::Data::clone(&readiness))
+                    .app_data(web::Data::new(session_store.clone()))
+                    .app_data(web::Data::clone(&login_throttle))
+                    .app_data(web::Data::clone(&totp_cipher))
+                    .app_data(web::Data::clone(&audit_log))
+                    .wrap(oauth_revalidate.clone())
+                    .wrap(
+                        actix_web::middleware::Logger::default()
+                            .exclude("/healthz")
+                            .exclude("/readyz"),
+                    )
+                    .wrap(
+                        SessionMiddleware::builder(session_store.clone(), session_key.clone())
+                            .cookie_secure(secure)
+                            .session_lifecycle(
+                                BrowserSession::default()
+                                    .state_ttl(idle_timeout)
+                                    .state_ttl_extension_policy(TtlExtensionPolicy::OnEveryRequest),
+                            )
+                            .build(),
+                    )
                     .wrap(Htmx)
                     // resources which are always available
                     .service(actix_files::Files::new("/css/", "static/css/"))
                     .service(actix_files::Files::new("/js/", "static/js/"))
                     .service(favicon)
+                    .configure(health::health_scoped)
                     .configure(oauth::oauth_login_scoped)
                     .configure(login::login_scoped)
+                    .configure(two_factor::two_factor_scoped)
+                    .configure(sessions::sessions_scoped)
                     .configure(users::users_scoped)
                     .configure(user::user_scoped)
                     .configure(mqtt_clients::clients_scoped)
-                    .configure(mqtt_client::client_scoped)
+                    .configure(|cfg| mqtt_client::client_scoped(cfg, extras))
+                    .configure(|cfg| {
+                        if extras {
+                            bridges::bridges_scoped(cfg);
+                            webhooks::webhooks_scoped(cfg);
+                            recordings::recordings_scoped(cfg);
+                            audit::audit_scoped(cfg);
+                            alerts::alerts_scoped(cfg);
+                        }
+                    })
+                    .configure(config_sync::config_scoped)
                     .service(web::scope("/").wrap(FullPageRender).service(index))
                 // guarded resources
             })
-            .bind(("0.0.0.0", 8080))?
-            .run()
-            .await
+            .disable_signals()
+            .shutdown_timeout(settings.shutdown.timeout);
+            let bind = settings.server.bind.as_str();
+            let server = match settings.server.tls() {
+                Some((cert, key)) => {
+                    let resolver =
+                        tls::CertResolver::new(cert, key).expect("Cannot load tls certificate");
+                    tokio::spawn(tls::watch(
+                        resolver.clone(),
+                        std::time::Duration::from_secs(settings.server.tls_watch_interval),
+                    ));
+                    log::info!("Serving HTTPS on {bind}");
+                    server.bind_rustls(bind, resolver.server_config())?
+                }
+                None => server.bind(bind)?,
+            }
+            .run();
+            let mut handles = vec![server.handle()];
+            let redirect = match &settings.server.redirect_bind {
+                Some(redirect_bind) => {
+                    log::info!(
+                        "Redirecting HTTP on {redirect_bind} to {}",
+                        settings.server.base_url()
+                    );
+                    let redirect = tls::redirect_server(redirect_bind, settings.server.base_url())?;
+                    handles.push(redirect.handle());
+                    Some(redirect)
+                }
+                None => None,
+            };
+            tokio::spawn(shutdown_on_signal(
+                shutdown_manager,
+                settings.shutdown.clone(),
+                handles,
+            ));
+            match redirect {
+                Some(redirect) => futures_util::future::try_join(server, redirect)
+                    .await
+                    .map(|_| ()),
+                None => server.await,
+            }
         }
     }
 }
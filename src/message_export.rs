@@ -0,0 +1,492 @@
+use std::{
+    io::{self, BufRead, BufReader, Read},
+    str::FromStr,
+};
+
+use base64::Engine;
+use futures_util::Stream;
+use rumqttc::QoS;
+use serde::{Deserialize, Serialize};
+
+use crate::{models::history::HistoryEntry, mqtt::MqttClientManager};
+
+/// Entries read from storage per exported chunk.
+const EXPORT_BATCH: usize = 500;
+/// Leading bytes of the binary format, the last one is the format version.
+const BINARY_MAGIC: &[u8; 5] = b"MQPL\x01";
+
+/// File formats message history can be exported to and imported from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageFormat {
+    /// one JSON object per line
+    Jsonl,
+    /// comma separated values with a header row
+    Csv,
+    /// length prefixed records, see [`encode`]
+    Binary,
+}
+
+impl FromStr for MessageFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "jsonl" | "json" => Ok(MessageFormat::Jsonl),
+            "csv" => Ok(MessageFormat::Csv),
+            "bin" | "binary" => Ok(MessageFormat::Binary),
+            other => Err(format!("unknown message format '{other}'")),
+        }
+    }
+}
+
+impl MessageFormat {
+    /// Guess the format from the extension of a file name.
+    pub fn from_file_name(name: &str) -> Option<MessageFormat> {
+        name.rsplit_once('.')?.1.parse().ok()
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            MessageFormat::Jsonl => "jsonl",
+            MessageFormat::Csv => "csv",
+            MessageFormat::Binary => "bin",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            MessageFormat::Jsonl => "application/x-ndjson",
+            MessageFormat::Csv => "text/csv",
+            MessageFormat::Binary => "application/octet-stream",
+        }
+    }
+}
+
+/// Which messages of a client's history to export.
+#[derive(Debug, Clone)]
+pub struct ExportQuery {
+    /// MQTT topic filter, wildcards allowed
+    pub filter: String,
+    /// unix timestamp in milliseconds, inclusive
+    pub from: Option<i64>,
+    /// unix timestamp in milliseconds, exclusive
+    pub to: Option<i64>,
+}
+
+impl ExportQuery {
+    /// Time range of the query, open ends reach to the start or end of time.
+    fn time(&self) -> std::ops::Range<i64> {
+        self.from.unwrap_or(i64::MIN)..self.to.unwrap_or(i64::MAX)
+    }
+
+    fn accepts(&self, entry: &HistoryEntry) -> bool {
+        rumqttc::matches(&entry.topic, &self.filter)
+            && self.from.is_none_or(|from| entry.timestamp >= from)
+            && self.to.is_none_or(|to| entry.timestamp < to)
+    }
+}
+
+/// Parse a point in time given as RFC 3339 or as `YYYY-MM-DDTHH:MM[:SS]` in UTC.
+pub fn parse_time(time: &str) -> Result<i64, String> {
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(time) {
+        return Ok(time.timestamp_millis());
+    }
+    ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"]
+        .iter()
+        .find_map(|format| chrono::NaiveDateTime::parse_from_str(time, format).ok())
+        .map(|time| time.and_utc().timestamp_millis())
+        .ok_or_else(|| format!("'{time}' is not a valid time"))
+}
+
+/// Record layout of the JSONL and CSV formats.
+#[derive(Serialize, Deserialize, Debug)]
+struct ExportRecord {
+    /// unix timestamp in milliseconds
+    timestamp: i64,
+    topic: String,
+    /// `utf8` or `base64`
+    encoding: String,
+    payload: String,
+    qos: u8,
+    retain: bool,
+    tag: Option<String>,
+}
+
+impl From<&HistoryEntry> for ExportRecord {
+    fn from(entry: &HistoryEntry) -> Self {
+        let (payload, encoding) = match std::str::from_utf8(&entry.payload) {
+            Ok(payload) => (payload.to_string(), "utf8"),
+            Err(_) => (
+                base64::engine::general_purpose::STANDARD.encode(&entry.payload),
+                "base64",
+            ),
+        };
+        ExportRecord {
+            timestamp: entry.timestamp,
+            topic: entry.topic.clone(),
+            encoding: encoding.into(),
+            payload,
+            qos: entry.qos,
+            retain: entry.retain,
+            tag: entry.tag.clone(),
+        }
+    }
+}
+
+impl TryFrom<ExportRecord> for HistoryEntry {
+    type Error = String;
+    fn try_from(record: ExportRecord) -> Result<Self, Self::Error> {
+        let payload = match record.encoding.as_str() {
+            "utf8" | "" => record.payload.into_bytes(),
+            "base64" => base64::engine::general_purpose::STANDARD
+                .decode(record.payload)
+                .map_err(|e| format!("invalid base64 payload: {e}"))?,
+            other => return Err(format!("unknown payload encoding '{other}'")),
+        };
+        if record.qos > 2 {
+            return Err(format!("'{}' is not a valid QoS", record.qos));
+        }
+        Ok(HistoryEntry {
+            timestamp: record.timestamp,
+            topic: record.topic,
+            payload,
+            qos: record.qos,
+            retain: record.retain,
+            tag: record.tag.filter(|tag| !tag.is_empty()),
+        })
+    }
+}
+
+/// Bytes written once before the first record.
+pub fn header(format: MessageFormat) -> Vec<u8> {
+    match format {
+        MessageFormat::Jsonl => Vec::new(),
+        MessageFormat::Csv => b"timestamp,topic,encoding,payload,qos,retain,tag\n".to_vec(),
+        MessageFormat::Binary => BINARY_MAGIC.to_vec(),
+    }
+}
+
+/// Encode a single entry.
+///
+/// A binary record is the timestamp as big endian `i64`, QoS and retain flag
+/// as one byte each, followed by topic, payload and tag each prefixed with
+/// their length as big endian `u32`.
+pub fn encode(format: MessageFormat, entry: &HistoryEntry) -> io::Result<Vec<u8>> {
+    match format {
+        MessageFormat::Jsonl => {
+            let mut line = serde_json::to_vec(&ExportRecord::from(entry))?;
+            line.push(b'\n');
+            Ok(line)
+        }
+        MessageFormat::Csv => {
+            let mut writer = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(Vec::new());
+            writer
+                .serialize(ExportRecord::from(entry))
+                .map_err(io::Error::other)?;
+            writer.into_inner().map_err(|e| e.into_error())
+        }
+        MessageFormat::Binary => {
+            let tag = entry.tag.as_deref().unwrap_or_default().as_bytes();
+            let mut record = Vec::with_capacity(22 + entry.topic.len() + entry.payload.len());
+            record.extend_from_slice(&entry.timestamp.to_be_bytes());
+            record.push(entry.qos);
+            record.push(entry.retain as u8);
+            for field in [entry.topic.as_bytes(), &entry.payload, tag] {
+                record.extend_from_slice(&(field.len() as u32).to_be_bytes());
+                record.extend_from_slice(field);
+            }
+            Ok(record)
+        }
+    }
+}
+
+/// Read entries from `reader` one by one.
+pub fn decode<R: Read + Send + 'static>(
+    format: MessageFormat,
+    reader: R,
+) -> Box<dyn Iterator<Item = Result<HistoryEntry, String>> + Send> {
+    match format {
+        MessageFormat::Jsonl => Box::new(
+            BufReader::new(reader)
+                .lines()
+                .filter(|line| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+                .map(|line| {
+                    let line = line.map_err(|e| e.to_string())?;
+                    let record: ExportRecord =
+                        serde_json::from_str(&line).map_err(|e| e.to_string())?;
+                    record.try_into()
+                }),
+        ),
+        MessageFormat::Csv => Box::new(csv::Reader::from_reader(reader).into_deserialize().map(
+            |record: Result<ExportRecord, csv::Error>| {
+                record.map_err(|e| e.to_string())?.try_into()
+            },
+        )),
+        MessageFormat::Binary => Box::new(BinaryDecoder {
+            reader: BufReader::new(reader),
+            started: false,
+            failed: false,
+        }),
+    }
+}
+
+struct BinaryDecoder<R> {
+    reader: BufReader<R>,
+    started: bool,
+    failed: bool,
+}
+
+impl<R: Read> BinaryDecoder<R> {
+    fn read_field(&mut self) -> Result<Vec<u8>, String> {
+        let mut len = [0u8; 4];
+        self.reader
+            .read_exact(&mut len)
+            .map_err(|e| e.to_string())?;
+        let len = u32::from_be_bytes(len) as u64;
+        // the length is untrusted, only what the file really holds is allocated
+        let mut field = Vec::new();
+        (&mut self.reader)
+            .take(len)
+            .read_to_end(&mut field)
+            .map_err(|e| e.to_string())?;
+        if field.len() as u64 != len {
+            return Err(format!(
+                "field of {len} bytes is cut off after {} bytes",
+                field.len()
+            ));
+        }
+        Ok(field)
+    }
+
+    fn read_entry(&mut self) -> Result<Option<HistoryEntry>, String> {
+        if !self.started {
+            self.started = true;
+            let mut magic = [0u8; 5];
+            self.reader
+                .read_exact(&mut magic)
+                .map_err(|_| "not a mqttpal binary export")?;
+            if &magic != BINARY_MAGIC {
+                return Err("not a mqttpal binary export".into());
+            }
+        }
+        if self
+            .reader
+            .fill_buf()
+            .map_err(|e| e.to_string())?
+            .is_empty()
+        {
+            return Ok(None);
+        }
+        let mut head = [0u8; 10];
+        self.reader
+            .read_exact(&mut head)
+            .map_err(|e| e.to_string())?;
+        let timestamp = i64::from_be_bytes(head[..8].try_into().unwrap());
+        let topic = String::from_utf8(self.read_field()?).map_err(|e| e.to_string())?;
+        let payload = self.read_field()?;
+        let tag = String::from_utf8(self.read_field()?).map_err(|e| e.to_string())?;
+        if head[8] > 2 {
+            return Err(format!("'{}' is not a valid QoS", head[8]));
+        }
+        Ok(Some(HistoryEntry {
+            timestamp,
+            topic,
+            payload,
+            qos: head[8],
+            retain: head[9] > 0,
+            tag: (!tag.is_empty()).then_some(tag),
+        }))
+    }
+}
+
+impl<R: Read> Iterator for BinaryDecoder<R> {
+    type Item = Result<HistoryEntry, String>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let entry = self.read_entry();
+        // a broken record leaves the reader somewhere in the middle, stop there
+        self.failed = entry.is_err();
+        entry.transpose()
+    }
+}
+
+/// Encoded history of `client` in chunks, starting with the format header.
+///
+/// An error is the last item of the stream, it is logged.
+pub fn export(
+    repo: crate::Repo,
+    client: String,
+    query: ExportQuery,
+    format: MessageFormat,
+) -> impl Stream<Item = io::Result<Vec<u8>>> {
+    let start = (Some(header(format)), None::<String>, false);
+    futures_util::stream::unfold(start, move |(header, after, done)| {
+        let (repo, client, query) = (repo.clone(), client.clone(), query.clone());
+        async move {
+            if let Some(header) = header {
+                return Some((Ok(header), (None, after, done)));
+            }
+            if done {
+                return None;
+            }
+            let (entries, last) = match HistoryEntry::page(
+                &repo,
+                &client,
+                after.as_deref(),
+                query.time(),
+                EXPORT_BATCH,
+            )
+            .await
+            {
+                Ok(page) => page,
+                Err(e) => {
+                    log::error!("Export of {} stopped: {}", client, e);
+                    return Some((Err(io::Error::other(e)), (None, None, true)));
+                }
+            };
+            let done = last.is_none();
+            let chunk = entries
+                .iter()
+                .filter(|entry| query.accepts(entry))
+                .map(|entry| encode(format, entry))
+                .collect::<io::Result<Vec<Vec<u8>>>>()
+                .map(|records| records.concat());
+            if let Err(e) = &chunk {
+                log::error!("Export of {} stopped: {}", client, e);
+                return Some((chunk, (None, None, true)));
+            }
+            Some((chunk, (None, last, done)))
+        }
+    })
+}
+
+/// What to do with imported messages.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportMode {
+    /// store them in the history of the client
+    History,
+    /// publish them on the client as fast as possible
+    Publish,
+}
+
+/// Import decoded entries into `client`, returns the number of imported messages.
+pub async fn import(
+    repo: &crate::Repo,
+    mqtt: &MqttClientManager,
+    client: &String,
+    entries: impl Iterator<Item = Result<HistoryEntry, String>>,
+    mode: ImportMode,
+) -> Result<usize, String> {
+    let mut imported = 0;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("record {}: {}", imported + 1, e))?;
+        match mode {
+            ImportMode::History => entry
+                .insert(repo, client)
+                .await
+                .map_err(|e| e.to_string())?,
+            ImportMode::Publish => {
+                let qos = rumqttc::qos(entry.qos).unwrap_or(QoS::AtLeastOnce);
+                mqtt.publish_with(client, entry.topic, entry.payload, qos, entry.retain)
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+        imported += 1;
+    }
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(payload: &[u8], tag: Option<&str>) -> HistoryEntry {
+        HistoryEntry {
+            timestamp: 1_700_000_000_123,
+            topic: "sensors/kitchen".into(),
+            payload: payload.to_vec(),
+            qos: 1,
+            retain: true,
+            tag: tag.map(String::from),
+        }
+    }
+
+    fn binary(entries: &[HistoryEntry]) -> Vec<u8> {
+        let mut file = header(MessageFormat::Binary);
+        for entry in entries {
+            file.extend(encode(MessageFormat::Binary, entry).unwrap());
+        }
+        file
+    }
+
+    fn decoded(format: MessageFormat, file: Vec<u8>) -> Vec<Result<HistoryEntry, String>> {
+        decode(format, std::io::Cursor::new(file)).collect()
+    }
+
+    fn assert_same(decoded: &HistoryEntry, entry: &HistoryEntry) {
+        assert_eq!(decoded.timestamp, entry.timestamp);
+        assert_eq!(decoded.topic, entry.topic);
+        assert_eq!(decoded.payload, entry.payload);
+        assert_eq!(decoded.qos, entry.qos);
+        assert_eq!(decoded.retain, entry.retain);
+        assert_eq!(decoded.tag, entry.tag);
+    }
+
+    #[test]
+    fn round_trips_every_format() {
+        let entries = [
+            entry(b"21.5", Some("kitchen")),
+            entry(&[0xff, 0, 0x80], None),
+        ];
+        for format in [
+            MessageFormat::Jsonl,
+            MessageFormat::Csv,
+            MessageFormat::Binary,
+        ] {
+            let mut file = header(format);
+            for entry in &entries {
+                file.extend(encode(format, entry).unwrap());
+            }
+            let decoded = decoded(format, file);
+            assert_eq!(decoded.len(), entries.len(), "{format:?}");
+            for (decoded, entry) in decoded.iter().zip(&entries) {
+                assert_same(decoded.as_ref().unwrap(), entry);
+            }
+        }
+    }
+
+    #[test]
+    fn stops_at_a_truncated_binary_record() {
+        let mut file = binary(&[entry(b"first", None), entry(b"second", None)]);
+        file.truncate(file.len() - 3);
+        let decoded = decoded(MessageFormat::Binary, file);
+        assert_eq!(decoded.len(), 2);
+        assert_same(decoded[0].as_ref().unwrap(), &entry(b"first", None));
+        assert!(decoded[1].is_err());
+    }
+
+    #[test]
+    fn refuses_field_lengths_beyond_the_file() {
+        let mut file = binary(&[]);
+        file.extend(1_700_000_000_000i64.to_be_bytes());
+        file.extend([0, 0]);
+        // a topic claiming 4 GiB followed by a few bytes
+        file.extend(u32::MAX.to_be_bytes());
+        file.extend(b"sensors");
+        let decoded = decoded(MessageFormat::Binary, file);
+        assert_eq!(decoded.len(), 1);
+        let error = decoded[0].as_ref().unwrap_err();
+        assert!(error.contains("cut off after 7 bytes"), "{error}");
+    }
+
+    #[test]
+    fn refuses_files_without_the_magic() {
+        let decoded = decoded(MessageFormat::Binary, b"MQTT\x01".to_vec());
+        assert_eq!(decoded.len(), 1);
+        assert!(decoded[0].is_err());
+    }
+}
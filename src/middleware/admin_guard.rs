@@ -0,0 +1,43 @@
+use actix_session::Session;
+use actix_web::{
+    dev::Payload,
+    error::{ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized},
+    web, Error, FromRequest, HttpRequest,
+};
+use futures_util::future::LocalBoxFuture;
+
+use crate::{
+    error::AppError,
+    models::user::{Role, User},
+};
+
+/// Only lets logged in users with the admin role through.
+pub struct AdminGuard {
+    pub username: String,
+}
+
+impl FromRequest for AdminGuard {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let session = Session::extract(req).into_inner();
+        let repo = req.app_data::<web::Data<crate::Repo>>().cloned();
+        Box::pin(async move {
+            let session = session.map_err(|_| ErrorUnauthorized("No session"))?;
+            let loggedin = session.get::<String>("loggedin").unwrap_or(None);
+            let username = session.get::<String>("username").unwrap_or(None);
+            let (Some("true"), Some(username)) = (loggedin.as_deref(), username) else {
+                return Err(ErrorUnauthorized("user not logged in"));
+            };
+            let repo = repo.ok_or_else(|| ErrorInternalServerError("no database"))?;
+            match User::get_by_name(&repo, &username)
+                .await
+                .map_err(AppError::from)?
+            {
+                Some(user) if user.role_id == Role::Admin as i32 => Ok(AdminGuard { username }),
+                _ => Err(ErrorForbidden("admin role required")),
+            }
+        })
+    }
+}
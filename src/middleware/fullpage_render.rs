@@ -2,7 +2,7 @@ use crate::middleware::htmx::HtmxHeaders;
 use actix_session::Session;
 use actix_web::{
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    Error, FromRequest, HttpMessage, HttpResponse,
+    web, Error, FromRequest, HttpMessage, HttpResponse,
 };
 use askama::Template;
 use futures_util::future::LocalBoxFuture;
@@ -13,6 +13,8 @@ use std::future::{ready, Ready};
 pub struct FullPageTemplate {
     pub user: Option<String>,
     pub body: String,
+    /// redis backed pages are linked
+    pub extras: bool,
 }
 
 pub struct FullPageRender;
@@ -75,8 +77,13 @@ where
                     let template = FullPageTemplate {
                         user: username,
                         body: resp_body,
+                        extras: req.app_data::<web::Data<crate::DbPool>>().is_some(),
                     };
-                    let new_resp = HttpResponse::build(status).body(template.render().unwrap());
+                    let new_resp = HttpResponse::build(status).body(
+                        template
+                            .render()
+                            .map_err(actix_web::error::ErrorInternalServerError)?,
+                    );
                     Ok(ServiceResponse::new(req, new_resp))
                 } else {
                     Ok(ServiceResponse::new(req, resp))
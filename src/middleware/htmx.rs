@@ -1,11 +1,19 @@
+use crate::error::{AppError, ErrorTemplate};
 use actix_web::{
     dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
-    http::header::{HeaderMap, HeaderName, HeaderValue},
-    Error, FromRequest, HttpMessage, HttpRequest,
+    http::{
+        header::{HeaderMap, HeaderName, HeaderValue},
+        StatusCode,
+    },
+    Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError,
 };
+use askama::Template;
 use futures_util::future::LocalBoxFuture;
 use std::future::{ready, Ready};
 
+/// Element of the full page that error responses of htmx requests are swapped into.
+const ERROR_TARGET: &str = "#errorBox";
+
 #[allow(unused_variables, dead_code)]
 #[derive(Default, Debug, Clone)]
 pub struct HtmxHeaders {
@@ -32,135 +40,63 @@ pub struct HtmxHeaders {
     trigger_after_swap: Option<String>,
 }
 
+fn header_string(value: &HeaderValue) -> Option<String> {
+    value.to_str().ok().map(str::to_string)
+}
+
+fn header_bool(value: &HeaderValue) -> Option<bool> {
+    value.to_str().ok()?.parse().ok()
+}
+
 #[allow(dead_code)]
 impl HtmxHeaders {
     fn from_headers_map(map: &HeaderMap) -> Self {
         HtmxHeaders {
-            boosted: map
-                .get("hx-boosted")
-                .map(|v| v.to_str().unwrap().parse::<bool>().unwrap()),
-            current_url: map
-                .get("hx-current-url")
-                .map(|v| v.to_str().unwrap().to_string()),
-            history_restore_request: map
-                .get("hx-history-restore-request")
-                .map(|v| v.to_str().unwrap().parse::<bool>().unwrap()),
-            prompt: map
-                .get("hx-prompt")
-                .map(|v| v.to_str().unwrap().to_string()),
-            request: map
-                .get("hx-request")
-                .map(|v| v.to_str().unwrap().parse::<bool>().unwrap()),
-            target: map
-                .get("hx-target")
-                .map(|v| v.to_str().unwrap().to_string()),
-            trigger_name: map
-                .get("hx-trigger-name")
-                .map(|v| v.to_str().unwrap().to_string()),
-            trigger: map
-                .get("hx-trigger")
-                .map(|v| v.to_str().unwrap().to_string()),
-            location: map
-                .get("hx-location")
-                .map(|v| v.to_str().unwrap().to_string()),
-            push_url: map
-                .get("hx-push-url")
-                .map(|v| v.to_str().unwrap().to_string()),
-            redirect: map
-                .get("hx-redirect")
-                .map(|v| v.to_str().unwrap().to_string()),
-            refresh: map
-                .get("hx-refresh")
-                .map(|v| v.to_str().unwrap().to_string()),
-            replace_url: map
-                .get("hx-replace-url")
-                .map(|v| v.to_str().unwrap().to_string()),
-            reswap: map
-                .get("hx-reswap")
-                .map(|v| v.to_str().unwrap().to_string()),
-            retarget: map
-                .get("hx-retarget")
-                .map(|v| v.to_str().unwrap().to_string()),
-            reselect: map
-                .get("hx-reselect")
-                .map(|v| v.to_str().unwrap().to_string()),
-            response_trigger: map
-                .get("hx-trigger")
-                .map(|v| v.to_str().unwrap().to_string()),
-            trigger_after_settle: map
-                .get("hx-trigger-after-settle")
-                .map(|v| v.to_str().unwrap().to_string()),
-            trigger_after_swap: map
-                .get("hx-trigger-after-swap")
-                .map(|v| v.to_str().unwrap().to_string()),
+            boosted: map.get("hx-boosted").and_then(header_bool),
+            current_url: map.get("hx-current-url").and_then(header_string),
+            history_restore_request: map.get("hx-history-restore-request").and_then(header_bool),
+            prompt: map.get("hx-prompt").and_then(header_string),
+            request: map.get("hx-request").and_then(header_bool),
+            target: map.get("hx-target").and_then(header_string),
+            trigger_name: map.get("hx-trigger-name").and_then(header_string),
+            trigger: map.get("hx-trigger").and_then(header_string),
+            location: map.get("hx-location").and_then(header_string),
+            push_url: map.get("hx-push-url").and_then(header_string),
+            redirect: map.get("hx-redirect").and_then(header_string),
+            refresh: map.get("hx-refresh").and_then(header_string),
+            replace_url: map.get("hx-replace-url").and_then(header_string),
+            reswap: map.get("hx-reswap").and_then(header_string),
+            retarget: map.get("hx-retarget").and_then(header_string),
+            reselect: map.get("hx-reselect").and_then(header_string),
+            response_trigger: map.get("hx-trigger").and_then(header_string),
+            trigger_after_settle: map.get("hx-trigger-after-settle").and_then(header_string),
+            trigger_after_swap: map.get("hx-trigger-after-swap").and_then(header_string),
         }
     }
     fn write_headers(&self, map: &mut HeaderMap) {
-        if let Some(location) = &self.location {
-            map.insert(
-                HeaderName::from_static("hx-location"),
-                HeaderValue::from_str(location).unwrap(),
-            );
-        }
-        if let Some(push_url) = &self.push_url {
-            map.insert(
-                HeaderName::from_static("hx-push-url"),
-                HeaderValue::from_str(push_url).unwrap(),
-            );
-        }
-        if let Some(redirect) = &self.redirect {
-            map.insert(
-                HeaderName::from_static("hx-redirect"),
-                HeaderValue::from_str(redirect).unwrap(),
-            );
-        }
-        if let Some(refresh) = &self.refresh {
-            map.insert(
-                HeaderName::from_static("hx-refresh"),
-                HeaderValue::from_str(refresh).unwrap(),
-            );
-        }
-        if let Some(replace_url) = &self.replace_url {
-            map.insert(
-                HeaderName::from_static("hx-replace-url"),
-                HeaderValue::from_str(replace_url).unwrap(),
-            );
-        }
-        if let Some(reswap) = &self.reswap {
-            map.insert(
-                HeaderName::from_static("hx-reswap"),
-                HeaderValue::from_str(reswap).unwrap(),
-            );
-        }
-        if let Some(reselect) = &self.reselect {
-            map.insert(
-                HeaderName::from_static("hx-reselect"),
-                HeaderValue::from_str(reselect).unwrap(),
-            );
-        }
-        if let Some(retarget) = &self.retarget {
-            map.insert(
-                HeaderName::from_static("hx-retarget"),
-                HeaderValue::from_str(retarget).unwrap(),
-            );
-        }
-        if let Some(response_trigger) = &self.response_trigger {
-            map.insert(
-                HeaderName::from_static("hx-trigger"),
-                HeaderValue::from_str(response_trigger).unwrap(),
-            );
-        }
-        if let Some(trigger_after_settle) = &self.trigger_after_settle {
-            map.insert(
-                HeaderName::from_static("hx-trigger-after-settle"),
-                HeaderValue::from_str(trigger_after_settle).unwrap(),
-            );
-        }
-        if let Some(trigger_after_swap) = &self.trigger_after_swap {
-            map.insert(
-                HeaderName::from_static("hx-trigger-after-swap"),
-                HeaderValue::from_str(trigger_after_swap).unwrap(),
-            );
+        let headers = [
+            ("hx-location", &self.location),
+            ("hx-push-url", &self.push_url),
+            ("hx-redirect", &self.redirect),
+            ("hx-refresh", &self.refresh),
+            ("hx-replace-url", &self.replace_url),
+            ("hx-reswap", &self.reswap),
+            ("hx-reselect", &self.reselect),
+            ("hx-retarget", &self.retarget),
+            ("hx-trigger", &self.response_trigger),
+            ("hx-trigger-after-settle", &self.trigger_after_settle),
+            ("hx-trigger-after-swap", &self.trigger_after_swap),
+        ];
+        for (name, value) in headers {
+            let Some(value) = value else {
+                continue;
+            };
+            match HeaderValue::from_str(value) {
+                Ok(value) => {
+                    map.insert(HeaderName::from_static(name), value);
+                }
+                Err(_) => log::warn!("Skipping invalid value for {name} header: {value:?}"),
+            }
         }
     }
     pub fn boosted(&self) -> bool {
@@ -271,8 +207,18 @@ where
         Box::pin(async move {
             let mut res = fut.await?;
             let req = res.request().to_owned();
-            let ext = req.extensions_mut();
-            let hx_hd = ext.get::<HtmxHeaders>().unwrap();
+            let Some(mut hx_hd) = req.extensions().get::<HtmxHeaders>().cloned() else {
+                return Ok(res);
+            };
+            // htmx does not swap error responses, show them in the error box instead
+            if hx_hd.request()
+                && hx_hd.redirect.is_none()
+                && (res.status().is_client_error() || res.status().is_server_error())
+            {
+                *res.response_mut().status_mut() = StatusCode::OK;
+                hx_hd.set_retarget(ERROR_TARGET);
+                hx_hd.set_reswap("innerHTML");
+            }
             hx_hd.write_headers(res.headers_mut());
             Ok(res)
         })
@@ -286,10 +232,20 @@ impl FromRequest for HtmxHeaders {
         if let Some(headers) = req.extensions().get::<HtmxHeaders>() {
             ready(Ok(headers.to_owned()))
         } else {
-            ready(Err(Error::from(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "no htmx headers",
-            ))))
+            ready(Err(Error::from(std::io::Error::other("no htmx headers"))))
         }
     }
 }
+
+/// Render `message` into the error container `target` of the submitting form.
+pub fn form_error(req: &HttpRequest, target: &str, message: &str) -> HttpResponse {
+    if let Some(htmx) = req.extensions_mut().get_mut::<HtmxHeaders>() {
+        htmx.set_retarget(target);
+        htmx.set_reswap("innerHTML");
+    }
+    let template = ErrorTemplate { message };
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().body(body),
+        Err(e) => AppError::from(e).error_response(),
+    }
+}
@@ -1,4 +1,6 @@
+pub mod admin_guard;
 pub mod fullpage_render;
 pub mod htmx;
 pub mod login_guard;
+pub mod oauth_revalidate;
 pub mod user_session;
@@ -0,0 +1,100 @@
+use std::{
+    collections::HashSet,
+    future::{ready, Ready},
+    rc::Rc,
+    sync::{Arc, Mutex},
+};
+
+use actix_session::Session;
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    Error, FromRequest, HttpMessage, HttpResponse,
+};
+use futures_util::future::LocalBoxFuture;
+
+use crate::{middleware::htmx::HtmxHeaders, oauth::OauthConfigs};
+
+/// Re-validates oauth sessions against their provider and logs them out
+/// when the provider refuses, see [`crate::oauth::revalidate`].
+///
+/// Has to run inside the session middleware.
+#[derive(Clone, Default)]
+pub struct OauthRevalidate {
+    /// refresh tokens being exchanged right now, shared by all workers
+    refreshing: Arc<Mutex<HashSet<String>>>,
+}
+
+impl<S> Transform<S, ServiceRequest> for OauthRevalidate
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type InitError = ();
+    type Transform = OauthRevalidateMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(OauthRevalidateMiddleware {
+            service: Rc::new(service),
+            refreshing: self.refreshing.clone(),
+        }))
+    }
+}
+
+pub struct OauthRevalidateMiddleware<S> {
+    service: Rc<S>,
+    refreshing: Arc<Mutex<HashSet<String>>>,
+}
+
+impl<S> Service<ServiceRequest> for OauthRevalidateMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let refreshing = self.refreshing.clone();
+        Box::pin(async move {
+            let configs = req.app_data::<OauthConfigs>().cloned();
+            let session = Session::extract(req.request()).into_inner();
+            if let (Some(configs), Ok(session)) = (configs, session) {
+                if let Err(reason) = crate::oauth::revalidate(&session, &configs, &refreshing).await
+                {
+                    log::info!(
+                        "Ending oauth session of {}: {reason}",
+                        session
+                            .get::<String>("username")
+                            .ok()
+                            .flatten()
+                            .unwrap_or_default()
+                    );
+                    session.purge();
+                    let htmx = match req.extensions_mut().get_mut::<HtmxHeaders>() {
+                        Some(htmx) if htmx.request() => {
+                            htmx.set_redirect("/login/");
+                            true
+                        }
+                        _ => false,
+                    };
+                    let response = if htmx {
+                        HttpResponse::Ok().finish()
+                    } else {
+                        HttpResponse::Found()
+                            .append_header(("Location", "/login/"))
+                            .finish()
+                    };
+                    return Ok(req.into_response(response));
+                }
+            }
+            service.call(req).await
+        })
+    }
+}
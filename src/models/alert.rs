@@ -0,0 +1,70 @@
+use bb8_redis::redis::cmd;
+use serde::{Deserialize, Serialize};
+
+use crate::storage::StorageError;
+
+/// Default upper bound of alerts kept per client.
+pub const ALERTS_MAX_LEN: usize = 500;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Alert {
+    /// unix timestamp in milliseconds
+    pub timestamp: i64,
+    pub rule: String,
+    pub topic: String,
+    pub message: String,
+}
+
+impl Alert {
+    pub fn time(&self) -> String {
+        chrono::DateTime::from_timestamp_millis(self.timestamp)
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default()
+    }
+
+    fn key(client: &str) -> String {
+        format!("mqtt_client:{}:alerts", client)
+    }
+
+    /// Store the alert, only the latest `max_len` alerts of the client are kept.
+    pub async fn insert(
+        &self,
+        pool: &crate::DbPool,
+        client: &str,
+        max_len: usize,
+    ) -> Result<(), StorageError> {
+        let mut conn = pool.get().await?;
+        let alert_json = serde_json::to_string(&self)?;
+        let _: () = bb8_redis::redis::pipe()
+            .cmd("LPUSH")
+            .arg(Self::key(client))
+            .arg(alert_json)
+            .ignore()
+            .cmd("LTRIM")
+            .arg(Self::key(client))
+            .arg(0)
+            .arg(max_len.saturating_sub(1))
+            .ignore()
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn latest(
+        pool: &crate::DbPool,
+        client: &str,
+        count: isize,
+    ) -> Result<Vec<Alert>, StorageError> {
+        let mut conn = pool.get().await?;
+        let alerts: Vec<String> = cmd("LRANGE")
+            .arg(Self::key(client))
+            .arg(0)
+            .arg(count - 1)
+            .query_async(&mut *conn)
+            .await?;
+        alerts
+            .iter()
+            .map(|alert| serde_json::from_str(alert).map_err(StorageError::from))
+            .collect()
+    }
+}
@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+
+use bb8_redis::redis::{cmd, pipe};
+use serde::{Deserialize, Serialize};
+
+use crate::{models::rule::Condition, storage::StorageError};
+
+/// Default upper bound of notifications kept per user.
+pub const NOTIFICATIONS_MAX_LEN: usize = 200;
+/// Firing and resolving within this many milliseconds count towards flapping.
+const FLAP_WINDOW: i64 = 60 * 60 * 1000;
+/// An alert changing more often than this within the window is flapping.
+const FLAP_LIMIT: usize = 6;
+
+/// What makes an alert fire.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AlertCondition {
+    /// no message on `topic` for `minutes`
+    Silent { topic: String, minutes: u64 },
+    /// the latest message on `topic` satisfies `condition`
+    Threshold { topic: String, condition: Condition },
+    /// the client is not connected to its broker
+    Disconnected,
+}
+
+impl AlertCondition {
+    /// Topic filter the condition watches, if any.
+    pub fn topic(&self) -> Option<&str> {
+        match self {
+            AlertCondition::Silent { topic, .. } | AlertCondition::Threshold { topic, .. } => {
+                Some(topic)
+            }
+            AlertCondition::Disconnected => None,
+        }
+    }
+}
+
+impl std::fmt::Display for AlertCondition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AlertCondition::Silent { topic, minutes } => {
+                write!(f, "no message on {topic} for {minutes} min")
+            }
+            AlertCondition::Threshold { topic, condition } => write!(f, "{condition} on {topic}"),
+            AlertCondition::Disconnected => write!(f, "client disconnected"),
+        }
+    }
+}
+
+/// Where the notifications of an alert go to.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AlertChannels {
+    /// the notification bell of the owner
+    pub in_app: bool,
+    /// the email address of the owner
+    pub email: bool,
+    /// POSTed as JSON
+    pub webhook: Option<String>,
+    /// key for the HMAC-SHA256 signature header, unsigned if `None`
+    pub webhook_secret: Option<String>,
+}
+
+impl std::fmt::Display for AlertChannels {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut channels = Vec::new();
+        if self.in_app {
+            channels.push("bell".to_string());
+        }
+        if self.email {
+            channels.push("email".to_string());
+        }
+        if let Some(url) = &self.webhook {
+            channels.push(format!("webhook {url}"));
+        }
+        f.write_str(&channels.join(", "))
+    }
+}
+
+/// Time in which an alert sends no notifications, e.g. during maintenance.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SilenceWindow {
+    /// unix timestamps in milliseconds
+    pub from: i64,
+    pub until: i64,
+    pub comment: String,
+}
+
+impl SilenceWindow {
+    pub fn covers(&self, now: i64) -> bool {
+        self.from <= now && now < self.until
+    }
+
+    pub fn time_range(&self) -> String {
+        let time = |t: i64| {
+            chrono::DateTime::from_timestamp_millis(t)
+                .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default()
+        };
+        format!("{} - {}", time(self.from), time(self.until))
+    }
+}
+
+/// Alert of a user on one of the clients.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AlertDefinition {
+    pub name: String,
+    /// user who defined the alert and receives its notifications
+    pub owner: String,
+    pub client: String,
+    pub condition: AlertCondition,
+    pub channels: AlertChannels,
+    /// seconds a change has to last before the alert fires or resolves
+    pub hold: u64,
+    #[serde(default)]
+    pub silences: Vec<SilenceWindow>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertStatus {
+    #[default]
+    Ok,
+    /// the condition holds, but not for long enough yet
+    Pending,
+    Firing,
+    /// the condition is gone, but not for long enough yet
+    Resolving,
+}
+
+impl std::fmt::Display for AlertStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AlertStatus::Ok => "ok",
+            AlertStatus::Pending => "pending",
+            AlertStatus::Firing => "firing",
+            AlertStatus::Resolving => "resolving",
+        })
+    }
+}
+
+/// State of an alert kept between evaluations.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct AlertState {
+    pub status: AlertStatus,
+    /// unix timestamp in milliseconds the status was entered
+    pub since: i64,
+    /// times the alert fired or resolved within the flap window
+    #[serde(default)]
+    pub changes: Vec<i64>,
+    /// notifications are held back while the alert is flapping
+    #[serde(default)]
+    pub flapping: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    Firing,
+    Resolved,
+    /// changes of the alert are not notified until it settles
+    Flapping,
+}
+
+impl std::fmt::Display for NotificationKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            NotificationKind::Firing => "firing",
+            NotificationKind::Resolved => "resolved",
+            NotificationKind::Flapping => "flapping",
+        })
+    }
+}
+
+impl AlertState {
+    pub fn since_time(&self) -> String {
+        chrono::DateTime::from_timestamp_millis(self.since)
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default()
+    }
+
+    /// Advance the state by whether the condition holds at `now`, `hold`
+    /// milliseconds are waited before firing and resolving.
+    ///
+    /// Returns the notification to send, if any.
+    pub fn update(&mut self, active: bool, now: i64, hold: i64) -> Option<NotificationKind> {
+        match (self.status, active) {
+            (AlertStatus::Ok, true) => self.enter(AlertStatus::Pending, now),
+            (AlertStatus::Pending, false) => self.enter(AlertStatus::Ok, now),
+            (AlertStatus::Firing, false) => self.enter(AlertStatus::Resolving, now),
+            (AlertStatus::Resolving, true) => self.enter(AlertStatus::Firing, now),
+            _ => {}
+        }
+        let changed = match self.status {
+            AlertStatus::Pending if now - self.since >= hold => {
+                self.enter(AlertStatus::Firing, now);
+                Some(NotificationKind::Firing)
+            }
+            AlertStatus::Resolving if now - self.since >= hold => {
+                self.enter(AlertStatus::Ok, now);
+                Some(NotificationKind::Resolved)
+            }
+            _ => None,
+        };
+        self.changes.retain(|change| now - change < FLAP_WINDOW);
+        if let Some(kind) = changed {
+            self.changes.push(now);
+            if self.flapping {
+                return None;
+            }
+            if self.changes.len() > FLAP_LIMIT {
+                self.flapping = true;
+                return Some(NotificationKind::Flapping);
+            }
+            return Some(kind);
+        }
+        // a flapping alert settled, tell where it ended up
+        if self.flapping && self.changes.len() <= FLAP_LIMIT / 2 {
+            self.flapping = false;
+            return match self.status {
+                AlertStatus::Firing | AlertStatus::Resolving => Some(NotificationKind::Firing),
+                AlertStatus::Ok | AlertStatus::Pending => Some(NotificationKind::Resolved),
+            };
+        }
+        None
+    }
+
+    fn enter(&mut self, status: AlertStatus, now: i64) {
+        self.status = status;
+        self.since = now;
+    }
+}
+
+impl AlertDefinition {
+    pub fn watches(&self, client: &str, topic: &str) -> bool {
+        self.client == client
+            && self
+                .condition
+                .topic()
+                .is_some_and(|filter| rumqttc::matches(topic, filter))
+    }
+
+    pub fn silenced(&self, now: i64) -> bool {
+        self.silences.iter().any(|silence| silence.covers(now))
+    }
+
+    pub async fn list(pool: &crate::DbPool) -> Result<Vec<AlertDefinition>, StorageError> {
+        let mut conn = pool.get().await?;
+        let alerts: Vec<String> = cmd("HVALS")
+            .arg("alert_definitions")
+            .query_async(&mut *conn)
+            .await?;
+        let mut alerts: Vec<AlertDefinition> = alerts
+            .iter()
+            .map(|alert| serde_json::from_str(alert).map_err(StorageError::from))
+            .collect::<Result<_, _>>()?;
+        alerts.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(alerts)
+    }
+
+    pub async fn list_by_owner(
+        pool: &crate::DbPool,
+        owner: &str,
+    ) -> Result<Vec<AlertDefinition>, StorageError> {
+        let mut alerts = Self::list(pool).await?;
+        alerts.retain(|alert| alert.owner == owner);
+        Ok(alerts)
+    }
+
+    pub async fn get_by_name(
+        pool: &crate::DbPool,
+        name: &str,
+    ) -> Result<Option<AlertDefinition>, StorageError> {
+        let mut conn = pool.get().await?;
+        let alert: Option<String> = cmd("HGET")
+            .arg("alert_definitions")
+            .arg(name)
+            .query_async(&mut *conn)
+            .await?;
+        alert
+            .map(|alert| serde_json::from_str(&alert))
+            .transpose()
+            .map_err(StorageError::from)
+    }
+
+    pub async fn insert(&self, pool: &crate::DbPool) -> Result<(), StorageError> {
+        let mut conn = pool.get().await?;
+        let alert_json = serde_json::to_string(&self)?;
+        let _: i32 = cmd("HSET")
+            .arg("alert_definitions")
+            .arg(&self.name)
+            .arg(alert_json)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete(pool: &crate::DbPool, name: &str) -> Result<bool, StorageError> {
+        let mut conn = pool.get().await?;
+        let (deleted, _): (i32, i32) = pipe()
+            .cmd("HDEL")
+            .arg("alert_definitions")
+            .arg(name)
+            .cmd("HDEL")
+            .arg("alert_states")
+            .arg(name)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    /// States of all alerts by name, alerts never evaluated are missing.
+    pub async fn states(pool: &crate::DbPool) -> Result<HashMap<String, AlertState>, StorageError> {
+        let mut conn = pool.get().await?;
+        let states: HashMap<String, String> = cmd("HGETALL")
+            .arg("alert_states")
+            .query_async(&mut *conn)
+            .await?;
+        Ok(states
+            .into_iter()
+            .filter_map(|(name, state)| Some((name, serde_json::from_str(&state).ok()?)))
+            .collect())
+    }
+
+    pub async fn save_state(
+        pool: &crate::DbPool,
+        name: &str,
+        state: &AlertState,
+    ) -> Result<(), StorageError> {
+        let mut conn = pool.get().await?;
+        let state_json = serde_json::to_string(state)?;
+        let _: i32 = cmd("HSET")
+            .arg("alert_states")
+            .arg(name)
+            .arg(state_json)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Entry of the notification bell of a user.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Notification {
+    /// unix timestamp in milliseconds
+    pub timestamp: i64,
+    pub alert: String,
+    pub client: String,
+    pub kind: NotificationKind,
+    pub message: String,
+}
+
+impl Notification {
+    pub fn time(&self) -> String {
+        chrono::DateTime::from_timestamp_millis(self.timestamp)
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default()
+    }
+
+    fn key(user: &str) -> String {
+        format!("user:{}:notifications", user)
+    }
+
+    fn read_key(user: &str) -> String {
+        format!("user:{}:notifications_read", user)
+    }
+
+    /// Store the notification, only the latest `max_len` notifications of the user are kept.
+    pub async fn insert(
+        &self,
+        pool: &crate::DbPool,
+        user: &str,
+        max_len: usize,
+    ) -> Result<(), StorageError> {
+        let mut conn = pool.get().await?;
+        let notification_json = serde_json::to_string(&self)?;
+        let _: () = pipe()
+            .cmd("LPUSH")
+            .arg(Self::key(user))
+            .arg(notification_json)
+            .ignore()
+            .cmd("LTRIM")
+            .arg(Self::key(user))
+            .arg(0)
+            .arg(max_len.saturating_sub(1))
+            .ignore()
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Notifications of `user`, newest first.
+    pub async fn list(pool: &crate::DbPool, user: &str) -> Result<Vec<Notification>, StorageError> {
+        let mut conn = pool.get().await?;
+        let notifications: Vec<String> = cmd("LRANGE")
+            .arg(Self::key(user))
+            .arg(0)
+            .arg(-1)
+            .query_async(&mut *conn)
+            .await?;
+        notifications
+            .iter()
+            .map(|notification| serde_json::from_str(notification).map_err(StorageError::from))
+            .collect()
+    }
+
+    /// Time up to which `user` has seen the notifications.
+    pub async fn read_until(pool: &crate::DbPool, user: &str) -> Result<i64, StorageError> {
+        let mut conn = pool.get().await?;
+        let read: Option<i64> = cmd("GET")
+            .arg(Self::read_key(user))
+            .query_async(&mut *conn)
+            .await?;
+        Ok(read.unwrap_or(0))
+    }
+
+    pub async fn mark_read(pool: &crate::DbPool, user: &str, now: i64) -> Result<(), StorageError> {
+        let mut conn = pool.get().await?;
+        let _: () = cmd("SET")
+            .arg(Self::read_key(user))
+            .arg(now)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn unread(pool: &crate::DbPool, user: &str) -> Result<usize, StorageError> {
+        let read = Self::read_until(pool, user).await?;
+        Ok(Self::list(pool, user)
+            .await?
+            .iter()
+            .filter(|notification| notification.timestamp > read)
+            .count())
+    }
+}
@@ -0,0 +1,94 @@
+use bb8_redis::redis::cmd;
+use rumqttc::QoS;
+use serde::{Deserialize, Serialize};
+
+use crate::storage::StorageError;
+
+/// Forwards messages matching `topic` from the `source` client to the `target` client.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Bridge {
+    pub name: String,
+    pub source: String,
+    pub target: String,
+    /// MQTT topic filter on the source client, wildcards allowed
+    pub topic: String,
+    /// removed from the topic before forwarding if present
+    pub strip_prefix: Option<String>,
+    /// prepended to the (stripped) topic before forwarding
+    pub add_prefix: Option<String>,
+    /// QoS used on the target, `None` keeps the QoS of the source message
+    pub qos: Option<u8>,
+}
+
+impl Bridge {
+    pub fn matches(&self, client: &str, topic: &str) -> bool {
+        self.source == client && rumqttc::matches(topic, &self.topic)
+    }
+
+    pub fn map_topic(&self, topic: &str) -> String {
+        let topic = match &self.strip_prefix {
+            Some(prefix) => topic.strip_prefix(prefix.as_str()).unwrap_or(topic),
+            None => topic,
+        };
+        match &self.add_prefix {
+            Some(prefix) => format!("{prefix}{topic}"),
+            None => topic.to_string(),
+        }
+    }
+
+    pub fn map_qos(&self, qos: QoS) -> QoS {
+        match self.qos {
+            Some(qos) => rumqttc::qos(qos).unwrap_or(QoS::AtLeastOnce),
+            None => qos,
+        }
+    }
+
+    pub async fn list(pool: &crate::DbPool) -> Result<Vec<Bridge>, StorageError> {
+        let mut conn = pool.get().await?;
+        let bridges: Vec<String> = cmd("HVALS").arg("bridges").query_async(&mut *conn).await?;
+        let mut bridges: Vec<Bridge> = bridges
+            .iter()
+            .map(|bridge| serde_json::from_str(bridge).map_err(StorageError::from))
+            .collect::<Result<_, _>>()?;
+        bridges.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(bridges)
+    }
+
+    pub async fn get_by_name(
+        pool: &crate::DbPool,
+        name: &str,
+    ) -> Result<Option<Bridge>, StorageError> {
+        let mut conn = pool.get().await?;
+        let bridge: Option<String> = cmd("HGET")
+            .arg("bridges")
+            .arg(name)
+            .query_async(&mut *conn)
+            .await?;
+        bridge
+            .map(|bridge| serde_json::from_str(&bridge))
+            .transpose()
+            .map_err(StorageError::from)
+    }
+
+    pub async fn insert(&self, pool: &crate::DbPool) -> Result<(), StorageError> {
+        let mut conn = pool.get().await?;
+        let bridge_json = serde_json::to_string(&self)?;
+        let _: i32 = cmd("HSET")
+            .arg("bridges")
+            .arg(&self.name)
+            .arg(bridge_json)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete(pool: &crate::DbPool, name: &str) -> Result<bool, StorageError> {
+        let mut conn = pool.get().await?;
+        let deleted: i32 = cmd("HDEL")
+            .arg("bridges")
+            .arg(name)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(deleted > 0)
+    }
+}
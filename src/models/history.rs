@@ -0,0 +1,57 @@
+use serde::{Deserialize, Serialize};
+
+use crate::storage::StorageError;
+
+/// Default upper bound of entries kept per client history.
+pub const HISTORY_MAX_LEN: usize = 10000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryEntry {
+    /// unix timestamp in milliseconds
+    pub timestamp: i64,
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: u8,
+    pub retain: bool,
+    pub tag: Option<String>,
+}
+
+impl HistoryEntry {
+    pub fn payload_string(&self) -> String {
+        String::from_utf8_lossy(&self.payload).into_owned()
+    }
+
+    pub fn time(&self) -> String {
+        chrono::DateTime::from_timestamp_millis(self.timestamp)
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S%.3f").to_string())
+            .unwrap_or_default()
+    }
+
+    pub async fn insert(&self, repo: &crate::Repo, client: &str) -> Result<(), StorageError> {
+        repo.insert_history(client, self).await
+    }
+
+    /// latest `count` entries, newest first
+    pub async fn latest(
+        repo: &crate::Repo,
+        client: &str,
+        count: usize,
+    ) -> Result<Vec<HistoryEntry>, StorageError> {
+        repo.latest_history(client, count).await
+    }
+
+    /// Up to `count` entries oldest first with their timestamp in `time`,
+    /// starting after the cursor `after`.
+    ///
+    /// Returns the entries together with the cursor to continue from, `None`
+    /// at the end of the history.
+    pub async fn page(
+        repo: &crate::Repo,
+        client: &str,
+        after: Option<&str>,
+        time: std::ops::Range<i64>,
+        count: usize,
+    ) -> Result<(Vec<HistoryEntry>, Option<String>), StorageError> {
+        repo.history_page(client, after, time, count).await
+    }
+}
@@ -1,2 +1,12 @@
+pub mod alert;
+pub mod alert_definition;
+pub mod audit;
+pub mod bridge;
+pub mod history;
 pub mod mqtt_client;
+pub mod publish_template;
+pub mod recording;
+pub mod rule;
+pub mod schedule;
 pub mod user;
+pub mod webhook;
@@ -0,0 +1,135 @@
+use bb8_redis::redis::cmd;
+use serde::{Deserialize, Serialize};
+
+use crate::storage::StorageError;
+
+/// Saved publish of a client, topic and payload may contain placeholders.
+///
+/// Names are unique per owner, users may have templates of the same name.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PublishTemplate {
+    pub name: String,
+    pub topic: String,
+    pub payload: String,
+    pub qos: u8,
+    pub retain: bool,
+    /// user who created the template
+    pub owner: String,
+    /// visible to all users instead of only the owner
+    pub shared: bool,
+}
+
+impl PublishTemplate {
+    pub fn visible_to(&self, user: &str) -> bool {
+        self.shared || self.owner == user
+    }
+
+    /// Names of the user-entered fields in topic and payload.
+    pub fn fields(&self) -> Vec<String> {
+        let mut fields = crate::payload::fields(&self.topic);
+        for field in crate::payload::fields(&self.payload) {
+            if !fields.contains(&field) {
+                fields.push(field);
+            }
+        }
+        fields
+    }
+
+    fn key(client: &str) -> String {
+        format!("mqtt_client:{}:templates", client)
+    }
+
+    fn counters_key(client: &str) -> String {
+        format!("mqtt_client:{}:template_counters", client)
+    }
+
+    /// Hash field of the template `name` of `owner`.
+    fn field(owner: &str, name: &str) -> String {
+        // a JSON array, as owner and name may contain any separator
+        serde_json::json!([owner, name]).to_string()
+    }
+
+    pub async fn list(
+        pool: &crate::DbPool,
+        client: &str,
+    ) -> Result<Vec<PublishTemplate>, StorageError> {
+        let mut conn = pool.get().await?;
+        let templates: Vec<String> = cmd("HVALS")
+            .arg(Self::key(client))
+            .query_async(&mut *conn)
+            .await?;
+        let mut templates: Vec<PublishTemplate> = templates
+            .iter()
+            .map(|template| serde_json::from_str(template).map_err(StorageError::from))
+            .collect::<Result<_, _>>()?;
+        templates.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.owner.cmp(&b.owner)));
+        Ok(templates)
+    }
+
+    pub async fn get_by_name(
+        pool: &crate::DbPool,
+        client: &str,
+        owner: &str,
+        name: &str,
+    ) -> Result<Option<PublishTemplate>, StorageError> {
+        let mut conn = pool.get().await?;
+        let template: Option<String> = cmd("HGET")
+            .arg(Self::key(client))
+            .arg(Self::field(owner, name))
+            .query_async(&mut *conn)
+            .await?;
+        template
+            .map(|template| serde_json::from_str(&template))
+            .transpose()
+            .map_err(StorageError::from)
+    }
+
+    pub async fn insert(&self, pool: &crate::DbPool, client: &str) -> Result<(), StorageError> {
+        let mut conn = pool.get().await?;
+        let template_json = serde_json::to_string(&self)?;
+        let _: i32 = cmd("HSET")
+            .arg(Self::key(client))
+            .arg(Self::field(&self.owner, &self.name))
+            .arg(template_json)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete(
+        pool: &crate::DbPool,
+        client: &str,
+        owner: &str,
+        name: &str,
+    ) -> Result<bool, StorageError> {
+        let mut conn = pool.get().await?;
+        let field = Self::field(owner, name);
+        let deleted: i32 = cmd("HDEL")
+            .arg(Self::key(client))
+            .arg(&field)
+            .query_async(&mut *conn)
+            .await?;
+        let _: i32 = cmd("HDEL")
+            .arg(Self::counters_key(client))
+            .arg(&field)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    /// Increment and return the publish counter of the template.
+    pub async fn next_counter(
+        &self,
+        pool: &crate::DbPool,
+        client: &str,
+    ) -> Result<u64, StorageError> {
+        let mut conn = pool.get().await?;
+        cmd("HINCRBY")
+            .arg(Self::counters_key(client))
+            .arg(Self::field(&self.owner, &self.name))
+            .arg(1)
+            .query_async(&mut *conn)
+            .await
+            .map_err(StorageError::from)
+    }
+}
@@ -0,0 +1,195 @@
+use bb8_redis::redis::{cmd, streams::StreamRangeReply};
+use serde::{Deserialize, Serialize};
+
+use crate::storage::StorageError;
+
+/// Default upper bound of messages kept per recording.
+pub const RECORDING_MAX_LEN: usize = 100000;
+
+/// Messages of `client` matching `filter`, recorded for later replay.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Recording {
+    pub name: String,
+    pub client: String,
+    /// MQTT topic filter, wildcards allowed
+    pub filter: String,
+    /// unix timestamp in milliseconds
+    pub started: i64,
+    /// planned end of the recording window, `None` records until stopped
+    pub until: Option<i64>,
+    /// unix timestamp in milliseconds the recording was stopped
+    pub stopped: Option<i64>,
+    /// state of the last replay
+    pub replay: Option<String>,
+}
+
+/// A single recorded message, `timestamp` is the unix time in milliseconds.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecordedMessage {
+    pub timestamp: i64,
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: u8,
+    pub retain: bool,
+}
+
+fn format_time(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp_millis(timestamp)
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default()
+}
+
+impl Recording {
+    pub fn is_active(&self, now: i64) -> bool {
+        self.stopped.is_none() && self.until.is_none_or(|until| until > now)
+    }
+
+    pub fn active(&self) -> bool {
+        self.is_active(chrono::Utc::now().timestamp_millis())
+    }
+
+    pub fn matches(&self, client: &str, topic: &str) -> bool {
+        self.client == client && rumqttc::matches(topic, &self.filter)
+    }
+
+    pub fn started_time(&self) -> String {
+        format_time(self.started)
+    }
+
+    /// end of the recording, either stopped or the end of its window
+    pub fn end_time(&self) -> Option<String> {
+        self.stopped.or(self.until).map(format_time)
+    }
+
+    fn messages_key(name: &str) -> String {
+        format!("recording:{}:messages", name)
+    }
+
+    pub async fn list(pool: &crate::DbPool) -> Result<Vec<Recording>, StorageError> {
+        let mut conn = pool.get().await?;
+        let recordings: Vec<String> = cmd("HVALS")
+            .arg("recordings")
+            .query_async(&mut *conn)
+            .await?;
+        let mut recordings: Vec<Recording> = recordings
+            .iter()
+            .map(|recording| serde_json::from_str(recording).map_err(StorageError::from))
+            .collect::<Result<_, _>>()?;
+        recordings.sort_by_key(|recording| std::cmp::Reverse(recording.started));
+        Ok(recordings)
+    }
+
+    pub async fn get_by_name(
+        pool: &crate::DbPool,
+        name: &str,
+    ) -> Result<Option<Recording>, StorageError> {
+        let mut conn = pool.get().await?;
+        let recording: Option<String> = cmd("HGET")
+            .arg("recordings")
+            .arg(name)
+            .query_async(&mut *conn)
+            .await?;
+        recording
+            .map(|recording| serde_json::from_str(&recording))
+            .transpose()
+            .map_err(StorageError::from)
+    }
+
+    pub async fn insert(&self, pool: &crate::DbPool) -> Result<(), StorageError> {
+        let mut conn = pool.get().await?;
+        let recording_json = serde_json::to_string(&self)?;
+        let _: i32 = cmd("HSET")
+            .arg("recordings")
+            .arg(&self.name)
+            .arg(recording_json)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete(pool: &crate::DbPool, name: &str) -> Result<bool, StorageError> {
+        let mut conn = pool.get().await?;
+        let deleted: i32 = cmd("HDEL")
+            .arg("recordings")
+            .arg(name)
+            .query_async(&mut *conn)
+            .await?;
+        let _: i32 = cmd("DEL")
+            .arg(Self::messages_key(name))
+            .query_async(&mut *conn)
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    pub async fn message_count(pool: &crate::DbPool, name: &str) -> Result<usize, StorageError> {
+        let mut conn = pool.get().await?;
+        cmd("XLEN")
+            .arg(Self::messages_key(name))
+            .query_async(&mut *conn)
+            .await
+            .map_err(StorageError::from)
+    }
+
+    /// Append a message, only about the latest `max_len` messages are kept.
+    pub async fn record(
+        pool: &crate::DbPool,
+        name: &str,
+        message: &RecordedMessage,
+        max_len: usize,
+    ) -> Result<(), StorageError> {
+        let mut conn = pool.get().await?;
+        let _: String = cmd("XADD")
+            .arg(Self::messages_key(name))
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(max_len)
+            .arg("*")
+            .arg("ts")
+            .arg(message.timestamp)
+            .arg("topic")
+            .arg(&message.topic)
+            .arg("payload")
+            .arg(&message.payload)
+            .arg("qos")
+            .arg(message.qos)
+            .arg("retain")
+            .arg(message.retain as u8)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Up to `count` messages in recording order, starting after the stream id `after`.
+    ///
+    /// Returns the messages together with the id to continue from.
+    pub async fn messages(
+        pool: &crate::DbPool,
+        name: &str,
+        after: Option<&str>,
+        count: usize,
+    ) -> Result<(Vec<RecordedMessage>, Option<String>), StorageError> {
+        let mut conn = pool.get().await?;
+        let start = after.map_or("-".to_string(), |id| format!("({id}"));
+        let reply: StreamRangeReply = cmd("XRANGE")
+            .arg(Self::messages_key(name))
+            .arg(start)
+            .arg("+")
+            .arg("COUNT")
+            .arg(count)
+            .query_async(&mut *conn)
+            .await?;
+        let last = reply.ids.last().map(|entry| entry.id.clone());
+        let messages = reply
+            .ids
+            .iter()
+            .map(|entry| RecordedMessage {
+                timestamp: entry.get("ts").unwrap_or(0),
+                topic: entry.get("topic").unwrap_or_default(),
+                payload: entry.get("payload").unwrap_or_default(),
+                qos: entry.get("qos").unwrap_or(0),
+                retain: entry.get::<u8>("retain").unwrap_or(0) > 0,
+            })
+            .collect();
+        Ok((messages, last))
+    }
+}
@@ -0,0 +1,272 @@
+use bb8_redis::redis::cmd;
+use serde::{Deserialize, Serialize};
+
+use crate::storage::StorageError;
+use serde_json::Value;
+use serde_json_path::JsonPath;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Contains,
+    Exists,
+}
+
+impl Comparison {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Comparison::Eq => "==",
+            Comparison::Ne => "!=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Contains => "contains",
+            Comparison::Exists => "exists",
+        }
+    }
+
+    fn compare(&self, found: &Value, expected: &Value) -> bool {
+        match self {
+            Comparison::Eq => found == expected,
+            Comparison::Ne => found != expected,
+            Comparison::Gt | Comparison::Ge | Comparison::Lt | Comparison::Le => {
+                let ordering = match (found, expected) {
+                    (Value::Number(a), Value::Number(b)) => a
+                        .as_f64()
+                        .zip(b.as_f64())
+                        .and_then(|(a, b)| a.partial_cmp(&b)),
+                    (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+                    _ => None,
+                };
+                let Some(ordering) = ordering else {
+                    return false;
+                };
+                match self {
+                    Comparison::Gt => ordering.is_gt(),
+                    Comparison::Ge => ordering.is_ge(),
+                    Comparison::Lt => ordering.is_lt(),
+                    _ => ordering.is_le(),
+                }
+            }
+            Comparison::Contains => match (found, expected) {
+                (Value::String(a), Value::String(b)) => a.contains(b.as_str()),
+                (Value::Array(a), b) => a.contains(b),
+                _ => false,
+            },
+            Comparison::Exists => true,
+        }
+    }
+}
+
+/// JSONPath comparison evaluated against the message payload.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Condition {
+    pub path: String,
+    pub op: Comparison,
+    #[serde(default)]
+    pub value: Value,
+}
+
+impl Condition {
+    /// Payloads which are not valid JSON never satisfy a condition.
+    pub fn evaluate(&self, payload: &[u8]) -> bool {
+        let Ok(path) = JsonPath::parse(&self.path) else {
+            log::error!("Invalid JSONPath in rule condition: {}", self.path);
+            return false;
+        };
+        let Ok(payload) = serde_json::from_slice::<Value>(payload) else {
+            return false;
+        };
+        path.query(&payload)
+            .all()
+            .into_iter()
+            .any(|found| self.op.compare(found, &self.value))
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.op {
+            Comparison::Exists => write!(f, "{} exists", self.path),
+            _ => write!(f, "{} {} {}", self.path, self.op.symbol(), self.value),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RuleAction {
+    /// publish the payload to `topic` on the managed client `client`,
+    /// `{topic}` is replaced with the topic of the incoming message
+    Republish {
+        client: String,
+        topic: String,
+    },
+    History {
+        tag: String,
+    },
+    Alert {
+        message: String,
+    },
+}
+
+impl std::fmt::Display for RuleAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuleAction::Republish { client, topic } => write!(f, "republish to {client}: {topic}"),
+            RuleAction::History { tag } => write!(f, "history tagged '{tag}'"),
+            RuleAction::Alert { message } => write!(f, "alert '{message}'"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rule {
+    pub name: String,
+    /// MQTT topic filter, wildcards allowed
+    pub topic: String,
+    pub condition: Option<Condition>,
+    pub actions: Vec<RuleAction>,
+}
+
+impl Rule {
+    pub fn matches(&self, topic: &str, payload: &[u8]) -> bool {
+        rumqttc::matches(topic, &self.topic)
+            && self
+                .condition
+                .as_ref()
+                .is_none_or(|condition| condition.evaluate(payload))
+    }
+
+    /// Whether a rule of `client` republishes onto `client` to a topic its
+    /// own filter matches, which would feed the rule its own messages forever.
+    ///
+    /// `{topic}` is checked with a topic of the filter, wildcards filled in.
+    pub fn republishes_into_itself(&self, client: &str) -> bool {
+        let sample = self.topic.replace(['+', '#'], "x");
+        self.actions.iter().any(|action| match action {
+            RuleAction::Republish {
+                client: target,
+                topic,
+            } => {
+                target == client
+                    && rumqttc::matches(&topic.replace("{topic}", &sample), &self.topic)
+            }
+            _ => false,
+        })
+    }
+
+    fn key(client: &str) -> String {
+        format!("mqtt_client:{}:rules", client)
+    }
+
+    pub async fn list(pool: &crate::DbPool, client: &str) -> Result<Vec<Rule>, StorageError> {
+        let mut conn = pool.get().await?;
+        let rules: Vec<String> = cmd("HVALS")
+            .arg(Self::key(client))
+            .query_async(&mut *conn)
+            .await?;
+        let mut rules: Vec<Rule> = rules
+            .iter()
+            .map(|rule| serde_json::from_str(rule).map_err(StorageError::from))
+            .collect::<Result<_, _>>()?;
+        rules.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(rules)
+    }
+
+    pub async fn insert(&self, pool: &crate::DbPool, client: &str) -> Result<(), StorageError> {
+        let mut conn = pool.get().await?;
+        let rule_json = serde_json::to_string(&self)?;
+        let _: i32 = cmd("HSET")
+            .arg(Self::key(client))
+            .arg(&self.name)
+            .arg(rule_json)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete(
+        pool: &crate::DbPool,
+        client: &str,
+        name: &str,
+    ) -> Result<bool, StorageError> {
+        let mut conn = pool.get().await?;
+        let deleted: i32 = cmd("HDEL")
+            .arg(Self::key(client))
+            .arg(name)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(deleted > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn condition(path: &str, op: Comparison, value: Value) -> Condition {
+        Condition {
+            path: path.into(),
+            op,
+            value,
+        }
+    }
+
+    #[test]
+    fn compares_numbers_and_strings() {
+        let payload = br#"{"temp": 21.5, "room": "kitchen", "tags": ["a", "b"]}"#;
+        let holds = |path, op, value| condition(path, op, value).evaluate(payload);
+        assert!(holds("$.temp", Comparison::Gt, json!(20)));
+        assert!(holds("$.temp", Comparison::Ge, json!(21.5)));
+        assert!(!holds("$.temp", Comparison::Lt, json!(21.5)));
+        assert!(holds("$.temp", Comparison::Le, json!(21.5)));
+        assert!(holds("$.room", Comparison::Eq, json!("kitchen")));
+        assert!(holds("$.room", Comparison::Ne, json!("hall")));
+        assert!(holds("$.room", Comparison::Lt, json!("living")));
+        assert!(holds("$.room", Comparison::Contains, json!("itch")));
+        assert!(holds("$.tags", Comparison::Contains, json!("b")));
+        // numbers and strings do not order against each other
+        assert!(!holds("$.temp", Comparison::Gt, json!("20")));
+        assert!(holds("$.room", Comparison::Exists, Value::Null));
+        assert!(!holds("$.door", Comparison::Exists, Value::Null));
+    }
+
+    #[test]
+    fn any_match_of_the_path_satisfies() {
+        let payload = br#"{"sensors": [{"temp": 18}, {"temp": 31}]}"#;
+        assert!(condition("$.sensors[*].temp", Comparison::Gt, json!(30)).evaluate(payload));
+        assert!(!condition("$.sensors[*].temp", Comparison::Gt, json!(40)).evaluate(payload));
+    }
+
+    #[test]
+    fn invalid_paths_and_payloads_never_hold() {
+        assert!(!condition("$.temp", Comparison::Exists, Value::Null).evaluate(b"21.5 C"));
+        assert!(!condition("temp[", Comparison::Exists, Value::Null).evaluate(br#"{"temp": 1}"#));
+    }
+
+    #[test]
+    fn notices_republishing_into_itself() {
+        let rule = |topic: &str, client: &str, target: &str| Rule {
+            name: "r".into(),
+            topic: topic.into(),
+            condition: None,
+            actions: vec![RuleAction::Republish {
+                client: client.into(),
+                topic: target.into(),
+            }],
+        };
+        assert!(rule("sensors/#", "c1", "{topic}").republishes_into_itself("c1"));
+        assert!(rule("sensors/#", "c1", "sensors/copy").republishes_into_itself("c1"));
+        assert!(rule("#", "c1", "out/{topic}").republishes_into_itself("c1"));
+        assert!(!rule("sensors/+", "c1", "out/{topic}").republishes_into_itself("c1"));
+        assert!(!rule("sensors/#", "c2", "{topic}").republishes_into_itself("c1"));
+    }
+}
@@ -0,0 +1,137 @@
+use std::str::FromStr;
+
+use bb8_redis::redis::cmd;
+use serde::{Deserialize, Serialize};
+
+use crate::storage::StorageError;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ScheduleTrigger {
+    /// cron expression with seconds, e.g. `0 */5 * * * *`
+    Cron(String),
+    /// interval in seconds
+    Interval(u64),
+}
+
+impl ScheduleTrigger {
+    /// First run strictly after `after` (unix timestamp in milliseconds).
+    pub fn next_after(&self, after: i64) -> Option<i64> {
+        match self {
+            ScheduleTrigger::Cron(expression) => {
+                let schedule = cron::Schedule::from_str(expression).ok()?;
+                let after = chrono::DateTime::from_timestamp_millis(after)?;
+                schedule
+                    .after(&after)
+                    .next()
+                    .map(|next| next.timestamp_millis())
+            }
+            ScheduleTrigger::Interval(seconds) => Some(after + *seconds as i64 * 1000),
+        }
+    }
+}
+
+impl std::fmt::Display for ScheduleTrigger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScheduleTrigger::Cron(expression) => write!(f, "cron {expression}"),
+            ScheduleTrigger::Interval(seconds) => write!(f, "every {seconds}s"),
+        }
+    }
+}
+
+/// Recurring publish on a client.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Schedule {
+    pub name: String,
+    pub topic: String,
+    /// payload template, see [`crate::payload::render`]
+    pub payload: String,
+    pub qos: u8,
+    pub retain: bool,
+    pub trigger: ScheduleTrigger,
+    pub enabled: bool,
+    /// unix timestamps in milliseconds
+    pub last_run: Option<i64>,
+    pub next_run: Option<i64>,
+    #[serde(default)]
+    pub runs: u64,
+    pub last_error: Option<String>,
+}
+
+fn format_time(timestamp: Option<i64>) -> String {
+    timestamp
+        .and_then(chrono::DateTime::from_timestamp_millis)
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or("-".into())
+}
+
+impl Schedule {
+    pub fn last_run_time(&self) -> String {
+        format_time(self.last_run)
+    }
+
+    pub fn next_run_time(&self) -> String {
+        format_time(self.next_run)
+    }
+
+    fn key(client: &str) -> String {
+        format!("mqtt_client:{}:schedules", client)
+    }
+
+    pub async fn list(pool: &crate::DbPool, client: &str) -> Result<Vec<Schedule>, StorageError> {
+        let mut conn = pool.get().await?;
+        let schedules: Vec<String> = cmd("HVALS")
+            .arg(Self::key(client))
+            .query_async(&mut *conn)
+            .await?;
+        let mut schedules: Vec<Schedule> = schedules
+            .iter()
+            .map(|schedule| serde_json::from_str(schedule).map_err(StorageError::from))
+            .collect::<Result<_, _>>()?;
+        schedules.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(schedules)
+    }
+
+    pub async fn get_by_name(
+        pool: &crate::DbPool,
+        client: &str,
+        name: &str,
+    ) -> Result<Option<Schedule>, StorageError> {
+        let mut conn = pool.get().await?;
+        let schedule: Option<String> = cmd("HGET")
+            .arg(Self::key(client))
+            .arg(name)
+            .query_async(&mut *conn)
+            .await?;
+        schedule
+            .map(|schedule| serde_json::from_str(&schedule))
+            .transpose()
+            .map_err(StorageError::from)
+    }
+
+    pub async fn insert(&self, pool: &crate::DbPool, client: &str) -> Result<(), StorageError> {
+        let mut conn = pool.get().await?;
+        let schedule_json = serde_json::to_string(&self)?;
+        let _: i32 = cmd("HSET")
+            .arg(Self::key(client))
+            .arg(&self.name)
+            .arg(schedule_json)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete(
+        pool: &crate::DbPool,
+        client: &str,
+        name: &str,
+    ) -> Result<bool, StorageError> {
+        let mut conn = pool.get().await?;
+        let deleted: i32 = cmd("HDEL")
+            .arg(Self::key(client))
+            .arg(name)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(deleted > 0)
+    }
+}
@@ -1,7 +1,7 @@
-use anyhow::bail;
-use bb8_redis::redis::cmd;
 use serde::{Deserialize, Serialize};
 
+use crate::storage::StorageError;
+
 pub enum Role {
     Admin = 0,
     User = 1,
@@ -12,19 +12,38 @@ impl From<i32> for Role {
         match i {
             0 => Role::Admin,
             1 => Role::User,
-            _ => panic!("Unknown role: {}", i),
+            _ => {
+                // never hand out more rights than the least privileged role
+                log::warn!("Unknown role {}, treating it as user", i);
+                Role::User
+            }
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub enum UserSource {
     #[default]
     Local,
     OAuth(String),
+    Ldap(String),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Setting which makes local admins enroll a second factor on their next login.
+const REQUIRE_ADMIN_TOTP: &str = "require_admin_totp";
+
+/// Second factor of a local user.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TotpConfig {
+    /// secret encrypted with the `totp_key`, see [`crate::totp::TotpCipher`]
+    pub secret: String,
+    /// SHA-256 of the unused recovery codes
+    pub recovery_codes: Vec<String>,
+    /// time step of the last accepted code, earlier ones are refused
+    pub last_step: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct User {
     pub name: String,
     pub email: Option<String>,
@@ -32,38 +51,18 @@ pub struct User {
     pub role_id: i32,
     #[serde(default)]
     pub source: UserSource,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp: Option<TotpConfig>,
 }
 
 impl User {
-    pub async fn insert_if_not_exist(
-        pool: &crate::DbPool,
-        check_name: &str,
-        source: UserSource,
-    ) -> Result<User, anyhow::Error> {
-        if let Some(user) = Self::get_by_name(pool, check_name).await {
-            if user.source != source {
-                bail!("User is logging in from unknown source!");
-            }
-            Ok(user)
-        } else {
-            let user = User {
-                name: check_name.into(),
-                email: None,
-                password: "".into(),
-                role_id: 0,
-                source,
-            };
-            user.insert(pool).await;
-            Ok(user)
-        }
-    }
     pub async fn check(
-        pool: &crate::DbPool,
+        repo: &crate::Repo,
         check_name: &str,
         check_password: &str,
         source: UserSource,
-    ) -> bool {
-        let user = User::get_by_name(pool, check_name).await;
+    ) -> Result<bool, StorageError> {
+        let user = User::get_by_name(repo, check_name).await?;
         if let Some(user) = user {
             if user.source != source {
                 log::error!(
@@ -72,68 +71,44 @@ impl User {
                     user.source,
                     source
                 );
-                return false;
+                return Ok(false);
             }
-            user.password == check_password
+            Ok(user.password == check_password)
         } else {
-            false
+            Ok(false)
         }
     }
 
-    pub async fn get_by_name(pool: &crate::DbPool, name: &str) -> Option<User> {
-        let mut conn = pool.get().await.expect("no connection available");
-        let user: Option<String> = cmd("HGET")
-            .arg("users")
-            .arg(name)
-            .query_async(&mut *conn)
-            .await
-            .expect("Cannot query users from redis");
-        if let Some(user) = user {
-            let user: User = serde_json::from_str(&user).expect("Cannot deserialize user");
-            Some(user)
-        } else {
-            None
-        }
+    pub async fn get_by_name(repo: &crate::Repo, name: &str) -> Result<Option<User>, StorageError> {
+        repo.get_user(name).await
     }
 
-    pub async fn list(pool: &crate::DbPool) -> Vec<User> {
-        let mut conn = pool.get().await.expect("no connection available");
-        let mut users_hash: Vec<String> = cmd("HGETALL")
-            .arg("users")
-            .query_async(&mut *conn)
-            .await
-            .expect("Cannot query users from redis");
-        let mut users: Vec<User> = Vec::new();
-        while let Some(user) = users_hash.pop() {
-            let user: User = serde_json::from_str(&user).expect("Cannot deserialize user");
-            users.push(user);
-            let _ = users_hash.pop();
-        }
-        users
+    pub async fn list(repo: &crate::Repo) -> Result<Vec<User>, StorageError> {
+        repo.list_users().await
     }
 
-    pub async fn insert(&self, pool: &crate::DbPool) {
-        let mut conn = pool.get().await.expect("no connection available");
-        let user_json = serde_json::to_string(&self).expect("Cannot serialize user");
-        let _: i32 = cmd("HSET")
-            .arg("users")
-            .arg(&self.name)
-            .arg(user_json)
-            .query_async(&mut *conn)
-            .await
-            .expect("Cannot insert user");
+    pub async fn insert(&self, repo: &crate::Repo) -> Result<(), StorageError> {
+        repo.insert_user(self).await
     }
 
-    pub async fn delete(pool: &crate::DbPool, name: &str) -> bool {
-        let mut conn = pool.get().await.expect("no connection available");
-        let res: Result<i32, redis::RedisError> = cmd("HDEL")
-            .arg("users")
-            .arg(name)
-            .query_async(&mut *conn)
-            .await;
-        match res {
-            Ok(i) => i > 0,
-            Err(_) => false,
-        }
+    pub async fn delete(repo: &crate::Repo, name: &str) -> Result<bool, StorageError> {
+        repo.delete_user(name).await
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.role_id == Role::Admin as i32
+    }
+
+    /// Whether local admins have to log in with a second factor.
+    pub async fn admin_totp_required(repo: &crate::Repo) -> Result<bool, StorageError> {
+        Ok(repo.get_setting(REQUIRE_ADMIN_TOTP).await?.as_deref() == Some("true"))
+    }
+
+    pub async fn set_admin_totp_required(
+        repo: &crate::Repo,
+        required: bool,
+    ) -> Result<(), StorageError> {
+        repo.set_setting(REQUIRE_ADMIN_TOTP, &required.to_string())
+            .await
     }
 }
@@ -0,0 +1,271 @@
+use bb8_redis::redis::{cmd, pipe};
+use serde::{Deserialize, Serialize};
+
+use crate::storage::StorageError;
+
+/// Default upper bound of delivery log entries kept per webhook.
+pub const LOG_MAX_LEN: usize = 200;
+
+/// POSTs messages of `client` matching `topic` to `url`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Webhook {
+    pub name: String,
+    pub client: String,
+    /// MQTT topic filter, wildcards allowed
+    pub topic: String,
+    pub url: String,
+    /// key for the HMAC-SHA256 signature header, unsigned if `None`
+    pub secret: Option<String>,
+}
+
+/// JSON body sent to the webhook url.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebhookEnvelope {
+    pub client: String,
+    pub topic: String,
+    pub payload: String,
+    /// `utf8` or `base64`, depending on whether the payload was valid UTF-8
+    pub encoding: String,
+    pub qos: u8,
+    pub retain: bool,
+    /// RFC 3339 receive time
+    pub timestamp: String,
+}
+
+/// Entry of the delivery queue.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebhookDelivery {
+    pub id: String,
+    pub webhook: String,
+    pub attempt: u32,
+    pub envelope: WebhookEnvelope,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeliveryLogEntry {
+    /// unix timestamp in milliseconds
+    pub timestamp: i64,
+    pub delivery: String,
+    pub topic: String,
+    pub attempt: u32,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    /// whether the delivery will be retried
+    pub retry: bool,
+}
+
+impl DeliveryLogEntry {
+    pub fn time(&self) -> String {
+        chrono::DateTime::from_timestamp_millis(self.timestamp)
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default()
+    }
+
+    fn key(webhook: &str) -> String {
+        format!("webhook:{}:log", webhook)
+    }
+
+    /// Store the entry, only the latest `max_len` entries of the webhook are kept.
+    pub async fn insert(
+        &self,
+        pool: &crate::DbPool,
+        webhook: &str,
+        max_len: usize,
+    ) -> Result<(), StorageError> {
+        let mut conn = pool.get().await?;
+        let entry_json = serde_json::to_string(&self)?;
+        let _: () = pipe()
+            .cmd("LPUSH")
+            .arg(Self::key(webhook))
+            .arg(entry_json)
+            .ignore()
+            .cmd("LTRIM")
+            .arg(Self::key(webhook))
+            .arg(0)
+            .arg(max_len.saturating_sub(1))
+            .ignore()
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn list(
+        pool: &crate::DbPool,
+        webhook: &str,
+    ) -> Result<Vec<DeliveryLogEntry>, StorageError> {
+        let mut conn = pool.get().await?;
+        let entries: Vec<String> = cmd("LRANGE")
+            .arg(Self::key(webhook))
+            .arg(0)
+            .arg(-1)
+            .query_async(&mut *conn)
+            .await?;
+        entries
+            .iter()
+            .map(|entry| serde_json::from_str(entry).map_err(StorageError::from))
+            .collect()
+    }
+}
+
+/// Move the delivery `ARGV[1]` from the queue `KEYS[1]` to the in-flight
+/// hash `KEYS[2]` under its id `ARGV[2]`, unless it was claimed already.
+pub(crate) const CLAIM: &str = r#"
+if redis.call('ZREM', KEYS[1], ARGV[1]) == 0 then
+    return 0
+end
+redis.call('HSET', KEYS[2], ARGV[2], ARGV[1])
+return 1
+"#;
+
+impl WebhookDelivery {
+    /// Add the delivery to the queue, due at `due` (unix timestamp in milliseconds).
+    pub async fn enqueue(&self, pool: &crate::DbPool, due: i64) -> Result<(), StorageError> {
+        let mut conn = pool.get().await?;
+        let delivery_json = serde_json::to_string(&self)?;
+        let _: i32 = cmd("ZADD")
+            .arg("webhook_queue")
+            .arg(due)
+            .arg(delivery_json)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Take up to `count` due deliveries off the queue.
+    ///
+    /// Claimed deliveries are kept in `webhook_inflight` until they are
+    /// finished, so they survive a restart in between.
+    pub async fn claim_due(
+        pool: &crate::DbPool,
+        now: i64,
+        count: usize,
+    ) -> Result<Vec<WebhookDelivery>, StorageError> {
+        let mut conn = pool.get().await?;
+        let due: Vec<String> = cmd("ZRANGEBYSCORE")
+            .arg("webhook_queue")
+            .arg("-inf")
+            .arg(now)
+            .arg("LIMIT")
+            .arg(0)
+            .arg(count)
+            .query_async(&mut *conn)
+            .await?;
+        let mut claimed = Vec::new();
+        for delivery_json in due {
+            let Ok(delivery) = serde_json::from_str::<WebhookDelivery>(&delivery_json) else {
+                log::error!("Dropping undecodable webhook delivery: {delivery_json}");
+                let _: i32 = cmd("ZREM")
+                    .arg("webhook_queue")
+                    .arg(&delivery_json)
+                    .query_async(&mut *conn)
+                    .await?;
+                continue;
+            };
+            let moved: i32 = cmd("EVAL")
+                .arg(CLAIM)
+                .arg(2)
+                .arg("webhook_queue")
+                .arg("webhook_inflight")
+                .arg(&delivery_json)
+                .arg(&delivery.id)
+                .query_async(&mut *conn)
+                .await?;
+            // someone else got it first
+            if moved == 1 {
+                claimed.push(delivery);
+            }
+        }
+        Ok(claimed)
+    }
+
+    pub async fn finish(&self, pool: &crate::DbPool) -> Result<(), StorageError> {
+        let mut conn = pool.get().await?;
+        let _: i32 = cmd("HDEL")
+            .arg("webhook_inflight")
+            .arg(&self.id)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Put deliveries which were in flight during the last shutdown back into the queue.
+    pub async fn requeue_inflight(pool: &crate::DbPool, now: i64) -> Result<(), StorageError> {
+        let mut conn = pool.get().await?;
+        let inflight: Vec<String> = cmd("HVALS")
+            .arg("webhook_inflight")
+            .query_async(&mut *conn)
+            .await?;
+        for delivery_json in inflight {
+            let _: i32 = cmd("ZADD")
+                .arg("webhook_queue")
+                .arg(now)
+                .arg(delivery_json)
+                .query_async(&mut *conn)
+                .await?;
+        }
+        let _: i32 = cmd("DEL")
+            .arg("webhook_inflight")
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+}
+
+impl Webhook {
+    pub fn matches(&self, client: &str, topic: &str) -> bool {
+        self.client == client && rumqttc::matches(topic, &self.topic)
+    }
+
+    pub async fn list(pool: &crate::DbPool) -> Result<Vec<Webhook>, StorageError> {
+        let mut conn = pool.get().await?;
+        let webhooks: Vec<String> = cmd("HVALS").arg("webhooks").query_async(&mut *conn).await?;
+        let mut webhooks: Vec<Webhook> = webhooks
+            .iter()
+            .map(|webhook| serde_json::from_str(webhook).map_err(StorageError::from))
+            .collect::<Result<_, _>>()?;
+        webhooks.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(webhooks)
+    }
+
+    pub async fn get_by_name(
+        pool: &crate::DbPool,
+        name: &str,
+    ) -> Result<Option<Webhook>, StorageError> {
+        let mut conn = pool.get().await?;
+        let webhook: Option<String> = cmd("HGET")
+            .arg("webhooks")
+            .arg(name)
+            .query_async(&mut *conn)
+            .await?;
+        webhook
+            .map(|webhook| serde_json::from_str(&webhook))
+            .transpose()
+            .map_err(StorageError::from)
+    }
+
+    pub async fn insert(&self, pool: &crate::DbPool) -> Result<(), StorageError> {
+        let mut conn = pool.get().await?;
+        let webhook_json = serde_json::to_string(&self)?;
+        let _: i32 = cmd("HSET")
+            .arg("webhooks")
+            .arg(&self.name)
+            .arg(webhook_json)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete(pool: &crate::DbPool, name: &str) -> Result<bool, StorageError> {
+        let mut conn = pool.get().await?;
+        let deleted: i32 = cmd("HDEL")
+            .arg("webhooks")
+            .arg(name)
+            .query_async(&mut *conn)
+            .await?;
+        let _: i32 = cmd("DEL")
+            .arg(DeliveryLogEntry::key(name))
+            .query_async(&mut *conn)
+            .await?;
+        Ok(deleted > 0)
+    }
+}
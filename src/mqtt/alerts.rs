@@ -0,0 +1,86 @@
+use std::{collections::HashMap, sync::MutexGuard};
+
+use rumqttc::Publish;
+
+use super::MqttClientManager;
+use crate::models::alert_definition::{AlertCondition, AlertDefinition};
+
+/// What the event loops saw for an alert, evaluated by the alerting task.
+#[derive(Debug, Clone, Default)]
+pub struct AlertObservation {
+    /// unix timestamp in milliseconds of the latest message on the watched topic
+    pub last_message: Option<i64>,
+    /// whether the latest message satisfied the threshold condition
+    pub breached: Option<bool>,
+}
+
+impl MqttClientManager {
+    /// The observations are plain values, a panic while holding the lock
+    /// leaves them usable, so the event loops keep running.
+    fn observations(&self) -> MutexGuard<'_, HashMap<String, AlertObservation>> {
+        self.alert_observations
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Refresh the cached alert definitions from redis.
+    pub async fn reload_alerts(&self) {
+        let Some(pool) = &self.pool else {
+            return;
+        };
+        match AlertDefinition::list(pool).await {
+            Ok(alerts) => {
+                let names: Vec<String> = alerts.iter().map(|alert| alert.name.clone()).collect();
+                *self.alerts.write().await = alerts;
+                let now = chrono::Utc::now().timestamp_millis();
+                let mut observations = self.observations();
+                observations.retain(|name, _| names.contains(name));
+                // silence of new alerts counts from now on
+                for name in names {
+                    observations.entry(name).or_insert(AlertObservation {
+                        last_message: Some(now),
+                        breached: None,
+                    });
+                }
+            }
+            Err(e) => log::error!("Cannot reload alerts, keeping the cached ones: {e}"),
+        }
+    }
+
+    /// Topic filters a client has to subscribe to in order to feed its alerts.
+    pub(super) async fn alert_topics(&self, client_name: &str) -> Vec<String> {
+        self.alerts
+            .read()
+            .await
+            .iter()
+            .filter(|alert| alert.client == client_name)
+            .filter_map(|alert| alert.condition.topic().map(String::from))
+            .collect()
+    }
+
+    /// Observation of the alert `name`, `None` if it is not cached (yet).
+    pub fn alert_observation(&self, name: &str) -> Option<AlertObservation> {
+        self.observations().get(name).cloned()
+    }
+
+    /// Note the incoming message for every alert watching its topic.
+    pub(super) async fn apply_alerts(&self, client_name: &str, publish: &Publish) {
+        let alerts = self.alerts.read().await;
+        let watching: Vec<&AlertDefinition> = alerts
+            .iter()
+            .filter(|alert| alert.watches(client_name, &publish.topic))
+            .collect();
+        if watching.is_empty() {
+            return;
+        }
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut observations = self.observations();
+        for alert in watching {
+            let observation = observations.entry(alert.name.clone()).or_default();
+            observation.last_message = Some(now);
+            if let AlertCondition::Threshold { condition, .. } = &alert.condition {
+                observation.breached = Some(condition.evaluate(&publish.payload));
+            }
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::PoisonError,
+    time::{Duration, Instant},
+};
+
+use rumqttc::Publish;
+
+use super::MqttClientManager;
+use crate::models::bridge::Bridge;
+
+/// Time in which a forwarded message coming back on the target client is
+/// recognized as our own echo and not forwarded again.
+const ECHO_WINDOW: Duration = Duration::from_secs(10);
+
+fn echo_key(client: &str, topic: &str, payload: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    client.hash(&mut hasher);
+    topic.hash(&mut hasher);
+    payload.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl MqttClientManager {
+    /// Refresh the cached bridges from redis.
+    pub async fn reload_bridges(&self) {
+        let Some(pool) = &self.pool else {
+            return;
+        };
+        match Bridge::list(pool).await {
+            Ok(bridges) => *self.bridges.write().await = bridges,
+            Err(e) => log::error!("Cannot reload bridges, keeping the cached ones: {e}"),
+        }
+    }
+
+    /// Topic filters a client has to subscribe to in order to feed its bridges.
+    pub(super) async fn bridge_topics(&self, client_name: &str) -> Vec<String> {
+        self.bridges
+            .read()
+            .await
+            .iter()
+            .filter(|bridge| bridge.source == client_name)
+            .map(|bridge| bridge.topic.clone())
+            .collect()
+    }
+
+    /// Returns true if the message was forwarded onto `client_name` by a
+    /// bridge shortly before, consuming the record.
+    fn is_echo(&self, client_name: &str, publish: &Publish) -> bool {
+        let key = echo_key(client_name, &publish.topic, &publish.payload);
+        let mut echoes = self
+            .bridge_echoes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        echoes.retain(|_, sent| sent.elapsed() < ECHO_WINDOW);
+        echoes.remove(&key).is_some()
+    }
+
+    fn remember_echo(&self, client_name: &str, topic: &str, payload: &[u8]) {
+        let key = echo_key(client_name, topic, payload);
+        self.bridge_echoes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key, Instant::now());
+    }
+
+    /// Forward an incoming message of `client_name` along all matching bridges.
+    pub(super) async fn apply_bridges(&self, client_name: &str, publish: &Publish) {
+        let matched: Vec<Bridge> = self
+            .bridges
+            .read()
+            .await
+            .iter()
+            .filter(|bridge| bridge.matches(client_name, &publish.topic))
+            .cloned()
+            .collect();
+        if matched.is_empty() {
+            return;
+        }
+        if self.is_echo(client_name, publish) {
+            log::debug!(
+                "Not bridging {} on client {}, it was forwarded by a bridge",
+                publish.topic,
+                client_name
+            );
+            return;
+        }
+        let manager = self.clone();
+        let publish = publish.clone();
+        self.spawn_tracked(async move {
+            for bridge in matched {
+                let topic = bridge.map_topic(&publish.topic);
+                let qos = bridge.map_qos(publish.qos);
+                log::info!(
+                    "Bridge {} forwarding {} to {} on client {}",
+                    bridge.name,
+                    publish.topic,
+                    topic,
+                    bridge.target
+                );
+                if !manager.clients.lock().await.contains_key(&bridge.target) {
+                    log::error!("Bridge {}: unknown client {}", bridge.name, bridge.target);
+                    continue;
+                }
+                manager.remember_echo(&bridge.target, &topic, &publish.payload);
+                if let Err(e) = manager
+                    .publish_with(
+                        &bridge.target,
+                        topic,
+                        publish.payload.to_vec(),
+                        qos,
+                        publish.retain,
+                    )
+                    .await
+                {
+                    log::error!("Bridge {}: forwarding failed: {}", bridge.name, e);
+                }
+            }
+        });
+    }
+}
@@ -1,8 +1,69 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    future::Future,
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, Instant},
+};
 
 use actix::{Actor, ActorContext, Addr, Context, Handler, Message, Recipient};
 use rumqttc::{AsyncClient, Event, MqttOptions, Packet, Publish, QoS};
-use tokio::{sync::Mutex, task::JoinHandle};
+use tokio::{
+    sync::{Mutex, RwLock},
+    task::{JoinHandle, JoinSet},
+};
+
+use crate::{
+    models::{
+        alert_definition::AlertDefinition, bridge::Bridge, recording::Recording, rule::Rule,
+        webhook::Webhook,
+    },
+    settings::{MqttSettings, RetentionSettings, ShutdownSettings},
+    storage::StorageError,
+};
+
+mod alerts;
+mod bridges;
+mod recordings;
+mod rpc;
+mod rules;
+mod webhooks;
+
+pub use alerts::AlertObservation;
+pub use recordings::{ReplaySpeed, TopicRewrite};
+pub use rpc::{Correlation, RpcRequest};
+
+#[derive(Debug)]
+pub enum MqttError {
+    /// no client with this name is registered
+    UnknownClient(String),
+    Options(rumqttc::OptionError),
+    Client(rumqttc::ClientError),
+}
+
+impl Display for MqttError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MqttError::UnknownClient(name) => write!(f, "MQTT client {name} is not connected"),
+            MqttError::Options(e) => write!(f, "invalid MQTT url: {e}"),
+            MqttError::Client(e) => write!(f, "MQTT client error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for MqttError {}
+
+impl From<rumqttc::OptionError> for MqttError {
+    fn from(e: rumqttc::OptionError) -> Self {
+        MqttError::Options(e)
+    }
+}
+
+impl From<rumqttc::ClientError> for MqttError {
+    fn from(e: rumqttc::ClientError) -> Self {
+        MqttError::Client(e)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum MqttMessage {
@@ -10,6 +71,8 @@ pub enum MqttMessage {
     Sub((i32, Recipient<MqttMessage>)),
     Unsub(i32),
     Disconnect,
+    /// mqttpal is shutting down, websockets are closed as going away
+    Shutdown,
 }
 
 impl Message for MqttMessage {
@@ -58,12 +121,19 @@ impl Handler<MqttMessage> for MqttClientActor {
                 log::info!("Stopping Actor!");
                 ctx.stop()
             }
+            MqttMessage::Shutdown => {
+                for addr in self.ws_subs.values() {
+                    addr.do_send(MqttMessage::Shutdown);
+                }
+                ctx.stop()
+            }
         }
     }
 }
 
 struct MqttClient {
     client: AsyncClient,
+    url: String,
     handle: JoinHandle<()>,
     addr: Addr<MqttClientActor>,
 }
@@ -71,19 +141,51 @@ struct MqttClient {
 impl Drop for MqttClient {
     fn drop(&mut self) {
         log::info!("Dropping client!");
-        self.client.try_disconnect().unwrap();
+        // the event loop may already be gone after a disconnect
+        let _ = self.client.try_disconnect();
     }
 }
 
 #[derive(Clone)]
 pub struct MqttClientManager {
     clients: Arc<Mutex<HashMap<String, MqttClient>>>,
+    rules: Arc<RwLock<HashMap<String, Vec<Rule>>>>,
+    bridges: Arc<RwLock<Vec<Bridge>>>,
+    bridge_echoes: Arc<StdMutex<HashMap<u64, Instant>>>,
+    webhooks: Arc<RwLock<Vec<Webhook>>>,
+    recordings: Arc<RwLock<Vec<Recording>>>,
+    alerts: Arc<RwLock<Vec<AlertDefinition>>>,
+    alert_observations: Arc<StdMutex<HashMap<String, AlertObservation>>>,
+    /// tasks started for incoming messages still running, awaited on shutdown
+    message_tasks: Arc<StdMutex<JoinSet<()>>>,
+    repo: crate::Repo,
+    /// redis backs rules, bridges, webhooks, recordings and alerts, without it they stay empty
+    pool: Option<crate::DbPool>,
+    settings: MqttSettings,
+    retention: RetentionSettings,
 }
 
 impl MqttClientManager {
-    pub fn new() -> Self {
+    pub fn new(
+        repo: crate::Repo,
+        pool: Option<crate::DbPool>,
+        settings: MqttSettings,
+        retention: RetentionSettings,
+    ) -> Self {
         MqttClientManager {
             clients: Arc::new(Mutex::new(HashMap::<String, MqttClient>::new())),
+            rules: Arc::new(RwLock::new(HashMap::new())),
+            bridges: Arc::new(RwLock::new(Vec::new())),
+            bridge_echoes: Arc::new(StdMutex::new(HashMap::new())),
+            webhooks: Arc::new(RwLock::new(Vec::new())),
+            recordings: Arc::new(RwLock::new(Vec::new())),
+            alerts: Arc::new(RwLock::new(Vec::new())),
+            alert_observations: Arc::new(StdMutex::new(HashMap::new())),
+            message_tasks: Arc::new(StdMutex::new(JoinSet::new())),
+            repo,
+            pool,
+            settings,
+            retention,
         }
     }
 
@@ -101,7 +203,7 @@ impl MqttClientManager {
         client_name: String,
         mqtt_url: String,
         topics: Vec<String>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<(), MqttError> {
         log::info!("registering client {} with url {}", client_name, mqtt_url);
         let mqtt_url = if !mqtt_url.contains("?client_id") {
             format!("{}?client_id={}", mqtt_url, client_name)
@@ -109,8 +211,16 @@ impl MqttClientManager {
             mqtt_url
         };
         let mut options = MqttOptions::parse_url(&mqtt_url)?;
-        options.set_max_packet_size(100000, 100000);
-        let (client, mut eventloop) = AsyncClient::new(options, 10);
+        self.settings.apply(&mqtt_url, &mut options);
+        let url = mqtt_url.clone();
+        let (client, mut eventloop) = AsyncClient::new(options, self.settings.channel_capacity);
+        let mut topics = topics;
+        topics.extend(self.bridge_topics(&client_name).await);
+        topics.extend(self.recording_topics(&client_name).await);
+        topics.extend(self.alert_topics(&client_name).await);
+        topics.extend(self.webhook_topics(&client_name).await);
+        self.reload_rules(&client_name).await;
+        topics.extend(self.rule_topics(&client_name).await);
         for topic in topics {
             client.subscribe(&topic, QoS::AtLeastOnce).await?;
         }
@@ -120,6 +230,7 @@ impl MqttClientManager {
         }
         .start();
         let addr = addr_handle.clone();
+        let manager = self.clone();
         let handle = tokio::spawn(async move {
             log::info!("Client {} connected!", cid);
             loop {
@@ -128,6 +239,11 @@ impl MqttClientManager {
                     Ok(Event::Incoming(inc)) => match inc {
                         Packet::Publish(publish) => {
                             log::info!("Client {} got message: {:?}", cid, publish);
+                            manager.apply_rules(&cid, &publish).await;
+                            manager.apply_bridges(&cid, &publish).await;
+                            manager.apply_webhooks(&cid, &publish).await;
+                            manager.apply_recordings(&cid, &publish).await;
+                            manager.apply_alerts(&cid, &publish).await;
                             let _ = addr_handle.send(MqttMessage::Message(publish)).await;
                         }
                         Packet::ConnAck(_) => {
@@ -155,6 +271,7 @@ impl MqttClientManager {
 
         let mqtt_client = MqttClient {
             client,
+            url,
             handle,
             addr,
         };
@@ -163,54 +280,163 @@ impl MqttClientManager {
     }
     pub async fn unregister_client(&self, client_name: &String) {
         log::info!("Unregistering client: {}", &client_name);
+        self.rules.write().await.remove(client_name);
         let mut clients = self.clients.lock().await;
         let client = clients.remove(client_name);
         if let Some(client) = client {
-            client.client.disconnect().await.unwrap();
+            if let Err(e) = client.client.disconnect().await {
+                log::warn!("Client {} failed to disconnect: {}", client_name, e);
+            }
+        }
+    }
+    /// Disconnect a client and wait until its queued messages are sent.
+    pub async fn unregister_client_and_wait(&self, client_name: &String, timeout: Duration) {
+        log::info!("Unregistering client: {}", &client_name);
+        self.rules.write().await.remove(client_name);
+        let client = self.clients.lock().await.remove(client_name);
+        if let Some(mut client) = client {
+            let _ = client.client.disconnect().await;
+            if tokio::time::timeout(timeout, &mut client.handle)
+                .await
+                .is_err()
+            {
+                log::warn!("Client {} did not disconnect in time", client_name);
+            }
+        }
+    }
+    /// Run `task` on its own, `shutdown` waits for it to finish.
+    pub(super) fn spawn_tracked<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut tasks = self.message_tasks.lock().unwrap_or_else(|e| e.into_inner());
+        // forget the tasks done in the meantime
+        while tasks.try_join_next().is_some() {}
+        tasks.spawn(task);
+    }
+
+    /// Publish the goodbye messages, disconnect every client and wait for the
+    /// event loops and the rule actions, bridge forwards, webhook queueing
+    /// and recording writes they started, so messages already received are
+    /// handled.
+    ///
+    /// The clients get half of the time left until `deadline`, the tasks the
+    /// rest, whatever is still busy then is aborted.
+    pub async fn shutdown(&self, settings: &ShutdownSettings, deadline: tokio::time::Instant) {
+        let clients: Vec<(String, MqttClient)> = self.clients.lock().await.drain().collect();
+        log::info!("Disconnecting {} MQTT clients", clients.len());
+        let now = tokio::time::Instant::now();
+        let disconnected_by = now + deadline.saturating_duration_since(now) / 2;
+        let disconnects = clients.into_iter().map(|(name, mut client)| async move {
+            for goodbye in settings.goodbye.iter().filter(|g| g.applies_to(&name)) {
+                let topic = goodbye.topic_for(&name);
+                let qos = rumqttc::qos(goodbye.qos).unwrap_or(QoS::AtMostOnce);
+                let payload = goodbye.payload.clone().into_bytes();
+                if let Err(e) = client
+                    .client
+                    .publish(&topic, qos, goodbye.retain, payload)
+                    .await
+                {
+                    log::warn!("Client {name} cannot publish goodbye to {topic}: {e}");
+                }
+            }
+            let _ = client.addr.send(MqttMessage::Shutdown).await;
+            if let Err(e) = client.client.disconnect().await {
+                log::warn!("Client {name} failed to disconnect: {e}");
+            }
+            if tokio::time::timeout_at(disconnected_by, &mut client.handle)
+                .await
+                .is_err()
+            {
+                log::warn!("Client {name} did not disconnect in time");
+                client.handle.abort();
+            }
+        });
+        futures_util::future::join_all(disconnects).await;
+        // the event loops are gone, so no task can be added anymore
+        let mut tasks =
+            std::mem::take(&mut *self.message_tasks.lock().unwrap_or_else(|e| e.into_inner()));
+        log::info!("Waiting for {} message tasks", tasks.len());
+        let drained = async { while tasks.join_next().await.is_some() {} };
+        if tokio::time::timeout_at(deadline, drained).await.is_err() {
+            log::warn!("{} message tasks did not finish in time", tasks.len());
+            tasks.abort_all();
         }
     }
     #[allow(dead_code)]
-    pub async fn subscribe(
-        &self,
-        client_name: &String,
-        topic: &String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn subscribe(&self, client_name: &String, topic: &String) -> Result<(), MqttError> {
         log::info!("Subscribing client: {} to topic: {}", client_name, topic);
         let mut clients = self.clients.lock().await;
-        let client = clients.get_mut(client_name).unwrap();
+        let client = clients
+            .get_mut(client_name)
+            .ok_or_else(|| MqttError::UnknownClient(client_name.clone()))?;
         client.client.subscribe(topic, QoS::AtLeastOnce).await?;
         Ok(())
     }
-    #[allow(dead_code)]
-    pub async fn unsubscribe(
-        &self,
-        client_name: &String,
-        topic: &String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn unsubscribe(&self, client_name: &String, topic: &String) -> Result<(), MqttError> {
         log::info!("Unsubscribing client: {} to topic: {}", client_name, topic);
         let mut clients = self.clients.lock().await;
-        let client = clients.get_mut(client_name).unwrap();
+        let client = clients
+            .get_mut(client_name)
+            .ok_or_else(|| MqttError::UnknownClient(client_name.clone()))?;
         client.client.unsubscribe(topic).await?;
         Ok(())
     }
-    pub async fn connected(&self, client_name: &String) -> bool {
+    /// Unsubscribe a running client from `filter` unless a stored subscription,
+    /// a bridge, an active recording or an alert of the client still needs it.
+    ///
+    /// Call this after the owner of the filter is removed from storage and the
+    /// caches are reloaded. Returns whether the filter was released.
+    pub async fn release_filter(
+        &self,
+        client_name: &String,
+        filter: &String,
+    ) -> Result<bool, StorageError> {
+        let still_needed = self.repo.topics(client_name).await?.contains(filter)
+            || self.bridge_topics(client_name).await.contains(filter)
+            || self.recording_topics(client_name).await.contains(filter)
+            || self.alert_topics(client_name).await.contains(filter)
+            || self.webhook_topics(client_name).await.contains(filter)
+            || self.rule_topics(client_name).await.contains(filter);
+        if still_needed {
+            log::info!("Client {client_name} keeps {filter}, it is still needed");
+            return Ok(false);
+        }
+        if self.get_client_actor_addr(client_name).await.is_some() {
+            let _ = self.unsubscribe(client_name, filter).await;
+        }
+        Ok(true)
+    }
+    pub async fn connected(&self, client_name: &String) -> Result<bool, MqttError> {
         let clients = self.clients.lock().await;
-        let client = clients.get(client_name).unwrap();
-        !client.handle.is_finished()
+        let client = clients
+            .get(client_name)
+            .ok_or_else(|| MqttError::UnknownClient(client_name.clone()))?;
+        Ok(!client.handle.is_finished())
     }
     pub async fn publish(
         &self,
         client_name: &String,
         topic: String,
         payload: Vec<u8>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<(), MqttError> {
+        self.publish_with(client_name, topic, payload, QoS::AtLeastOnce, false)
+            .await
+    }
+    pub async fn publish_with(
+        &self,
+        client_name: &String,
+        topic: String,
+        payload: Vec<u8>,
+        qos: QoS,
+        retain: bool,
+    ) -> Result<(), MqttError> {
         log::info!("Publishing to client: {} to topic: {}", client_name, topic);
         let mut clients = self.clients.lock().await;
-        let client = clients.get_mut(client_name).unwrap();
-        client
-            .client
-            .publish(topic, QoS::AtLeastOnce, false, payload)
-            .await?;
+        let client = clients
+            .get_mut(client_name)
+            .ok_or_else(|| MqttError::UnknownClient(client_name.clone()))?;
+        client.client.publish(topic, qos, retain, payload).await?;
         Ok(())
     }
 }
@@ -0,0 +1,151 @@
+use std::time::Duration;
+
+use rumqttc::{Publish, QoS};
+
+use super::MqttClientManager;
+use crate::models::recording::{RecordedMessage, Recording};
+
+/// Messages read from redis at once during a replay.
+const REPLAY_BATCH: usize = 500;
+
+/// Pace of a replay relative to the original recording.
+#[derive(Debug, Clone, Copy)]
+pub enum ReplaySpeed {
+    Original,
+    /// original timing divided by the factor
+    Scaled(f64),
+    /// no delay between messages
+    Fast,
+}
+
+/// Topic rewrite applied to replayed messages, like on bridges.
+#[derive(Debug, Clone, Default)]
+pub struct TopicRewrite {
+    pub strip_prefix: Option<String>,
+    pub add_prefix: Option<String>,
+}
+
+impl TopicRewrite {
+    fn apply(&self, topic: &str) -> String {
+        let topic = match &self.strip_prefix {
+            Some(prefix) => topic.strip_prefix(prefix.as_str()).unwrap_or(topic),
+            None => topic,
+        };
+        match &self.add_prefix {
+            Some(prefix) => format!("{prefix}{topic}"),
+            None => topic.to_string(),
+        }
+    }
+}
+
+impl MqttClientManager {
+    /// Refresh the cached active recordings from redis.
+    pub async fn reload_recordings(&self) {
+        let Some(pool) = &self.pool else {
+            return;
+        };
+        let now = chrono::Utc::now().timestamp_millis();
+        match Recording::list(pool).await {
+            Ok(recordings) => {
+                *self.recordings.write().await = recordings
+                    .into_iter()
+                    .filter(|recording| recording.is_active(now))
+                    .collect();
+            }
+            Err(e) => log::error!("Cannot reload recordings, keeping the cached ones: {e}"),
+        }
+    }
+
+    /// Topic filters a client has to subscribe to in order to feed its recordings.
+    pub(super) async fn recording_topics(&self, client_name: &str) -> Vec<String> {
+        self.recordings
+            .read()
+            .await
+            .iter()
+            .filter(|recording| recording.client == client_name)
+            .map(|recording| recording.filter.clone())
+            .collect()
+    }
+
+    /// Store the incoming message in every active recording it matches.
+    pub(super) async fn apply_recordings(&self, client_name: &str, publish: &Publish) {
+        let now = chrono::Utc::now().timestamp_millis();
+        let matched: Vec<String> = self
+            .recordings
+            .read()
+            .await
+            .iter()
+            .filter(|recording| {
+                recording.is_active(now) && recording.matches(client_name, &publish.topic)
+            })
+            .map(|recording| recording.name.clone())
+            .collect();
+        let Some(pool) = self.pool.clone() else {
+            return;
+        };
+        if matched.is_empty() {
+            return;
+        }
+        let message = RecordedMessage {
+            timestamp: now,
+            topic: publish.topic.clone(),
+            payload: publish.payload.to_vec(),
+            qos: publish.qos as u8,
+            retain: publish.retain,
+        };
+        let max_len = self.retention.recording;
+        self.spawn_tracked(async move {
+            for recording in matched {
+                if let Err(e) = Recording::record(&pool, &recording, &message, max_len).await {
+                    log::error!("Cannot record message into {}: {}", recording, e);
+                }
+            }
+        });
+    }
+
+    /// Publish all messages of a recording on `target`, returns the number of messages sent.
+    pub async fn replay(
+        &self,
+        recording: &str,
+        target: &String,
+        speed: ReplaySpeed,
+        rewrite: &TopicRewrite,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let pool = self.pool.as_ref().ok_or("recordings need redis")?;
+        let start = tokio::time::Instant::now();
+        let mut first: Option<i64> = None;
+        let mut after: Option<String> = None;
+        let mut sent = 0;
+        loop {
+            let (messages, last) =
+                Recording::messages(pool, recording, after.as_deref(), REPLAY_BATCH).await?;
+            if messages.is_empty() {
+                return Ok(sent);
+            }
+            for message in messages {
+                let offset = (message.timestamp - *first.get_or_insert(message.timestamp)).max(0);
+                let delay = match speed {
+                    ReplaySpeed::Original => Some(Duration::from_millis(offset as u64)),
+                    ReplaySpeed::Scaled(factor) => {
+                        Some(Duration::from_secs_f64(offset as f64 / 1000.0 / factor))
+                    }
+                    ReplaySpeed::Fast => None,
+                };
+                if let Some(delay) = delay {
+                    tokio::time::sleep_until(start + delay).await;
+                }
+                let qos = rumqttc::qos(message.qos).unwrap_or(QoS::AtLeastOnce);
+                self.publish_with(
+                    target,
+                    rewrite.apply(&message.topic),
+                    message.payload,
+                    qos,
+                    message.retain,
+                )
+                .await?;
+                sent += 1;
+            }
+            after = last;
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    error::Error,
+    time::{Duration, Instant},
+};
+
+use rumqttc::QoS;
+
+use super::MqttClientManager;
+use crate::settings::MqttSettings;
+
+/// How a response is matched to its request.
+#[derive(Debug, Clone)]
+pub enum Correlation {
+    /// MQTT 5 correlation data and response topic properties
+    CorrelationData,
+    /// id written into and read back from a field of a JSON payload
+    JsonField(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct RpcRequest {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub response_topic: String,
+    pub correlation: Correlation,
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct RpcResponse {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub correlation_id: String,
+    pub latency: Duration,
+}
+
+impl MqttClientManager {
+    /// Publish a request and wait for the matching response.
+    ///
+    /// Requests run on a short lived connection of their own, so the response
+    /// subscription does not show up on the client and MQTT 5 properties are
+    /// available even though the client itself speaks MQTT 3.1.1.
+    pub async fn request(
+        &self,
+        client_name: &String,
+        request: RpcRequest,
+    ) -> Result<RpcResponse, Box<dyn Error>> {
+        let url = {
+            let clients = self.clients.lock().await;
+            let client = clients
+                .get(client_name)
+                .ok_or_else(|| format!("client {client_name} is not registered"))?;
+            client.url.clone()
+        };
+        let correlation_id = uuid::Uuid::new_v4().to_string();
+        let url = rpc_url(&url, &format!("{client_name}-rpc-{}", &correlation_id[..8]))?;
+        log::info!(
+            "Request of client {} to topic {}, waiting on {}",
+            client_name,
+            request.topic,
+            request.response_topic
+        );
+        let timeout = request.timeout;
+        let response = match request.correlation.clone() {
+            Correlation::CorrelationData => {
+                let v5 = request_v5(&self.settings, url, request, correlation_id);
+                tokio::time::timeout(timeout, v5).await
+            }
+            Correlation::JsonField(field) => {
+                let v4 = request_v4(&self.settings, url, request, field, correlation_id);
+                tokio::time::timeout(timeout, v4).await
+            }
+        };
+        response.map_err(|_| format!("no response within {} seconds", timeout.as_secs()))?
+    }
+}
+
+/// Connection url of the client with its client id replaced.
+fn rpc_url(url: &str, client_id: &str) -> Result<String, Box<dyn Error>> {
+    let mut url = url::Url::parse(url)?;
+    let query: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| key != "client_id")
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(query)
+        .append_pair("client_id", client_id);
+    Ok(url.to_string())
+}
+
+/// Insert the correlation id into a JSON object payload.
+fn with_json_field(payload: &[u8], field: &str, id: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut json: serde_json::Value = serde_json::from_slice(payload)
+        .map_err(|e| format!("payload has to be a JSON object: {e}"))?;
+    let object = json
+        .as_object_mut()
+        .ok_or("payload has to be a JSON object")?;
+    object.insert(field.to_string(), serde_json::Value::String(id.to_string()));
+    Ok(serde_json::to_vec(&json)?)
+}
+
+fn json_field_matches(payload: &[u8], field: &str, id: &str) -> bool {
+    serde_json::from_slice::<serde_json::Value>(payload)
+        .ok()
+        .and_then(|json| json.get(field).cloned())
+        .is_some_and(|value| value.as_str() == Some(id))
+}
+
+async fn request_v4(
+    settings: &MqttSettings,
+    url: String,
+    request: RpcRequest,
+    field: String,
+    correlation_id: String,
+) -> Result<RpcResponse, Box<dyn Error>> {
+    use rumqttc::{AsyncClient, Event, MqttOptions, Packet};
+
+    let payload = with_json_field(&request.payload, &field, &correlation_id)?;
+    let mut options = MqttOptions::parse_url(&url)?;
+    settings.apply(&url, &mut options);
+    let (client, mut eventloop) = AsyncClient::new(options, settings.channel_capacity);
+    client
+        .subscribe(&request.response_topic, QoS::AtLeastOnce)
+        .await?;
+    let mut sent: Option<Instant> = None;
+    loop {
+        match eventloop.poll().await? {
+            Event::Incoming(Packet::SubAck(_)) if sent.is_none() => {
+                client
+                    .publish(&request.topic, QoS::AtLeastOnce, false, payload.clone())
+                    .await?;
+                sent = Some(Instant::now());
+            }
+            Event::Incoming(Packet::Publish(publish)) => {
+                let Some(sent) = sent else { continue };
+                if json_field_matches(&publish.payload, &field, &correlation_id) {
+                    let _ = client.try_disconnect();
+                    return Ok(RpcResponse {
+                        topic: publish.topic,
+                        payload: publish.payload.to_vec(),
+                        correlation_id,
+                        latency: sent.elapsed(),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn request_v5(
+    settings: &MqttSettings,
+    url: String,
+    request: RpcRequest,
+    correlation_id: String,
+) -> Result<RpcResponse, Box<dyn Error>> {
+    use rumqttc::v5::{
+        mqttbytes::{v5::Packet, v5::PublishProperties, QoS},
+        AsyncClient, Event, MqttOptions,
+    };
+
+    let mut options = MqttOptions::parse_url(&url)?;
+    settings.apply_v5(&url, &mut options);
+    let (client, mut eventloop) = AsyncClient::new(options, settings.channel_capacity);
+    client
+        .subscribe(request.response_topic.clone(), QoS::AtLeastOnce)
+        .await?;
+    let properties = PublishProperties {
+        response_topic: Some(request.response_topic.clone()),
+        correlation_data: Some(correlation_id.clone().into()),
+        ..Default::default()
+    };
+    let mut sent: Option<Instant> = None;
+    loop {
+        match eventloop.poll().await? {
+            Event::Incoming(Packet::SubAck(_)) if sent.is_none() => {
+                client
+                    .publish_with_properties(
+                        request.topic.clone(),
+                        QoS::AtLeastOnce,
+                        false,
+                        request.payload.clone(),
+                        properties.clone(),
+                    )
+                    .await?;
+                sent = Some(Instant::now());
+            }
+            Event::Incoming(Packet::Publish(publish)) => {
+                let Some(sent) = sent else { continue };
+                let matches = publish
+                    .properties
+                    .as_ref()
+                    .and_then(|properties| properties.correlation_data.as_ref())
+                    .is_some_and(|data| data.as_ref() == correlation_id.as_bytes());
+                if matches {
+                    let _ = client.try_disconnect();
+                    return Ok(RpcResponse {
+                        topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+                        payload: publish.payload.to_vec(),
+                        correlation_id,
+                        latency: sent.elapsed(),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+}
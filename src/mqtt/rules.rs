@@ -0,0 +1,133 @@
+use rumqttc::Publish;
+
+use super::MqttClientManager;
+use crate::models::{
+    alert::Alert,
+    history::HistoryEntry,
+    rule::{Rule, RuleAction},
+};
+
+impl MqttClientManager {
+    /// Refresh the cached rules of a client from redis.
+    pub async fn reload_rules(&self, client_name: &str) {
+        let Some(pool) = &self.pool else {
+            return;
+        };
+        match Rule::list(pool, client_name).await {
+            Ok(rules) => {
+                self.rules
+                    .write()
+                    .await
+                    .insert(client_name.to_string(), rules);
+            }
+            Err(e) => {
+                log::error!("Cannot reload rules of {client_name}, keeping the cached ones: {e}")
+            }
+        }
+    }
+
+    /// Topic filters a client has to subscribe to in order to feed its rules.
+    pub(super) async fn rule_topics(&self, client_name: &str) -> Vec<String> {
+        self.rules
+            .read()
+            .await
+            .get(client_name)
+            .map(|rules| rules.iter().map(|rule| rule.topic.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Evaluate the rules of `client_name` against an incoming message.
+    ///
+    /// Matching happens inline, the actions are run on a separate task so
+    /// that a republish onto the same client cannot stall its event loop.
+    pub(super) async fn apply_rules(&self, client_name: &str, publish: &Publish) {
+        let matched: Vec<Rule> = match self.rules.read().await.get(client_name) {
+            Some(rules) => rules
+                .iter()
+                .filter(|rule| rule.matches(&publish.topic, &publish.payload))
+                .cloned()
+                .collect(),
+            None => return,
+        };
+        if matched.is_empty() {
+            return;
+        }
+        let manager = self.clone();
+        let client_name = client_name.to_string();
+        let publish = publish.clone();
+        self.spawn_tracked(async move {
+            for rule in matched {
+                log::info!("Rule {} of client {} matched", rule.name, client_name);
+                for action in &rule.actions {
+                    manager
+                        .run_action(&client_name, &rule, action, &publish)
+                        .await;
+                }
+            }
+        });
+    }
+
+    async fn run_action(
+        &self,
+        client_name: &str,
+        rule: &Rule,
+        action: &RuleAction,
+        publish: &Publish,
+    ) {
+        let Some(pool) = &self.pool else {
+            return;
+        };
+        match action {
+            RuleAction::Republish { client, topic } => {
+                if !self.clients.lock().await.contains_key(client) {
+                    log::error!("Rule {}: unknown client {} to republish", rule.name, client);
+                    return;
+                }
+                let topic = topic.replace("{topic}", &publish.topic);
+                if let Err(e) = self
+                    .publish_with(
+                        client,
+                        topic,
+                        publish.payload.to_vec(),
+                        publish.qos,
+                        publish.retain,
+                    )
+                    .await
+                {
+                    log::error!("Rule {}: republish failed: {}", rule.name, e);
+                }
+            }
+            RuleAction::History { tag } => {
+                let entry = HistoryEntry {
+                    timestamp: chrono::Utc::now().timestamp_millis(),
+                    topic: publish.topic.clone(),
+                    payload: publish.payload.to_vec(),
+                    qos: publish.qos as u8,
+                    retain: publish.retain,
+                    tag: Some(tag.clone()),
+                };
+                if let Err(e) = entry.insert(&self.repo, client_name).await {
+                    log::error!("Rule {}: cannot store history entry: {}", rule.name, e);
+                }
+            }
+            RuleAction::Alert { message } => {
+                log::warn!(
+                    "Rule {} of client {} raised alert on {}: {}",
+                    rule.name,
+                    client_name,
+                    publish.topic,
+                    message
+                );
+                let alert = Alert {
+                    timestamp: chrono::Utc::now().timestamp_millis(),
+                    rule: rule.name.clone(),
+                    topic: publish.topic.clone(),
+                    message: message.clone(),
+                };
+                if let Err(e) = alert.insert(pool, client_name, self.retention.alerts).await {
+                    log::error!("Rule {}: cannot store alert: {}", rule.name, e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use base64::Engine;
+use rumqttc::Publish;
+
+use super::MqttClientManager;
+use crate::models::webhook::{Webhook, WebhookDelivery, WebhookEnvelope};
+
+impl MqttClientManager {
+    /// Refresh the cached webhooks from redis.
+    pub async fn reload_webhooks(&self) {
+        let Some(pool) = &self.pool else {
+            return;
+        };
+        match Webhook::list(pool).await {
+            Ok(webhooks) => *self.webhooks.write().await = webhooks,
+            Err(e) => log::error!("Cannot reload webhooks, keeping the cached ones: {e}"),
+        }
+    }
+
+    /// Topic filters a client has to subscribe to in order to feed its webhooks.
+    pub(super) async fn webhook_topics(&self, client_name: &str) -> Vec<String> {
+        self.webhooks
+            .read()
+            .await
+            .iter()
+            .filter(|webhook| webhook.client == client_name)
+            .map(|webhook| webhook.topic.clone())
+            .collect()
+    }
+
+    /// Queue a delivery for every webhook matching the incoming message.
+    pub(super) async fn apply_webhooks(&self, client_name: &str, publish: &Publish) {
+        let matched: Vec<String> = self
+            .webhooks
+            .read()
+            .await
+            .iter()
+            .filter(|webhook| webhook.matches(client_name, &publish.topic))
+            .map(|webhook| webhook.name.clone())
+            .collect();
+        let Some(pool) = self.pool.clone() else {
+            return;
+        };
+        if matched.is_empty() {
+            return;
+        }
+        let now = chrono::Utc::now();
+        let (payload, encoding) = match std::str::from_utf8(&publish.payload) {
+            Ok(payload) => (payload.to_string(), "utf8"),
+            Err(_) => (
+                base64::engine::general_purpose::STANDARD.encode(&publish.payload),
+                "base64",
+            ),
+        };
+        let envelope = WebhookEnvelope {
+            client: client_name.to_string(),
+            topic: publish.topic.clone(),
+            payload,
+            encoding: encoding.into(),
+            qos: publish.qos as u8,
+            retain: publish.retain,
+            timestamp: now.to_rfc3339(),
+        };
+        self.spawn_tracked(async move {
+            for webhook in matched {
+                let delivery = WebhookDelivery {
+                    id: format!("{:032x}", rand::random::<u128>()),
+                    webhook,
+                    attempt: 1,
+                    envelope: envelope.clone(),
+                };
+                if let Err(e) = delivery.enqueue(&pool, now.timestamp_millis()).await {
+                    log::error!(
+                        "Cannot queue delivery for webhook {}: {}",
+                        delivery.webhook,
+                        e
+                    );
+                }
+            }
+        });
+    }
+}
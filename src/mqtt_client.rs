@@ -1,13 +1,37 @@
 use crate::{
-    middleware::{fullpage_render::FullPageRender, login_guard::LoginGuard},
-    models::mqtt_client::MqttClient,
-    mqtt::MqttClientManager,
+    audit::{AuditLog, Auditor},
+    error::AppError,
+    history,
+    middleware::htmx::form_error,
+    middleware::{
+        fullpage_render::FullPageRender, login_guard::LoginGuard, user_session::UserSession,
+    },
+    models::{
+        alert::Alert,
+        alert_definition::AlertDefinition,
+        audit::{self, AuditAction},
+        bridge::Bridge,
+        history::HistoryEntry,
+        mqtt_client::MqttClient,
+        publish_template::PublishTemplate,
+        recording::Recording,
+        rule::Rule,
+        schedule::Schedule,
+        webhook::Webhook,
+    },
+    mqtt::{Correlation, MqttClientManager, RpcRequest},
     mqtt_clients::MqttClientListTemplate,
+    publish_templates::{self, PublishResultTemplate},
+    recordings,
+    rules::{self, MqttClientRulesTemplate},
+    schedules,
+    storage::StorageError,
     subscribe,
 };
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::{web, HttpRequest, HttpResponse};
 use askama::Template;
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct NewMqttClientForm {
@@ -24,10 +48,20 @@ impl From<NewMqttClientForm> for MqttClient {
     }
 }
 
-pub fn client_scoped(cfg: &mut web::ServiceConfig) {
+/// Routes of a single client, rules, schedules and publish templates are
+/// kept in redis and only there with `extras`.
+pub fn client_scoped(cfg: &mut web::ServiceConfig, extras: bool) {
     cfg.service(
         web::scope("/mqtt_client")
             .configure(subscribe::subscribe_scoped)
+            .configure(|cfg| {
+                if extras {
+                    rules::rules_scoped(cfg);
+                    schedules::schedules_scoped(cfg);
+                    publish_templates::templates_scoped(cfg);
+                }
+            })
+            .configure(history::history_scoped)
             .service(
                 web::resource("/{id}")
                     .route(web::get().to(get).wrap(FullPageRender))
@@ -40,30 +74,84 @@ pub fn client_scoped(cfg: &mut web::ServiceConfig) {
 
 async fn post(
     _: LoginGuard,
-    db: web::Data<crate::DbPool>,
+    repo: web::Data<crate::Repo>,
     mqtt: web::Data<MqttClientManager>,
+    auditor: Auditor,
     form: web::Form<NewMqttClientForm>,
-) -> impl Responder {
+) -> Result<HttpResponse, AppError> {
     let client: MqttClient = form.into_inner().into();
-    client.insert(&db).await;
-    let _ = mqtt.register_client(client.name, client.url, vec![]).await;
-    let mqtt_clients = MqttClient::list(&db).await;
+    let previous = MqttClient::get_by_name(&repo, &client.name).await?;
+    client.insert(&repo).await?;
+    let diff = audit::diff(previous.as_ref(), Some(&client));
+    auditor
+        .record(AuditAction::ClientCreate, &client.name, diff)
+        .await;
+    if let Err(e) = mqtt
+        .register_client(client.name.clone(), client.url, vec![])
+        .await
+    {
+        log::warn!("Cannot connect client {}: {}", client.name, e);
+    }
+    let mqtt_clients = MqttClient::list(&repo).await?;
     let template = MqttClientListTemplate { mqtt_clients };
-    HttpResponse::Ok().body(template.render().unwrap())
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+/// Delete a client together with the bridges, webhooks, alerts and recordings using it.
+pub async fn remove_client(
+    repo: &crate::Repo,
+    db: Option<&crate::DbPool>,
+    mqtt: &MqttClientManager,
+    name: &String,
+) -> Result<bool, StorageError> {
+    if !MqttClient::delete(repo, name).await? {
+        return Ok(false);
+    }
+    mqtt.unregister_client(name).await;
+    let Some(db) = db else {
+        return Ok(true);
+    };
+    for bridge in Bridge::list(db).await? {
+        if bridge.source == *name || bridge.target == *name {
+            Bridge::delete(db, &bridge.name).await?;
+        }
+    }
+    mqtt.reload_bridges().await;
+    for webhook in Webhook::list(db).await? {
+        if webhook.client == *name {
+            Webhook::delete(db, &webhook.name).await?;
+        }
+    }
+    mqtt.reload_webhooks().await;
+    for alert in AlertDefinition::list(db).await? {
+        if alert.client == *name {
+            AlertDefinition::delete(db, &alert.name).await?;
+        }
+    }
+    mqtt.reload_alerts().await;
+    for recording in Recording::list(db).await? {
+        if recording.client == *name {
+            recordings::stop(db, mqtt, &recording.name).await?;
+        }
+    }
+    Ok(true)
 }
 
 async fn delete(
     _: LoginGuard,
-    db: web::Data<crate::DbPool>,
+    repo: web::Data<crate::Repo>,
+    db: Option<web::Data<crate::DbPool>>,
     mqtt: web::Data<MqttClientManager>,
+    auditor: Auditor,
     name: web::Path<String>,
-) -> impl Responder {
-    let deleted = MqttClient::delete(&db, &name).await;
-    if deleted {
-        let _ = mqtt.unregister_client(&name).await;
-        HttpResponse::Ok().body("")
+) -> Result<HttpResponse, AppError> {
+    let previous = MqttClient::get_by_name(&repo, &name).await?;
+    if remove_client(&repo, db.as_ref().map(|db| db.get_ref()), &mqtt, &name).await? {
+        let diff = audit::diff(previous.as_ref(), None);
+        auditor.record(AuditAction::ClientDelete, &name, diff).await;
+        Ok(HttpResponse::Ok().body(""))
     } else {
-        HttpResponse::NotFound().body("MqttClient not found")
+        Err(AppError::NotFound("MQTT client".into()))
     }
 }
 
@@ -74,45 +162,173 @@ struct MqttClientTemplate {
     topics: Vec<String>,
     uri: String,
     connected: bool,
+    rules: Vec<Rule>,
+    clients: Vec<String>,
+    alerts: Vec<Alert>,
+    schedules: Vec<Schedule>,
+    user: String,
+    templates: Vec<PublishTemplate>,
+    history: Vec<HistoryEntry>,
+    /// rules, schedules and templates are only stored in redis
+    extras: bool,
 }
 
 //#[get("/{id}")]
 async fn get(
     _: LoginGuard,
-    db: web::Data<crate::DbPool>,
+    usession: UserSession,
+    repo: web::Data<crate::Repo>,
+    db: Option<web::Data<crate::DbPool>>,
     mqtt: web::Data<MqttClientManager>,
     name: web::Path<String>,
-) -> impl Responder {
-    let db_client = MqttClient::get_by_name(&db, &name).await;
-    let topics = MqttClient::topics(&db, &name).await;
-    if let Some(db_client) = db_client {
-        let template = MqttClientTemplate {
-            name: db_client.name.clone(),
-            uri: db_client.url.clone(),
-            topics,
-            connected: mqtt.connected(&db_client.name).await,
-        };
-        HttpResponse::Ok().body(template.render().unwrap())
-    } else {
-        HttpResponse::Ok().body("Client not found")
+) -> Result<HttpResponse, AppError> {
+    let Some(db_client) = MqttClient::get_by_name(&repo, &name).await? else {
+        return Err(AppError::NotFound("MQTT client".into()));
+    };
+    let topics = MqttClient::topics(&repo, &name).await?;
+    let user = usession.username.unwrap_or_default();
+    let mut template = MqttClientTemplate {
+        name: db_client.name.clone(),
+        uri: db_client.url.clone(),
+        topics,
+        connected: mqtt.connected(&db_client.name).await.unwrap_or(false),
+        rules: Vec::new(),
+        clients: Vec::new(),
+        alerts: Vec::new(),
+        schedules: Vec::new(),
+        templates: Vec::new(),
+        user,
+        history: HistoryEntry::latest(&repo, &name, 50).await?,
+        extras: db.is_some(),
+    };
+    if let Some(db) = db {
+        let MqttClientRulesTemplate {
+            rules,
+            clients,
+            alerts,
+            ..
+        } = MqttClientRulesTemplate::load(&db, &repo, db_client.name.clone()).await?;
+        template.rules = rules;
+        template.clients = clients;
+        template.alerts = alerts;
+        template.schedules = Schedule::list(&db, &db_client.name).await?;
+        template.templates =
+            publish_templates::visible_templates(&db, &db_client.name, &template.user).await?;
     }
+    Ok(HttpResponse::Ok().body(template.render()?))
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct MqttClientPublishForm {
     topic: String,
     payload: String,
+    rpc: Option<String>,
+    response_topic: Option<String>,
+    correlation: Option<String>,
+    correlation_field: Option<String>,
+    timeout: Option<u64>,
+}
+
+impl TryFrom<MqttClientPublishForm> for RpcRequest {
+    type Error = String;
+    fn try_from(form: MqttClientPublishForm) -> Result<Self, Self::Error> {
+        let response_topic = form.response_topic.unwrap_or_default();
+        if response_topic.is_empty() || !rumqttc::valid_topic(&response_topic) {
+            return Err(format!(
+                "'{}' is not a valid response topic.",
+                response_topic
+            ));
+        }
+        let correlation = match form.correlation.as_deref() {
+            Some("json") => {
+                let field = form.correlation_field.unwrap_or_default();
+                if field.trim().is_empty() {
+                    return Err("A JSON field for the correlation id is required.".into());
+                }
+                Correlation::JsonField(field)
+            }
+            _ => Correlation::CorrelationData,
+        };
+        let timeout = match form.timeout {
+            Some(seconds) if (1..=300).contains(&seconds) => seconds,
+            _ => return Err("The timeout has to be between 1 and 300 seconds.".into()),
+        };
+        Ok(RpcRequest {
+            topic: form.topic,
+            payload: form.payload.into_bytes(),
+            response_topic,
+            correlation,
+            timeout: Duration::from_secs(timeout),
+        })
+    }
+}
+
+#[derive(Template)]
+#[template(path = "rpc_result.html")]
+struct RpcResultTemplate {
+    topic: String,
+    payload: String,
+    correlation_id: String,
+    latency_ms: u128,
 }
 
 async fn post_publish(
     _: LoginGuard,
+    req: HttpRequest,
     mqtt: web::Data<MqttClientManager>,
+    auditor: Auditor,
     form: web::Form<MqttClientPublishForm>,
     name: web::Path<String>,
-) -> impl Responder {
+) -> Result<HttpResponse, AppError> {
     let form = form.into_inner();
-    let _ = mqtt
-        .publish(&name, form.topic, Vec::from(form.payload.as_bytes()))
+    if !rumqttc::valid_topic(&form.topic) {
+        let message = format!("'{}' is not a valid topic.", form.topic);
+        return Ok(form_error(&req, "#responseBox", &message));
+    }
+    let mut details = AuditLog::publish_details(&form.topic, &form.payload, form.rpc.is_some());
+    if form.rpc.is_none() {
+        mqtt.publish(
+            &name,
+            form.topic.clone(),
+            Vec::from(form.payload.as_bytes()),
+        )
+        .await?;
+        auditor
+            .record(AuditAction::Publish, &name, Some(details))
+            .await;
+        let result = PublishResultTemplate {
+            topic: form.topic,
+            payload: form.payload,
+        };
+        return Ok(HttpResponse::Ok().body(result.render()?));
+    }
+    let request: RpcRequest = match form.try_into() {
+        Ok(request) => request,
+        Err(e) => return Ok(form_error(&req, "#responseBox", &e)),
+    };
+    let response = mqtt.request(&name, request).await;
+    // a request which timed out was published nevertheless
+    details["outcome"] = match &response {
+        Ok(_) => "answered".into(),
+        Err(e) => e.to_string().into(),
+    };
+    auditor
+        .record(AuditAction::Publish, &name, Some(details))
         .await;
-    HttpResponse::Ok().body("okay")
+    match response {
+        Ok(response) => {
+            let result = RpcResultTemplate {
+                topic: response.topic,
+                payload: String::from_utf8_lossy(&response.payload).into_owned(),
+                correlation_id: response.correlation_id,
+                latency_ms: response.latency.as_millis(),
+            };
+            Ok(HttpResponse::Ok().body(result.render()?))
+        }
+        Err(e) => Ok(form_error(
+            &req,
+            "#responseBox",
+            &format!("Request failed: {e}"),
+        )),
+    }
 }
@@ -1,7 +1,8 @@
-use actix_web::{get, web, HttpResponse, Responder};
+use actix_web::{get, web, HttpResponse};
 use askama::Template;
 
 use crate::{
+    error::AppError,
     middleware::{fullpage_render::FullPageRender, login_guard::LoginGuard},
     models::mqtt_client::MqttClient,
 };
@@ -21,8 +22,8 @@ pub struct MqttClientListTemplate {
 }
 
 #[get("/")]
-async fn get(_: LoginGuard, db: web::Data<crate::DbPool>) -> impl Responder {
-    let mqtt_clients = MqttClient::list(&db).await;
+async fn get(_: LoginGuard, repo: web::Data<crate::Repo>) -> Result<HttpResponse, AppError> {
+    let mqtt_clients = MqttClient::list(&repo).await?;
     let template = MqttClientListTemplate { mqtt_clients };
-    HttpResponse::Ok().body(template.render().unwrap())
+    Ok(HttpResponse::Ok().body(template.render()?))
 }
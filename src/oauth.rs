@@ -1,25 +1,65 @@
-use core::panic;
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
 
 use actix_session::Session;
 use actix_web::{
     web::{self, Path},
-    HttpRequest, HttpResponse, Responder,
+    HttpRequest, HttpResponse,
 };
+use base64::Engine;
 use openidconnect::{
     core::{
-        CoreClient, CoreProviderMetadata, CoreResponseType, CoreTokenResponse, CoreUserInfoClaims,
+        CoreClient, CoreGenderClaim, CoreIdToken, CoreIdTokenClaims, CoreResponseType,
+        CoreTokenResponse,
     },
     reqwest::async_http_client,
-    AccessToken, AuthenticationFlow, AuthorizationCode, ClientId, ClientSecret, CsrfToken,
-    IssuerUrl, Nonce, OAuth2TokenResponse, RedirectUrl, Scope, TokenResponse,
+    AccessToken, AccessTokenHash, AuthenticationFlow, AuthorizationCode, ClientId, ClientSecret,
+    CsrfToken, EndSessionUrl, IssuerUrl, LogoutRequest, Nonce, OAuth2TokenResponse,
+    PkceCodeChallenge, PkceCodeVerifier, PostLogoutRedirectUrl, ProviderMetadataWithLogout,
+    RedirectUrl, RefreshToken, RequestTokenError, Scope, SubjectIdentifier, TokenResponse,
+    UserInfoClaims,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit::AuditLog,
+    config_sync::RoleConfig,
+    error::AppError,
+    login_throttle::LoginThrottle,
+    models::user::{User, UserSource},
+    settings::Settings,
 };
-use serde::Deserialize;
 
-use crate::models::user::User;
+/// Milliseconds a login may take at the provider before the callback is refused.
+const PENDING_AUTH_TIMEOUT: i64 = 10 * 60 * 1000;
+/// Milliseconds until an oauth session is re-validated if the provider does
+/// not say when the access token expires, or could not be reached.
+const REVALIDATE_FALLBACK: i64 = 5 * 60 * 1000;
+
+/// The authorization request a callback has to answer, kept in the session
+/// between the redirect to the provider and the callback.
+#[derive(Serialize, Deserialize, Debug)]
+struct PendingAuth {
+    provider: String,
+    state: String,
+    nonce: String,
+    pkce_verifier: String,
+    /// unix timestamp in milliseconds
+    created: i64,
+}
 
 pub type OauthConfigs = web::Data<HashMap<String, OauthConfig>>;
 
+/// Claims beyond the standard ones, e.g. the groups of the user.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExtraClaims(HashMap<String, serde_json::Value>);
+
+impl openidconnect::AdditionalClaims for ExtraClaims {}
+
+type UserInfo = UserInfoClaims<ExtraClaims, CoreGenderClaim>;
+
 #[derive(Deserialize, Debug)]
 pub struct OauthConfig {
     pub ui_name: String,
@@ -29,9 +69,34 @@ pub struct OauthConfig {
     pub scope: String,
     #[serde(default)]
     pub username_field: UsernameField,
+    /// email domains which may log in, any if empty
+    #[serde(default)]
+    pub allowed_domains: Vec<String>,
+    /// groups of which the user needs at least one, any user if empty
+    #[serde(default)]
+    pub required_groups: Vec<String>,
+    /// claim holding the groups, nested claims are separated by dots
+    #[serde(default = "default_groups_claim")]
+    pub groups_claim: String,
+    /// the first matching mapping decides the role, it is synced on every login
+    #[serde(default)]
+    pub role_mappings: Vec<RoleMapping>,
+    /// role of users no mapping matches
+    #[serde(default = "default_role")]
+    pub default_role: RoleConfig,
+    #[serde(default)]
+    pub provisioning: Provisioning,
     #[serde(skip)]
     pub name: Option<String>,
     #[serde(skip)]
+    pub redirect_uri: String,
+    /// where the provider sends the browser after logging out there
+    #[serde(skip)]
+    pub post_logout_redirect_uri: String,
+    /// set if the provider supports RP-initiated logout
+    #[serde(skip)]
+    pub end_session_endpoint: Option<EndSessionUrl>,
+    #[serde(skip)]
     pub client: Option<CoreClient>,
 }
 
@@ -42,49 +107,159 @@ pub enum UsernameField {
     Email,
 }
 
+fn default_groups_claim() -> String {
+    "groups".into()
+}
+
+fn default_role() -> RoleConfig {
+    RoleConfig::User
+}
+
+/// A claim value granting a role, e.g. `groups` containing `mqtt-admins`.
+#[derive(Deserialize, Debug)]
+pub struct RoleMapping {
+    /// nested claims are separated by dots
+    pub claim: String,
+    pub value: String,
+    pub role: RoleConfig,
+}
+
+/// Who gets an account on the first login.
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Provisioning {
+    /// everybody passing the domain and group rules
+    #[default]
+    JustInTime,
+    /// only users created beforehand with the source of the provider,
+    /// e.g. `oauth:<provider>`
+    InviteOnly,
+}
+
+/// Values of the claim at the dotted `path`, arrays give all their entries.
+fn claim_values(claims: &serde_json::Value, path: &str) -> Vec<String> {
+    let mut value = claims;
+    for key in path.split('.') {
+        match value.get(key) {
+            Some(next) => value = next,
+            None => return Vec::new(),
+        }
+    }
+    let to_string = |value: &serde_json::Value| match value {
+        serde_json::Value::String(s) => Some(s.clone()),
+        serde_json::Value::Bool(_) | serde_json::Value::Number(_) => Some(value.to_string()),
+        _ => None,
+    };
+    match value {
+        serde_json::Value::Array(values) => values.iter().filter_map(to_string).collect(),
+        value => to_string(value).into_iter().collect(),
+    }
+}
+
 impl OauthConfig {
-    pub async fn init(&mut self, name: String, redirect_uri: String) {
+    /// Check the domain and group rules, returns the role of the user or why
+    /// the login is refused.
+    fn admit(&self, claims: &serde_json::Value) -> Result<i32, String> {
+        if !self.allowed_domains.is_empty() {
+            if claims.get("email_verified") != Some(&serde_json::Value::Bool(true)) {
+                return Err("email address is not verified".into());
+            }
+            let domain = claim_values(claims, "email")
+                .first()
+                .and_then(|email| email.rsplit_once('@'))
+                .map(|(_, domain)| domain.to_lowercase());
+            let allowed = domain.as_ref().is_some_and(|domain| {
+                self.allowed_domains
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(domain))
+            });
+            if !allowed {
+                return Err(format!(
+                    "email domain {} is not allowed",
+                    domain.unwrap_or_default()
+                ));
+            }
+        }
+        if !self.required_groups.is_empty() {
+            let groups = claim_values(claims, &self.groups_claim);
+            if !self
+                .required_groups
+                .iter()
+                .any(|group| groups.contains(group))
+            {
+                return Err("not a member of a required group".into());
+            }
+        }
+        let role = self
+            .role_mappings
+            .iter()
+            .find(|mapping| claim_values(claims, &mapping.claim).contains(&mapping.value))
+            .map_or(&self.default_role, |mapping| &mapping.role);
+        Ok(role.role_id())
+    }
+
+    pub async fn init(&mut self, name: String, base_url: &str) -> anyhow::Result<()> {
         log::info!("Initializing Oauth config {name}");
+        let redirect_uri = format!("{base_url}/oauth/{name}/callback");
+        self.post_logout_redirect_uri = format!("{base_url}/login/");
         self.name = Some(name);
         // define OIDC Parameters
-        let issuer = IssuerUrl::new(self.issuer.clone()).unwrap();
+        let issuer = IssuerUrl::new(self.issuer.clone())?;
         let client_id = ClientId::new(self.client_id.clone());
         let client_secret = ClientSecret::new(self.client_secret.clone());
 
         // discover metadata from issuer
-        let metadata = CoreProviderMetadata::discover_async(issuer, async_http_client)
-            .await
-            .unwrap();
+        let metadata =
+            ProviderMetadataWithLogout::discover_async(issuer, async_http_client).await?;
+        self.end_session_endpoint = metadata.additional_metadata().end_session_endpoint.clone();
 
         // create client from metadata
         let client = CoreClient::from_provider_metadata(metadata, client_id, Some(client_secret))
-            .set_redirect_uri(RedirectUrl::new(redirect_uri).unwrap());
+            .set_redirect_uri(RedirectUrl::new(redirect_uri.clone())?);
+        self.redirect_uri = redirect_uri;
         self.client = Some(client);
+        Ok(())
     }
 
-    async fn get_auth_url(&self) -> String {
-        let Some(client) = &self.client else {
-            panic!("OidcClient needs to be initialized before usage");
-        };
-        // get authorize_url
-        let (auth_url, _, _) = client
+    fn client(&self) -> anyhow::Result<&CoreClient> {
+        self.client
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("OidcClient needs to be initialized before usage"))
+    }
+
+    /// Authorization url with a fresh state, nonce and PKCE challenge, together
+    /// with what the callback needs to check the answer.
+    fn get_auth_url(&self, provider: &str) -> anyhow::Result<(String, PendingAuth)> {
+        let client = self.client()?;
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+        let (auth_url, state, nonce) = client
             .authorize_url(
                 AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
                 CsrfToken::new_random,
                 Nonce::new_random,
             )
             .add_scope(Scope::new(self.scope.clone()))
+            .set_pkce_challenge(pkce_challenge)
             .url();
-        auth_url.to_string()
-    }
-    async fn exchange_code_for_token(&self, code: String) -> anyhow::Result<CoreTokenResponse> {
-        // define OIDC Parameters
-        let Some(client) = &self.client else {
-            panic!("OidcClient needs to be initialized before usage");
+        let pending = PendingAuth {
+            provider: provider.to_string(),
+            state: state.secret().clone(),
+            nonce: nonce.secret().clone(),
+            pkce_verifier: pkce_verifier.secret().clone(),
+            created: now(),
         };
+        Ok((auth_url.to_string(), pending))
+    }
+    async fn exchange_code_for_token(
+        &self,
+        code: String,
+        pkce_verifier: String,
+    ) -> anyhow::Result<CoreTokenResponse> {
+        let client = self.client()?;
         // exchange provided code for auth token
         let token = match client
             .exchange_code(AuthorizationCode::new(code))
+            .set_pkce_verifier(PkceCodeVerifier::new(pkce_verifier))
             .request_async(async_http_client)
             .await
         {
@@ -96,18 +271,38 @@ impl OauthConfig {
         };
         Ok(token)
     }
+    /// Check signature, issuer, audience, expiry and nonce of the ID token
+    /// and that it was issued together with the access token.
+    fn verify_id_token(
+        &self,
+        token_response: &CoreTokenResponse,
+        nonce: &Nonce,
+    ) -> anyhow::Result<CoreIdTokenClaims> {
+        let client = self.client()?;
+        let id_token = token_response
+            .id_token()
+            .ok_or_else(|| anyhow::anyhow!("provider did not return an id token"))?;
+        let claims = id_token.claims(&client.id_token_verifier(), nonce)?;
+        if let Some(expected) = claims.access_token_hash() {
+            let actual = AccessTokenHash::from_token(
+                token_response.access_token(),
+                &id_token.signing_alg()?,
+            )?;
+            if actual != *expected {
+                anyhow::bail!("access token does not belong to the id token");
+            }
+        }
+        Ok(claims.clone())
+    }
     async fn fetch_user_info(
         &self,
         access_token: AccessToken,
-    ) -> Result<CoreUserInfoClaims, Box<dyn std::error::Error>> {
-        // define OIDC Parameters
-        let Some(client) = &self.client else {
-            panic!("OidcClient needs to be initialized before usage");
-        };
-        // Fetch user info using the access token
+        subject: SubjectIdentifier,
+    ) -> anyhow::Result<UserInfo> {
+        let client = self.client()?;
+        // Fetch user info using the access token, it has to describe the subject of the id token
         let user_info = client
-            .user_info(access_token, None)
-            .unwrap()
+            .user_info(access_token, Some(subject))?
             .request_async(async_http_client)
             .await?;
 
@@ -115,18 +310,28 @@ impl OauthConfig {
     }
 }
 
-pub async fn get_oauth_configs() -> anyhow::Result<OauthConfigs> {
-    let hostname = std::env::var("WEB_HOSTNAME").unwrap_or("localhost:8080".into());
-    let Some(file) = std::env::var("OAUTH_FILE").ok() else {
-        return Ok(web::Data::new(HashMap::new()));
+/// Read and initialize the oauth providers, together with the names of the
+/// providers whose metadata could not be loaded.
+pub async fn get_oauth_configs(settings: &Settings) -> anyhow::Result<(OauthConfigs, Vec<String>)> {
+    let Some(file) = &settings.oauth_file else {
+        return Ok((web::Data::new(HashMap::new()), Vec::new()));
     };
     let file = std::fs::File::open(file)?;
     let mut configs: HashMap<String, OauthConfig> = serde_yaml::from_reader(file)?;
     for (name, config) in configs.iter_mut() {
-        let redirect_uri = format!("http://{}/oauth/{}/callback", &hostname, &name);
-        config.init(name.clone(), redirect_uri).await;
+        if let Err(e) = config.init(name.clone(), settings.server.base_url()).await {
+            log::error!("Cannot initialize oauth config {name}, disabling it: {e}");
+        }
     }
-    Ok(web::Data::new(configs))
+    // a provider that is down at startup must not keep the others from working
+    let mut failed: Vec<String> = configs
+        .iter()
+        .filter(|(_, config)| config.client.is_none())
+        .map(|(name, _)| name.clone())
+        .collect();
+    failed.sort();
+    configs.retain(|_, config| config.client.is_some());
+    Ok((web::Data::new(configs), failed))
 }
 
 pub fn oauth_login_scoped(cfg: &mut web::ServiceConfig) {
@@ -137,99 +342,756 @@ pub fn oauth_login_scoped(cfg: &mut web::ServiceConfig) {
     );
 }
 
-pub async fn oauth_login(config_name: Path<String>, configs: OauthConfigs) -> impl Responder {
+pub async fn oauth_login(
+    config_name: Path<String>,
+    configs: OauthConfigs,
+    session: Session,
+) -> Result<HttpResponse, AppError> {
     let config_name = config_name.into_inner();
     let Some(config) = configs.get(&config_name) else {
-        return HttpResponse::NotFound().body("cannot find desired oauth configuration");
+        return Err(AppError::NotFound("OAuth configuration".into()));
     };
-    let auth_url = config.get_auth_url().await;
-    HttpResponse::Found()
+    let (auth_url, pending) = config
+        .get_auth_url(&config_name)
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    session
+        .insert("oauth_pending", pending)
+        .map_err(|e| AppError::Internal(format!("cannot store login state: {e}")))?;
+    Ok(HttpResponse::Found()
         .append_header(("Location", auth_url.as_str()))
-        .finish()
+        .finish())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn auth_callback_handler(
-    db: web::Data<crate::DbPool>,
     req: HttpRequest,
+    repo: web::Data<crate::Repo>,
+    throttle: web::Data<LoginThrottle>,
+    audit: web::Data<AuditLog>,
     config_name: Path<String>,
     configs: OauthConfigs,
     params: web::Query<AuthCallbackParams>,
     session: Session,
-) -> impl Responder {
-    // Extract authorization code from the callback parameters
+) -> Result<HttpResponse, AppError> {
     let config_name = config_name.into_inner();
     let Some(config) = configs.get(&config_name) else {
-        return HttpResponse::NotFound().body("cannot find desired oauth configuration");
+        return Err(AppError::NotFound("OAuth configuration".into()));
+    };
+    // the state is good for a single callback
+    let pending = session
+        .remove_as::<PendingAuth>("oauth_pending")
+        .and_then(Result::ok);
+    if let Some(error) = &params.error {
+        log::warn!(
+            "Oauth provider {} refused the login: {} {}",
+            config_name,
+            error,
+            params.error_description.as_deref().unwrap_or_default()
+        );
+        return Err(AppError::Forbidden(format!(
+            "Login refused by provider: {error}"
+        )));
+    }
+    let Some(pending) = pending.filter(|pending| {
+        pending.provider == config_name
+            && params.state.as_deref() == Some(pending.state.as_str())
+            && pending.created + PENDING_AUTH_TIMEOUT > now()
+    }) else {
+        log::warn!("Refusing oauth callback of {config_name} with unknown or expired state");
+        return Err(AppError::BadRequest(
+            "Login state does not match, start the login again.".into(),
+        ));
+    };
+    let Some(code) = params.code.clone() else {
+        return Err(AppError::BadRequest("Authorization code missing.".into()));
     };
-    let redirect_uri = format!(
-        // TODO: use correct protocol here
-        "http://{}/oauth/{}/callback",
-        req.connection_info().host(),
-        &config_name
-    );
     log::info!(
-        "Authenticating user on oauth provider {} with redirect uri '{}' and code '{}'",
+        "Authenticating user on oauth provider {} with redirect uri '{}'",
         &config_name,
-        &redirect_uri,
-        params.code.clone()
+        &config.redirect_uri,
     );
-    log::debug!("{:?}", params);
     // Exchange the authorization code for an access token
-    let token_response = config.exchange_code_for_token(params.code.clone()).await;
-
-    match token_response {
-        Ok(token_response) => {
-            // Extract user info from the access token
-            log::info!("{:?}", token_response);
-            let token = token_response.access_token().to_owned();
-            let Ok(id_token_json) = serde_json::to_string(&token_response.id_token()) else {
-                return HttpResponse::InternalServerError().body("unable to serialize id_token");
-            };
-            let user_info = config.fetch_user_info(token).await.unwrap();
-            log::info!("{:?}", user_info);
-            let user_name = match config.username_field {
-                UsernameField::PreferredUsername => {
-                    user_info.preferred_username().map(|x| x.to_string())
-                }
-                UsernameField::Email => user_info.email().map(|x| x.to_string()),
+    let token_response = config
+        .exchange_code_for_token(code, pending.pkce_verifier)
+        .await
+        .map_err(|e| {
+            AppError::Internal(format!(
+                "Failed to exchange authorization code for access token {}",
+                e
+            ))
+        })?;
+    let claims = config
+        .verify_id_token(&token_response, &Nonce::new(pending.nonce))
+        .map_err(|e| {
+            log::warn!("Refusing id token of {config_name}: {e}");
+            AppError::Forbidden("The id token of the provider is not valid.".into())
+        })?;
+    let Ok(id_token_json) = serde_json::to_string(&token_response.id_token()) else {
+        return Err(AppError::Internal("unable to serialize id_token".into()));
+    };
+    let user_info = config
+        .fetch_user_info(
+            token_response.access_token().to_owned(),
+            claims.subject().clone(),
+        )
+        .await
+        .map_err(|e| AppError::Internal(format!("cannot fetch user info: {e}")))?;
+    log::debug!("{:?}", user_info);
+    let user_name = match config.username_field {
+        UsernameField::PreferredUsername => user_info.preferred_username().map(|x| x.to_string()),
+        UsernameField::Email => user_info.email().map(|x| x.to_string()),
+    };
+    // fallback to sub
+    let user_name = user_name.unwrap_or(user_info.subject().to_string());
+
+    let ip = throttle.client_ip(&req);
+    let all_claims = merged_claims(&token_response, &user_info);
+    let role_id = match config.admit(&all_claims) {
+        Ok(role_id) => role_id,
+        Err(reason) => {
+            log::warn!("Refusing oauth login of {user_name} on {config_name}: {reason}");
+            audit.login_failed(&user_name, &reason, &ip).await;
+            return Err(AppError::Forbidden(format!("Login refused: {reason}.")));
+        }
+    };
+    let source = UserSource::OAuth(config_name.clone());
+    let provisioned = provision_user(
+        &repo,
+        source.clone(),
+        &config.provisioning,
+        !config.role_mappings.is_empty(),
+        &user_name,
+        user_info.email().map(|email| email.to_string()),
+        role_id,
+    )
+    .await;
+    let user = match provisioned {
+        Ok(user) => user,
+        Err(e) => {
+            audit.login_failed(&user_name, &e.to_string(), &ip).await;
+            return Err(e);
+        }
+    };
+    audit.login_succeeded(&user.name, &source, &ip).await;
+    session.renew();
+    let _ = session.insert("loggedin", "true");
+    let _ = session.insert("username", user.name);
+    let _ = session.insert("jwt", id_token_json);
+    let _ = session.insert("oauth_config", &config_name);
+    let _ = session.insert("oauth_subject", claims.subject().as_str());
+    store_tokens(&session, &token_response, None);
+    Ok(HttpResponse::Found()
+        .append_header(("Location", "/"))
+        .finish())
+}
+
+/// Claims of the id token, its signature has been verified already.
+fn id_token_payload(token_response: &CoreTokenResponse) -> serde_json::Value {
+    token_response
+        .id_token()
+        .and_then(|id_token| {
+            let id_token = id_token.to_string();
+            let payload = id_token.split('.').nth(1)?;
+            let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+                .decode(payload)
+                .ok()?;
+            serde_json::from_slice(&payload).ok()
+        })
+        .unwrap_or_else(|| serde_json::json!({}))
+}
+
+/// Claims of the id token and the user info, the user info has the last
+/// word on claims both of them carry.
+fn merged_claims(token_response: &CoreTokenResponse, user_info: &UserInfo) -> serde_json::Value {
+    let mut claims = id_token_payload(token_response);
+    if let (Some(claims), Ok(serde_json::Value::Object(info))) =
+        (claims.as_object_mut(), serde_json::to_value(user_info))
+    {
+        claims.extend(info);
+    }
+    claims
+}
+
+/// Look up or create the user of an admitted external login.
+///
+/// The role is synced if the provider maps roles, otherwise it is only set on
+/// the first login and left to the admins afterwards.
+pub async fn provision_user(
+    repo: &crate::Repo,
+    source: UserSource,
+    provisioning: &Provisioning,
+    sync_role: bool,
+    user_name: &str,
+    email: Option<String>,
+    role_id: i32,
+) -> Result<User, AppError> {
+    match User::get_by_name(repo, user_name).await? {
+        Some(user) if user.source != source => {
+            log::warn!(
+                "User {} belongs to {:?}, refusing login with {:?}",
+                user.name,
+                user.source,
+                source
+            );
+            Err(AppError::Forbidden(
+                "The user logs in with another provider.".into(),
+            ))
+        }
+        Some(mut user) => {
+            if sync_role && user.role_id != role_id {
+                log::info!(
+                    "Role of {} changes from {} to {} by {:?}",
+                    user.name,
+                    user.role_id,
+                    role_id,
+                    source
+                );
+                user.role_id = role_id;
+                user.insert(repo).await?;
+            }
+            Ok(user)
+        }
+        None if *provisioning == Provisioning::InviteOnly => {
+            log::warn!("Refusing login of {user_name} with {source:?}, not invited");
+            Err(AppError::Forbidden("You have not been invited.".into()))
+        }
+        None => {
+            log::info!("Creating user {user_name} on the first login with {source:?}");
+            let user = User {
+                name: user_name.to_string(),
+                email,
+                password: "".into(),
+                role_id,
+                source,
+                totp: None,
             };
-            // fallback to sub
-            let user_name = user_name.unwrap_or(user_info.subject().to_string());
-
-            // Check user in database
-            let user = match User::insert_if_not_exist(
-                &db,
-                &user_name,
-                crate::models::user::UserSource::OAuth("Google".into()),
-            )
+            user.insert(repo).await?;
+            Ok(user)
+        }
+    }
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// Keep what the session needs to re-validate against the provider.
+fn store_tokens(
+    session: &Session,
+    token_response: &CoreTokenResponse,
+    previous_refresh_token: Option<String>,
+) {
+    let expires_in = token_response
+        .expires_in()
+        .map_or(REVALIDATE_FALLBACK, |expires_in| {
+            expires_in.as_millis() as i64
+        });
+    let _ = session.insert("token_expires", now() + expires_in);
+    // providers which do not rotate refresh tokens keep the old one valid
+    if let Some(refresh_token) = token_response
+        .refresh_token()
+        .map(|token| token.secret().clone())
+        .or(previous_refresh_token)
+    {
+        let _ = session.insert("refresh_token", refresh_token);
+    }
+}
+
+impl OauthConfig {
+    /// Exchange the refresh token and check the claims against the login rules
+    /// again, returns why the session has to end if it does.
+    async fn refresh(&self, session: &Session, refresh_token: &str) -> Result<(), String> {
+        let client = self.client().map_err(|e| e.to_string())?;
+        let token_response = match client
+            .exchange_refresh_token(&RefreshToken::new(refresh_token.to_string()))
+            .request_async(async_http_client)
             .await
-            {
-                Ok(u) => u,
-                Err(e) => {
-                    log::error!("cannot login user: {}", e.to_string());
-                    return HttpResponse::InternalServerError().body(format!("{e}"));
-                }
-            };
-            let _ = session.insert("loggedin", "true");
-            let _ = session.insert("username", user.name);
-            let _ = session.insert("jwt", id_token_json);
-            let _ = session.insert("oauth_config", &config_name);
-            HttpResponse::Found()
-                .append_header(("Location", "/"))
-                .finish()
+        {
+            Ok(token_response) => token_response,
+            Err(RequestTokenError::ServerResponse(e)) => {
+                return Err(format!("refresh refused: {e}"));
+            }
+            Err(e) => {
+                // an unreachable provider must not log everybody out
+                log::warn!(
+                    "Cannot refresh oauth session on {}, trying again later: {e}",
+                    self.ui_name
+                );
+                let _ = session.insert("token_expires", now() + REVALIDATE_FALLBACK);
+                return Ok(());
+            }
+        };
+        let subject = session
+            .get::<String>("oauth_subject")
+            .ok()
+            .flatten()
+            .ok_or("session has no subject")?;
+        if let Some(id_token) = token_response.id_token() {
+            // refreshed id tokens carry no nonce
+            let claims = id_token
+                .claims(&client.id_token_verifier(), |_: Option<&Nonce>| Ok(()))
+                .map_err(|e| format!("invalid id token: {e}"))?;
+            if claims.subject().as_str() != subject {
+                return Err("id token of another subject".into());
+            }
+            if let Ok(id_token_json) = serde_json::to_string(&Some(id_token)) {
+                let _ = session.insert("jwt", id_token_json);
+            }
         }
-        Err(e) => HttpResponse::InternalServerError().body(format!(
-            "Failed to exchange authorization code for access token {}",
-            e
-        )),
+        let user_info = self
+            .fetch_user_info(
+                token_response.access_token().to_owned(),
+                SubjectIdentifier::new(subject),
+            )
+            .await
+            .map_err(|e| format!("cannot fetch user info: {e}"))?;
+        self.admit(&merged_claims(&token_response, &user_info))?;
+        store_tokens(session, &token_response, Some(refresh_token.to_string()));
+        Ok(())
+    }
+}
+
+/// Re-validate an oauth session against its provider once the access token expired.
+///
+/// Returns why the session has to end if the provider refuses the refresh or
+/// the claims no longer pass the login rules. Sessions without a refresh
+/// token are only ended by their timeouts.
+pub async fn revalidate(
+    session: &Session,
+    configs: &HashMap<String, OauthConfig>,
+    refreshing: &Mutex<HashSet<String>>,
+) -> Result<(), String> {
+    let Some(provider) = session.get::<String>("oauth_config").ok().flatten() else {
+        return Ok(());
+    };
+    let Some(expires) = session.get::<i64>("token_expires").ok().flatten() else {
+        return Ok(());
+    };
+    if expires > now() {
+        return Ok(());
+    }
+    let Some(config) = configs.get(&provider) else {
+        return Err(format!("provider {provider} is not configured anymore"));
+    };
+    let Some(refresh_token) = session.get::<String>("refresh_token").ok().flatten() else {
+        return Ok(());
+    };
+    // concurrent requests of the session leave the refresh to the first one
+    let claimed = refreshing
+        .lock()
+        .is_ok_and(|mut refreshing| refreshing.insert(refresh_token.clone()));
+    if !claimed {
+        return Ok(());
+    }
+    let result = config.refresh(session, &refresh_token).await;
+    if let Ok(mut refreshing) = refreshing.lock() {
+        refreshing.remove(&refresh_token);
     }
+    result
+}
+
+/// Url logging the user out at the provider too, if it supports that.
+pub fn end_session_url(
+    session: &Session,
+    configs: &HashMap<String, OauthConfig>,
+) -> Option<String> {
+    let provider = session.get::<String>("oauth_config").ok().flatten()?;
+    let config = configs.get(&provider)?;
+    let mut request = LogoutRequest::from(config.end_session_endpoint.clone()?)
+        .set_client_id(ClientId::new(config.client_id.clone()));
+    if let Some(id_token) = session
+        .get::<String>("jwt")
+        .ok()
+        .flatten()
+        .and_then(|jwt| serde_json::from_str::<Option<CoreIdToken>>(&jwt).ok())
+        .flatten()
+    {
+        request = request.set_id_token_hint(&id_token);
+    }
+    if let Ok(redirect_uri) = PostLogoutRedirectUrl::new(config.post_logout_redirect_uri.clone()) {
+        request = request.set_post_logout_redirect_uri(redirect_uri);
+    }
+    Some(request.http_get_url().to_string())
 }
 
 #[derive(serde::Deserialize, Debug)]
 pub struct AuthCallbackParams {
-    // state: String,
-    code: String,
-    // scope: String,
-    // authuser: u32,
-    // prompt: String,
+    state: Option<String>,
+    code: Option<String>,
+    /// set instead of the code when the provider refused the login
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_http::Request;
+    use actix_session::SessionMiddleware;
+    use actix_web::{
+        body::MessageBody,
+        cookie::{Cookie, Key},
+        dev::{Service, ServiceResponse},
+        http::StatusCode,
+        test, App,
+    };
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use openidconnect::{
+        core::{CoreJwsSigningAlgorithm, CoreRsaPrivateSigningKey},
+        Audience, EmptyAdditionalClaims, JsonWebKeyId, PrivateSigningKey, StandardClaims,
+    };
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::{
+        session_store::ServerSessionStore,
+        settings::{LoginSettings, SessionSettings},
+        storage::MemoryRepository,
+        test_support::{HttpRequest as IssuerRequest, HttpResponse as IssuerResponse, HttpServer},
+    };
+
+    const SIGNING_KEY: &str = include_str!("../tests/fixtures/issuer_key.pem");
+    const BASE_URL: &str = "http://mqttpal.test";
+
+    /// What the stub issuer puts into the id token and expects at the token endpoint.
+    #[derive(Default)]
+    struct Expected {
+        nonce: String,
+        code_challenge: String,
+    }
+
+    fn stub_issuer(request: &IssuerRequest, expected: &Mutex<Expected>) -> IssuerResponse {
+        let issuer = format!("http://{}", request.header("host").unwrap_or_default());
+        let key =
+            CoreRsaPrivateSigningKey::from_pem(SIGNING_KEY, Some(JsonWebKeyId::new("stub".into())))
+                .expect("valid test key");
+        match request.path.as_str() {
+            "/.well-known/openid-configuration" => (
+                200,
+                json!({
+                    "issuer": issuer,
+                    "authorization_endpoint": format!("{issuer}/authorize"),
+                    "token_endpoint": format!("{issuer}/token"),
+                    "userinfo_endpoint": format!("{issuer}/userinfo"),
+                    "jwks_uri": format!("{issuer}/jwks"),
+                    "end_session_endpoint": format!("{issuer}/logout"),
+                    "response_types_supported": ["code"],
+                    "subject_types_supported": ["public"],
+                    "id_token_signing_alg_values_supported": ["RS256"],
+                }),
+            ),
+            "/jwks" => (200, json!({ "keys": [key.as_verification_key()] })),
+            "/token" => {
+                let form = request.form();
+                let expected = expected.lock().unwrap();
+                let verifier = form.get("code_verifier").cloned().unwrap_or_default();
+                let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier));
+                if form.get("code").map(String::as_str) != Some("the-code")
+                    || challenge != expected.code_challenge
+                {
+                    return (400, json!({ "error": "invalid_grant" }));
+                }
+                let access_token = AccessToken::new("the-access-token".into());
+                let claims = CoreIdTokenClaims::new(
+                    IssuerUrl::new(issuer).unwrap(),
+                    vec![Audience::new("mqttpal".into())],
+                    chrono::Utc::now() + chrono::Duration::minutes(5),
+                    chrono::Utc::now(),
+                    StandardClaims::new(SubjectIdentifier::new("sub-1".into())),
+                    EmptyAdditionalClaims {},
+                )
+                .set_nonce(Some(Nonce::new(expected.nonce.clone())));
+                let id_token = CoreIdToken::new(
+                    claims,
+                    &key,
+                    CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
+                    Some(&access_token),
+                    None,
+                )
+                .unwrap();
+                (
+                    200,
+                    json!({
+                        "access_token": access_token.secret(),
+                        "token_type": "Bearer",
+                        "expires_in": 3600,
+                        "id_token": id_token.to_string(),
+                    }),
+                )
+            }
+            "/userinfo" => (
+                200,
+                json!({ "sub": "sub-1", "email": "kim@example.com", "groups": ["mqtt-admins"] }),
+            ),
+            _ => (404, json!({})),
+        }
+    }
+
+    async fn start_issuer() -> (HttpServer, Arc<Mutex<Expected>>) {
+        let expected = Arc::new(Mutex::new(Expected::default()));
+        let state = expected.clone();
+        let server = HttpServer::start(move |request| stub_issuer(request, &state)).await;
+        (server, expected)
+    }
+
+    async fn config(issuer: &HttpServer) -> OauthConfig {
+        let mut config: OauthConfig = serde_yaml::from_str(&format!(
+            "
+ui_name: Stub
+issuer: {}
+client_id: mqttpal
+client_secret: secret
+scope: email
+role_mappings:
+  - claim: groups
+    value: mqtt-admins
+    role: admin
+",
+            issuer.url
+        ))
+        .unwrap();
+        config.init("stub".into(), BASE_URL).await.unwrap();
+        config
+    }
+
+    /// Change the pending login in the session, like an attacker or the clock would.
+    async fn tamper(session: Session, what: web::Path<String>) -> HttpResponse {
+        let mut pending: PendingAuth = session.get("oauth_pending").unwrap().unwrap();
+        match what.as_str() {
+            "expire" => pending.created -= PENDING_AUTH_TIMEOUT,
+            "verifier" => {
+                pending.pkce_verifier = "forged-verifier-forged-verifier-forged-ver".into()
+            }
+            _ => unreachable!("unknown tampering {what}"),
+        }
+        session.insert("oauth_pending", pending).unwrap();
+        HttpResponse::Ok().finish()
+    }
+
+    async fn app(
+        repo: crate::Repo,
+        config: OauthConfig,
+    ) -> impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error>
+    {
+        let store = ServerSessionStore::new(None, &SessionSettings::default());
+        test::init_service(
+            App::new()
+                .wrap(SessionMiddleware::new(store, Key::generate()))
+                .app_data(web::Data::new(repo))
+                .app_data(web::Data::new(LoginThrottle::new(
+                    None,
+                    &LoginSettings::default(),
+                )))
+                .app_data(web::Data::new(AuditLog::new(None, 10)))
+                .app_data(web::Data::new(HashMap::from([(
+                    "stub".to_string(),
+                    config,
+                )])))
+                .configure(oauth_login_scoped)
+                .route("/tamper/{what}", web::get().to(tamper)),
+        )
+        .await
+    }
+
+    /// Start a login, returns the session cookie and the authorization url.
+    async fn start_login<S, B>(
+        app: &S,
+        expected: &Mutex<Expected>,
+    ) -> (Cookie<'static>, reqwest::Url)
+    where
+        S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    {
+        let response = test::call_service(
+            app,
+            test::TestRequest::get().uri("/oauth/stub/").to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        let cookie = response.response().cookies().next().unwrap().into_owned();
+        let location = response
+            .headers()
+            .get("location")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        let url = reqwest::Url::parse(location).unwrap();
+        let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        *expected.lock().unwrap() = Expected {
+            nonce: query["nonce"].clone(),
+            code_challenge: query["code_challenge"].clone(),
+        };
+        (cookie, url)
+    }
+
+    fn query(url: &reqwest::Url, name: &str) -> String {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+            .unwrap_or_default()
+    }
+
+    async fn get<S, B>(app: &S, cookie: &Cookie<'static>, uri: &str) -> StatusCode
+    where
+        S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    {
+        let request = test::TestRequest::get()
+            .uri(uri)
+            .cookie(cookie.clone())
+            .to_request();
+        test::call_service(app, request).await.status()
+    }
+
+    fn token_requests(issuer: &HttpServer) -> usize {
+        issuer
+            .requests()
+            .iter()
+            .filter(|request| request.path == "/token")
+            .count()
+    }
+
+    #[tokio::test]
+    async fn discovers_the_issuer() {
+        let (issuer, _) = start_issuer().await;
+        let config = config(&issuer).await;
+        assert!(config.client.is_some());
+        assert_eq!(
+            config.redirect_uri,
+            format!("{BASE_URL}/oauth/stub/callback")
+        );
+        assert_eq!(
+            config.end_session_endpoint.map(|url| url.url().to_string()),
+            Some(format!("{}/logout", issuer.url))
+        );
+        let paths: Vec<String> = issuer.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(paths, ["/.well-known/openid-configuration", "/jwks"]);
+    }
+
+    #[tokio::test]
+    async fn logs_in_with_the_pending_state_nonce_and_pkce_verifier() {
+        let (issuer, expected) = start_issuer().await;
+        let repo: crate::Repo = Arc::new(MemoryRepository::new(10));
+        let app = app(repo.clone(), config(&issuer).await).await;
+        let (cookie, url) = start_login(&app, &expected).await;
+        assert_eq!(url.path(), "/authorize");
+        assert_eq!(query(&url, "client_id"), "mqttpal");
+        assert_eq!(query(&url, "response_type"), "code");
+        assert_eq!(query(&url, "code_challenge_method"), "S256");
+        assert_eq!(
+            query(&url, "redirect_uri"),
+            format!("{BASE_URL}/oauth/stub/callback")
+        );
+
+        let callback = format!(
+            "/oauth/stub/callback?code=the-code&state={}",
+            query(&url, "state")
+        );
+        let request = test::TestRequest::get()
+            .uri(&callback)
+            .cookie(cookie)
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers().get("location").unwrap(), "/");
+        let user = User::get_by_name(&repo, "kim@example.com")
+            .await
+            .unwrap()
+            .expect("the user is provisioned");
+        assert_eq!(user.source, UserSource::OAuth("stub".into()));
+        assert_eq!(user.role_id, RoleConfig::Admin.role_id());
+    }
+
+    #[tokio::test]
+    async fn refuses_a_callback_with_another_state() {
+        let (issuer, expected) = start_issuer().await;
+        let app = app(Arc::new(MemoryRepository::new(10)), config(&issuer).await).await;
+        let (cookie, url) = start_login(&app, &expected).await;
+        let forged = "/oauth/stub/callback?code=the-code&state=forged";
+        assert_eq!(get(&app, &cookie, forged).await, StatusCode::BAD_REQUEST);
+        // the pending login is used up by the failed attempt
+        let callback = format!(
+            "/oauth/stub/callback?code=the-code&state={}",
+            query(&url, "state")
+        );
+        assert_eq!(get(&app, &cookie, &callback).await, StatusCode::BAD_REQUEST);
+        assert_eq!(token_requests(&issuer), 0);
+    }
+
+    #[tokio::test]
+    async fn refuses_an_id_token_with_another_nonce() {
+        let (issuer, expected) = start_issuer().await;
+        let repo: crate::Repo = Arc::new(MemoryRepository::new(10));
+        let app = app(repo.clone(), config(&issuer).await).await;
+        let (cookie, url) = start_login(&app, &expected).await;
+        expected.lock().unwrap().nonce = "replayed-nonce".into();
+        let callback = format!(
+            "/oauth/stub/callback?code=the-code&state={}",
+            query(&url, "state")
+        );
+        assert_eq!(get(&app, &cookie, &callback).await, StatusCode::FORBIDDEN);
+        assert!(User::get_by_name(&repo, "kim@example.com")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn sends_the_pkce_verifier_of_the_pending_login() {
+        let (issuer, expected) = start_issuer().await;
+        let app = app(Arc::new(MemoryRepository::new(10)), config(&issuer).await).await;
+        let (cookie, url) = start_login(&app, &expected).await;
+        assert_eq!(get(&app, &cookie, "/tamper/verifier").await, StatusCode::OK);
+        let callback = format!(
+            "/oauth/stub/callback?code=the-code&state={}",
+            query(&url, "state")
+        );
+        assert_eq!(
+            get(&app, &cookie, &callback).await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(token_requests(&issuer), 1);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_callback_after_ten_minutes() {
+        let (issuer, expected) = start_issuer().await;
+        let app = app(Arc::new(MemoryRepository::new(10)), config(&issuer).await).await;
+        let (cookie, url) = start_login(&app, &expected).await;
+        assert_eq!(get(&app, &cookie, "/tamper/expire").await, StatusCode::OK);
+        let callback = format!(
+            "/oauth/stub/callback?code=the-code&state={}",
+            query(&url, "state")
+        );
+        assert_eq!(get(&app, &cookie, &callback).await, StatusCode::BAD_REQUEST);
+        assert_eq!(token_requests(&issuer), 0);
+    }
+
+    #[tokio::test]
+    async fn allows_domains_only_for_verified_addresses() {
+        let config: OauthConfig = serde_yaml::from_str(
+            "
+ui_name: Stub
+issuer: https://issuer.example
+client_id: mqttpal
+client_secret: secret
+scope: email
+allowed_domains: [example.com]
+",
+        )
+        .unwrap();
+        let claims = |verified: serde_json::Value| {
+            let mut claims = json!({ "email": "kim@Example.com" });
+            if !verified.is_null() {
+                claims["email_verified"] = verified;
+            }
+            claims
+        };
+        assert!(config.admit(&claims(json!(true))).is_ok());
+        assert!(config.admit(&claims(json!(false))).is_err());
+        assert!(config.admit(&claims(json!("true"))).is_err());
+        assert!(config.admit(&claims(serde_json::Value::Null)).is_err());
+        let other = json!({ "email": "kim@other.com", "email_verified": true });
+        assert!(config.admit(&other).is_err());
+    }
 }
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+
+use rand::Rng;
+
+const BUILTINS: [&str; 5] = ["now", "timestamp", "counter", "uuid", "random"];
+
+/// Values available to payload templates.
+#[derive(Default)]
+pub struct PayloadContext {
+    /// number of the current publish, starting at 1
+    pub counter: u64,
+    /// values of user-entered fields
+    pub fields: HashMap<String, String>,
+}
+
+/// Part of a payload template.
+enum Piece<'a> {
+    /// text taken as it is
+    Text(&'a str),
+    /// trimmed contents of a `{{...}}` placeholder
+    Placeholder(&'a str),
+}
+
+/// Split a template into text and placeholders, in order.
+fn pieces(template: &str) -> impl Iterator<Item = Result<Piece<'_>, String>> {
+    let mut rest = template;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let start = rest.find("{{").unwrap_or(rest.len());
+        if start > 0 {
+            let text = &rest[..start];
+            rest = &rest[start..];
+            return Some(Ok(Piece::Text(text)));
+        }
+        let Some(end) = rest.find("}}") else {
+            rest = "";
+            return Some(Err("unclosed '{{' in payload template".into()));
+        };
+        let placeholder = rest[2..end].trim();
+        rest = &rest[end + 2..];
+        Some(Ok(Piece::Placeholder(placeholder)))
+    })
+}
+
+/// Iterate over the trimmed contents of all `{{...}}` placeholders.
+fn placeholders(template: &str) -> impl Iterator<Item = Result<&str, String>> {
+    pieces(template).filter_map(|piece| match piece {
+        Ok(Piece::Text(_)) => None,
+        Ok(Piece::Placeholder(placeholder)) => Some(Ok(placeholder)),
+        Err(e) => Some(Err(e)),
+    })
+}
+
+/// Names of the user-entered fields a template expects, in order of appearance.
+pub fn fields(template: &str) -> Vec<String> {
+    let mut fields: Vec<String> = Vec::new();
+    for placeholder in placeholders(template).flatten() {
+        let name = placeholder.split_whitespace().next().unwrap_or_default();
+        if !name.is_empty() && !BUILTINS.contains(&name) && !fields.iter().any(|f| f == name) {
+            fields.push(name.to_string());
+        }
+    }
+    fields
+}
+
+/// Replace the `{{...}}` placeholders of a payload template.
+///
+/// Supported placeholders:
+/// - `{{now}}`: current time as RFC 3339
+/// - `{{timestamp}}`: current unix timestamp in seconds
+/// - `{{counter}}`: number of the current publish
+/// - `{{uuid}}`: random UUID v4
+/// - `{{random 0 100}}`: random integer between both bounds (inclusive)
+/// - `{{name}}`: any other name is a field entered by the user
+pub fn render(template: &str, ctx: &PayloadContext) -> Result<String, String> {
+    let mut rendered = String::with_capacity(template.len());
+    for piece in pieces(template) {
+        match piece? {
+            Piece::Text(text) => rendered.push_str(text),
+            Piece::Placeholder(placeholder) => {
+                rendered.push_str(&placeholder_value(placeholder, ctx)?)
+            }
+        }
+    }
+    Ok(rendered)
+}
+
+/// Check a template for syntax errors without rendering it.
+pub fn validate(template: &str) -> Result<(), String> {
+    for placeholder in placeholders(template) {
+        let placeholder = placeholder?;
+        let mut parts = placeholder.split_whitespace();
+        match parts.next() {
+            Some("random") => {
+                random_bounds(parts)?;
+            }
+            Some(_) => {}
+            None => return Err("empty placeholder '{{}}'".into()),
+        }
+    }
+    Ok(())
+}
+
+fn random_bounds<'a>(mut args: impl Iterator<Item = &'a str>) -> Result<(i64, i64), String> {
+    let usage = || "usage: {{random <min> <max>}}".to_string();
+    let min: i64 = args
+        .next()
+        .ok_or_else(usage)?
+        .parse()
+        .map_err(|_| usage())?;
+    let max: i64 = args
+        .next()
+        .ok_or_else(usage)?
+        .parse()
+        .map_err(|_| usage())?;
+    if min > max {
+        return Err(format!("random: {min} is larger than {max}"));
+    }
+    Ok((min, max))
+}
+
+fn placeholder_value(placeholder: &str, ctx: &PayloadContext) -> Result<String, String> {
+    let mut parts = placeholder.split_whitespace();
+    match parts.next() {
+        Some("now") => Ok(chrono::Utc::now().to_rfc3339()),
+        Some("timestamp") => Ok(chrono::Utc::now().timestamp().to_string()),
+        Some("counter") => Ok(ctx.counter.to_string()),
+        Some("uuid") => Ok(uuid::Uuid::new_v4().to_string()),
+        Some("random") => {
+            let (min, max) = random_bounds(parts)?;
+            Ok(rand::thread_rng().gen_range(min..=max).to_string())
+        }
+        Some(field) => ctx
+            .fields
+            .get(field)
+            .cloned()
+            .ok_or_else(|| format!("missing value for field '{field}'")),
+        None => Err("empty placeholder '{{}}'".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> PayloadContext {
+        PayloadContext {
+            counter: 7,
+            fields: HashMap::from([("room".to_string(), "kitchen".to_string())]),
+        }
+    }
+
+    #[test]
+    fn renders_builtins_and_fields() {
+        let rendered = render(r#"{"n": {{ counter }}, "room": "{{room}}"}"#, &context()).unwrap();
+        assert_eq!(rendered, r#"{"n": 7, "room": "kitchen"}"#);
+        let id = render("{{uuid}}", &context()).unwrap();
+        assert!(uuid::Uuid::parse_str(&id).is_ok());
+        let timestamp: i64 = render("{{timestamp}}", &context())
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((timestamp - chrono::Utc::now().timestamp()).abs() <= 1);
+        for _ in 0..20 {
+            let random: i64 = render("{{random -2 2}}", &context())
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert!((-2..=2).contains(&random));
+        }
+        // text without placeholders is kept as it is, stray braces included
+        assert_eq!(render("a }} b { c", &context()).unwrap(), "a }} b { c");
+    }
+
+    #[test]
+    fn refuses_broken_placeholders() {
+        assert!(render("{{counter", &context()).is_err());
+        assert!(render("{{}}", &context()).is_err());
+        assert!(render("{{door}}", &context()).is_err());
+        assert!(validate("{{random 5 1}}").is_err());
+        assert!(validate("{{random one two}}").is_err());
+        assert!(validate("{{random 1 5}} {{door}}").is_ok());
+    }
+
+    #[test]
+    fn lists_the_fields_once() {
+        let template = "{{room}} {{counter}} {{ level }} {{room}} {{random 1 2}}";
+        assert_eq!(fields(template), ["room", "level"]);
+    }
+}
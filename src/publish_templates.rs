@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+
+use crate::{
+    audit::{AuditLog, Auditor},
+    error::AppError,
+    middleware::{htmx::form_error, login_guard::LoginGuard, user_session::UserSession},
+    models::{audit::AuditAction, publish_template::PublishTemplate},
+    mqtt::MqttClientManager,
+    payload::{self, PayloadContext},
+    storage::StorageError,
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use askama::Template;
+use serde::{Deserialize, Serialize};
+
+pub fn templates_scoped(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("{name}/templates")
+            .service(web::resource("").route(web::post().to(post)))
+            .service(web::resource("/{owner}/{template}").route(web::delete().to(delete)))
+            .service(web::resource("/{owner}/{template}/share").route(web::post().to(post_share)))
+            .service(
+                web::resource("/{owner}/{template}/publish").route(web::post().to(post_publish)),
+            ),
+    );
+}
+
+#[derive(Template)]
+#[template(path = "mqtt_client_templates.html")]
+struct MqttClientTemplatesTemplate {
+    name: String,
+    user: String,
+    templates: Vec<PublishTemplate>,
+}
+
+/// Templates of a client visible to `user`.
+pub async fn visible_templates(
+    db: &crate::DbPool,
+    client: &str,
+    user: &str,
+) -> Result<Vec<PublishTemplate>, StorageError> {
+    Ok(PublishTemplate::list(db, client)
+        .await?
+        .into_iter()
+        .filter(|template| template.visible_to(user))
+        .collect())
+}
+
+impl MqttClientTemplatesTemplate {
+    async fn load(db: &crate::DbPool, name: String, user: String) -> Result<Self, StorageError> {
+        let templates = visible_templates(db, &name, &user).await?;
+        Ok(MqttClientTemplatesTemplate {
+            name,
+            user,
+            templates,
+        })
+    }
+}
+
+#[derive(Template)]
+#[template(path = "publish_result.html")]
+pub struct PublishResultTemplate {
+    pub topic: String,
+    pub payload: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PublishTemplateForm {
+    name: String,
+    topic: String,
+    payload: String,
+    qos: u8,
+    retain: Option<String>,
+    shared: Option<String>,
+}
+
+async fn post(
+    _: LoginGuard,
+    usession: UserSession,
+    req: HttpRequest,
+    db: web::Data<crate::DbPool>,
+    form: web::Form<PublishTemplateForm>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let user = usession.username.unwrap_or_default();
+    let form = form.into_inner();
+    if form.name.trim().is_empty() {
+        return Ok(form_error(
+            &req,
+            "#template-errors",
+            "Template name is required.",
+        ));
+    }
+    if form.qos > 2 {
+        return Ok(form_error(
+            &req,
+            "#template-errors",
+            "QoS has to be 0, 1 or 2.",
+        ));
+    }
+    if let Err(e) = payload::validate(&form.topic).and(payload::validate(&form.payload)) {
+        return Ok(form_error(&req, "#template-errors", &e));
+    }
+    let template = PublishTemplate {
+        name: form.name,
+        topic: form.topic,
+        payload: form.payload,
+        qos: form.qos,
+        retain: form.retain.is_some(),
+        owner: user.clone(),
+        shared: form.shared.is_some(),
+    };
+    template.insert(&db, &name).await?;
+    let template = MqttClientTemplatesTemplate::load(&db, name.into_inner(), user).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+async fn delete(
+    _: LoginGuard,
+    usession: UserSession,
+    db: web::Data<crate::DbPool>,
+    path: web::Path<(String, String, String)>,
+) -> Result<HttpResponse, AppError> {
+    let user = usession.username.unwrap_or_default();
+    let (name, owner, template) = path.into_inner();
+    match PublishTemplate::get_by_name(&db, &name, &owner, &template).await? {
+        Some(template) if template.owner == user => {
+            PublishTemplate::delete(&db, &name, &owner, &template.name).await?;
+        }
+        Some(_) => {
+            return Err(AppError::Forbidden(
+                "Only the owner can delete a template".into(),
+            ))
+        }
+        None => return Err(AppError::NotFound("Template".into())),
+    }
+    let template = MqttClientTemplatesTemplate::load(&db, name, user).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+async fn post_share(
+    _: LoginGuard,
+    usession: UserSession,
+    db: web::Data<crate::DbPool>,
+    path: web::Path<(String, String, String)>,
+) -> Result<HttpResponse, AppError> {
+    let user = usession.username.unwrap_or_default();
+    let (name, owner, template) = path.into_inner();
+    match PublishTemplate::get_by_name(&db, &name, &owner, &template).await? {
+        Some(mut template) if template.owner == user => {
+            template.shared = !template.shared;
+            template.insert(&db, &name).await?;
+        }
+        Some(_) => {
+            return Err(AppError::Forbidden(
+                "Only the owner can share a template".into(),
+            ))
+        }
+        None => return Err(AppError::NotFound("Template".into())),
+    }
+    let template = MqttClientTemplatesTemplate::load(&db, name, user).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+async fn post_publish(
+    _: LoginGuard,
+    auditor: Auditor,
+    req: HttpRequest,
+    db: web::Data<crate::DbPool>,
+    mqtt: web::Data<MqttClientManager>,
+    fields: web::Form<HashMap<String, String>>,
+    path: web::Path<(String, String, String)>,
+) -> Result<HttpResponse, AppError> {
+    let user = auditor.user.clone();
+    let (name, owner, template) = path.into_inner();
+    let Some(template) = PublishTemplate::get_by_name(&db, &name, &owner, &template)
+        .await?
+        .filter(|template| template.visible_to(&user))
+    else {
+        return Err(AppError::NotFound("Template".into()));
+    };
+    let ctx = PayloadContext {
+        counter: template.next_counter(&db, &name).await?,
+        fields: fields.into_inner(),
+    };
+    let rendered = payload::render(&template.topic, &ctx)
+        .and_then(|topic| Ok((topic, payload::render(&template.payload, &ctx)?)));
+    let (topic, payload) = match rendered {
+        Ok(rendered) => rendered,
+        Err(e) => return Ok(form_error(&req, "#responseBox", &e)),
+    };
+    if !rumqttc::valid_topic(&topic) {
+        let message = format!("'{topic}' is not a valid topic.");
+        return Ok(form_error(&req, "#responseBox", &message));
+    }
+    let qos = rumqttc::qos(template.qos).unwrap_or(rumqttc::QoS::AtLeastOnce);
+    mqtt.publish_with(
+        &name,
+        topic.clone(),
+        payload.clone().into_bytes(),
+        qos,
+        template.retain,
+    )
+    .await?;
+    let details = AuditLog::publish_details(&topic, &payload, false);
+    auditor
+        .record(AuditAction::Publish, &name, Some(details))
+        .await;
+    let result = PublishResultTemplate { topic, payload };
+    Ok(HttpResponse::Ok().body(result.render()?))
+}
@@ -0,0 +1,287 @@
+use crate::{
+    audit::Auditor,
+    error::AppError,
+    middleware::{fullpage_render::FullPageRender, htmx::form_error, login_guard::LoginGuard},
+    models::{audit::AuditAction, mqtt_client::MqttClient, recording::Recording},
+    mqtt::{MqttClientManager, ReplaySpeed, TopicRewrite},
+    storage::StorageError,
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use askama::Template;
+use serde::{Deserialize, Serialize};
+
+pub fn recordings_scoped(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/recordings")
+            .service(
+                web::resource("/")
+                    .route(web::get().to(get).wrap(FullPageRender))
+                    .route(web::post().to(post)),
+            )
+            .service(web::resource("/{name}").route(web::delete().to(delete)))
+            .service(web::resource("/{name}/stop").route(web::post().to(post_stop)))
+            .service(web::resource("/{name}/replay").route(web::post().to(post_replay))),
+    );
+}
+
+#[derive(Template)]
+#[template(path = "recordings.html")]
+struct RecordingListTemplate {
+    recordings: Vec<(Recording, usize)>,
+    clients: Vec<String>,
+}
+
+impl RecordingListTemplate {
+    async fn load(db: &crate::DbPool, repo: &crate::Repo) -> Result<Self, StorageError> {
+        let mut clients: Vec<String> = MqttClient::list(repo)
+            .await?
+            .into_iter()
+            .map(|c| c.name)
+            .collect();
+        clients.sort();
+        let mut recordings = Vec::new();
+        for recording in Recording::list(db).await? {
+            let messages = Recording::message_count(db, &recording.name).await?;
+            recordings.push((recording, messages));
+        }
+        Ok(RecordingListTemplate {
+            recordings,
+            clients,
+        })
+    }
+}
+
+/// Stop a recording once its time window is over.
+pub fn stop_after_window(db: crate::DbPool, mqtt: MqttClientManager, recording: &Recording) {
+    let Some(until) = recording.until else {
+        return;
+    };
+    let name = recording.name.clone();
+    tokio::spawn(async move {
+        let remaining = (until - chrono::Utc::now().timestamp_millis()).max(0);
+        tokio::time::sleep(std::time::Duration::from_millis(remaining as u64)).await;
+        if let Err(e) = stop(&db, &mqtt, &name).await {
+            log::error!("Cannot stop recording {}: {}", name, e);
+        }
+    });
+}
+
+/// Mark a recording as stopped and drop its subscription if nothing else needs it.
+pub async fn stop(
+    db: &crate::DbPool,
+    mqtt: &MqttClientManager,
+    name: &str,
+) -> Result<(), StorageError> {
+    let Some(mut recording) = Recording::get_by_name(db, name).await? else {
+        return Ok(());
+    };
+    if recording.stopped.is_some() {
+        return Ok(());
+    }
+    let now = chrono::Utc::now().timestamp_millis();
+    recording.stopped = Some(recording.until.map_or(now, |until| until.min(now)));
+    recording.insert(db).await?;
+    mqtt.reload_recordings().await;
+    mqtt.release_filter(&recording.client, &recording.filter)
+        .await?;
+    Ok(())
+}
+
+async fn get(
+    _: LoginGuard,
+    db: web::Data<crate::DbPool>,
+    repo: web::Data<crate::Repo>,
+) -> Result<HttpResponse, AppError> {
+    let template = RecordingListTemplate::load(&db, &repo).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RecordingForm {
+    name: String,
+    client: String,
+    filter: String,
+    /// length of the recording window in minutes, empty records until stopped
+    minutes: Option<String>,
+}
+
+impl TryFrom<RecordingForm> for Recording {
+    type Error = String;
+    fn try_from(form: RecordingForm) -> Result<Self, Self::Error> {
+        if form.name.trim().is_empty() {
+            return Err("Recording name is required.".into());
+        }
+        if !rumqttc::valid_filter(&form.filter) {
+            return Err(format!("'{}' is not a valid topic filter.", form.filter));
+        }
+        let started = chrono::Utc::now().timestamp_millis();
+        let until = match form.minutes.as_deref() {
+            None | Some("") => None,
+            Some(minutes) => match minutes.parse::<u32>() {
+                Ok(minutes) if minutes > 0 => Some(started + minutes as i64 * 60 * 1000),
+                _ => return Err(format!("'{minutes}' is not a valid number of minutes.")),
+            },
+        };
+        Ok(Recording {
+            name: form.name,
+            client: form.client,
+            filter: form.filter,
+            started,
+            until,
+            stopped: None,
+            replay: None,
+        })
+    }
+}
+
+async fn post(
+    _: LoginGuard,
+    req: HttpRequest,
+    db: web::Data<crate::DbPool>,
+    repo: web::Data<crate::Repo>,
+    mqtt: web::Data<MqttClientManager>,
+    form: web::Form<RecordingForm>,
+) -> Result<HttpResponse, AppError> {
+    let recording: Result<Recording, String> = form.into_inner().try_into();
+    let recording = match recording {
+        Ok(recording)
+            if Recording::get_by_name(&db, &recording.name)
+                .await?
+                .is_some() =>
+        {
+            Err(format!("Recording '{}' already exists.", recording.name))
+        }
+        Ok(recording)
+            if mqtt
+                .get_client_actor_addr(&recording.client)
+                .await
+                .is_none() =>
+        {
+            Err(format!("Client '{}' is not connected.", recording.client))
+        }
+        other => other,
+    };
+    let recording = match recording {
+        Ok(recording) => recording,
+        Err(e) => return Ok(form_error(&req, "#recording-errors", &e)),
+    };
+    recording.insert(&db).await?;
+    mqtt.reload_recordings().await;
+    let _ = mqtt.subscribe(&recording.client, &recording.filter).await;
+    stop_after_window(db.get_ref().clone(), mqtt.get_ref().clone(), &recording);
+    let template = RecordingListTemplate::load(&db, &repo).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+async fn post_stop(
+    _: LoginGuard,
+    db: web::Data<crate::DbPool>,
+    repo: web::Data<crate::Repo>,
+    mqtt: web::Data<MqttClientManager>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    stop(&db, &mqtt, &name).await?;
+    let template = RecordingListTemplate::load(&db, &repo).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+async fn delete(
+    _: LoginGuard,
+    db: web::Data<crate::DbPool>,
+    mqtt: web::Data<MqttClientManager>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    if Recording::get_by_name(&db, &name).await?.is_none() {
+        return Err(AppError::NotFound("Recording".into()));
+    }
+    stop(&db, &mqtt, &name).await?;
+    Recording::delete(&db, &name).await?;
+    Ok(HttpResponse::Ok().body(""))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ReplayForm {
+    target: String,
+    speed: String,
+    factor: Option<String>,
+    strip_prefix: Option<String>,
+    add_prefix: Option<String>,
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn post_replay(
+    _: LoginGuard,
+    req: HttpRequest,
+    db: web::Data<crate::DbPool>,
+    repo: web::Data<crate::Repo>,
+    mqtt: web::Data<MqttClientManager>,
+    auditor: Auditor,
+    form: web::Form<ReplayForm>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let form = form.into_inner();
+    let Some(mut recording) = Recording::get_by_name(&db, &name).await? else {
+        return Err(AppError::NotFound("Recording".into()));
+    };
+    let speed = match form.speed.as_str() {
+        "original" => ReplaySpeed::Original,
+        "fast" => ReplaySpeed::Fast,
+        _ => match form.factor.and_then(|factor| factor.parse::<f64>().ok()) {
+            Some(factor) if factor > 0.0 => ReplaySpeed::Scaled(factor),
+            _ => {
+                return Ok(form_error(
+                    &req,
+                    "#replay-errors",
+                    "The speed factor has to be above 0.",
+                ))
+            }
+        },
+    };
+    if mqtt.get_client_actor_addr(&form.target).await.is_none() {
+        let message = format!("Client '{}' is not connected.", form.target);
+        return Ok(form_error(&req, "#replay-errors", &message));
+    }
+    let rewrite = TopicRewrite {
+        strip_prefix: form.strip_prefix.filter(|p| !p.is_empty()),
+        add_prefix: form.add_prefix.filter(|p| !p.is_empty()),
+    };
+    recording.replay = Some(format!("replaying onto {}", form.target));
+    recording.insert(&db).await?;
+    let (pool, mqtt) = (db.get_ref().clone(), mqtt.get_ref().clone());
+    let target = form.target;
+    tokio::spawn(async move {
+        log::info!("Replaying recording {} onto {}", recording.name, target);
+        let result = mqtt
+            .replay(&recording.name, &target, speed, &rewrite)
+            .await
+            .map_err(|e| e.to_string());
+        let details = match &result {
+            Ok(sent) => serde_json::json!({"recording": recording.name, "replayed": sent}),
+            Err(e) => serde_json::json!({"recording": recording.name, "error": e}),
+        };
+        auditor
+            .record(AuditAction::Publish, &target, Some(details))
+            .await;
+        let now = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S");
+        let state = match result {
+            Ok(sent) => format!("replayed {sent} messages onto {target} at {now}"),
+            Err(e) => {
+                log::error!("Replay of {} failed: {}", recording.name, e);
+                format!("replay onto {target} failed at {now}: {e}")
+            }
+        };
+        // re-read so a stop from the ui in the meantime is not overwritten
+        let stored = async {
+            if let Some(mut stored) = Recording::get_by_name(&pool, &recording.name).await? {
+                stored.replay = Some(state);
+                stored.insert(&pool).await?;
+            }
+            Ok::<_, StorageError>(())
+        };
+        if let Err(e) = stored.await {
+            log::error!("Cannot store replay state of {}: {}", recording.name, e);
+        }
+    });
+    let template = RecordingListTemplate::load(&db, &repo).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
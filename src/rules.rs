@@ -0,0 +1,181 @@
+use crate::{
+    error::AppError,
+    middleware::{htmx::form_error, login_guard::LoginGuard},
+    models::{
+        alert::Alert,
+        mqtt_client::MqttClient,
+        rule::{Comparison, Condition, Rule, RuleAction},
+    },
+    mqtt::MqttClientManager,
+    storage::StorageError,
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use askama::Template;
+use serde::{Deserialize, Serialize};
+use serde_json_path::JsonPath;
+
+pub fn rules_scoped(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("{name}/rules")
+            .service(web::resource("").route(web::post().to(post)))
+            .service(web::resource("/{rule}").route(web::delete().to(delete))),
+    );
+}
+
+#[derive(Template)]
+#[template(path = "mqtt_client_rules.html")]
+pub struct MqttClientRulesTemplate {
+    pub name: String,
+    pub rules: Vec<Rule>,
+    pub clients: Vec<String>,
+    pub alerts: Vec<Alert>,
+}
+
+impl MqttClientRulesTemplate {
+    pub async fn load(
+        db: &crate::DbPool,
+        repo: &crate::Repo,
+        name: String,
+    ) -> Result<Self, StorageError> {
+        let rules = Rule::list(db, &name).await?;
+        let alerts = Alert::latest(db, &name, 10).await?;
+        let mut clients: Vec<String> = MqttClient::list(repo)
+            .await?
+            .into_iter()
+            .map(|c| c.name)
+            .collect();
+        clients.sort();
+        Ok(MqttClientRulesTemplate {
+            name,
+            rules,
+            clients,
+            alerts,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RuleForm {
+    name: String,
+    topic: String,
+    condition_path: Option<String>,
+    condition_op: Option<Comparison>,
+    condition_value: Option<String>,
+    republish_client: Option<String>,
+    republish_topic: Option<String>,
+    history_tag: Option<String>,
+    alert_message: Option<String>,
+}
+
+pub fn non_empty(value: Option<String>) -> Option<String> {
+    value.filter(|v| !v.trim().is_empty())
+}
+
+/// Condition of the form fields, `None` without a JSONPath.
+pub fn parse_condition(
+    path: Option<String>,
+    op: Option<Comparison>,
+    value: Option<String>,
+) -> Result<Option<Condition>, String> {
+    let Some(path) = non_empty(path) else {
+        return Ok(None);
+    };
+    if let Err(e) = JsonPath::parse(&path) {
+        return Err(format!("Invalid JSONPath: {e}"));
+    }
+    let value = non_empty(value)
+        .map(|v| serde_json::from_str(&v).unwrap_or(serde_json::Value::String(v)))
+        .unwrap_or_default();
+    Ok(Some(Condition {
+        path,
+        op: op.unwrap_or(Comparison::Exists),
+        value,
+    }))
+}
+
+impl TryFrom<RuleForm> for Rule {
+    type Error = String;
+    fn try_from(form: RuleForm) -> Result<Self, Self::Error> {
+        if form.name.trim().is_empty() {
+            return Err("Rule name is required.".into());
+        }
+        if !rumqttc::valid_filter(&form.topic) {
+            return Err(format!("'{}' is not a valid topic filter.", form.topic));
+        }
+        let condition =
+            parse_condition(form.condition_path, form.condition_op, form.condition_value)?;
+        let mut actions = Vec::new();
+        if let Some(topic) = non_empty(form.republish_topic) {
+            let Some(client) = non_empty(form.republish_client) else {
+                return Err("Select a client to republish to.".into());
+            };
+            actions.push(RuleAction::Republish { client, topic });
+        }
+        if let Some(tag) = non_empty(form.history_tag) {
+            actions.push(RuleAction::History { tag });
+        }
+        if let Some(message) = non_empty(form.alert_message) {
+            actions.push(RuleAction::Alert { message });
+        }
+        if actions.is_empty() {
+            return Err("A rule needs at least one action.".into());
+        }
+        Ok(Rule {
+            name: form.name,
+            topic: form.topic,
+            condition,
+            actions,
+        })
+    }
+}
+
+async fn post(
+    _: LoginGuard,
+    req: HttpRequest,
+    db: web::Data<crate::DbPool>,
+    repo: web::Data<crate::Repo>,
+    mqtt: web::Data<MqttClientManager>,
+    form: web::Form<RuleForm>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let rule: Rule = match form.into_inner().try_into() {
+        Ok(rule) => rule,
+        Err(e) => return Ok(form_error(&req, "#rule-errors", &e)),
+    };
+    if rule.republishes_into_itself(&name) {
+        let message = format!(
+            "Republishing onto {name} to a topic matching '{}' would trigger the rule again.",
+            rule.topic
+        );
+        return Ok(form_error(&req, "#rule-errors", &message));
+    }
+    rule.insert(&db, &name).await?;
+    mqtt.reload_rules(&name).await;
+    if mqtt.get_client_actor_addr(&name).await.is_some() {
+        let _ = mqtt.subscribe(&name, &rule.topic).await;
+    }
+    let template = MqttClientRulesTemplate::load(&db, &repo, name.into_inner()).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+async fn delete(
+    _: LoginGuard,
+    db: web::Data<crate::DbPool>,
+    repo: web::Data<crate::Repo>,
+    mqtt: web::Data<MqttClientManager>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, AppError> {
+    let (name, rule) = path.into_inner();
+    let Some(rule) = Rule::list(&db, &name)
+        .await?
+        .into_iter()
+        .find(|existing| existing.name == rule)
+    else {
+        return Err(AppError::NotFound("Rule".into()));
+    };
+    Rule::delete(&db, &name, &rule.name).await?;
+    mqtt.reload_rules(&name).await;
+    mqtt.release_filter(&name, &rule.topic).await?;
+    let template = MqttClientRulesTemplate::load(&db, &repo, name).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
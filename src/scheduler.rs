@@ -0,0 +1,108 @@
+use std::time::Duration;
+
+use crate::{
+    models::{mqtt_client::MqttClient, schedule::Schedule},
+    mqtt::MqttClientManager,
+    payload::{self, PayloadContext},
+    storage::StorageError,
+};
+
+const TICK: Duration = Duration::from_secs(1);
+
+/// Background task running the scheduled publishes of all clients.
+pub async fn run(pool: crate::DbPool, repo: crate::Repo, mqtt: MqttClientManager) {
+    loop {
+        // run each tick on its own task so a failing redis does not end the scheduler
+        let tick = tokio::spawn(run_due(pool.clone(), repo.clone(), mqtt.clone()));
+        if let Err(e) = tick.await {
+            log::error!("Scheduler tick failed: {e}");
+        }
+        tokio::time::sleep(TICK).await;
+    }
+}
+
+async fn run_due(pool: crate::DbPool, repo: crate::Repo, mqtt: MqttClientManager) {
+    let now = chrono::Utc::now().timestamp_millis();
+    let clients = match MqttClient::list(&repo).await {
+        Ok(clients) => clients,
+        Err(e) => {
+            log::error!("Scheduler cannot list clients: {e}");
+            return;
+        }
+    };
+    for client in clients {
+        if let Err(e) = run_client(&pool, &mqtt, &client.name, now).await {
+            log::error!(
+                "Scheduler cannot run schedules of client {}: {}",
+                client.name,
+                e
+            );
+        }
+    }
+}
+
+async fn run_client(
+    pool: &crate::DbPool,
+    mqtt: &MqttClientManager,
+    client: &String,
+    now: i64,
+) -> Result<(), StorageError> {
+    for schedule in Schedule::list(pool, client).await? {
+        if !schedule.enabled || schedule.next_run.is_none_or(|next| next > now) {
+            continue;
+        }
+        let error = run_schedule(mqtt, client, &schedule).await.err();
+        if let Some(e) = &error {
+            log::error!(
+                "Scheduled publish {} of client {} failed: {}",
+                schedule.name,
+                client,
+                e
+            );
+        }
+        // re-read so a toggle from the ui in the meantime is not overwritten
+        let Some(mut schedule) = Schedule::get_by_name(pool, client, &schedule.name).await? else {
+            continue;
+        };
+        schedule.runs += 1;
+        schedule.last_run = Some(now);
+        schedule.last_error = error;
+        schedule.next_run = if schedule.enabled {
+            schedule.trigger.next_after(now)
+        } else {
+            None
+        };
+        schedule.insert(pool, client).await?;
+    }
+    Ok(())
+}
+
+async fn run_schedule(
+    mqtt: &MqttClientManager,
+    client: &String,
+    schedule: &Schedule,
+) -> Result<(), String> {
+    if mqtt.get_client_actor_addr(client).await.is_none() {
+        return Err("client is not registered".into());
+    }
+    let ctx = PayloadContext {
+        counter: schedule.runs + 1,
+        ..Default::default()
+    };
+    let payload = payload::render(&schedule.payload, &ctx)?;
+    let qos = rumqttc::qos(schedule.qos).map_err(|e| format!("{e:?}"))?;
+    log::info!(
+        "Running scheduled publish {} of client {}",
+        schedule.name,
+        client
+    );
+    mqtt.publish_with(
+        client,
+        schedule.topic.clone(),
+        payload.into_bytes(),
+        qos,
+        schedule.retain,
+    )
+    .await
+    .map_err(|e| e.to_string())
+}
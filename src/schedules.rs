@@ -0,0 +1,203 @@
+use crate::{
+    error::AppError,
+    middleware::{htmx::form_error, login_guard::LoginGuard},
+    models::schedule::{Schedule, ScheduleTrigger},
+    payload::{self, PayloadContext},
+    storage::StorageError,
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use askama::Template;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+pub fn schedules_scoped(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("{name}/schedules")
+            .service(web::resource("").route(web::post().to(post)))
+            .service(web::resource("/{schedule}").route(web::delete().to(delete)))
+            .service(web::resource("/{schedule}/toggle").route(web::post().to(post_toggle))),
+    );
+}
+
+#[derive(Template)]
+#[template(path = "mqtt_client_schedules.html")]
+struct MqttClientSchedulesTemplate {
+    name: String,
+    schedules: Vec<Schedule>,
+}
+
+impl MqttClientSchedulesTemplate {
+    async fn load(db: &crate::DbPool, name: String) -> Result<Self, StorageError> {
+        let schedules = Schedule::list(db, &name).await?;
+        Ok(MqttClientSchedulesTemplate { name, schedules })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ScheduleForm {
+    name: String,
+    topic: String,
+    payload: String,
+    qos: u8,
+    retain: Option<String>,
+    trigger: String,
+    cron: Option<String>,
+    interval: Option<u64>,
+}
+
+impl TryFrom<ScheduleForm> for Schedule {
+    type Error = String;
+    fn try_from(form: ScheduleForm) -> Result<Self, Self::Error> {
+        if form.name.trim().is_empty() {
+            return Err("Schedule name is required.".into());
+        }
+        if !rumqttc::valid_topic(&form.topic) {
+            return Err(format!("'{}' is not a valid topic.", form.topic));
+        }
+        if form.qos > 2 {
+            return Err(format!("'{}' is not a valid QoS.", form.qos));
+        }
+        let ctx = PayloadContext {
+            counter: 1,
+            ..Default::default()
+        };
+        payload::render(&form.payload, &ctx)?;
+        let trigger = match form.trigger.as_str() {
+            "cron" => {
+                let expression = form.cron.unwrap_or_default();
+                if let Err(e) = cron::Schedule::from_str(&expression) {
+                    return Err(format!("Invalid cron expression: {e}"));
+                }
+                ScheduleTrigger::Cron(expression)
+            }
+            _ => match form.interval {
+                Some(seconds) if seconds > 0 => ScheduleTrigger::Interval(seconds),
+                _ => return Err("The interval needs to be at least one second.".into()),
+            },
+        };
+        let now = chrono::Utc::now().timestamp_millis();
+        Ok(Schedule {
+            name: form.name,
+            topic: form.topic,
+            payload: form.payload,
+            qos: form.qos,
+            retain: form.retain.is_some(),
+            next_run: trigger.next_after(now),
+            trigger,
+            enabled: true,
+            last_run: None,
+            runs: 0,
+            last_error: None,
+        })
+    }
+}
+
+async fn post(
+    _: LoginGuard,
+    req: HttpRequest,
+    db: web::Data<crate::DbPool>,
+    form: web::Form<ScheduleForm>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let schedule: Schedule = match form.into_inner().try_into() {
+        Ok(schedule) => schedule,
+        Err(e) => return Ok(form_error(&req, "#schedule-errors", &e)),
+    };
+    schedule.insert(&db, &name).await?;
+    let template = MqttClientSchedulesTemplate::load(&db, name.into_inner()).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+async fn delete(
+    _: LoginGuard,
+    db: web::Data<crate::DbPool>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, AppError> {
+    let (name, schedule) = path.into_inner();
+    if !Schedule::delete(&db, &name, &schedule).await? {
+        return Err(AppError::NotFound("Schedule".into()));
+    }
+    let template = MqttClientSchedulesTemplate::load(&db, name).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+async fn post_toggle(
+    _: LoginGuard,
+    db: web::Data<crate::DbPool>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, AppError> {
+    let (name, schedule) = path.into_inner();
+    let Some(mut schedule) = Schedule::get_by_name(&db, &name, &schedule).await? else {
+        return Err(AppError::NotFound("Schedule".into()));
+    };
+    schedule.enabled = !schedule.enabled;
+    schedule.next_run = if schedule.enabled {
+        schedule
+            .trigger
+            .next_after(chrono::Utc::now().timestamp_millis())
+    } else {
+        None
+    };
+    schedule.insert(&db, &name).await?;
+    let template = MqttClientSchedulesTemplate::load(&db, name).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn form(trigger: &str, cron: Option<&str>, interval: Option<u64>) -> ScheduleForm {
+        ScheduleForm {
+            name: "heartbeat".into(),
+            topic: "status/heartbeat".into(),
+            payload: "{{counter}}".into(),
+            qos: 0,
+            retain: None,
+            trigger: trigger.into(),
+            cron: cron.map(String::from),
+            interval,
+        }
+    }
+
+    /// 2024-01-01 00:00:00 UTC in milliseconds.
+    const NEW_YEAR: i64 = 1_704_067_200_000;
+
+    #[test]
+    fn parses_cron_expressions_with_seconds() {
+        let schedule = Schedule::try_from(form("cron", Some("0 */5 * * * *"), None)).unwrap();
+        let ScheduleTrigger::Cron(expression) = &schedule.trigger else {
+            panic!("expected a cron trigger, got {}", schedule.trigger);
+        };
+        assert_eq!(expression, "0 */5 * * * *");
+        assert!(schedule.next_run.is_some());
+        // strictly after, a run on the boundary is not repeated
+        assert_eq!(
+            schedule.trigger.next_after(NEW_YEAR),
+            Some(NEW_YEAR + 5 * 60 * 1000)
+        );
+        assert_eq!(schedule.trigger.next_after(NEW_YEAR - 1), Some(NEW_YEAR));
+    }
+
+    #[test]
+    fn refuses_invalid_cron_expressions() {
+        for expression in ["", "every minute", "0 61 * * * *"] {
+            let error = Schedule::try_from(form("cron", Some(expression), None)).unwrap_err();
+            assert!(
+                error.starts_with("Invalid cron expression"),
+                "{expression}: {error}"
+            );
+        }
+    }
+
+    #[test]
+    fn parses_intervals_of_at_least_a_second() {
+        let schedule = Schedule::try_from(form("interval", None, Some(90))).unwrap();
+        assert_eq!(
+            schedule.trigger.next_after(NEW_YEAR),
+            Some(NEW_YEAR + 90_000)
+        );
+        assert!(Schedule::try_from(form("interval", None, Some(0))).is_err());
+        assert!(Schedule::try_from(form("interval", None, None)).is_err());
+    }
+}
@@ -0,0 +1,394 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use actix_session::storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError};
+use actix_web::cookie::time::Duration;
+use bb8_redis::redis::{cmd, pipe};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::{settings::SessionSettings, storage::StorageError};
+
+/// Redis set holding the keys of all sessions, expired ones are pruned on listing.
+const SESSIONS_KEY: &str = "sessions";
+
+/// A login session as kept on the server, the cookie only carries its key.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredSession {
+    /// public id used to revoke the session, never the session key itself
+    pub id: String,
+    /// unix timestamp in milliseconds
+    pub created: i64,
+    /// unix timestamp in milliseconds of the last request
+    pub last_seen: i64,
+    /// entries of the actix session, values are JSON encoded
+    state: HashMap<String, String>,
+}
+
+impl StoredSession {
+    fn state_value(&self, key: &str) -> Option<String> {
+        self.state
+            .get(key)
+            .and_then(|value| serde_json::from_str(value).ok())
+    }
+
+    pub fn username(&self) -> Option<String> {
+        self.state_value("username")
+    }
+
+    /// `local` or the name of the oauth or LDAP provider the user logged in with.
+    pub fn source(&self) -> String {
+        self.state_value("oauth_config")
+            .or_else(|| self.state_value("ldap_config"))
+            .unwrap_or_else(|| "local".to_string())
+    }
+
+    fn format(timestamp: i64) -> String {
+        chrono::DateTime::from_timestamp_millis(timestamp)
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn created_time(&self) -> String {
+        Self::format(self.created)
+    }
+
+    pub fn last_seen_time(&self) -> String {
+        Self::format(self.last_seen)
+    }
+}
+
+/// Sessions by key with their expiry in unix milliseconds.
+type MemorySessions = HashMap<String, (StoredSession, i64)>;
+
+/// Where the sessions live, redis if mqttpal has a connection to it.
+#[derive(Clone)]
+enum Backend {
+    Redis(crate::DbPool),
+    /// lost on restart
+    Memory(Arc<Mutex<MemorySessions>>),
+}
+
+/// Server side session store with idle and absolute timeouts.
+///
+/// The idle timeout is the ttl handed in by the session middleware, which
+/// extends it on every request. The absolute timeout counts from the login
+/// and is never extended.
+#[derive(Clone)]
+pub struct ServerSessionStore {
+    backend: Backend,
+    /// milliseconds
+    absolute_timeout: i64,
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+fn session_key(key: &str) -> String {
+    format!("session:{}", key)
+}
+
+impl ServerSessionStore {
+    pub fn new(pool: Option<crate::DbPool>, settings: &SessionSettings) -> Self {
+        let backend = match pool {
+            Some(pool) => Backend::Redis(pool),
+            None => {
+                log::warn!("Sessions are kept in memory, everybody is logged out on restart");
+                Backend::Memory(Arc::new(Mutex::new(HashMap::new())))
+            }
+        };
+        ServerSessionStore {
+            backend,
+            absolute_timeout: settings.absolute_timeout as i64 * 1000,
+        }
+    }
+
+    fn memory(
+        sessions: &Mutex<MemorySessions>,
+    ) -> Result<std::sync::MutexGuard<'_, MemorySessions>, StorageError> {
+        sessions
+            .lock()
+            .map_err(|_| StorageError::Backend("session store is poisoned".into()))
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<StoredSession>, StorageError> {
+        let session = match &self.backend {
+            Backend::Redis(pool) => {
+                let mut conn = pool.get().await?;
+                let session: Option<String> = cmd("GET")
+                    .arg(session_key(key))
+                    .query_async(&mut *conn)
+                    .await?;
+                session
+                    .map(|session| serde_json::from_str(&session))
+                    .transpose()
+                    .map_err(StorageError::from)?
+            }
+            Backend::Memory(sessions) => Self::memory(sessions)?
+                .get(key)
+                .filter(|(_, expires)| *expires > now())
+                .map(|(session, _)| session.clone()),
+        };
+        Ok(session.filter(|session| session.created + self.absolute_timeout > now()))
+    }
+
+    /// Store the session for `ttl`, cut short by the absolute timeout.
+    ///
+    /// With `existing` the session is only written if it is still there, so a
+    /// request running while the session gets revoked cannot bring it back.
+    /// Returns whether the session was written.
+    async fn put(
+        &self,
+        key: &str,
+        session: &StoredSession,
+        ttl: &Duration,
+        existing: bool,
+    ) -> Result<bool, StorageError> {
+        let ttl = ttl
+            .whole_milliseconds()
+            .min((session.created + self.absolute_timeout - now()) as i128)
+            .max(1000) as i64;
+        match &self.backend {
+            Backend::Redis(pool) => {
+                let mut conn = pool.get().await?;
+                let mut set = cmd("SET");
+                set.arg(session_key(key))
+                    .arg(serde_json::to_string(session)?)
+                    .arg("PX")
+                    .arg(ttl);
+                if existing {
+                    set.arg("XX");
+                }
+                let written: Option<String> = set.query_async(&mut *conn).await?;
+                if written.is_none() {
+                    return Ok(false);
+                }
+                let _: () = cmd("SADD")
+                    .arg(SESSIONS_KEY)
+                    .arg(key)
+                    .query_async(&mut *conn)
+                    .await?;
+            }
+            Backend::Memory(sessions) => {
+                let mut sessions = Self::memory(sessions)?;
+                let now = now();
+                if existing {
+                    if sessions.get(key).is_none_or(|(_, expires)| *expires <= now) {
+                        return Ok(false);
+                    }
+                } else {
+                    // new logins clean up behind the expired sessions
+                    sessions.retain(|_, (_, expires)| *expires > now);
+                }
+                sessions.insert(key.to_string(), (session.clone(), now + ttl));
+            }
+        }
+        Ok(true)
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), StorageError> {
+        match &self.backend {
+            Backend::Redis(pool) => {
+                let mut conn = pool.get().await?;
+                let _: () = pipe()
+                    .cmd("DEL")
+                    .arg(session_key(key))
+                    .ignore()
+                    .cmd("SREM")
+                    .arg(SESSIONS_KEY)
+                    .arg(key)
+                    .ignore()
+                    .query_async(&mut *conn)
+                    .await?;
+            }
+            Backend::Memory(sessions) => {
+                Self::memory(sessions)?.remove(key);
+            }
+        }
+        Ok(())
+    }
+
+    /// All active sessions by their key.
+    async fn entries(&self) -> Result<Vec<(String, StoredSession)>, StorageError> {
+        let now = now();
+        let entries = match &self.backend {
+            Backend::Redis(pool) => {
+                let mut conn = pool.get().await?;
+                let keys: Vec<String> = cmd("SMEMBERS")
+                    .arg(SESSIONS_KEY)
+                    .query_async(&mut *conn)
+                    .await?;
+                if keys.is_empty() {
+                    return Ok(Vec::new());
+                }
+                let sessions: Vec<Option<String>> = cmd("MGET")
+                    .arg(keys.iter().map(|key| session_key(key)).collect::<Vec<_>>())
+                    .query_async(&mut *conn)
+                    .await?;
+                let mut entries = Vec::new();
+                let mut expired = Vec::new();
+                for (key, session) in keys.into_iter().zip(sessions) {
+                    match session {
+                        Some(session) => entries.push((key, serde_json::from_str(&session)?)),
+                        None => expired.push(key),
+                    }
+                }
+                if !expired.is_empty() {
+                    let _: () = cmd("SREM")
+                        .arg(SESSIONS_KEY)
+                        .arg(expired)
+                        .query_async(&mut *conn)
+                        .await?;
+                }
+                entries
+            }
+            Backend::Memory(sessions) => {
+                let mut sessions = Self::memory(sessions)?;
+                sessions.retain(|_, (_, expires)| *expires > now);
+                sessions
+                    .iter()
+                    .map(|(key, (session, _))| (key.clone(), session.clone()))
+                    .collect()
+            }
+        };
+        Ok(entries
+            .into_iter()
+            .filter(|(_, session)| session.created + self.absolute_timeout > now)
+            .collect())
+    }
+
+    /// Active sessions of logged in users, ordered by user and newest first.
+    pub async fn list(&self) -> Result<Vec<StoredSession>, StorageError> {
+        let mut sessions: Vec<StoredSession> = self
+            .entries()
+            .await?
+            .into_iter()
+            .map(|(_, session)| session)
+            .filter(|session| session.username().is_some())
+            .collect();
+        sessions.sort_by(|a, b| {
+            a.username()
+                .cmp(&b.username())
+                .then(b.last_seen.cmp(&a.last_seen))
+        });
+        Ok(sessions)
+    }
+
+    /// Log out the session with the public `id`, returns false if there is none.
+    pub async fn revoke(&self, id: &str) -> Result<bool, StorageError> {
+        let Some((key, session)) = self
+            .entries()
+            .await?
+            .into_iter()
+            .find(|(_, session)| session.id == id)
+        else {
+            return Ok(false);
+        };
+        log::info!(
+            "Revoking session {} of {}",
+            id,
+            session.username().unwrap_or_default()
+        );
+        self.remove(&key).await?;
+        Ok(true)
+    }
+
+    /// Log out every session of `username`, returns how many there were.
+    pub async fn revoke_user(&self, username: &str) -> Result<usize, StorageError> {
+        let mut revoked = 0;
+        for (key, session) in self.entries().await? {
+            if session.username().as_deref() == Some(username) {
+                self.remove(&key).await?;
+                revoked += 1;
+            }
+        }
+        log::info!("Revoked {} sessions of {}", revoked, username);
+        Ok(revoked)
+    }
+}
+
+fn generate_key() -> String {
+    rand::thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(64)
+        .map(char::from)
+        .collect()
+}
+
+#[async_trait::async_trait(?Send)]
+impl SessionStore for ServerSessionStore {
+    async fn load(
+        &self,
+        session_key: &SessionKey,
+    ) -> Result<Option<HashMap<String, String>>, LoadError> {
+        let session = self
+            .get(session_key.as_ref())
+            .await
+            .map_err(|e| LoadError::Other(e.into()))?;
+        Ok(session.map(|session| session.state))
+    }
+
+    async fn save(
+        &self,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        let key = generate_key();
+        let session = StoredSession {
+            id: format!("{:032x}", rand::random::<u128>()),
+            created: now(),
+            last_seen: now(),
+            state: session_state,
+        };
+        self.put(&key, &session, ttl, false)
+            .await
+            .map_err(|e| SaveError::Other(e.into()))?;
+        SessionKey::try_from(key).map_err(|e| SaveError::Other(e.into()))
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        let session = self
+            .get(session_key.as_ref())
+            .await
+            .map_err(|e| UpdateError::Other(e.into()))?;
+        let written = match session {
+            Some(mut session) => {
+                session.state = session_state;
+                session.last_seen = now();
+                self.put(session_key.as_ref(), &session, ttl, true)
+                    .await
+                    .map_err(|e| UpdateError::Other(e.into()))?
+            }
+            None => false,
+        };
+        if written {
+            Ok(session_key)
+        } else {
+            // the state still holds the login, it must not outlive a revoke
+            Err(UpdateError::Other(anyhow::anyhow!(
+                "session was revoked or expired"
+            )))
+        }
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> anyhow::Result<()> {
+        if let Some(mut session) = self.get(session_key.as_ref()).await? {
+            session.last_seen = now();
+            self.put(session_key.as_ref(), &session, ttl, true).await?;
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> anyhow::Result<()> {
+        self.remove(session_key.as_ref()).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+use crate::{
+    error::AppError,
+    middleware::{admin_guard::AdminGuard, fullpage_render::FullPageRender},
+    session_store::{ServerSessionStore, StoredSession},
+    storage::StorageError,
+};
+use actix_web::{web, HttpResponse};
+use askama::Template;
+
+pub fn sessions_scoped(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/sessions")
+            .service(web::resource("/").route(web::get().to(get).wrap(FullPageRender)))
+            .service(web::resource("/user/{name}").route(web::delete().to(delete_user)))
+            .service(web::resource("/{id}").route(web::delete().to(delete))),
+    );
+}
+
+#[derive(Template)]
+#[template(path = "sessions.html")]
+struct SessionListTemplate {
+    /// sessions grouped by user
+    users: Vec<(String, Vec<StoredSession>)>,
+}
+
+impl SessionListTemplate {
+    async fn load(store: &ServerSessionStore) -> Result<Self, StorageError> {
+        let mut users: Vec<(String, Vec<StoredSession>)> = Vec::new();
+        // the store lists them ordered by user already
+        for session in store.list().await? {
+            let username = session.username().unwrap_or_default();
+            match users.last_mut() {
+                Some((user, sessions)) if *user == username => sessions.push(session),
+                _ => users.push((username, vec![session])),
+            }
+        }
+        Ok(SessionListTemplate { users })
+    }
+}
+
+async fn get(
+    _: AdminGuard,
+    store: web::Data<ServerSessionStore>,
+) -> Result<HttpResponse, AppError> {
+    let template = SessionListTemplate::load(&store).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+async fn delete(
+    _: AdminGuard,
+    store: web::Data<ServerSessionStore>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    if !store.revoke(&id).await? {
+        return Err(AppError::NotFound("Session".into()));
+    }
+    let template = SessionListTemplate::load(&store).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+async fn delete_user(
+    _: AdminGuard,
+    store: web::Data<ServerSessionStore>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    store.revoke_user(&name).await?;
+    let template = SessionListTemplate::load(&store).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
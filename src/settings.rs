@@ -0,0 +1,771 @@
+use std::{
+    net::ToSocketAddrs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use base64::Engine;
+use clap::Args;
+use rumqttc::MqttOptions;
+use serde::{Deserialize, Serialize};
+
+use crate::models::{
+    alert::ALERTS_MAX_LEN, alert_definition::NOTIFICATIONS_MAX_LEN, audit::AUDIT_MAX_LEN,
+    history::HISTORY_MAX_LEN, recording::RECORDING_MAX_LEN, webhook::LOG_MAX_LEN,
+};
+
+/// Server settings, layered from the config file, the environment and the
+/// flags of `serve`, later layers win.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// `redis://`, `sqlite://` or `memory://`
+    pub database_url: Option<String>,
+    /// base64 encoded 64 byte key, a random one is generated if missing
+    pub session_key: Option<String>,
+    /// base64 encoded 32 byte key encrypting the TOTP secrets, two-factor
+    /// authentication is not available without it
+    pub totp_key: Option<String>,
+    /// YAML file with the oauth providers
+    pub oauth_file: Option<PathBuf>,
+    /// YAML file with the LDAP directories
+    pub ldap_file: Option<PathBuf>,
+    pub server: ServerSettings,
+    pub session: SessionSettings,
+    pub login: LoginSettings,
+    pub pool: PoolSettings,
+    pub mqtt: MqttSettings,
+    pub retention: RetentionSettings,
+    pub shutdown: ShutdownSettings,
+    pub health: HealthSettings,
+    pub smtp: SmtpSettings,
+    pub log: LogSettings,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub bind: String,
+    /// url the server is reached at from the browser, used for oauth redirects
+    pub public_url: String,
+    /// PEM certificate chain, `bind` serves HTTPS when set together with `tls_key`
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of the certificate
+    pub tls_key: Option<PathBuf>,
+    /// plain HTTP address redirecting to `public_url`, e.g. 0.0.0.0:80
+    pub redirect_bind: Option<String>,
+    /// seconds between checks of the certificate files for changes, 0 disables them
+    pub tls_watch_interval: u64,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        ServerSettings {
+            bind: "0.0.0.0:8080".into(),
+            public_url: "http://localhost:8080".into(),
+            tls_cert: None,
+            tls_key: None,
+            redirect_bind: None,
+            tls_watch_interval: 30,
+        }
+    }
+}
+
+impl ServerSettings {
+    /// Scheme of the public url, `http` or `https`.
+    pub fn scheme(&self) -> &str {
+        self.public_url
+            .split_once("://")
+            .map_or("http", |(scheme, _)| scheme)
+    }
+
+    /// Whether the browser talks HTTPS to us, session cookies are only sent
+    /// over HTTPS then.
+    pub fn secure(&self) -> bool {
+        self.scheme().eq_ignore_ascii_case("https")
+    }
+
+    /// Certificate and key paths if HTTPS is enabled.
+    pub fn tls(&self) -> Option<(&Path, &Path)> {
+        self.tls_cert.as_deref().zip(self.tls_key.as_deref())
+    }
+
+    /// Public url without a trailing slash.
+    pub fn base_url(&self) -> &str {
+        self.public_url.trim_end_matches('/')
+    }
+}
+
+/// Lifetime of login sessions.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionSettings {
+    /// seconds without a request until a session ends
+    pub idle_timeout: u64,
+    /// seconds after the login until a session ends, however active it is
+    pub absolute_timeout: u64,
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        SessionSettings {
+            idle_timeout: 8 * 60 * 60,
+            absolute_timeout: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+/// Throttling of failed password logins.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoginSettings {
+    /// seconds to wait after the first failure, doubled with every further one
+    pub base_delay: u64,
+    /// upper bound of the delay in seconds
+    pub max_delay: u64,
+    /// failures of one username until it is locked out
+    pub user_lockout_after: u32,
+    /// failures from one IP address until it is locked out
+    pub ip_lockout_after: u32,
+    /// seconds a lockout lasts
+    pub lockout_duration: u64,
+    /// seconds without failures until they are forgotten
+    pub window: u64,
+    /// take the client address from `Forwarded` and `X-Forwarded-For`, only
+    /// enable this behind a reverse proxy which sets them
+    pub trust_proxy_headers: bool,
+}
+
+impl Default for LoginSettings {
+    fn default() -> Self {
+        LoginSettings {
+            base_delay: 1,
+            max_delay: 60,
+            user_lockout_after: 5,
+            ip_lockout_after: 20,
+            lockout_duration: 15 * 60,
+            window: 15 * 60,
+            trust_proxy_headers: false,
+        }
+    }
+}
+
+/// Sizing of the database connection pool.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolSettings {
+    pub max_size: u32,
+    pub min_idle: u32,
+    /// seconds to wait for a free connection
+    pub connection_timeout: u64,
+}
+
+impl Default for PoolSettings {
+    fn default() -> Self {
+        PoolSettings {
+            max_size: 5,
+            min_idle: 2,
+            connection_timeout: 5,
+        }
+    }
+}
+
+impl PoolSettings {
+    pub fn connection_timeout(&self) -> Duration {
+        Duration::from_secs(self.connection_timeout)
+    }
+}
+
+/// Options of the MQTT connections, parameters given in a client url take precedence.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttSettings {
+    /// bytes, for incoming and outgoing packets
+    pub max_packet_size: usize,
+    /// seconds
+    pub keep_alive: u64,
+    /// requests queued per client before publishing blocks
+    pub channel_capacity: usize,
+}
+
+impl Default for MqttSettings {
+    fn default() -> Self {
+        MqttSettings {
+            max_packet_size: 100000,
+            keep_alive: 60,
+            channel_capacity: 10,
+        }
+    }
+}
+
+impl MqttSettings {
+    /// Apply the settings to `options` parsed from `url`, unless the url sets them itself.
+    pub fn apply(&self, url: &str, options: &mut MqttOptions) {
+        if !url.contains("keep_alive_secs=") {
+            options.set_keep_alive(Duration::from_secs(self.keep_alive));
+        }
+        if !url.contains("_packet_size_bytes=") {
+            options.set_max_packet_size(self.max_packet_size, self.max_packet_size);
+        }
+    }
+
+    /// Same as [`MqttSettings::apply`] for MQTT v5 connections.
+    pub fn apply_v5(&self, url: &str, options: &mut rumqttc::v5::MqttOptions) {
+        if !url.contains("keep_alive_secs=") {
+            options.set_keep_alive(Duration::from_secs(self.keep_alive));
+        }
+        if !url.contains("_packet_size_bytes=") {
+            options.set_max_packet_size(Some(self.max_packet_size as u32));
+        }
+    }
+}
+
+/// Number of entries kept per client, recording, webhook or user.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionSettings {
+    pub history: usize,
+    pub alerts: usize,
+    pub recording: usize,
+    pub webhook_log: usize,
+    pub audit: usize,
+    pub notifications: usize,
+}
+
+impl Default for RetentionSettings {
+    fn default() -> Self {
+        RetentionSettings {
+            history: HISTORY_MAX_LEN,
+            alerts: ALERTS_MAX_LEN,
+            recording: RECORDING_MAX_LEN,
+            webhook_log: LOG_MAX_LEN,
+            audit: AUDIT_MAX_LEN,
+            notifications: NOTIFICATIONS_MAX_LEN,
+        }
+    }
+}
+
+/// What happens to the MQTT clients on SIGTERM or Ctrl-C.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownSettings {
+    /// seconds to wait for clients to disconnect and connections to close
+    pub timeout: u64,
+    /// published before each client disconnects, e.g. to clear a retained status
+    pub goodbye: Vec<GoodbyeMessage>,
+}
+
+impl Default for ShutdownSettings {
+    fn default() -> Self {
+        ShutdownSettings {
+            timeout: 10,
+            goodbye: Vec::new(),
+        }
+    }
+}
+
+impl ShutdownSettings {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GoodbyeMessage {
+    /// client publishing the message, all clients if missing
+    pub client: Option<String>,
+    /// `{client}` is replaced by the name of the client
+    pub topic: String,
+    /// an empty retained payload deletes the retained message
+    #[serde(default)]
+    pub payload: String,
+    #[serde(default)]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+}
+
+impl GoodbyeMessage {
+    pub fn applies_to(&self, client: &str) -> bool {
+        self.client.as_deref().is_none_or(|c| c == client)
+    }
+
+    pub fn topic_for(&self, client: &str) -> String {
+        self.topic.replace("{client}", client)
+    }
+}
+
+/// What `/readyz` checks besides storage and oauth.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthSettings {
+    /// clients which have to be connected for mqttpal to be ready
+    pub required_clients: Vec<String>,
+}
+
+/// Mail server sending the alert notifications, there is no mail without a host.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpSettings {
+    pub host: Option<String>,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// sender address, e.g. `MQTTPal <mqttpal@example.com>`
+    pub from: String,
+}
+
+impl Default for SmtpSettings {
+    fn default() -> Self {
+        SmtpSettings {
+            host: None,
+            port: 587,
+            security: SmtpSecurity::Starttls,
+            username: None,
+            password: None,
+            from: "mqttpal@localhost".into(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// upgrade the plain connection, usually on port 587
+    Starttls,
+    /// TLS from the start, usually on port 465
+    Tls,
+    /// plain text, only for local mail relays
+    None,
+}
+
+impl FromStr for SmtpSecurity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "starttls" => Ok(SmtpSecurity::Starttls),
+            "tls" => Ok(SmtpSecurity::Tls),
+            "none" => Ok(SmtpSecurity::None),
+            _ => Err("use starttls, tls or none".into()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSettings {
+    /// filter in `RUST_LOG` syntax, e.g. `info,mqttpal=debug`
+    pub filter: String,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        LogSettings {
+            filter: "info".into(),
+        }
+    }
+}
+
+/// Flags of `serve` and `check-config` overriding the config file and the environment.
+#[derive(Args, Debug, Default, Clone)]
+pub struct ServeArgs {
+    /// address to listen on, e.g. 0.0.0.0:8080
+    #[arg(long)]
+    pub bind: Option<String>,
+    /// url the server is reached at, e.g. https://mqttpal.example.com
+    #[arg(long)]
+    pub public_url: Option<String>,
+    /// PEM certificate chain to serve HTTPS with
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of the certificate
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// plain HTTP address redirecting to the public url
+    #[arg(long)]
+    pub redirect_bind: Option<String>,
+    /// maximum number of database connections
+    #[arg(long)]
+    pub pool_max_size: Option<u32>,
+    /// database connections kept open while idle
+    #[arg(long)]
+    pub pool_min_idle: Option<u32>,
+    /// seconds to wait for a database connection
+    #[arg(long)]
+    pub pool_timeout: Option<u64>,
+    /// maximum MQTT packet size in bytes
+    #[arg(long)]
+    pub mqtt_max_packet_size: Option<usize>,
+    /// MQTT keep alive in seconds
+    #[arg(long)]
+    pub mqtt_keep_alive: Option<u64>,
+    /// messages kept in the history of each client
+    #[arg(long)]
+    pub history_max_len: Option<usize>,
+    /// seconds to wait for clients and connections on shutdown
+    #[arg(long)]
+    pub shutdown_timeout: Option<u64>,
+    /// client which has to be connected for /readyz, may be repeated
+    #[arg(long = "required-client")]
+    pub required_clients: Vec<String>,
+    /// log filter in RUST_LOG syntax
+    #[arg(long)]
+    pub log: Option<String>,
+}
+
+impl ServeArgs {
+    pub fn apply(&self, settings: &mut Settings) {
+        fn set<T: Clone>(target: &mut T, value: &Option<T>) {
+            if let Some(value) = value {
+                *target = value.clone();
+            }
+        }
+        set(&mut settings.server.bind, &self.bind);
+        set(&mut settings.server.public_url, &self.public_url);
+        if self.tls_cert.is_some() {
+            settings.server.tls_cert = self.tls_cert.clone();
+            settings.server.tls_key = self.tls_key.clone();
+        }
+        if self.redirect_bind.is_some() {
+            settings.server.redirect_bind = self.redirect_bind.clone();
+        }
+        set(&mut settings.pool.max_size, &self.pool_max_size);
+        set(&mut settings.pool.min_idle, &self.pool_min_idle);
+        set(&mut settings.pool.connection_timeout, &self.pool_timeout);
+        set(
+            &mut settings.mqtt.max_packet_size,
+            &self.mqtt_max_packet_size,
+        );
+        set(&mut settings.mqtt.keep_alive, &self.mqtt_keep_alive);
+        set(&mut settings.retention.history, &self.history_max_len);
+        set(&mut settings.shutdown.timeout, &self.shutdown_timeout);
+        if !self.required_clients.is_empty() {
+            settings.health.required_clients = self.required_clients.clone();
+        }
+        set(&mut settings.log.filter, &self.log);
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn env_parse<T>(name: &str, target: &mut T) -> anyhow::Result<()>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    if let Some(value) = env_var(name) {
+        *target = value
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid value '{value}' for {name}: {e}"))?;
+    }
+    Ok(())
+}
+
+impl Settings {
+    /// Read the config file, if any, and apply the environment on top.
+    ///
+    /// Without `file` the path is taken from `MQTTPAL_CONFIG`.
+    pub fn load(file: Option<&Path>) -> anyhow::Result<Settings> {
+        let file = file
+            .map(Path::to_path_buf)
+            .or_else(|| env_var("MQTTPAL_CONFIG").map(PathBuf::from));
+        let mut settings = match file {
+            Some(file) => Self::from_file(&file)
+                .with_context(|| format!("cannot read config file {}", file.display()))?,
+            None => Settings::default(),
+        };
+        settings.apply_env()?;
+        Ok(settings)
+    }
+
+    fn from_file(file: &Path) -> anyhow::Result<Settings> {
+        let content = std::fs::read_to_string(file)?;
+        match file.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Ok(toml::from_str(&content)?),
+            Some("yaml" | "yml") => Ok(serde_yaml::from_str(&content)?),
+            _ => bail!("unknown config format, use a .toml, .yaml or .yml file"),
+        }
+    }
+
+    fn apply_env(&mut self) -> anyhow::Result<()> {
+        if let Some(url) = env_var("DATABASE_URL") {
+            self.database_url = Some(url);
+        }
+        if let Some(key) = env_var("SESSION_KEY") {
+            self.session_key = Some(key);
+        }
+        if let Some(key) = env_var("MQTTPAL_TOTP_KEY") {
+            self.totp_key = Some(key);
+        }
+        if let Some(file) = env_var("OAUTH_FILE") {
+            self.oauth_file = Some(file.into());
+        }
+        if let Some(file) = env_var("LDAP_FILE") {
+            self.ldap_file = Some(file.into());
+        }
+        // host and port only, kept for existing deployments
+        if let Some(host) = env_var("WEB_HOSTNAME") {
+            self.server.public_url = format!("http://{host}");
+        }
+        if let Some(filter) = env_var("RUST_LOG") {
+            self.log.filter = filter;
+        }
+        env_parse("MQTTPAL_BIND", &mut self.server.bind)?;
+        env_parse("MQTTPAL_PUBLIC_URL", &mut self.server.public_url)?;
+        if let Some(cert) = env_var("MQTTPAL_TLS_CERT") {
+            self.server.tls_cert = Some(cert.into());
+        }
+        if let Some(key) = env_var("MQTTPAL_TLS_KEY") {
+            self.server.tls_key = Some(key.into());
+        }
+        if let Some(bind) = env_var("MQTTPAL_REDIRECT_BIND") {
+            self.server.redirect_bind = Some(bind);
+        }
+        env_parse(
+            "MQTTPAL_TLS_WATCH_INTERVAL",
+            &mut self.server.tls_watch_interval,
+        )?;
+        env_parse(
+            "MQTTPAL_SESSION_IDLE_TIMEOUT",
+            &mut self.session.idle_timeout,
+        )?;
+        env_parse(
+            "MQTTPAL_SESSION_ABSOLUTE_TIMEOUT",
+            &mut self.session.absolute_timeout,
+        )?;
+        env_parse(
+            "MQTTPAL_LOGIN_TRUST_PROXY_HEADERS",
+            &mut self.login.trust_proxy_headers,
+        )?;
+        env_parse("MQTTPAL_POOL_MAX_SIZE", &mut self.pool.max_size)?;
+        env_parse("MQTTPAL_POOL_MIN_IDLE", &mut self.pool.min_idle)?;
+        env_parse("MQTTPAL_POOL_TIMEOUT", &mut self.pool.connection_timeout)?;
+        env_parse(
+            "MQTTPAL_MQTT_MAX_PACKET_SIZE",
+            &mut self.mqtt.max_packet_size,
+        )?;
+        env_parse("MQTTPAL_MQTT_KEEP_ALIVE", &mut self.mqtt.keep_alive)?;
+        env_parse(
+            "MQTTPAL_MQTT_CHANNEL_CAPACITY",
+            &mut self.mqtt.channel_capacity,
+        )?;
+        env_parse("MQTTPAL_HISTORY_MAX_LEN", &mut self.retention.history)?;
+        env_parse("MQTTPAL_ALERTS_MAX_LEN", &mut self.retention.alerts)?;
+        env_parse("MQTTPAL_RECORDING_MAX_LEN", &mut self.retention.recording)?;
+        env_parse(
+            "MQTTPAL_WEBHOOK_LOG_MAX_LEN",
+            &mut self.retention.webhook_log,
+        )?;
+        env_parse("MQTTPAL_AUDIT_MAX_LEN", &mut self.retention.audit)?;
+        env_parse(
+            "MQTTPAL_NOTIFICATIONS_MAX_LEN",
+            &mut self.retention.notifications,
+        )?;
+        env_parse("MQTTPAL_SHUTDOWN_TIMEOUT", &mut self.shutdown.timeout)?;
+        if let Some(clients) = env_var("MQTTPAL_REQUIRED_CLIENTS") {
+            self.health.required_clients = clients
+                .split(',')
+                .map(str::trim)
+                .filter(|client| !client.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        if let Some(host) = env_var("MQTTPAL_SMTP_HOST") {
+            self.smtp.host = Some(host);
+        }
+        env_parse("MQTTPAL_SMTP_PORT", &mut self.smtp.port)?;
+        env_parse("MQTTPAL_SMTP_SECURITY", &mut self.smtp.security)?;
+        if let Some(username) = env_var("MQTTPAL_SMTP_USERNAME") {
+            self.smtp.username = Some(username);
+        }
+        if let Some(password) = env_var("MQTTPAL_SMTP_PASSWORD") {
+            self.smtp.password = Some(password);
+        }
+        env_parse("MQTTPAL_SMTP_FROM", &mut self.smtp.from)?;
+        Ok(())
+    }
+
+    /// Check the settings for problems, all of them are reported at once.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+        match self.database_url.as_deref() {
+            None => problems.push("database_url is required".to_string()),
+            Some(url) => {
+                let scheme = url.split_once(':').map(|(scheme, _)| scheme);
+                if !matches!(
+                    scheme,
+                    Some("memory" | "sqlite" | "redis" | "rediss" | "redis+unix" | "unix")
+                ) {
+                    problems.push(format!(
+                        "unsupported database_url '{url}', use redis://, sqlite:// or memory://"
+                    ));
+                }
+            }
+        }
+        if let Some(key) = &self.session_key {
+            match base64::engine::general_purpose::STANDARD.decode(key) {
+                Ok(key) if key.len() >= 64 => {}
+                Ok(_) => problems.push("session_key has to be at least 64 bytes".to_string()),
+                Err(e) => problems.push(format!("session_key is not valid base64: {e}")),
+            }
+        }
+        if let Some(key) = &self.totp_key {
+            match base64::engine::general_purpose::STANDARD.decode(key) {
+                Ok(key) if key.len() == 32 => {}
+                Ok(_) => problems.push("totp_key has to be 32 bytes".to_string()),
+                Err(e) => problems.push(format!("totp_key is not valid base64: {e}")),
+            }
+        }
+        if let Some(file) = &self.oauth_file {
+            if !file.is_file() {
+                problems.push(format!("oauth_file {} does not exist", file.display()));
+            }
+        }
+        if let Some(file) = &self.ldap_file {
+            if !file.is_file() {
+                problems.push(format!("ldap_file {} does not exist", file.display()));
+            }
+        }
+        if let Err(e) = self.server.bind.to_socket_addrs() {
+            problems.push(format!("invalid bind address '{}': {e}", self.server.bind));
+        }
+        match url::Url::parse(&self.server.public_url) {
+            Ok(url) if !matches!(url.scheme(), "http" | "https") => problems.push(format!(
+                "public_url has to use http or https, not {}",
+                url.scheme()
+            )),
+            Ok(url) if url.host().is_none() => problems.push("public_url needs a host".to_string()),
+            Ok(_) => {}
+            Err(e) => problems.push(format!(
+                "invalid public_url '{}': {e}",
+                self.server.public_url
+            )),
+        }
+        match (&self.server.tls_cert, &self.server.tls_key) {
+            (Some(cert), Some(key)) => {
+                if let Err(e) = crate::tls::load_certified_key(cert, key) {
+                    problems.push(format!("cannot load tls certificate: {e:#}"));
+                }
+                if !self.server.secure() {
+                    problems.push("public_url has to use https when tls is enabled".to_string());
+                }
+            }
+            (None, None) => {}
+            _ => problems.push("tls_cert and tls_key have to be set together".to_string()),
+        }
+        if let Some(bind) = &self.server.redirect_bind {
+            if self.server.tls().is_none() {
+                problems.push("redirect_bind needs tls_cert and tls_key".to_string());
+            }
+            if let Err(e) = bind.to_socket_addrs() {
+                problems.push(format!("invalid redirect_bind address '{bind}': {e}"));
+            }
+        }
+        if self.session.idle_timeout < 60 {
+            problems.push("session.idle_timeout has to be at least 60 seconds".to_string());
+        }
+        if self.session.absolute_timeout < self.session.idle_timeout {
+            problems.push(
+                "session.absolute_timeout cannot be shorter than session.idle_timeout".to_string(),
+            );
+        }
+        if self.login.user_lockout_after == 0 || self.login.ip_lockout_after == 0 {
+            problems.push("login lockouts need at least 1 failure".to_string());
+        }
+        if self.login.max_delay < self.login.base_delay {
+            problems.push("login.max_delay cannot be shorter than login.base_delay".to_string());
+        }
+        if self.pool.max_size == 0 {
+            problems.push("pool.max_size has to be at least 1".to_string());
+        }
+        if self.pool.min_idle > self.pool.max_size {
+            problems.push("pool.min_idle cannot be larger than pool.max_size".to_string());
+        }
+        if self.mqtt.max_packet_size == 0 || self.mqtt.max_packet_size > u32::MAX as usize {
+            problems.push("mqtt.max_packet_size is out of range".to_string());
+        }
+        if self.mqtt.keep_alive != 0 && self.mqtt.keep_alive < 5 {
+            problems.push("mqtt.keep_alive has to be 0 or at least 5 seconds".to_string());
+        }
+        if self.mqtt.channel_capacity == 0 {
+            problems.push("mqtt.channel_capacity has to be at least 1".to_string());
+        }
+        let retention = &self.retention;
+        for (name, value) in [
+            ("history", retention.history),
+            ("alerts", retention.alerts),
+            ("recording", retention.recording),
+            ("webhook_log", retention.webhook_log),
+            ("audit", retention.audit),
+            ("notifications", retention.notifications),
+        ] {
+            if value == 0 {
+                problems.push(format!("retention.{name} has to be at least 1"));
+            }
+        }
+        if self.shutdown.timeout == 0 {
+            problems.push("shutdown.timeout has to be at least 1 second".to_string());
+        }
+        for goodbye in &self.shutdown.goodbye {
+            if goodbye.qos > 2 {
+                problems.push(format!(
+                    "shutdown.goodbye for {} has an invalid qos {}",
+                    goodbye.topic, goodbye.qos
+                ));
+            }
+            if goodbye.topic.is_empty() || goodbye.topic.contains(['+', '#']) {
+                problems.push(format!(
+                    "shutdown.goodbye topic '{}' has to be a non empty topic without wildcards",
+                    goodbye.topic
+                ));
+            }
+        }
+        if self.smtp.host.is_some() {
+            if let Err(e) = self.smtp.from.parse::<lettre::message::Mailbox>() {
+                problems.push(format!("invalid smtp.from '{}': {e}", self.smtp.from));
+            }
+            if self.smtp.username.is_some() != self.smtp.password.is_some() {
+                problems
+                    .push("smtp.username and smtp.password have to be set together".to_string());
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// The settings as TOML with the keys and the database password hidden.
+    pub fn to_redacted_toml(&self) -> String {
+        let mut settings = self.clone();
+        if settings.session_key.is_some() {
+            settings.session_key = Some("<redacted>".into());
+        }
+        if settings.totp_key.is_some() {
+            settings.totp_key = Some("<redacted>".into());
+        }
+        if settings.smtp.password.is_some() {
+            settings.smtp.password = Some("<redacted>".into());
+        }
+        if let Some(url) = settings
+            .database_url
+            .as_deref()
+            .and_then(|url| url::Url::parse(url).ok())
+            .filter(|url| url.password().is_some())
+        {
+            let mut url = url;
+            let _ = url.set_password(Some("redacted"));
+            settings.database_url = Some(url.to_string());
+        }
+        toml::to_string_pretty(&settings)
+            .unwrap_or_else(|e| format!("# cannot print settings: {e}"))
+    }
+}
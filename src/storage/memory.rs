@@ -0,0 +1,187 @@
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    ops::Range,
+};
+
+use async_trait::async_trait;
+use tokio::sync::RwLock;
+
+use super::{Repository, StorageError};
+use crate::models::{history::HistoryEntry, mqtt_client::MqttClient, user::User};
+
+/// Keeps everything in memory, all data is lost on restart.
+#[derive(Default)]
+pub struct MemoryRepository {
+    users: RwLock<HashMap<String, User>>,
+    settings: RwLock<HashMap<String, String>>,
+    clients: RwLock<HashMap<String, MqttClient>>,
+    topics: RwLock<HashMap<String, BTreeSet<String>>>,
+    /// entries per client with their sequence number, oldest first
+    history: RwLock<HashMap<String, VecDeque<(u64, HistoryEntry)>>>,
+    history_max_len: usize,
+}
+
+impl MemoryRepository {
+    pub fn new(history_max_len: usize) -> Self {
+        MemoryRepository {
+            history_max_len,
+            ..Default::default()
+        }
+    }
+}
+
+#[async_trait]
+impl Repository for MemoryRepository {
+    async fn ping(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, StorageError> {
+        Ok(self.users.read().await.values().cloned().collect())
+    }
+
+    async fn get_user(&self, name: &str) -> Result<Option<User>, StorageError> {
+        Ok(self.users.read().await.get(name).cloned())
+    }
+
+    async fn insert_user(&self, user: &User) -> Result<(), StorageError> {
+        self.users
+            .write()
+            .await
+            .insert(user.name.clone(), user.clone());
+        Ok(())
+    }
+
+    async fn delete_user(&self, name: &str) -> Result<bool, StorageError> {
+        Ok(self.users.write().await.remove(name).is_some())
+    }
+
+    async fn get_setting(&self, key: &str) -> Result<Option<String>, StorageError> {
+        Ok(self.settings.read().await.get(key).cloned())
+    }
+
+    async fn set_setting(&self, key: &str, value: &str) -> Result<(), StorageError> {
+        self.settings
+            .write()
+            .await
+            .insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    async fn list_clients(&self) -> Result<Vec<MqttClient>, StorageError> {
+        Ok(self.clients.read().await.values().cloned().collect())
+    }
+
+    async fn get_client(&self, name: &str) -> Result<Option<MqttClient>, StorageError> {
+        Ok(self.clients.read().await.get(name).cloned())
+    }
+
+    async fn insert_client(&self, client: &MqttClient) -> Result<(), StorageError> {
+        self.clients
+            .write()
+            .await
+            .insert(client.name.clone(), client.clone());
+        Ok(())
+    }
+
+    async fn delete_client(&self, name: &str) -> Result<bool, StorageError> {
+        self.topics.write().await.remove(name);
+        self.history.write().await.remove(name);
+        Ok(self.clients.write().await.remove(name).is_some())
+    }
+
+    async fn topics(&self, client: &str) -> Result<Vec<String>, StorageError> {
+        Ok(self
+            .topics
+            .read()
+            .await
+            .get(client)
+            .map(|topics| topics.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn subscribe(&self, client: &str, topic: &str) -> Result<bool, StorageError> {
+        Ok(self
+            .topics
+            .write()
+            .await
+            .entry(client.to_string())
+            .or_default()
+            .insert(topic.to_string()))
+    }
+
+    async fn unsubscribe(&self, client: &str, topic: &str) -> Result<bool, StorageError> {
+        Ok(self
+            .topics
+            .write()
+            .await
+            .get_mut(client)
+            .is_some_and(|topics| topics.remove(topic)))
+    }
+
+    async fn insert_history(&self, client: &str, entry: &HistoryEntry) -> Result<(), StorageError> {
+        let mut history = self.history.write().await;
+        let entries = history.entry(client.to_string()).or_default();
+        let id = entries.back().map_or(1, |(id, _)| id + 1);
+        entries.push_back((id, entry.clone()));
+        if entries.len() > self.history_max_len {
+            entries.pop_front();
+        }
+        Ok(())
+    }
+
+    async fn latest_history(
+        &self,
+        client: &str,
+        count: usize,
+    ) -> Result<Vec<HistoryEntry>, StorageError> {
+        Ok(self
+            .history
+            .read()
+            .await
+            .get(client)
+            .map(|entries| {
+                entries
+                    .iter()
+                    .rev()
+                    .take(count)
+                    .map(|(_, entry)| entry.clone())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn history_page(
+        &self,
+        client: &str,
+        after: Option<&str>,
+        time: Range<i64>,
+        count: usize,
+    ) -> Result<(Vec<HistoryEntry>, Option<String>), StorageError> {
+        let after: u64 = match after {
+            Some(after) => after
+                .parse()
+                .map_err(|_| StorageError::Backend(format!("invalid cursor '{after}'")))?,
+            None => 0,
+        };
+        let history = self.history.read().await;
+        let page: Vec<&(u64, HistoryEntry)> = history
+            .get(client)
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter(|(id, entry)| *id > after && time.contains(&entry.timestamp))
+                    .take(count)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let last = page
+            .last()
+            .filter(|_| page.len() == count)
+            .map(|(id, _)| id.to_string());
+        Ok((
+            page.into_iter().map(|(_, entry)| entry.clone()).collect(),
+            last,
+        ))
+    }
+}
@@ -0,0 +1,274 @@
+use std::{fmt::Display, ops::Range};
+
+use async_trait::async_trait;
+
+use crate::models::{history::HistoryEntry, mqtt_client::MqttClient, user::User};
+
+mod memory;
+mod redis;
+mod sqlite;
+
+pub use self::redis::RedisRepository;
+pub use memory::MemoryRepository;
+pub use sqlite::SqliteRepository;
+
+#[derive(Debug)]
+pub enum StorageError {
+    /// the backend is unreachable or failed to run a command
+    Backend(String),
+    /// a stored record cannot be read or written
+    Serialization(String),
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::Backend(e) => write!(f, "storage backend error: {e}"),
+            StorageError::Serialization(e) => write!(f, "cannot (de)serialize record: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(e: serde_json::Error) -> Self {
+        StorageError::Serialization(e.to_string())
+    }
+}
+
+/// Persistence of users, runtime settings, clients, their subscriptions and message history.
+#[async_trait]
+pub trait Repository: Send + Sync {
+    /// Check that the backend is reachable.
+    async fn ping(&self) -> Result<(), StorageError>;
+
+    async fn list_users(&self) -> Result<Vec<User>, StorageError>;
+    async fn get_user(&self, name: &str) -> Result<Option<User>, StorageError>;
+    /// Insert or replace a user.
+    async fn insert_user(&self, user: &User) -> Result<(), StorageError>;
+    async fn delete_user(&self, name: &str) -> Result<bool, StorageError>;
+
+    /// Value of an application setting changed at runtime.
+    async fn get_setting(&self, key: &str) -> Result<Option<String>, StorageError>;
+    async fn set_setting(&self, key: &str, value: &str) -> Result<(), StorageError>;
+
+    async fn list_clients(&self) -> Result<Vec<MqttClient>, StorageError>;
+    async fn get_client(&self, name: &str) -> Result<Option<MqttClient>, StorageError>;
+    /// Insert or replace a client.
+    async fn insert_client(&self, client: &MqttClient) -> Result<(), StorageError>;
+    /// Delete a client together with everything stored for it.
+    async fn delete_client(&self, name: &str) -> Result<bool, StorageError>;
+
+    async fn topics(&self, client: &str) -> Result<Vec<String>, StorageError>;
+    /// Add a topic filter, returns false if the client was already subscribed.
+    async fn subscribe(&self, client: &str, topic: &str) -> Result<bool, StorageError>;
+    /// Remove a topic filter, returns false if the client was not subscribed.
+    async fn unsubscribe(&self, client: &str, topic: &str) -> Result<bool, StorageError>;
+
+    /// Append to the history of a client, only the latest
+    /// [`RetentionSettings::history`](crate::settings::RetentionSettings::history) entries are kept.
+    async fn insert_history(&self, client: &str, entry: &HistoryEntry) -> Result<(), StorageError>;
+    /// Latest `count` entries, newest first.
+    async fn latest_history(
+        &self,
+        client: &str,
+        count: usize,
+    ) -> Result<Vec<HistoryEntry>, StorageError>;
+    /// Up to `count` entries oldest first after the opaque cursor `after`
+    /// with their timestamp in `time`, together with the cursor to continue
+    /// from, `None` once the history is scanned to its end.
+    async fn history_page(
+        &self,
+        client: &str,
+        after: Option<&str>,
+        time: Range<i64>,
+        count: usize,
+    ) -> Result<(Vec<HistoryEntry>, Option<String>), StorageError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::user::{TotpConfig, UserSource},
+        settings::PoolSettings,
+    };
+
+    fn user(name: &str, role_id: i32) -> User {
+        User {
+            name: name.into(),
+            email: Some(format!("{name}@example.com")),
+            password: "secret".into(),
+            role_id,
+            source: UserSource::Ldap("corp".into()),
+            totp: Some(TotpConfig {
+                secret: "encrypted".into(),
+                recovery_codes: vec!["code".into()],
+                last_step: 42,
+            }),
+        }
+    }
+
+    fn client(name: &str, url: &str) -> MqttClient {
+        MqttClient {
+            name: name.into(),
+            url: url.into(),
+        }
+    }
+
+    /// What every backend has to do with users.
+    async fn user_contract(repo: &dyn Repository) {
+        assert!(repo.get_user("kim").await.unwrap().is_none());
+        repo.insert_user(&user("kim", 1)).await.unwrap();
+        repo.insert_user(&user("ann", 0)).await.unwrap();
+        let kim = repo
+            .get_user("kim")
+            .await
+            .unwrap()
+            .expect("kim was inserted");
+        assert_eq!(kim.email.as_deref(), Some("kim@example.com"));
+        assert_eq!(kim.password, "secret");
+        assert_eq!(kim.role_id, 1);
+        assert_eq!(kim.source, UserSource::Ldap("corp".into()));
+        let totp = kim.totp.expect("the second factor is kept");
+        assert_eq!(totp.recovery_codes, ["code"]);
+        assert_eq!(totp.last_step, 42);
+
+        // inserting again replaces the user
+        repo.insert_user(&User {
+            totp: None,
+            ..user("kim", 0)
+        })
+        .await
+        .unwrap();
+        let kim = repo
+            .get_user("kim")
+            .await
+            .unwrap()
+            .expect("kim was replaced");
+        assert_eq!(kim.role_id, 0);
+        assert!(kim.totp.is_none());
+        let mut names: Vec<String> = repo
+            .list_users()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|user| user.name)
+            .collect();
+        names.sort();
+        assert_eq!(names, ["ann", "kim"]);
+
+        assert!(repo.delete_user("kim").await.unwrap());
+        assert!(!repo.delete_user("kim").await.unwrap());
+        assert!(repo.get_user("kim").await.unwrap().is_none());
+        assert_eq!(repo.list_users().await.unwrap().len(), 1);
+    }
+
+    /// What every backend has to do with clients and their topics.
+    async fn client_contract(repo: &dyn Repository) {
+        assert!(repo.get_client("c1").await.unwrap().is_none());
+        repo.insert_client(&client("c1", "mqtt://a:1883"))
+            .await
+            .unwrap();
+        repo.insert_client(&client("c2", "mqtt://b:1883"))
+            .await
+            .unwrap();
+        repo.insert_client(&client("c1", "mqtt://c:1883"))
+            .await
+            .unwrap();
+        let c1 = repo
+            .get_client("c1")
+            .await
+            .unwrap()
+            .expect("c1 was inserted");
+        assert_eq!(c1.url, "mqtt://c:1883");
+        let mut names: Vec<String> = repo
+            .list_clients()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|client| client.name)
+            .collect();
+        names.sort();
+        assert_eq!(names, ["c1", "c2"]);
+
+        assert!(repo.topics("c1").await.unwrap().is_empty());
+        assert!(repo.subscribe("c1", "sensors/#").await.unwrap());
+        assert!(repo.subscribe("c1", "alarms/+").await.unwrap());
+        assert!(!repo.subscribe("c1", "sensors/#").await.unwrap());
+        assert!(repo.subscribe("c2", "sensors/#").await.unwrap());
+        let mut topics = repo.topics("c1").await.unwrap();
+        topics.sort();
+        assert_eq!(topics, ["alarms/+", "sensors/#"]);
+
+        assert!(repo.unsubscribe("c1", "alarms/+").await.unwrap());
+        assert!(!repo.unsubscribe("c1", "alarms/+").await.unwrap());
+        assert_eq!(repo.topics("c1").await.unwrap(), ["sensors/#"]);
+
+        // the topics go with the client, other clients keep theirs
+        assert!(repo.delete_client("c1").await.unwrap());
+        assert!(!repo.delete_client("c1").await.unwrap());
+        assert!(repo.get_client("c1").await.unwrap().is_none());
+        assert!(repo.topics("c1").await.unwrap().is_empty());
+        assert_eq!(repo.topics("c2").await.unwrap(), ["sensors/#"]);
+    }
+
+    /// What every backend has to do when paging through a history.
+    async fn history_contract(repo: &dyn Repository) {
+        // imported entries can be stored out of time order
+        for timestamp in [30, 10, 20, 40] {
+            let entry = HistoryEntry {
+                timestamp,
+                topic: format!("sensors/{timestamp}"),
+                payload: timestamp.to_string().into_bytes(),
+                qos: 0,
+                retain: false,
+                tag: None,
+            };
+            repo.insert_history("c1", &entry).await.unwrap();
+        }
+        let mut seen = Vec::new();
+        let mut after = None;
+        loop {
+            let (entries, last) = repo
+                .history_page("c1", after.as_deref(), 15..40, 2)
+                .await
+                .unwrap();
+            seen.extend(entries.iter().map(|entry| entry.timestamp));
+            match last {
+                Some(last) => after = Some(last),
+                None => break,
+            }
+        }
+        assert_eq!(seen, [30, 20]);
+        let (entries, last) = repo.history_page("c2", None, 0..100, 2).await.unwrap();
+        assert!(entries.is_empty());
+        assert!(last.is_none());
+    }
+
+    async fn contract(repo: &dyn Repository) {
+        repo.ping().await.unwrap();
+        user_contract(repo).await;
+        client_contract(repo).await;
+        history_contract(repo).await;
+    }
+
+    #[tokio::test]
+    async fn memory_repository() {
+        contract(&MemoryRepository::new(10)).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_repository() {
+        let path = std::env::temp_dir().join(format!("mqttpal-test-{}.db", std::process::id()));
+        let url = format!("sqlite://{}", path.display());
+        let repo = SqliteRepository::connect(&url, &PoolSettings::default(), 10)
+            .await
+            .unwrap();
+        contract(&repo).await;
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use async_trait::async_trait;
+use bb8_redis::redis::{
+    cmd,
+    streams::{StreamId, StreamRangeReply},
+    RedisError,
+};
+
+use super::{Repository, StorageError};
+use crate::models::{history::HistoryEntry, mqtt_client::MqttClient, user::User};
+use std::ops::Range;
+
+impl From<RedisError> for StorageError {
+    fn from(e: RedisError) -> Self {
+        StorageError::Backend(e.to_string())
+    }
+}
+
+impl From<bb8::RunError<RedisError>> for StorageError {
+    fn from(e: bb8::RunError<RedisError>) -> Self {
+        StorageError::Backend(e.to_string())
+    }
+}
+
+/// Stores users and clients as JSON in the `users` and `mqtt_clients`
+/// hashes, runtime settings in the `settings` hash, topics in a set and the history in a stream per client.
+pub struct RedisRepository {
+    pool: crate::DbPool,
+    history_max_len: usize,
+}
+
+impl RedisRepository {
+    pub fn new(pool: crate::DbPool, history_max_len: usize) -> Self {
+        RedisRepository {
+            pool,
+            history_max_len,
+        }
+    }
+
+    fn topics_key(client: &str) -> String {
+        format!("mqtt_client:{}:topics", client)
+    }
+
+    fn history_key(client: &str) -> String {
+        format!("mqtt_client:{}:history", client)
+    }
+
+    fn history_entry(entry: &StreamId) -> HistoryEntry {
+        let tag: String = entry.get("tag").unwrap_or_default();
+        HistoryEntry {
+            timestamp: entry.get("ts").unwrap_or(0),
+            topic: entry.get("topic").unwrap_or_default(),
+            payload: entry.get("payload").unwrap_or_default(),
+            qos: entry.get("qos").unwrap_or(0),
+            retain: entry.get::<u8>("retain").unwrap_or(0) > 0,
+            tag: (!tag.is_empty()).then_some(tag),
+        }
+    }
+}
+
+#[async_trait]
+impl Repository for RedisRepository {
+    async fn ping(&self) -> Result<(), StorageError> {
+        let mut conn = self.pool.get().await?;
+        let _: String = cmd("PING").query_async(&mut *conn).await?;
+        Ok(())
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, StorageError> {
+        let mut conn = self.pool.get().await?;
+        let users: Vec<String> = cmd("HVALS").arg("users").query_async(&mut *conn).await?;
+        Ok(users
+            .iter()
+            .map(|user| serde_json::from_str(user))
+            .collect::<Result<_, _>>()?)
+    }
+
+    async fn get_user(&self, name: &str) -> Result<Option<User>, StorageError> {
+        let mut conn = self.pool.get().await?;
+        let user: Option<String> = cmd("HGET")
+            .arg("users")
+            .arg(name)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(user.map(|user| serde_json::from_str(&user)).transpose()?)
+    }
+
+    async fn insert_user(&self, user: &User) -> Result<(), StorageError> {
+        let mut conn = self.pool.get().await?;
+        let user_json = serde_json::to_string(user)?;
+        let _: i32 = cmd("HSET")
+            .arg("users")
+            .arg(&user.name)
+            .arg(user_json)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_user(&self, name: &str) -> Result<bool, StorageError> {
+        let mut conn = self.pool.get().await?;
+        let deleted: i32 = cmd("HDEL")
+            .arg("users")
+            .arg(name)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    async fn get_setting(&self, key: &str) -> Result<Option<String>, StorageError> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("HGET")
+            .arg("settings")
+            .arg(key)
+            .query_async(&mut *conn)
+            .await?)
+    }
+
+    async fn set_setting(&self, key: &str, value: &str) -> Result<(), StorageError> {
+        let mut conn = self.pool.get().await?;
+        let _: () = cmd("HSET")
+            .arg("settings")
+            .arg(key)
+            .arg(value)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn list_clients(&self) -> Result<Vec<MqttClient>, StorageError> {
+        let mut conn = self.pool.get().await?;
+        let clients: Vec<String> = cmd("HVALS")
+            .arg("mqtt_clients")
+            .query_async(&mut *conn)
+            .await?;
+        Ok(clients
+            .iter()
+            .map(|client| serde_json::from_str(client))
+            .collect::<Result<_, _>>()?)
+    }
+
+    async fn get_client(&self, name: &str) -> Result<Option<MqttClient>, StorageError> {
+        let mut conn = self.pool.get().await?;
+        let client: Option<String> = cmd("HGET")
+            .arg("mqtt_clients")
+            .arg(name)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(client
+            .map(|client| serde_json::from_str(&client))
+            .transpose()?)
+    }
+
+    async fn insert_client(&self, client: &MqttClient) -> Result<(), StorageError> {
+        let mut conn = self.pool.get().await?;
+        let client_json = serde_json::to_string(client)?;
+        let _: i32 = cmd("HSET")
+            .arg("mqtt_clients")
+            .arg(&client.name)
+            .arg(client_json)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_client(&self, name: &str) -> Result<bool, StorageError> {
+        let mut conn = self.pool.get().await?;
+        let deleted: i32 = cmd("HDEL")
+            .arg("mqtt_clients")
+            .arg(name)
+            .query_async(&mut *conn)
+            .await?;
+        let _: i32 = cmd("DEL")
+            .arg(Self::topics_key(name))
+            .arg(format!("mqtt_client:{}:rules", name))
+            .arg(Self::history_key(name))
+            .arg(format!("mqtt_client:{}:alerts", name))
+            .arg(format!("mqtt_client:{}:schedules", name))
+            .arg(format!("mqtt_client:{}:templates", name))
+            .arg(format!("mqtt_client:{}:template_counters", name))
+            .query_async(&mut *conn)
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    async fn topics(&self, client: &str) -> Result<Vec<String>, StorageError> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("SMEMBERS")
+            .arg(Self::topics_key(client))
+            .query_async(&mut *conn)
+            .await?)
+    }
+
+    async fn subscribe(&self, client: &str, topic: &str) -> Result<bool, StorageError> {
+        let mut conn = self.pool.get().await?;
+        let added: i32 = cmd("SADD")
+            .arg(Self::topics_key(client))
+            .arg(topic)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(added > 0)
+    }
+
+    async fn unsubscribe(&self, client: &str, topic: &str) -> Result<bool, StorageError> {
+        let mut conn = self.pool.get().await?;
+        let removed: i32 = cmd("SREM")
+            .arg(Self::topics_key(client))
+            .arg(topic)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(removed > 0)
+    }
+
+    async fn insert_history(&self, client: &str, entry: &HistoryEntry) -> Result<(), StorageError> {
+        let mut conn = self.pool.get().await?;
+        let _: String = cmd("XADD")
+            .arg(Self::history_key(client))
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(self.history_max_len)
+            .arg("*")
+            .arg("ts")
+            .arg(entry.timestamp)
+            .arg("topic")
+            .arg(&entry.topic)
+            .arg("payload")
+            .arg(&entry.payload)
+            .arg("qos")
+            .arg(entry.qos)
+            .arg("retain")
+            .arg(entry.retain as u8)
+            .arg("tag")
+            .arg(entry.tag.as_deref().unwrap_or(""))
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn latest_history(
+        &self,
+        client: &str,
+        count: usize,
+    ) -> Result<Vec<HistoryEntry>, StorageError> {
+        let mut conn = self.pool.get().await?;
+        let reply: StreamRangeReply = cmd("XREVRANGE")
+            .arg(Self::history_key(client))
+            .arg("+")
+            .arg("-")
+            .arg("COUNT")
+            .arg(count)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(reply.ids.iter().map(Self::history_entry).collect())
+    }
+
+    async fn history_page(
+        &self,
+        client: &str,
+        after: Option<&str>,
+        time: Range<i64>,
+        count: usize,
+    ) -> Result<(Vec<HistoryEntry>, Option<String>), StorageError> {
+        let mut conn = self.pool.get().await?;
+        let start = after.map_or("-".to_string(), |id| format!("({id}"));
+        let reply: StreamRangeReply = cmd("XRANGE")
+            .arg(Self::history_key(client))
+            .arg(start)
+            .arg("+")
+            .arg("COUNT")
+            .arg(count)
+            .query_async(&mut *conn)
+            .await?;
+        // stream ids are the time of storing, imported entries can be older,
+        // so the range is checked on every entry scanned
+        let last = reply
+            .ids
+            .last()
+            .filter(|_| reply.ids.len() == count)
+            .map(|entry| entry.id.clone());
+        let entries = reply
+            .ids
+            .iter()
+            .map(Self::history_entry)
+            .filter(|entry| time.contains(&entry.timestamp))
+            .collect();
+        Ok((entries, last))
+    }
+}
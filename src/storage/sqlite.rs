@@ -0,0 +1,298 @@
+use std::{ops::Range, str::FromStr};
+
+use async_trait::async_trait;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions};
+
+use super::{Repository, StorageError};
+use crate::{
+    models::{history::HistoryEntry, mqtt_client::MqttClient, user::User},
+    settings::PoolSettings,
+};
+
+impl From<sqlx::Error> for StorageError {
+    fn from(e: sqlx::Error) -> Self {
+        StorageError::Backend(e.to_string())
+    }
+}
+
+impl From<sqlx::migrate::MigrateError> for StorageError {
+    fn from(e: sqlx::migrate::MigrateError) -> Self {
+        StorageError::Backend(e.to_string())
+    }
+}
+
+type UserRow = (String, Option<String>, String, i32, String, Option<String>);
+type HistoryRow = (i64, i64, String, Vec<u8>, u8, bool, Option<String>);
+
+/// Embedded database in a single file, the schema is kept up to date with
+/// the migrations in `migrations/`.
+pub struct SqliteRepository {
+    pool: SqlitePool,
+    history_max_len: usize,
+}
+
+impl SqliteRepository {
+    /// Open or create the database at `url` and run pending migrations.
+    pub async fn connect(
+        url: &str,
+        pool: &PoolSettings,
+        history_max_len: usize,
+    ) -> Result<Self, StorageError> {
+        let options = SqliteConnectOptions::from_str(url)?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(pool.max_size)
+            .min_connections(pool.min_idle)
+            .acquire_timeout(pool.connection_timeout())
+            .connect_with(options)
+            .await?;
+        sqlx::migrate!().run(&pool).await?;
+        Ok(SqliteRepository {
+            pool,
+            history_max_len,
+        })
+    }
+
+    fn user(row: UserRow) -> Result<User, StorageError> {
+        let (name, email, password, role_id, source, totp) = row;
+        Ok(User {
+            name,
+            email,
+            password,
+            role_id,
+            source: serde_json::from_str(&source)?,
+            totp: totp.map(|totp| serde_json::from_str(&totp)).transpose()?,
+        })
+    }
+
+    fn history_entry(row: HistoryRow) -> HistoryEntry {
+        let (_, timestamp, topic, payload, qos, retain, tag) = row;
+        HistoryEntry {
+            timestamp,
+            topic,
+            payload,
+            qos,
+            retain,
+            tag,
+        }
+    }
+}
+
+#[async_trait]
+impl Repository for SqliteRepository {
+    async fn ping(&self) -> Result<(), StorageError> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, StorageError> {
+        let rows: Vec<UserRow> = sqlx::query_as(
+            "SELECT name, email, password, role_id, source, totp FROM users ORDER BY name",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter().map(Self::user).collect()
+    }
+
+    async fn get_user(&self, name: &str) -> Result<Option<User>, StorageError> {
+        let row: Option<UserRow> = sqlx::query_as(
+            "SELECT name, email, password, role_id, source, totp FROM users WHERE name = ?",
+        )
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await?;
+        row.map(Self::user).transpose()
+    }
+
+    async fn insert_user(&self, user: &User) -> Result<(), StorageError> {
+        sqlx::query(
+            "INSERT OR REPLACE INTO users (name, email, password, role_id, source, totp)
+             VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&user.name)
+        .bind(&user.email)
+        .bind(&user.password)
+        .bind(user.role_id)
+        .bind(serde_json::to_string(&user.source)?)
+        .bind(user.totp.as_ref().map(serde_json::to_string).transpose()?)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_user(&self, name: &str) -> Result<bool, StorageError> {
+        let result = sqlx::query("DELETE FROM users WHERE name = ?")
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_setting(&self, key: &str) -> Result<Option<String>, StorageError> {
+        let row: Option<(String,)> = sqlx::query_as("SELECT value FROM settings WHERE key = ?")
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|(value,)| value))
+    }
+
+    async fn set_setting(&self, key: &str, value: &str) -> Result<(), StorageError> {
+        sqlx::query("INSERT OR REPLACE INTO settings (key, value) VALUES (?, ?)")
+            .bind(key)
+            .bind(value)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn list_clients(&self) -> Result<Vec<MqttClient>, StorageError> {
+        let rows: Vec<(String, String)> =
+            sqlx::query_as("SELECT name, url FROM mqtt_clients ORDER BY name")
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(name, url)| MqttClient { name, url })
+            .collect())
+    }
+
+    async fn get_client(&self, name: &str) -> Result<Option<MqttClient>, StorageError> {
+        let row: Option<(String, String)> =
+            sqlx::query_as("SELECT name, url FROM mqtt_clients WHERE name = ?")
+                .bind(name)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(row.map(|(name, url)| MqttClient { name, url }))
+    }
+
+    async fn insert_client(&self, client: &MqttClient) -> Result<(), StorageError> {
+        sqlx::query("INSERT OR REPLACE INTO mqtt_clients (name, url) VALUES (?, ?)")
+            .bind(&client.name)
+            .bind(&client.url)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_client(&self, name: &str) -> Result<bool, StorageError> {
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query("DELETE FROM mqtt_clients WHERE name = ?")
+            .bind(name)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM topics WHERE client = ?")
+            .bind(name)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM history WHERE client = ?")
+            .bind(name)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn topics(&self, client: &str) -> Result<Vec<String>, StorageError> {
+        let rows: Vec<(String,)> =
+            sqlx::query_as("SELECT topic FROM topics WHERE client = ? ORDER BY topic")
+                .bind(client)
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(rows.into_iter().map(|(topic,)| topic).collect())
+    }
+
+    async fn subscribe(&self, client: &str, topic: &str) -> Result<bool, StorageError> {
+        let result = sqlx::query("INSERT OR IGNORE INTO topics (client, topic) VALUES (?, ?)")
+            .bind(client)
+            .bind(topic)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn unsubscribe(&self, client: &str, topic: &str) -> Result<bool, StorageError> {
+        let result = sqlx::query("DELETE FROM topics WHERE client = ? AND topic = ?")
+            .bind(client)
+            .bind(topic)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn insert_history(&self, client: &str, entry: &HistoryEntry) -> Result<(), StorageError> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO history (client, timestamp, topic, payload, qos, retain, tag)
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(client)
+        .bind(entry.timestamp)
+        .bind(&entry.topic)
+        .bind(&entry.payload)
+        .bind(entry.qos)
+        .bind(entry.retain)
+        .bind(&entry.tag)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            "DELETE FROM history WHERE client = ?1 AND id <= (
+                SELECT id FROM history WHERE client = ?1 ORDER BY id DESC LIMIT 1 OFFSET ?2
+            )",
+        )
+        .bind(client)
+        .bind(self.history_max_len as i64)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn latest_history(
+        &self,
+        client: &str,
+        count: usize,
+    ) -> Result<Vec<HistoryEntry>, StorageError> {
+        let rows: Vec<HistoryRow> = sqlx::query_as(
+            "SELECT id, timestamp, topic, payload, qos, retain, tag FROM history
+             WHERE client = ? ORDER BY id DESC LIMIT ?",
+        )
+        .bind(client)
+        .bind(count as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(Self::history_entry).collect())
+    }
+
+    async fn history_page(
+        &self,
+        client: &str,
+        after: Option<&str>,
+        time: Range<i64>,
+        count: usize,
+    ) -> Result<(Vec<HistoryEntry>, Option<String>), StorageError> {
+        let after: i64 = match after {
+            Some(after) => after
+                .parse()
+                .map_err(|_| StorageError::Backend(format!("invalid cursor '{after}'")))?,
+            None => 0,
+        };
+        let rows: Vec<HistoryRow> = sqlx::query_as(
+            "SELECT id, timestamp, topic, payload, qos, retain, tag FROM history
+             WHERE client = ? AND id > ? AND timestamp >= ? AND timestamp < ?
+             ORDER BY id LIMIT ?",
+        )
+        .bind(client)
+        .bind(after)
+        .bind(time.start)
+        .bind(time.end)
+        .bind(count as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        let last = rows
+            .last()
+            .filter(|_| rows.len() == count)
+            .map(|(id, ..)| id.to_string());
+        Ok((rows.into_iter().map(Self::history_entry).collect(), last))
+    }
+}
@@ -1,10 +1,12 @@
 use crate::{
+    audit::Auditor,
+    error::AppError,
     middleware::login_guard::LoginGuard,
-    models::mqtt_client::MqttClient,
+    models::{audit::AuditAction, mqtt_client::MqttClient},
     mqtt::{MqttClientActor, MqttClientManager, MqttMessage},
 };
-use actix::{Actor, Addr, AsyncContext, Handler, StreamHandler};
-use actix_web::{web, Error, HttpRequest, HttpResponse, Responder};
+use actix::{Actor, ActorContext, Addr, AsyncContext, Handler, StreamHandler};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
 use actix_web_actors::ws::{self};
 use askama::Template;
 use serde::{Deserialize, Serialize};
@@ -51,14 +53,23 @@ impl Handler<MqttMessage> for WsSubscription {
         match msg {
             MqttMessage::Message(publsh) => {
                 let topic = publsh.topic;
-                let payload = String::from_utf8(publsh.payload.into()).unwrap();
-                let response = MessageTemplate { topic, payload }.render().unwrap();
-                ctx.text(response);
+                let payload = String::from_utf8_lossy(&publsh.payload).into_owned();
+                match (MessageTemplate { topic, payload }).render() {
+                    Ok(response) => ctx.text(response),
+                    Err(e) => log::error!("Cannot render mqtt message: {e}"),
+                }
             }
             MqttMessage::Disconnect => {
                 log::info!("Disconnect from mqtt manager!");
                 ctx.close(None);
             }
+            MqttMessage::Shutdown => {
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Away,
+                    description: Some("mqttpal is shutting down".into()),
+                }));
+                ctx.stop();
+            }
             _ => (),
         }
     }
@@ -97,7 +108,7 @@ async fn ws(
             stream,
         )
     } else {
-        Ok(HttpResponse::NotFound().body("MqttClient not found"))
+        Err(AppError::NotFound("MQTT client".into()).into())
     }
 }
 
@@ -115,36 +126,46 @@ struct MqttClientSubTemplate {
 
 async fn post_subscribe(
     _: LoginGuard,
-    db: web::Data<crate::DbPool>,
+    repo: web::Data<crate::Repo>,
     mqtt: web::Data<MqttClientManager>,
+    auditor: Auditor,
     form: web::Form<MqttClientSubQuery>,
     name: web::Path<String>,
-) -> impl Responder {
+) -> Result<HttpResponse, AppError> {
     let topic = form.into_inner().topic;
     let _ = mqtt.subscribe(&name, &topic).await;
-    MqttClient::subscribe(&db, &name, &topic).await;
-    let topics = MqttClient::topics(&db, &name).await;
+    MqttClient::subscribe(&repo, &name, &topic).await?;
+    let details = serde_json::json!({ "topic": topic });
+    auditor
+        .record(AuditAction::Subscribe, &name, Some(details))
+        .await;
+    let topics = MqttClient::topics(&repo, &name).await?;
     let template = MqttClientSubTemplate {
         name: name.into_inner(),
         topics,
     };
-    HttpResponse::Ok().body(template.render().unwrap())
+    Ok(HttpResponse::Ok().body(template.render()?))
 }
 
 async fn post_unsubscribe(
     _: LoginGuard,
-    db: web::Data<crate::DbPool>,
+    repo: web::Data<crate::Repo>,
     mqtt: web::Data<MqttClientManager>,
+    auditor: Auditor,
     query: web::Query<MqttClientSubQuery>,
     name: web::Path<String>,
-) -> impl Responder {
+) -> Result<HttpResponse, AppError> {
     let topic = query.into_inner().topic;
-    let _ = mqtt.unsubscribe(&name, &topic).await;
-    MqttClient::unsubscribe(&db, &name, &topic).await;
-    let topics = MqttClient::topics(&db, &name).await;
+    MqttClient::unsubscribe(&repo, &name, &topic).await?;
+    mqtt.release_filter(&name, &topic).await?;
+    let details = serde_json::json!({ "topic": topic });
+    auditor
+        .record(AuditAction::Unsubscribe, &name, Some(details))
+        .await;
+    let topics = MqttClient::topics(&repo, &name).await?;
     let template = MqttClientSubTemplate {
         name: name.into_inner(),
         topics,
     };
-    HttpResponse::Ok().body(template.render().unwrap())
+    Ok(HttpResponse::Ok().body(template.render()?))
 }
@@ -0,0 +1,682 @@
+//! Stand-ins for the servers MQTTPal talks to, so tests run without them.
+//!
+//! Every stand-in listens on a free local port and keeps what it received
+//! for the test to look at.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+/// How long `wait_for` waits for a stand-in to receive something.
+const WAIT: Duration = Duration::from_secs(10);
+
+async fn listen() -> (TcpListener, u16) {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("cannot bind a local port");
+    let port = listener.local_addr().expect("bound listener").port();
+    (listener, port)
+}
+
+/// Poll `check` until it returns something or `WAIT` passed.
+pub async fn wait_for<T>(mut check: impl FnMut() -> Option<T>) -> T {
+    let deadline = tokio::time::Instant::now() + WAIT;
+    loop {
+        if let Some(value) = check() {
+            return value;
+        }
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "stand-in did not receive anything in time"
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+enum Value {
+    Hash(BTreeMap<Vec<u8>, Vec<u8>>),
+    List(VecDeque<Vec<u8>>),
+    SortedSet(Vec<(f64, Vec<u8>)>),
+}
+
+enum Reply {
+    Ok,
+    Nil,
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Array(Vec<Vec<u8>>),
+    Error(String),
+}
+
+impl Reply {
+    fn encode(self) -> Vec<u8> {
+        let bulk = |value: Vec<u8>| {
+            let mut out = format!("${}\r\n", value.len()).into_bytes();
+            out.extend(value);
+            out.extend(b"\r\n");
+            out
+        };
+        match self {
+            Reply::Ok => b"+OK\r\n".to_vec(),
+            Reply::Nil => b"$-1\r\n".to_vec(),
+            Reply::Integer(n) => format!(":{n}\r\n").into_bytes(),
+            Reply::Bulk(value) => bulk(value),
+            Reply::Array(values) => {
+                let mut out = format!("*{}\r\n", values.len()).into_bytes();
+                values.into_iter().for_each(|value| out.extend(bulk(value)));
+                out
+            }
+            Reply::Error(e) => format!("-ERR {e}\r\n").into_bytes(),
+        }
+    }
+}
+
+/// In-memory redis speaking enough of RESP for the commands MQTTPal sends.
+#[derive(Clone, Default)]
+struct MiniRedis {
+    data: Arc<Mutex<HashMap<Vec<u8>, Value>>>,
+}
+
+fn text(arg: &[u8]) -> String {
+    String::from_utf8_lossy(arg).into_owned()
+}
+
+fn number<T: std::str::FromStr>(arg: &[u8]) -> Option<T> {
+    text(arg).parse().ok()
+}
+
+fn score(arg: &[u8]) -> f64 {
+    match text(arg).as_str() {
+        "-inf" => f64::NEG_INFINITY,
+        "+inf" | "inf" => f64::INFINITY,
+        score => score.parse().unwrap_or(f64::NAN),
+    }
+}
+
+/// Start and end of a list range, negative indices count from the end.
+fn range(len: usize, start: i64, stop: i64) -> std::ops::Range<usize> {
+    let index = |i: i64| if i < 0 { len as i64 + i } else { i };
+    let start = index(start).max(0) as usize;
+    let stop = (index(stop) + 1).clamp(0, len as i64) as usize;
+    start.min(stop)..stop
+}
+
+impl MiniRedis {
+    /// Scripts are not interpreted, the known ones are run step by step.
+    fn eval(&self, args: &[Vec<u8>]) -> Reply {
+        let keys = number(&args[1]).unwrap_or(0) + 2;
+        let (keys, argv) = (&args[2..keys], &args[keys..]);
+        if args[0] == crate::models::webhook::CLAIM.as_bytes() {
+            let removed = self.run(vec![b"ZREM".to_vec(), keys[0].clone(), argv[0].clone()]);
+            if let Reply::Integer(1) = removed {
+                let (inflight, id, delivery) = (&keys[1], &argv[1], &argv[0]);
+                self.run(vec![
+                    b"HSET".to_vec(),
+                    inflight.clone(),
+                    id.clone(),
+                    delivery.clone(),
+                ]);
+            }
+            return removed;
+        }
+        Reply::Error("unknown script".into())
+    }
+
+    fn run(&self, args: Vec<Vec<u8>>) -> Reply {
+        let Some((command, args)) = args.split_first() else {
+            return Reply::Error("empty command".into());
+        };
+        if text(command).eq_ignore_ascii_case("EVAL") {
+            return self.eval(args);
+        }
+        let mut data = self.data.lock().unwrap();
+        let wrong_type = || Reply::Error("WRONGTYPE".into());
+        macro_rules! get {
+            ($variant:ident, $key:expr) => {
+                match data
+                    .entry($key.clone())
+                    .or_insert_with(|| Value::$variant(Default::default()))
+                {
+                    Value::$variant(value) => value,
+                    _ => return wrong_type(),
+                }
+            };
+        }
+        match text(command).to_uppercase().as_str() {
+            "PING" => Reply::Bulk(b"PONG".to_vec()),
+            // no command the tests need stores plain strings
+            "GET" => match data.get(&args[0]) {
+                Some(_) => wrong_type(),
+                None => Reply::Nil,
+            },
+            "DEL" => Reply::Integer(
+                args.iter()
+                    .filter(|key| data.remove(*key).is_some())
+                    .count() as i64,
+            ),
+            "HSET" => {
+                let hash = get!(Hash, args[0]);
+                let added = args[1..]
+                    .chunks(2)
+                    .filter(|pair| hash.insert(pair[0].clone(), pair[1].clone()).is_none())
+                    .count();
+                Reply::Integer(added as i64)
+            }
+            "HGET" => match data.get(&args[0]) {
+                Some(Value::Hash(hash)) => {
+                    hash.get(&args[1]).cloned().map_or(Reply::Nil, Reply::Bulk)
+                }
+                Some(_) => wrong_type(),
+                None => Reply::Nil,
+            },
+            "HDEL" => {
+                let hash = get!(Hash, args[0]);
+                let removed = args[1..]
+                    .iter()
+                    .filter(|field| hash.remove(*field).is_some())
+                    .count();
+                Reply::Integer(removed as i64)
+            }
+            "HGETALL" | "HVALS" => {
+                let hash = get!(Hash, args[0]);
+                let values = if text(command).eq_ignore_ascii_case("HVALS") {
+                    hash.values().cloned().collect()
+                } else {
+                    hash.iter()
+                        .flat_map(|(field, value)| [field.clone(), value.clone()])
+                        .collect()
+                };
+                Reply::Array(values)
+            }
+            "LPUSH" => {
+                let list = get!(List, args[0]);
+                for value in &args[1..] {
+                    list.push_front(value.clone());
+                }
+                Reply::Integer(list.len() as i64)
+            }
+            "LRANGE" | "LTRIM" => {
+                let list = get!(List, args[0]);
+                let (start, stop) = (
+                    number(&args[1]).unwrap_or(0),
+                    number(&args[2]).unwrap_or(-1),
+                );
+                let range = range(list.len(), start, stop);
+                if text(command).eq_ignore_ascii_case("LTRIM") {
+                    *list = list.drain(range).collect();
+                    Reply::Ok
+                } else {
+                    Reply::Array(list.range(range).cloned().collect())
+                }
+            }
+            "ZADD" => {
+                let set = get!(SortedSet, args[0]);
+                let mut added = 0;
+                for pair in args[1..].chunks(2) {
+                    let before = set.len();
+                    set.retain(|(_, member)| *member != pair[1]);
+                    added += (set.len() == before) as i64;
+                    set.push((score(&pair[0]), pair[1].clone()));
+                }
+                set.sort_by(|a, b| a.0.total_cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+                Reply::Integer(added)
+            }
+            "ZREM" => {
+                let set = get!(SortedSet, args[0]);
+                let before = set.len();
+                set.retain(|(_, member)| !args[1..].contains(member));
+                Reply::Integer((before - set.len()) as i64)
+            }
+            "ZRANGEBYSCORE" => {
+                let set = get!(SortedSet, args[0]);
+                let (min, max) = (score(&args[1]), score(&args[2]));
+                let (offset, count) = match args.get(3) {
+                    Some(limit) if text(limit).eq_ignore_ascii_case("LIMIT") => (
+                        number(&args[4]).unwrap_or(0),
+                        number(&args[5]).unwrap_or(usize::MAX),
+                    ),
+                    _ => (0, usize::MAX),
+                };
+                Reply::Array(
+                    set.iter()
+                        .filter(|(score, _)| (min..=max).contains(score))
+                        .skip(offset)
+                        .take(count)
+                        .map(|(_, member)| member.clone())
+                        .collect(),
+                )
+            }
+            command => Reply::Error(format!("unknown command {command}")),
+        }
+    }
+
+    async fn serve(self, stream: TcpStream) {
+        let (read, mut write) = stream.into_split();
+        let mut read = BufReader::new(read);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if read.read_line(&mut line).await.unwrap_or(0) == 0 {
+                return;
+            }
+            let Some(count) = line
+                .trim_end()
+                .strip_prefix('*')
+                .and_then(|n| n.parse().ok())
+            else {
+                return;
+            };
+            let mut args = Vec::with_capacity(count);
+            for _ in 0..count {
+                line.clear();
+                let _ = read.read_line(&mut line).await;
+                let Some(len) = line
+                    .trim_end()
+                    .strip_prefix('$')
+                    .and_then(|n| n.parse().ok())
+                else {
+                    return;
+                };
+                let mut arg = vec![0u8; len + 2];
+                if read.read_exact(&mut arg).await.is_err() {
+                    return;
+                }
+                arg.truncate(len);
+                args.push(arg);
+            }
+            let reply = self.run(args).encode();
+            if write.write_all(&reply).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Pool connected to a fresh, empty redis stand-in.
+pub async fn redis_pool() -> crate::DbPool {
+    let (listener, port) = listen().await;
+    let redis = MiniRedis::default();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(redis.clone().serve(stream));
+        }
+    });
+    let manager =
+        bb8_redis::RedisMultiplexedConnectionManager::new(format!("redis://127.0.0.1:{port}"))
+            .expect("valid redis url");
+    bb8::Pool::builder()
+        .build(manager)
+        .await
+        .expect("redis stand-in is reachable")
+}
+
+/// Request received by an `HttpServer`, header names are lower case.
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: String,
+    /// path with the query
+    pub path: String,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).expect("request body is JSON")
+    }
+
+    /// Fields of an `application/x-www-form-urlencoded` body.
+    pub fn form(&self) -> HashMap<String, String> {
+        url::form_urlencoded::parse(&self.body)
+            .into_owned()
+            .collect()
+    }
+}
+
+/// Status and JSON body answered to a request.
+pub type HttpResponse = (u16, serde_json::Value);
+
+/// HTTP/1.1 server answering every request with `respond`, one request per
+/// connection.
+pub struct HttpServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<HttpRequest>>>,
+}
+
+impl HttpServer {
+    pub async fn start<F>(respond: F) -> Self
+    where
+        F: Fn(&HttpRequest) -> HttpResponse + Send + Sync + 'static,
+    {
+        let (listener, port) = listen().await;
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let respond = Arc::new(respond);
+        let received = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (respond, received) = (respond.clone(), received.clone());
+                tokio::spawn(async move {
+                    let Some(request) = read_request(stream).await else {
+                        return;
+                    };
+                    let (request, mut stream) = request;
+                    let (status, body) = respond(&request);
+                    received.lock().unwrap().push(request);
+                    let body = body.to_string();
+                    let response = format!(
+                        "HTTP/1.1 {status} Stand-in\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        HttpServer {
+            url: format!("http://127.0.0.1:{port}"),
+            requests,
+        }
+    }
+
+    pub fn requests(&self) -> Vec<HttpRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+async fn read_request(stream: TcpStream) -> Option<(HttpRequest, TcpStream)> {
+    let mut stream = BufReader::new(stream);
+    let mut line = String::new();
+    stream.read_line(&mut line).await.ok()?;
+    let mut parts = line.split_whitespace();
+    let (method, path) = (parts.next()?.to_string(), parts.next()?.to_string());
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        stream.read_line(&mut line).await.ok()?;
+        let Some((name, value)) = line.trim_end().split_once(':') else {
+            break;
+        };
+        headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+    }
+    let len = headers
+        .get("content-length")
+        .and_then(|len| len.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await.ok()?;
+    let request = HttpRequest {
+        method,
+        path,
+        headers,
+        body,
+    };
+    Some((request, stream.into_inner()))
+}
+
+/// Entry of the `LdapServer` directory, binds as it need `password`.
+pub struct LdapEntry {
+    pub dn: String,
+    pub password: Option<String>,
+    pub attributes: Vec<(String, Vec<String>)>,
+}
+
+/// LDAP server answering simple binds and searches with an equality filter.
+pub struct LdapServer {
+    pub url: String,
+    /// the DN of each bind and whether it succeeded
+    binds: Arc<Mutex<Vec<(String, bool)>>>,
+}
+
+fn ber(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    match content.len() {
+        len if len < 0x80 => out.push(len as u8),
+        len => {
+            let bytes: Vec<u8> = len
+                .to_be_bytes()
+                .into_iter()
+                .skip_while(|b| *b == 0)
+                .collect();
+            out.push(0x80 | bytes.len() as u8);
+            out.extend(bytes);
+        }
+    }
+    out.extend(content);
+    out
+}
+
+fn ber_string(value: &str) -> Vec<u8> {
+    ber(0x04, value.as_bytes())
+}
+
+/// Tag, content and the rest after the first element of `data`.
+fn ber_next(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, data) = data.split_first()?;
+    let (&len, mut data) = data.split_first()?;
+    let len = if len & 0x80 == 0 {
+        len as usize
+    } else {
+        let (bytes, rest) = data.split_at_checked((len & 0x7f) as usize)?;
+        data = rest;
+        bytes.iter().fold(0, |len, b| len << 8 | *b as usize)
+    };
+    let (content, rest) = data.split_at_checked(len)?;
+    Some((tag, content, rest))
+}
+
+fn ber_elements(mut data: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut elements = Vec::new();
+    while let Some((tag, content, rest)) = ber_next(data) {
+        elements.push((tag, content));
+        data = rest;
+    }
+    elements
+}
+
+impl LdapEntry {
+    fn values(&self, attribute: &[u8]) -> Vec<&String> {
+        let attribute = String::from_utf8_lossy(attribute);
+        self.attributes
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case(&attribute))
+            .flat_map(|(_, values)| values)
+            .collect()
+    }
+
+    /// Only equality filters are understood, like the default ones.
+    fn matches(&self, tag: u8, filter: &[u8]) -> bool {
+        match (tag, &ber_elements(filter)[..]) {
+            (0xa3, [(_, attribute), (_, value)]) => self
+                .values(attribute)
+                .iter()
+                .any(|v| v.as_bytes().eq_ignore_ascii_case(value)),
+            _ => false,
+        }
+    }
+
+    fn search_result(&self, id: &[u8]) -> Vec<u8> {
+        let attributes: Vec<u8> = self
+            .attributes
+            .iter()
+            .flat_map(|(name, values)| {
+                let values: Vec<u8> = values.iter().flat_map(|v| ber_string(v)).collect();
+                ber(0x30, &[ber_string(name), ber(0x31, &values)].concat())
+            })
+            .collect();
+        let entry = ber(
+            0x64,
+            &[ber_string(&self.dn), ber(0x30, &attributes)].concat(),
+        );
+        ber(0x30, &[ber(0x02, id), entry].concat())
+    }
+}
+
+/// LDAP result of operation `op` with `code`, 0 is success.
+fn ldap_result(id: &[u8], op: u8, code: u8) -> Vec<u8> {
+    let result = [ber(0x0a, &[code]), ber_string(""), ber_string("")].concat();
+    ber(0x30, &[ber(0x02, id), ber(op, &result)].concat())
+}
+
+impl LdapServer {
+    pub async fn start(entries: Vec<LdapEntry>) -> Self {
+        let (listener, port) = listen().await;
+        let entries = Arc::new(entries);
+        let binds = Arc::new(Mutex::new(Vec::new()));
+        let received = binds.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(Self::serve(stream, entries.clone(), received.clone()));
+            }
+        });
+        LdapServer {
+            url: format!("ldap://127.0.0.1:{port}"),
+            binds,
+        }
+    }
+
+    pub fn binds(&self) -> Vec<(String, bool)> {
+        self.binds.lock().unwrap().clone()
+    }
+
+    async fn read_message(stream: &mut TcpStream) -> Option<Vec<u8>> {
+        let mut header = vec![stream.read_u8().await.ok()?, stream.read_u8().await.ok()?];
+        let mut len = header[1] as usize;
+        if len & 0x80 != 0 {
+            let mut bytes = vec![0u8; len & 0x7f];
+            stream.read_exact(&mut bytes).await.ok()?;
+            len = bytes.iter().fold(0, |len, b| len << 8 | *b as usize);
+            header.extend(bytes);
+        }
+        let mut content = vec![0u8; len];
+        stream.read_exact(&mut content).await.ok()?;
+        Some([header, content].concat())
+    }
+
+    async fn serve(
+        mut stream: TcpStream,
+        entries: Arc<Vec<LdapEntry>>,
+        binds: Arc<Mutex<Vec<(String, bool)>>>,
+    ) {
+        while let Some(message) = Self::read_message(&mut stream).await {
+            let Some((_, message, _)) = ber_next(&message) else {
+                return;
+            };
+            let elements = ber_elements(message);
+            let [(_, id), (op, request), ..] = elements[..] else {
+                return;
+            };
+            let response = match op {
+                // bind
+                0x60 => {
+                    let [_, (_, dn), (_, password)] = ber_elements(request)[..] else {
+                        return;
+                    };
+                    let dn = String::from_utf8_lossy(dn).into_owned();
+                    let password = String::from_utf8_lossy(password);
+                    let ok = (dn.is_empty() && password.is_empty())
+                        || entries.iter().any(|entry| {
+                            entry.dn == dn
+                                && !password.is_empty()
+                                && entry.password.as_deref() == Some(&password)
+                        });
+                    binds.lock().unwrap().push((dn, ok));
+                    ldap_result(id, 0x61, if ok { 0 } else { 49 })
+                }
+                // search
+                0x63 => {
+                    let elements = ber_elements(request);
+                    let base = String::from_utf8_lossy(elements[0].1).to_lowercase();
+                    let (tag, filter) = elements[6];
+                    let found: Vec<u8> = entries
+                        .iter()
+                        .filter(|entry| entry.dn.to_lowercase().ends_with(&base))
+                        .filter(|entry| entry.matches(tag, filter))
+                        .flat_map(|entry| entry.search_result(id))
+                        .collect();
+                    [found, ldap_result(id, 0x65, 0)].concat()
+                }
+                // unbind
+                _ => return,
+            };
+            if stream.write_all(&response).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// SMTP server accepting every mail without authentication.
+pub struct SmtpServer {
+    pub port: u16,
+    /// the DATA of each mail
+    mails: Arc<Mutex<Vec<String>>>,
+}
+
+impl SmtpServer {
+    pub async fn start() -> Self {
+        let (listener, port) = listen().await;
+        let mails = Arc::new(Mutex::new(Vec::new()));
+        let received = mails.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(Self::serve(stream, received.clone()));
+            }
+        });
+        SmtpServer { port, mails }
+    }
+
+    pub fn mails(&self) -> Vec<String> {
+        self.mails.lock().unwrap().clone()
+    }
+
+    async fn serve(stream: TcpStream, mails: Arc<Mutex<Vec<String>>>) {
+        let (read, mut write) = stream.into_split();
+        let mut read = BufReader::new(read);
+        let _ = write.write_all(b"220 stand-in ESMTP\r\n").await;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if read.read_line(&mut line).await.unwrap_or(0) == 0 {
+                return;
+            }
+            let command = line.get(..4).unwrap_or_default().to_uppercase();
+            let reply: &[u8] = match command.as_str() {
+                "EHLO" => b"250-stand-in\r\n250 8BITMIME\r\n",
+                "DATA" => {
+                    let _ = write.write_all(b"354 go ahead\r\n").await;
+                    let mut data = String::new();
+                    loop {
+                        line.clear();
+                        if read.read_line(&mut line).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        if line == ".\r\n" {
+                            break;
+                        }
+                        data.push_str(&line);
+                    }
+                    mails.lock().unwrap().push(data);
+                    b"250 queued\r\n"
+                }
+                "QUIT" => {
+                    let _ = write.write_all(b"221 bye\r\n").await;
+                    return;
+                }
+                _ => b"250 OK\r\n",
+            };
+            if write.write_all(reply).await.is_err() {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use actix_web::{dev::Server, web, App, HttpRequest, HttpResponse, HttpServer};
+use anyhow::{bail, Context};
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, PrivateKey, ServerConfig,
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Read a PEM certificate chain and the matching private key.
+pub fn load_certified_key(cert: &Path, key: &Path) -> anyhow::Result<CertifiedKey> {
+    let mut reader = BufReader::new(
+        File::open(cert).with_context(|| format!("cannot open {}", cert.display()))?,
+    );
+    let chain: Vec<Certificate> = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if chain.is_empty() {
+        bail!("no certificate found in {}", cert.display());
+    }
+    let mut reader =
+        BufReader::new(File::open(key).with_context(|| format!("cannot open {}", key.display()))?);
+    let key_der = rustls_pemfile::read_all(&mut reader)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::ECKey(der) => Some(der),
+            _ => None,
+        })
+        .with_context(|| format!("no private key found in {}", key.display()))?;
+    let signing_key = sign::any_supported_type(&PrivateKey(key_der))
+        .map_err(|e| anyhow::anyhow!("unsupported private key in {}: {e}", key.display()))?;
+    Ok(CertifiedKey::new(chain, signing_key))
+}
+
+/// Hands out the current certificate, swapped in place on reload so
+/// established listeners pick it up with the next handshake.
+pub struct CertResolver {
+    cert: PathBuf,
+    key: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    pub fn new(cert: &Path, key: &Path) -> anyhow::Result<Arc<Self>> {
+        let current = load_certified_key(cert, key)?;
+        Ok(Arc::new(CertResolver {
+            cert: cert.to_path_buf(),
+            key: key.to_path_buf(),
+            current: RwLock::new(Arc::new(current)),
+        }))
+    }
+
+    /// Load the files again, the old certificate stays in use if they are broken.
+    pub fn reload(&self) {
+        match load_certified_key(&self.cert, &self.key) {
+            Ok(certified_key) => {
+                if let Ok(mut current) = self.current.write() {
+                    *current = Arc::new(certified_key);
+                }
+                log::info!("Reloaded tls certificate {}", self.cert.display());
+            }
+            Err(e) => log::error!("Cannot reload tls certificate, keeping the old one: {e:#}"),
+        }
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        modified(&self.cert).zip(modified(&self.key))
+    }
+
+    pub fn server_config(self: &Arc<Self>) -> ServerConfig {
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.current.read().ok().map(|current| current.clone())
+    }
+}
+
+/// Reload the certificate on SIGHUP and whenever the files change.
+///
+/// The files are polled every `interval`, a zero interval only listens for SIGHUP.
+pub async fn watch(resolver: Arc<CertResolver>, interval: Duration) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => Some(hangup),
+        Err(e) => {
+            log::error!("Cannot listen for SIGHUP, certificates only reload on change: {e}");
+            None
+        }
+    };
+    let mut last_modified = resolver.modified();
+    loop {
+        tokio::select! {
+            Some(()) = async {
+                match hangup.as_mut() {
+                    Some(hangup) => hangup.recv().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                log::info!("Received SIGHUP, reloading tls certificate");
+                resolver.reload();
+                last_modified = resolver.modified();
+            }
+            _ = tokio::time::sleep(interval), if !interval.is_zero() => {
+                let modified = resolver.modified();
+                if modified.is_some() && modified != last_modified {
+                    resolver.reload();
+                    last_modified = modified;
+                }
+            }
+            else => break,
+        }
+    }
+}
+
+async fn redirect(req: HttpRequest, base_url: web::Data<String>) -> HttpResponse {
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    HttpResponse::PermanentRedirect()
+        .append_header(("Location", format!("{}{}", base_url.as_str(), path)))
+        .finish()
+}
+
+/// Plain HTTP listener sending every request to the same path on `base_url`.
+///
+/// The target is always the configured public url, never the `Host` header.
+/// It ignores signals, the shutdown in `main` stops it together with the app.
+pub fn redirect_server(bind: &str, base_url: &str) -> std::io::Result<Server> {
+    let base_url = web::Data::new(base_url.to_string());
+    Ok(HttpServer::new(move || {
+        App::new()
+            .app_data(base_url.clone())
+            .default_service(web::to(redirect))
+    })
+    .workers(1)
+    .disable_signals()
+    .bind(bind)?
+    .run())
+}
@@ -0,0 +1,243 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+/// Seconds a code is valid for.
+const PERIOD: i64 = 30;
+const DIGITS: u32 = 6;
+/// Steps before and after the current one which are accepted as well,
+/// clocks of phones drift.
+const WINDOW: i64 = 1;
+const ISSUER: &str = "MQTTPal";
+/// Length of the nonce in front of the encrypted secret.
+const NONCE_LEN: usize = 12;
+const RECOVERY_CODES: usize = 10;
+
+/// Encrypts the TOTP secrets of the users with the configured `totp_key`.
+///
+/// Without a key two-factor authentication cannot be enrolled, users who
+/// enrolled before cannot finish their login until the key is back.
+pub struct TotpCipher {
+    cipher: Option<Aes256Gcm>,
+}
+
+impl TotpCipher {
+    /// `key` is base64 encoded and has to be 32 bytes long.
+    pub fn new(key: Option<&str>) -> anyhow::Result<Self> {
+        let cipher = key
+            .map(|key| {
+                let key = base64::engine::general_purpose::STANDARD.decode(key)?;
+                Aes256Gcm::new_from_slice(&key)
+                    .map_err(|_| anyhow::anyhow!("totp_key has to be 32 bytes"))
+            })
+            .transpose()?;
+        if cipher.is_none() {
+            log::warn!("No totp_key configured, two-factor authentication is not available");
+        }
+        Ok(TotpCipher { cipher })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    fn cipher(&self) -> anyhow::Result<&Aes256Gcm> {
+        self.cipher
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("no totp_key configured"))
+    }
+
+    /// base64 of the nonce followed by the ciphertext.
+    pub fn encrypt(&self, secret: &[u8]) -> anyhow::Result<String> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend(
+            self.cipher()?
+                .encrypt(&nonce, secret)
+                .map_err(|_| anyhow::anyhow!("cannot encrypt totp secret"))?,
+        );
+        Ok(base64::engine::general_purpose::STANDARD.encode(encrypted))
+    }
+
+    pub fn decrypt(&self, encrypted: &str) -> anyhow::Result<Vec<u8>> {
+        let encrypted = base64::engine::general_purpose::STANDARD.decode(encrypted)?;
+        if encrypted.len() <= NONCE_LEN {
+            anyhow::bail!("encrypted totp secret is too short");
+        }
+        let (nonce, ciphertext) = encrypted.split_at(NONCE_LEN);
+        self.cipher()?
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("cannot decrypt totp secret, was totp_key changed?"))
+    }
+}
+
+/// A random secret of 160 bits as recommended by RFC 4226.
+pub fn generate_secret() -> Vec<u8> {
+    rand::thread_rng().gen::<[u8; 20]>().to_vec()
+}
+
+/// RFC 4648 base32 without padding, the encoding authenticator apps expect.
+pub fn base32(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut encoded = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    encoded
+}
+
+/// `otpauth://` URI to add the account to an authenticator app.
+pub fn provisioning_uri(name: &str, secret: &[u8]) -> String {
+    let label = url::form_urlencoded::byte_serialize(format!("{ISSUER}:{name}").as_bytes())
+        .collect::<String>()
+        .replace('+', "%20");
+    format!(
+        "otpauth://totp/{label}?secret={}&issuer={ISSUER}&algorithm=SHA1&digits={DIGITS}&period={PERIOD}",
+        base32(secret)
+    )
+}
+
+/// The provisioning URI as QR code to scan with the phone.
+pub fn qr_svg(uri: &str) -> anyhow::Result<String> {
+    let code = qrcode::QrCode::new(uri.as_bytes())?;
+    Ok(code
+        .render::<qrcode::render::svg::Color>()
+        .min_dimensions(200, 200)
+        .build())
+}
+
+/// Code of the time step `step` as defined by RFC 6238.
+fn code_at(secret: &[u8], step: i64) -> u32 {
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(secret).expect("hmac takes keys of any size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    binary % 10u32.pow(DIGITS)
+}
+
+/// Check `code` against the current time, returns the matching time step.
+///
+/// Steps up to `last_step` were used already and are refused, so a code
+/// cannot be replayed.
+pub fn verify(secret: &[u8], code: &str, last_step: i64) -> Option<i64> {
+    verify_at(secret, code, last_step, chrono::Utc::now().timestamp())
+}
+
+/// [`verify`] at the unix time `now` in seconds.
+fn verify_at(secret: &[u8], code: &str, last_step: i64, now: i64) -> Option<i64> {
+    let code = code.trim().replace(' ', "");
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let current = now / PERIOD;
+    (current - WINDOW..=current + WINDOW)
+        .filter(|step| *step > last_step)
+        .find(|step| code_at(secret, *step) == code)
+}
+
+/// Fresh recovery codes to show once, together with the hashes to store.
+pub fn generate_recovery_codes() -> (Vec<String>, Vec<String>) {
+    let codes: Vec<String> = (0..RECOVERY_CODES)
+        .map(|_| {
+            let code: String = rand::thread_rng()
+                .sample_iter(Alphanumeric)
+                .filter(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+                .take(10)
+                .map(char::from)
+                .collect();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect();
+    let hashes = codes.iter().map(|code| hash_recovery_code(code)).collect();
+    (codes, hashes)
+}
+
+/// Recovery codes are stored as SHA-256, case and dashes do not matter.
+pub fn hash_recovery_code(code: &str) -> String {
+    let code: String = code
+        .trim()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    format!("{:x}", Sha256::digest(code.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Secret of the SHA-1 test vectors in appendix B of RFC 6238.
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn matches_the_rfc_6238_vectors() {
+        // the RFC lists eight digits, the last six are the code
+        let vectors = [
+            (59, 94287082),
+            (1111111109, 7081804),
+            (1111111111, 14050471),
+            (1234567890, 89005924),
+            (2000000000, 69279037),
+            (20000000000, 65353130),
+        ];
+        for (time, code) in vectors {
+            assert_eq!(
+                code_at(SECRET, time / PERIOD),
+                code % 1_000_000,
+                "at {time}"
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_one_step_either_side() {
+        let now = 1111111111;
+        let step = now / PERIOD;
+        let code = |step| format!("{:06}", code_at(SECRET, step));
+        for offset in -1..=1 {
+            assert_eq!(
+                verify_at(SECRET, &code(step + offset), 0, now),
+                Some(step + offset)
+            );
+        }
+        assert_eq!(verify_at(SECRET, &code(step - 2), 0, now), None);
+        assert_eq!(verify_at(SECRET, &code(step + 2), 0, now), None);
+        // with spaces as some apps show it
+        let spaced = format!("{} {}", &code(step)[..3], &code(step)[3..]);
+        assert_eq!(verify_at(SECRET, &spaced, 0, now), Some(step));
+    }
+
+    #[test]
+    fn refuses_used_steps() {
+        let now = 1234567890;
+        let step = now / PERIOD;
+        let code = format!("{:06}", code_at(SECRET, step));
+        assert_eq!(verify_at(SECRET, &code, step - 1, now), Some(step));
+        assert_eq!(verify_at(SECRET, &code, step, now), None);
+        assert_eq!(verify_at(SECRET, "12345", 0, now), None);
+    }
+}
@@ -0,0 +1,491 @@
+use actix_session::Session;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use askama::Template;
+use serde::Deserialize;
+
+use crate::{
+    audit::AuditLog,
+    error::AppError,
+    login::complete_login,
+    login_throttle::LoginThrottle,
+    middleware::{
+        admin_guard::AdminGuard,
+        fullpage_render::FullPageRender,
+        htmx::{form_error, HtmxHeaders},
+        login_guard::LoginGuard,
+        user_session::UserSession,
+    },
+    models::user::{TotpConfig, User, UserSource},
+    storage::StorageError,
+    totp::{self, TotpCipher},
+};
+
+/// Milliseconds between the password and the second step of a login.
+const PENDING_TIMEOUT: i64 = 5 * 60 * 1000;
+
+pub fn two_factor_scoped(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/2fa")
+            .service(web::resource("/").route(web::delete().to(delete)))
+            .service(
+                web::resource("/setup")
+                    .route(web::get().to(get_setup).wrap(FullPageRender))
+                    .route(web::post().to(post_setup)),
+            )
+            .service(web::resource("/verify").route(web::post().to(post_verify)))
+            .service(web::resource("/recovery").route(web::post().to(post_recovery)))
+            .service(web::resource("/require").route(web::post().to(post_require)))
+            .service(web::resource("/user/{name}").route(web::delete().to(delete_user))),
+    );
+}
+
+/// What has to happen after the password of a local user was right.
+pub enum SecondStep {
+    /// no second factor, the login is complete
+    None,
+    /// ask for a code of the enrolled authenticator
+    Verify,
+    /// the user is an admin without a second factor while it is required
+    Enroll,
+}
+
+impl SecondStep {
+    pub async fn of(
+        repo: &crate::Repo,
+        cipher: &TotpCipher,
+        user: &User,
+    ) -> Result<Self, StorageError> {
+        if user.totp.is_some() {
+            return Ok(SecondStep::Verify);
+        }
+        if user.is_admin() && User::admin_totp_required(repo).await? {
+            if cipher.enabled() {
+                return Ok(SecondStep::Enroll);
+            }
+            log::warn!(
+                "Two-factor authentication is required for admins but no totp_key is configured, \
+                 letting {} in with the password only",
+                user.name
+            );
+        }
+        Ok(SecondStep::None)
+    }
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// Name of the user who passed the password check for `key` a moment ago.
+fn pending(session: &Session, key: &str) -> Option<String> {
+    let since = session.get::<i64>("pending_since").ok().flatten()?;
+    if since + PENDING_TIMEOUT < now() {
+        return None;
+    }
+    session.get::<String>(key).ok().flatten()
+}
+
+/// Answer the password step of a login which needs a second factor.
+///
+/// The session is not logged in until the second step is done.
+pub async fn begin(
+    req: &HttpRequest,
+    session: &Session,
+    cipher: &TotpCipher,
+    user: &User,
+    step: SecondStep,
+) -> Result<HttpResponse, AppError> {
+    let _ = session.insert("pending_since", now());
+    let body = match step {
+        SecondStep::None => {
+            return Err(AppError::Internal(format!(
+                "the login of {} needs no second step",
+                user.name
+            )))
+        }
+        SecondStep::Verify => {
+            let _ = session.insert("pending_2fa", &user.name);
+            TotpVerifyTemplate {}.render()?
+        }
+        SecondStep::Enroll => {
+            log::info!("Admin {} has to enroll a second factor", user.name);
+            let _ = session.insert("pending_enroll", &user.name);
+            TotpSetupTemplate::new(session, cipher, user)?.render()?
+        }
+    };
+    if let Some(htmx) = req.extensions_mut().get_mut::<HtmxHeaders>() {
+        htmx.set_retarget("#mainWindow");
+        htmx.set_reswap("innerHTML");
+    }
+    Ok(HttpResponse::Ok().body(body))
+}
+
+#[derive(Deserialize, Debug)]
+struct CodeForm {
+    code: String,
+}
+
+#[derive(Template)]
+#[template(path = "totp_verify.html")]
+struct TotpVerifyTemplate {}
+
+#[derive(Template)]
+#[template(path = "totp_setup.html")]
+struct TotpSetupTemplate {
+    uri: String,
+    secret: String,
+    qr: String,
+}
+
+impl TotpSetupTemplate {
+    /// Start an enrollment with a fresh secret kept in the session until confirmed.
+    fn new(session: &Session, cipher: &TotpCipher, user: &User) -> Result<Self, AppError> {
+        let secret = totp::generate_secret();
+        let encrypted = cipher
+            .encrypt(&secret)
+            .map_err(|e| AppError::Internal(format!("cannot start enrollment: {e:#}")))?;
+        let _ = session.insert("totp_setup", encrypted);
+        let uri = totp::provisioning_uri(&user.name, &secret);
+        let qr = totp::qr_svg(&uri)
+            .map_err(|e| AppError::Internal(format!("cannot render qr code: {e}")))?;
+        Ok(TotpSetupTemplate {
+            secret: totp::base32(&secret),
+            uri,
+            qr,
+        })
+    }
+}
+
+#[derive(Template)]
+#[template(path = "totp_recovery.html")]
+struct TotpRecoveryTemplate {
+    codes: Vec<String>,
+    /// the codes were shown as the last step of a login
+    login: bool,
+}
+
+/// Second factor section of the user edit page.
+#[derive(Template)]
+#[template(path = "user_totp.html")]
+pub struct UserTotpTemplate {
+    pub user: User,
+    /// the page shows the logged in user
+    pub own: bool,
+    pub totp_available: bool,
+}
+
+/// Whether 2FA is required for admins, shown on the users page.
+#[derive(Template)]
+#[template(path = "totp_policy.html")]
+pub struct TotpPolicyTemplate {
+    pub require_admin_totp: bool,
+    pub totp_available: bool,
+}
+
+/// The local user enrolling a second factor, logged in or in the middle of a login.
+async fn enrolling_user(
+    repo: &crate::Repo,
+    usersession: &UserSession,
+    session: &Session,
+) -> Result<User, AppError> {
+    let name = usersession
+        .username
+        .clone()
+        .or_else(|| pending(session, "pending_enroll"))
+        .ok_or_else(|| AppError::Forbidden("not logged in".into()))?;
+    match User::get_by_name(repo, &name).await? {
+        Some(user) if user.source == UserSource::Local => Ok(user),
+        Some(_) => Err(AppError::BadRequest(
+            "Only local users log in with a second factor.".into(),
+        )),
+        None => Err(AppError::NotFound("User".into())),
+    }
+}
+
+fn require_cipher(cipher: &TotpCipher) -> Result<(), AppError> {
+    if cipher.enabled() {
+        Ok(())
+    } else {
+        Err(AppError::BadRequest(
+            "Two-factor authentication is not configured.".into(),
+        ))
+    }
+}
+
+async fn get_setup(
+    repo: web::Data<crate::Repo>,
+    cipher: web::Data<TotpCipher>,
+    usersession: UserSession,
+    session: Session,
+) -> Result<HttpResponse, AppError> {
+    require_cipher(&cipher)?;
+    let user = enrolling_user(&repo, &usersession, &session).await?;
+    let template = TotpSetupTemplate::new(&session, &cipher, &user)?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn post_setup(
+    req: HttpRequest,
+    repo: web::Data<crate::Repo>,
+    cipher: web::Data<TotpCipher>,
+    throttle: web::Data<LoginThrottle>,
+    audit: web::Data<AuditLog>,
+    usersession: UserSession,
+    session: Session,
+    form: web::Form<CodeForm>,
+) -> Result<HttpResponse, AppError> {
+    require_cipher(&cipher)?;
+    let mut user = enrolling_user(&repo, &usersession, &session).await?;
+    let Some(encrypted) = session.get::<String>("totp_setup").ok().flatten() else {
+        return Err(AppError::BadRequest("Start the enrollment again.".into()));
+    };
+    let login = usersession.username.is_none();
+    let ip = throttle.client_ip(&req);
+    // an enrollment during a login is part of it and throttled like it
+    let attempt = if login {
+        match throttle.attempt(&user.name, &ip).await? {
+            Ok(attempt) => Some(attempt),
+            Err(blocked) => {
+                log::info!("Refusing enrollment of {} from {}", user.name, ip);
+                audit.login_failed(&user.name, "locked", &ip).await;
+                return Ok(form_error(&req, "#form-errors", &blocked.message()));
+            }
+        }
+    } else {
+        None
+    };
+    let secret = cipher
+        .decrypt(&encrypted)
+        .map_err(|e| AppError::Internal(format!("cannot finish enrollment: {e:#}")))?;
+    let Some(step) = totp::verify(&secret, &form.code, 0) else {
+        if let Some(attempt) = &attempt {
+            throttle.failed(attempt);
+            audit
+                .login_failed(&user.name, "wrong enrollment code", &ip)
+                .await;
+        }
+        return Ok(form_error(
+            &req,
+            "#form-errors",
+            "Code wrong, check the clock of your phone.",
+        ));
+    };
+    let (codes, recovery_codes) = totp::generate_recovery_codes();
+    user.totp = Some(TotpConfig {
+        secret: encrypted,
+        recovery_codes,
+        last_step: step,
+    });
+    user.insert(&repo).await?;
+    session.remove("totp_setup");
+    log::info!("{} enrolled a second factor", user.name);
+    if let Some(attempt) = &attempt {
+        throttle.succeeded(attempt).await?;
+        complete_login(&session, &user.name);
+        audit.login_succeeded(&user.name, &user.source, &ip).await;
+    }
+    let template = TotpRecoveryTemplate { codes, login };
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+/// The user `name` with the second factor taken out and its decrypted secret.
+async fn enrolled(
+    repo: &crate::Repo,
+    cipher: &TotpCipher,
+    name: &str,
+) -> Result<(User, TotpConfig, Vec<u8>), AppError> {
+    let Some(mut user) = User::get_by_name(repo, name).await? else {
+        return Err(AppError::NotFound("User".into()));
+    };
+    let Some(config) = user.totp.take() else {
+        return Err(AppError::BadRequest(
+            "Two-factor authentication was turned off, log in again.".into(),
+        ));
+    };
+    let secret = cipher.decrypt(&config.secret).map_err(|e| {
+        log::error!("Cannot check second factor of {name}: {e:#}");
+        AppError::Internal("cannot check the code".into())
+    })?;
+    Ok((user, config, secret))
+}
+
+async fn post_verify(
+    req: HttpRequest,
+    repo: web::Data<crate::Repo>,
+    cipher: web::Data<TotpCipher>,
+    throttle: web::Data<LoginThrottle>,
+    audit: web::Data<AuditLog>,
+    session: Session,
+    form: web::Form<CodeForm>,
+) -> Result<HttpResponse, AppError> {
+    let Some(name) = pending(&session, "pending_2fa") else {
+        if let Some(htmx) = req.extensions_mut().get_mut::<HtmxHeaders>() {
+            htmx.set_redirect("/login/");
+        }
+        return Ok(HttpResponse::Unauthorized().body("Log in with your password again."));
+    };
+    let ip = throttle.client_ip(&req);
+    let attempt = match throttle.attempt(&name, &ip).await? {
+        Ok(attempt) => attempt,
+        Err(blocked) => {
+            log::info!("Refusing second factor of {} from {}", name, ip);
+            audit.login_failed(&name, "locked", &ip).await;
+            return Ok(form_error(&req, "#form-errors", &blocked.message()));
+        }
+    };
+    // nobody is to blame if the code cannot be checked at all
+    let (mut user, mut config, secret) = match enrolled(&repo, &cipher, &name).await {
+        Ok(enrolled) => enrolled,
+        Err(e) => {
+            throttle.release(&attempt).await?;
+            return Err(e);
+        }
+    };
+    let accepted = if let Some(step) = totp::verify(&secret, &form.code, config.last_step) {
+        config.last_step = step;
+        true
+    } else {
+        let hash = totp::hash_recovery_code(&form.code);
+        let before = config.recovery_codes.len();
+        config.recovery_codes.retain(|code| *code != hash);
+        let used = config.recovery_codes.len() < before;
+        if used {
+            log::info!(
+                "{} logged in with a recovery code, {} left",
+                name,
+                config.recovery_codes.len()
+            );
+        }
+        used
+    };
+    if !accepted {
+        throttle.failed(&attempt);
+        audit.login_failed(&name, "wrong second factor", &ip).await;
+        return Ok(form_error(&req, "#form-errors", "Code wrong."));
+    }
+    user.totp = Some(config);
+    if let Err(e) = user.insert(&repo).await {
+        throttle.release(&attempt).await?;
+        return Err(e.into());
+    }
+    throttle.succeeded(&attempt).await?;
+    session.remove("pending_2fa");
+    session.remove("pending_since");
+    complete_login(&session, &name);
+    audit.login_succeeded(&name, &user.source, &ip).await;
+    if let Some(htmx) = req.extensions_mut().get_mut::<HtmxHeaders>() {
+        htmx.set_redirect("/");
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Replace the recovery codes of the logged in user.
+async fn post_recovery(
+    _: LoginGuard,
+    repo: web::Data<crate::Repo>,
+    usersession: UserSession,
+    session: Session,
+) -> Result<HttpResponse, AppError> {
+    let mut user = enrolling_user(&repo, &usersession, &session).await?;
+    let Some(config) = user.totp.as_mut() else {
+        return Err(AppError::BadRequest(
+            "Two-factor authentication is not enabled.".into(),
+        ));
+    };
+    let (codes, recovery_codes) = totp::generate_recovery_codes();
+    config.recovery_codes = recovery_codes;
+    user.insert(&repo).await?;
+    log::info!("{} replaced the recovery codes", user.name);
+    let template = TotpRecoveryTemplate {
+        codes,
+        login: false,
+    };
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+async fn remove_totp(
+    repo: &crate::Repo,
+    cipher: &TotpCipher,
+    mut user: User,
+    own: bool,
+) -> Result<HttpResponse, AppError> {
+    user.totp = None;
+    user.insert(repo).await?;
+    let template = UserTotpTemplate {
+        user,
+        own,
+        totp_available: cipher.enabled(),
+    };
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+/// Turn off the second factor of the logged in user.
+async fn delete(
+    _: LoginGuard,
+    repo: web::Data<crate::Repo>,
+    cipher: web::Data<TotpCipher>,
+    usersession: UserSession,
+    session: Session,
+) -> Result<HttpResponse, AppError> {
+    let user = enrolling_user(&repo, &usersession, &session).await?;
+    if user.is_admin() && User::admin_totp_required(&repo).await? {
+        return Err(AppError::BadRequest(
+            "Admins have to keep two-factor authentication.".into(),
+        ));
+    }
+    log::info!("{} turned off two-factor authentication", user.name);
+    remove_totp(&repo, &cipher, user, true).await
+}
+
+/// Reset the second factor of a user who lost the authenticator.
+async fn delete_user(
+    admin: AdminGuard,
+    repo: web::Data<crate::Repo>,
+    cipher: web::Data<TotpCipher>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let Some(user) = User::get_by_name(&repo, &name).await? else {
+        return Err(AppError::NotFound("User".into()));
+    };
+    log::info!(
+        "{} reset two-factor authentication of {}",
+        admin.username,
+        user.name
+    );
+    let own = admin.username == user.name;
+    remove_totp(&repo, &cipher, user, own).await
+}
+
+#[derive(Deserialize, Debug)]
+struct RequireForm {
+    /// checkbox, missing when unchecked
+    required: Option<String>,
+}
+
+async fn post_require(
+    admin: AdminGuard,
+    repo: web::Data<crate::Repo>,
+    cipher: web::Data<TotpCipher>,
+    form: web::Form<RequireForm>,
+) -> Result<HttpResponse, AppError> {
+    let required = form.required.is_some();
+    if required {
+        require_cipher(&cipher)?;
+    }
+    User::set_admin_totp_required(&repo, required).await?;
+    log::info!(
+        "{} {} two-factor authentication for admins",
+        admin.username,
+        if required {
+            "required"
+        } else {
+            "stopped requiring"
+        }
+    );
+    let template = TotpPolicyTemplate {
+        require_admin_totp: required,
+        totp_available: cipher.enabled(),
+    };
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
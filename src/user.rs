@@ -1,10 +1,21 @@
-use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
 use askama::Template;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    middleware::{fullpage_render::FullPageRender, htmx::HtmxHeaders, login_guard::LoginGuard},
-    models::user::{Role, User},
+    audit::Auditor,
+    error::AppError,
+    login_throttle::{LockStatus, LoginThrottle},
+    middleware::{
+        admin_guard::AdminGuard, fullpage_render::FullPageRender, htmx::HtmxHeaders,
+        login_guard::LoginGuard, user_session::UserSession,
+    },
+    models::{
+        audit::{self, AuditAction},
+        user::{Role, User, UserSource},
+    },
+    session_store::ServerSessionStore,
+    totp::TotpCipher,
     users::UserListTemplate,
 };
 
@@ -15,6 +26,7 @@ pub fn user_scoped(cfg: &mut web::ServiceConfig) {
             .service(
                 web::resource("/{id}/edit").route(web::get().to(get_edit).wrap(FullPageRender)),
             )
+            .service(web::resource("/{id}/unlock").route(web::post().to(post_unlock)))
             .service(web::resource("/").route(web::post().to(post))),
     );
 }
@@ -22,17 +34,23 @@ pub fn user_scoped(cfg: &mut web::ServiceConfig) {
 async fn delete(
     _: LoginGuard,
     req: HttpRequest,
-    db: web::Data<crate::DbPool>,
+    repo: web::Data<crate::Repo>,
+    store: web::Data<ServerSessionStore>,
+    auditor: Auditor,
     name: web::Path<String>,
-) -> impl Responder {
-    let deleted = User::delete(&db, &name).await;
+) -> Result<HttpResponse, AppError> {
+    let previous = User::get_by_name(&repo, &name).await?;
+    let deleted = User::delete(&repo, &name).await?;
     if deleted {
+        store.revoke_user(&name).await?;
+        let diff = audit::diff(previous.as_ref(), None);
+        auditor.record(AuditAction::UserDelete, &name, diff).await;
         if let Some(htmx) = req.extensions_mut().get_mut::<HtmxHeaders>() {
             htmx.set_redirect("/users/");
         }
-        HttpResponse::Ok().body("User deleted.")
+        Ok(HttpResponse::Ok().body("User deleted."))
     } else {
-        HttpResponse::NotFound().body("User not found.")
+        Err(AppError::NotFound("User".into()))
     }
 }
 
@@ -52,10 +70,12 @@ impl From<UserForm> for User {
             email: form.email,
             role_id: form.role_id.unwrap_or(Role::User as i32),
             source: crate::models::user::UserSource::Local,
+            totp: None,
         }
     }
 }
 
+#[allow(dead_code)]
 #[derive(Template)]
 #[template(path = "user_row.html")]
 struct UserRowTemplate {
@@ -64,32 +84,83 @@ struct UserRowTemplate {
 
 async fn post(
     _: LoginGuard,
-    db: web::Data<crate::DbPool>,
+    repo: web::Data<crate::Repo>,
+    store: web::Data<ServerSessionStore>,
+    cipher: web::Data<TotpCipher>,
+    auditor: Auditor,
     form: web::Form<UserForm>,
-) -> impl Responder {
-    let user: User = form.into_inner().into();
-    user.insert(&db).await;
-    let users = User::list(&db).await;
-    let template = UserListTemplate { users };
-    HttpResponse::Ok().body(template.render().unwrap())
+) -> Result<HttpResponse, AppError> {
+    let mut user: User = form.into_inner().into();
+    let previous = User::get_by_name(&repo, &user.name).await?;
+    // the form does not carry the second factor
+    user.totp = previous.as_ref().and_then(|previous| previous.totp.clone());
+    user.insert(&repo).await?;
+    let action = match previous {
+        Some(_) => AuditAction::UserUpdate,
+        None => AuditAction::UserCreate,
+    };
+    let diff = audit::diff(previous.as_ref(), Some(&user));
+    auditor.record(action, &user.name, diff).await;
+    // sessions keep the rights of the old role otherwise
+    if previous.is_some_and(|previous| previous.role_id != user.role_id) {
+        store.revoke_user(&user.name).await?;
+    }
+    let template = UserListTemplate::load(&repo, &cipher).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
 }
 
 #[derive(Template)]
 #[template(path = "user_edit.html")]
 struct UserEditTemplate {
     user: User,
+    lock: LockStatus,
+    /// the page shows the logged in user
+    own: bool,
+    totp_available: bool,
 }
 
 async fn get_edit(
     _: LoginGuard,
-    db: web::Data<crate::DbPool>,
+    repo: web::Data<crate::Repo>,
+    throttle: web::Data<LoginThrottle>,
+    cipher: web::Data<TotpCipher>,
+    usersession: UserSession,
     name: web::Path<String>,
-) -> impl Responder {
-    let user = User::get_by_name(&db, &name).await;
+) -> Result<HttpResponse, AppError> {
+    let user = User::get_by_name(&repo, &name).await?;
     if let Some(user) = user {
-        let template = UserEditTemplate { user };
-        HttpResponse::Ok().body(template.render().unwrap())
+        let lock = throttle.status(&user.name).await?;
+        let own = usersession.username.as_deref() == Some(user.name.as_str());
+        let template = UserEditTemplate {
+            user,
+            lock,
+            own,
+            totp_available: cipher.enabled(),
+        };
+        Ok(HttpResponse::Ok().body(template.render()?))
     } else {
-        HttpResponse::NotFound().body("User not found")
+        Err(AppError::NotFound("User".into()))
     }
 }
+
+#[derive(Template)]
+#[template(path = "user_lock.html")]
+struct UserLockTemplate {
+    user: User,
+    lock: LockStatus,
+}
+
+async fn post_unlock(
+    _: AdminGuard,
+    repo: web::Data<crate::Repo>,
+    throttle: web::Data<LoginThrottle>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let Some(user) = User::get_by_name(&repo, &name).await? else {
+        return Err(AppError::NotFound("User".into()));
+    };
+    throttle.unlock(&user.name).await?;
+    let lock = throttle.status(&user.name).await?;
+    let template = UserLockTemplate { user, lock };
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
@@ -1,8 +1,11 @@
 use crate::{
+    error::AppError,
     middleware::{fullpage_render::FullPageRender, login_guard::LoginGuard},
     models::user::User,
+    storage::StorageError,
+    totp::TotpCipher,
 };
-use actix_web::{get, web, HttpResponse, Responder};
+use actix_web::{get, web, HttpResponse};
 use askama::Template;
 
 pub fn users_scoped(cfg: &mut web::ServiceConfig) {
@@ -13,11 +16,26 @@ pub fn users_scoped(cfg: &mut web::ServiceConfig) {
 #[template(path = "users.html")]
 pub struct UserListTemplate {
     pub users: Vec<User>,
+    pub require_admin_totp: bool,
+    pub totp_available: bool,
+}
+
+impl UserListTemplate {
+    pub async fn load(repo: &crate::Repo, cipher: &TotpCipher) -> Result<Self, StorageError> {
+        Ok(UserListTemplate {
+            users: User::list(repo).await?,
+            require_admin_totp: User::admin_totp_required(repo).await?,
+            totp_available: cipher.enabled(),
+        })
+    }
 }
 
 #[get("/")]
-async fn get(_: LoginGuard, db: web::Data<crate::DbPool>) -> impl Responder {
-    let users = User::list(&db).await;
-    let template = UserListTemplate { users };
-    HttpResponse::Ok().body(template.render().unwrap())
+async fn get(
+    _: LoginGuard,
+    repo: web::Data<crate::Repo>,
+    cipher: web::Data<TotpCipher>,
+) -> Result<HttpResponse, AppError> {
+    let template = UserListTemplate::load(&repo, &cipher).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
 }
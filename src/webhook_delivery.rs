@@ -0,0 +1,268 @@
+use std::time::Duration;
+
+use actix_web::http::header::CONTENT_TYPE;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{
+    models::webhook::{DeliveryLogEntry, Webhook, WebhookDelivery},
+    storage::StorageError,
+};
+
+/// Deliveries are dropped after this many failed attempts.
+const MAX_ATTEMPTS: u32 = 8;
+/// Deliveries sent concurrently per poll.
+const BATCH_SIZE: usize = 20;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Delay before attempt `attempt + 1`: 5s, 10s, 20s, ... capped at 30 minutes.
+fn backoff_millis(attempt: u32) -> i64 {
+    let delay = 5_000i64.saturating_mul(1 << attempt.saturating_sub(1).min(20));
+    delay.min(30 * 60 * 1000)
+}
+
+/// Hex encoded HMAC-SHA256 of `body`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Background task working off the webhook delivery queue, `log_max_len`
+/// delivery log entries are kept per webhook.
+pub async fn run(pool: crate::DbPool, log_max_len: usize) {
+    let http = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("Cannot create http client");
+    if let Err(e) =
+        WebhookDelivery::requeue_inflight(&pool, chrono::Utc::now().timestamp_millis()).await
+    {
+        log::error!("Cannot requeue in-flight webhook deliveries: {e}");
+    }
+    loop {
+        // run each poll on its own task so a failing redis does not end the worker
+        let poll = tokio::spawn(deliver_due(pool.clone(), http.clone(), log_max_len));
+        if let Err(e) = poll.await {
+            log::error!("Webhook delivery poll failed: {e}");
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+async fn deliver_due(pool: crate::DbPool, http: reqwest::Client, log_max_len: usize) {
+    let now = chrono::Utc::now().timestamp_millis();
+    let deliveries = match WebhookDelivery::claim_due(&pool, now, BATCH_SIZE).await {
+        Ok(deliveries) => deliveries,
+        Err(e) => {
+            log::error!("Cannot claim webhook deliveries: {e}");
+            return;
+        }
+    };
+    futures_util::future::join_all(deliveries.into_iter().map(|delivery| async {
+        let id = delivery.id.clone();
+        if let Err(e) = deliver(&pool, &http, delivery, log_max_len).await {
+            log::error!("Webhook delivery {id} failed to update its state: {e}");
+        }
+    }))
+    .await;
+}
+
+async fn deliver(
+    pool: &crate::DbPool,
+    http: &reqwest::Client,
+    delivery: WebhookDelivery,
+    log_max_len: usize,
+) -> Result<(), StorageError> {
+    let Some(webhook) = Webhook::get_by_name(pool, &delivery.webhook).await? else {
+        log::info!("Dropping delivery for deleted webhook {}", delivery.webhook);
+        return delivery.finish(pool).await;
+    };
+    let body = serde_json::to_vec(&delivery.envelope)?;
+    let mut request = http
+        .post(&webhook.url)
+        .header(CONTENT_TYPE, "application/json")
+        .header("X-Mqttpal-Webhook", &webhook.name)
+        .header("X-Mqttpal-Delivery", &delivery.id);
+    if let Some(secret) = &webhook.secret {
+        request = request.header(
+            "X-Mqttpal-Signature",
+            format!("sha256={}", sign(secret, &body)),
+        );
+    }
+    let (status, error) = match request.body(body).send().await {
+        Ok(response) if response.status().is_success() => (Some(response.status().as_u16()), None),
+        Ok(response) => (
+            Some(response.status().as_u16()),
+            Some(format!("unexpected status {}", response.status())),
+        ),
+        Err(e) => (None, Some(e.to_string())),
+    };
+    let retry = error.is_some() && delivery.attempt < MAX_ATTEMPTS;
+    match &error {
+        Some(e) => log::warn!(
+            "Webhook {} delivery {} attempt {} failed: {}",
+            webhook.name,
+            delivery.id,
+            delivery.attempt,
+            e
+        ),
+        None => log::info!("Webhook {} delivered {}", webhook.name, delivery.id),
+    }
+    let now = chrono::Utc::now().timestamp_millis();
+    DeliveryLogEntry {
+        timestamp: now,
+        delivery: delivery.id.clone(),
+        topic: delivery.envelope.topic.clone(),
+        attempt: delivery.attempt,
+        status,
+        error,
+        retry,
+    }
+    .insert(pool, &webhook.name, log_max_len)
+    .await?;
+    if retry {
+        let next = WebhookDelivery {
+            attempt: delivery.attempt + 1,
+            ..delivery.clone()
+        };
+        next.enqueue(pool, now + backoff_millis(delivery.attempt))
+            .await?;
+    }
+    delivery.finish(pool).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{
+        models::webhook::{WebhookEnvelope, LOG_MAX_LEN},
+        test_support::{redis_pool, wait_for, HttpServer},
+    };
+
+    fn delivery(id: &str) -> WebhookDelivery {
+        WebhookDelivery {
+            id: id.into(),
+            webhook: "hook".into(),
+            attempt: 1,
+            envelope: WebhookEnvelope {
+                client: "c1".into(),
+                topic: "sensors/1".into(),
+                payload: "21.5".into(),
+                encoding: "utf8".into(),
+                qos: 0,
+                retain: false,
+                timestamp: "2026-01-01T00:00:00+00:00".into(),
+            },
+        }
+    }
+
+    async fn setup(server: &HttpServer) -> (crate::DbPool, reqwest::Client) {
+        let pool = redis_pool().await;
+        Webhook {
+            name: "hook".into(),
+            client: "c1".into(),
+            topic: "sensors/#".into(),
+            url: format!("{}/hook", server.url),
+            secret: Some("s3cret".into()),
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+        (pool, reqwest::Client::new())
+    }
+
+    #[tokio::test]
+    async fn signs_and_retries_once_after_server_error() {
+        let calls = AtomicUsize::new(0);
+        let server = HttpServer::start(move |_| match calls.fetch_add(1, Ordering::SeqCst) {
+            0 => (503, serde_json::json!({})),
+            _ => (200, serde_json::json!({})),
+        })
+        .await;
+        let (pool, http) = setup(&server).await;
+        let now = chrono::Utc::now().timestamp_millis();
+        delivery("d1").enqueue(&pool, now).await.unwrap();
+
+        deliver_due(pool.clone(), http.clone(), LOG_MAX_LEN).await;
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/hook");
+        assert_eq!(request.header("x-mqttpal-delivery"), Some("d1"));
+        let signature = format!("sha256={}", sign("s3cret", &request.body));
+        assert_eq!(
+            request.header("x-mqttpal-signature"),
+            Some(signature.as_str())
+        );
+        assert_eq!(request.json()["topic"], "sensors/1");
+
+        // the retry waits for its backoff
+        let now = chrono::Utc::now().timestamp_millis();
+        let due = WebhookDelivery::claim_due(&pool, now, BATCH_SIZE)
+            .await
+            .unwrap();
+        assert!(due.is_empty());
+        let later = now + backoff_millis(1);
+        let due = WebhookDelivery::claim_due(&pool, later, BATCH_SIZE)
+            .await
+            .unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].attempt, 2);
+        for delivery in due {
+            deliver(&pool, &http, delivery, LOG_MAX_LEN).await.unwrap();
+        }
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].body, requests[0].body);
+
+        let log = DeliveryLogEntry::list(&pool, "hook").await.unwrap();
+        let attempts: Vec<_> = log
+            .iter()
+            .map(|entry| (entry.attempt, entry.status, entry.retry))
+            .collect();
+        assert_eq!(attempts, [(2, Some(200), false), (1, Some(503), true)]);
+        let due = WebhookDelivery::claim_due(&pool, i64::MAX, BATCH_SIZE)
+            .await
+            .unwrap();
+        assert!(due.is_empty());
+    }
+
+    #[tokio::test]
+    async fn requeues_deliveries_in_flight_during_a_restart() {
+        let server = HttpServer::start(|_| (200, serde_json::json!({}))).await;
+        let (pool, http) = setup(&server).await;
+        let now = chrono::Utc::now().timestamp_millis();
+        delivery("d2").enqueue(&pool, now).await.unwrap();
+        // claimed, but the process stops before delivering it
+        let claimed = WebhookDelivery::claim_due(&pool, now, BATCH_SIZE)
+            .await
+            .unwrap();
+        assert_eq!(claimed.len(), 1);
+        assert!(WebhookDelivery::claim_due(&pool, i64::MAX, BATCH_SIZE)
+            .await
+            .unwrap()
+            .is_empty());
+
+        WebhookDelivery::requeue_inflight(&pool, now).await.unwrap();
+        deliver_due(pool.clone(), http, LOG_MAX_LEN).await;
+        let requests = wait_for(|| Some(server.requests()).filter(|r| !r.is_empty())).await;
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].header("x-mqttpal-delivery"), Some("d2"));
+        // delivered once, nothing is left to requeue
+        WebhookDelivery::requeue_inflight(&pool, now).await.unwrap();
+        assert!(WebhookDelivery::claim_due(&pool, i64::MAX, BATCH_SIZE)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
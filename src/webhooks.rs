@@ -0,0 +1,151 @@
+use crate::{
+    error::AppError,
+    middleware::{fullpage_render::FullPageRender, htmx::form_error, login_guard::LoginGuard},
+    models::{
+        mqtt_client::MqttClient,
+        webhook::{DeliveryLogEntry, Webhook},
+    },
+    mqtt::MqttClientManager,
+    storage::StorageError,
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use askama::Template;
+use serde::{Deserialize, Serialize};
+
+pub fn webhooks_scoped(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/webhooks")
+            .service(
+                web::resource("/")
+                    .route(web::get().to(get).wrap(FullPageRender))
+                    .route(web::post().to(post)),
+            )
+            .service(web::resource("/{name}").route(web::delete().to(delete)))
+            .service(
+                web::resource("/{name}/log").route(web::get().to(get_log).wrap(FullPageRender)),
+            ),
+    );
+}
+
+#[derive(Template)]
+#[template(path = "webhooks.html")]
+struct WebhookListTemplate {
+    webhooks: Vec<Webhook>,
+    clients: Vec<String>,
+}
+
+impl WebhookListTemplate {
+    async fn load(db: &crate::DbPool, repo: &crate::Repo) -> Result<Self, StorageError> {
+        let mut clients: Vec<String> = MqttClient::list(repo)
+            .await?
+            .into_iter()
+            .map(|c| c.name)
+            .collect();
+        clients.sort();
+        Ok(WebhookListTemplate {
+            webhooks: Webhook::list(db).await?,
+            clients,
+        })
+    }
+}
+
+async fn get(
+    _: LoginGuard,
+    db: web::Data<crate::DbPool>,
+    repo: web::Data<crate::Repo>,
+) -> Result<HttpResponse, AppError> {
+    let template = WebhookListTemplate::load(&db, &repo).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct WebhookForm {
+    name: String,
+    client: String,
+    topic: String,
+    url: String,
+    secret: Option<String>,
+}
+
+impl TryFrom<WebhookForm> for Webhook {
+    type Error = String;
+    fn try_from(form: WebhookForm) -> Result<Self, Self::Error> {
+        if form.name.trim().is_empty() {
+            return Err("Webhook name is required.".into());
+        }
+        if !rumqttc::valid_filter(&form.topic) {
+            return Err(format!("'{}' is not a valid topic filter.", form.topic));
+        }
+        match reqwest::Url::parse(&form.url) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+            _ => return Err(format!("'{}' is not a valid http(s) url.", form.url)),
+        }
+        Ok(Webhook {
+            name: form.name,
+            client: form.client,
+            topic: form.topic,
+            url: form.url,
+            secret: form.secret.filter(|s| !s.is_empty()),
+        })
+    }
+}
+
+async fn post(
+    _: LoginGuard,
+    req: HttpRequest,
+    db: web::Data<crate::DbPool>,
+    repo: web::Data<crate::Repo>,
+    mqtt: web::Data<MqttClientManager>,
+    form: web::Form<WebhookForm>,
+) -> Result<HttpResponse, AppError> {
+    let webhook: Webhook = match form.into_inner().try_into() {
+        Ok(webhook) => webhook,
+        Err(e) => return Ok(form_error(&req, "#webhook-errors", &e)),
+    };
+    if Webhook::get_by_name(&db, &webhook.name).await?.is_some() {
+        let message = format!("Webhook '{}' already exists.", webhook.name);
+        return Ok(form_error(&req, "#webhook-errors", &message));
+    }
+    webhook.insert(&db).await?;
+    mqtt.reload_webhooks().await;
+    if mqtt.get_client_actor_addr(&webhook.client).await.is_some() {
+        let _ = mqtt.subscribe(&webhook.client, &webhook.topic).await;
+    }
+    let template = WebhookListTemplate::load(&db, &repo).await?;
+    Ok(HttpResponse::Ok().body(template.render()?))
+}
+
+async fn delete(
+    _: LoginGuard,
+    db: web::Data<crate::DbPool>,
+    mqtt: web::Data<MqttClientManager>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let Some(webhook) = Webhook::get_by_name(&db, &name).await? else {
+        return Err(AppError::NotFound("Webhook".into()));
+    };
+    Webhook::delete(&db, &name).await?;
+    mqtt.reload_webhooks().await;
+    mqtt.release_filter(&webhook.client, &webhook.topic).await?;
+    Ok(HttpResponse::Ok().body(""))
+}
+
+#[derive(Template)]
+#[template(path = "webhook_log.html")]
+struct WebhookLogTemplate {
+    webhook: Webhook,
+    entries: Vec<DeliveryLogEntry>,
+}
+
+async fn get_log(
+    _: LoginGuard,
+    db: web::Data<crate::DbPool>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let Some(webhook) = Webhook::get_by_name(&db, &name).await? else {
+        return Err(AppError::NotFound("Webhook".into()));
+    };
+    let entries = DeliveryLogEntry::list(&db, &name).await?;
+    let template = WebhookLogTemplate { webhook, entries };
+    Ok(HttpResponse::Ok().body(template.render()?))
+}